@@ -0,0 +1,199 @@
+use base58;
+use checksum::{double_sha512_hash,sha512_hash};
+use message::{read_var_int,write_var_int_64};
+use std::error::Error;
+use std::fmt;
+use std::io::Cursor;
+use std::str::FromStr;
+
+const PREFIX: &'static str = "BM-";
+
+#[derive(Clone,Debug,PartialEq,Eq,Hash)]
+pub struct Address {
+    version: u64,
+    stream: u32,
+    ripe: Vec<u8> // 20 bytes
+}
+
+#[derive(Debug,PartialEq)]
+pub enum AddressError {
+    BadEncoding,
+    BadChecksum,
+    UnsupportedVersion(u64),
+    BadRipeLength
+}
+
+impl fmt::Display for AddressError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &AddressError::BadEncoding => write!(f, "Address is not valid base58"),
+            &AddressError::BadChecksum => write!(f, "Address checksum does not match - check for typing mistakes"),
+            &AddressError::UnsupportedVersion(version) => write!(f, "Address version {} is not supported", version),
+            &AddressError::BadRipeLength => write!(f, "Address has the wrong length")
+        }
+    }
+}
+
+impl Error for AddressError {}
+
+impl Address {
+    pub fn new(version: u64, stream: u32, ripe: &[u8]) -> Address {
+        assert!(ripe.len() == 20);
+        Address {
+            version: version,
+            stream: stream,
+            ripe: ripe.to_vec()
+        }
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn stream(&self) -> u32 {
+        self.stream
+    }
+
+    pub fn ripe(&self) -> &[u8] {
+        &self.ripe
+    }
+
+    // Anyone who knows the address can derive this key, which v4 pubkeys and v5 broadcasts are encrypted to
+    pub fn tag_private_key(&self) -> [u8; 32] {
+        let mut private_key = [0u8; 32];
+        private_key.copy_from_slice(&self.double_hash()[0..32]);
+        private_key
+    }
+
+    pub fn tag(&self) -> Vec<u8> {
+        self.double_hash()[32..64].to_vec()
+    }
+
+    // Older addresses have no tag, and their broadcasts use a single hash of the address data instead
+    pub fn broadcast_private_key(&self) -> [u8; 32] {
+        if self.version >= 4 {
+            return self.tag_private_key();
+        }
+
+        let mut private_key = [0u8; 32];
+        private_key.copy_from_slice(&sha512_hash(&self.hash_input())[0..32]);
+        private_key
+    }
+
+    fn double_hash(&self) -> [u8; 64] {
+        double_sha512_hash(&self.hash_input())
+    }
+
+    fn hash_input(&self) -> Vec<u8> {
+        let mut input = vec![];
+        write_var_int_64(&mut input, self.version);
+        write_var_int_64(&mut input, self.stream as u64);
+        input.extend(&self.ripe);
+        input
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // v4 addresses drop all leading zeros from the ripe; older versions at most two
+        let max_stripped = match self.version {
+            4 => 20,
+            _ => 2
+        };
+        let stripped = self.ripe.iter().take(max_stripped).take_while(|&&b| b == 0).count();
+
+        let mut data = vec![];
+        write_var_int_64(&mut data, self.version);
+        write_var_int_64(&mut data, self.stream as u64);
+        data.extend(&self.ripe[stripped..]);
+        let checksum = double_sha512_hash(&data);
+        data.extend(&checksum[0..4]);
+
+        write!(f, "{}{}", PREFIX, base58::encode(&data))
+    }
+}
+
+impl FromStr for Address {
+    type Err = AddressError;
+
+    fn from_str(s: &str) -> Result<Address, AddressError> {
+        let trimmed = s.trim();
+        let encoded = match trimmed.starts_with(PREFIX) {
+            true => &trimmed[PREFIX.len()..],
+            false => trimmed
+        };
+
+        let data = try!(base58::decode(encoded).ok_or(AddressError::BadEncoding));
+        if data.len() < 4 {
+            return Err(AddressError::BadEncoding);
+        }
+
+        let (contents, checksum) = data.split_at(data.len() - 4);
+        if &double_sha512_hash(contents)[0..4] != checksum {
+            return Err(AddressError::BadChecksum);
+        }
+
+        let mut cursor = Cursor::new(contents);
+        let version = try!(read_var_int(&mut cursor, u64::max_value()).map_err(|_| AddressError::BadEncoding));
+        let stream = try!(read_var_int(&mut cursor, u32::max_value() as u64).map_err(|_| AddressError::BadEncoding)) as u32;
+        let stored_ripe = &contents[cursor.position() as usize..];
+
+        let allowed_lengths = match version {
+            2 | 3 => 18..21,
+            4 => 4..21,
+            _ => return Err(AddressError::UnsupportedVersion(version))
+        };
+        if !allowed_lengths.contains(&stored_ripe.len()) || (version == 4 && stored_ripe[0] == 0) {
+            return Err(AddressError::BadRipeLength);
+        }
+
+        let mut ripe = vec![0u8; 20 - stored_ripe.len()];
+        ripe.extend(stored_ripe);
+
+        Ok(Address::new(version, stream, &ripe))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use super::{Address,AddressError};
+
+    fn example_ripe() -> Vec<u8> {
+        let mut ripe = vec![ 0, 0x2f ];
+        ripe.extend(1..19);
+        ripe
+    }
+
+    #[test]
+    fn test_v4_address_roundtrip() {
+        let address = Address::new(4, 1, &example_ripe());
+
+        assert_eq!("BM-2cTfvWz6rmkyjDemoWTC2RDyK4Vb14fK3z", address.to_string());
+        assert_eq!(Ok(address.clone()), Address::from_str("BM-2cTfvWz6rmkyjDemoWTC2RDyK4Vb14fK3z"));
+        assert_eq!(Ok(address), Address::from_str("2cTfvWz6rmkyjDemoWTC2RDyK4Vb14fK3z"));
+    }
+
+    #[test]
+    fn test_v3_address_roundtrip() {
+        let address = Address::new(3, 1, &example_ripe());
+
+        assert_eq!("BM-2D84wQgp9bJ6unWgn67sYHxBgZEeNzpKba", address.to_string());
+        assert_eq!(Ok(address), Address::from_str("BM-2D84wQgp9bJ6unWgn67sYHxBgZEeNzpKba"));
+    }
+
+    #[test]
+    fn test_tag() {
+        let address = Address::new(4, 1, &example_ripe());
+
+        assert_eq!(&[ 0x47, 0x70, 0x70, 0xb9 ], &address.tag_private_key()[0..4]);
+        assert_eq!(&[ 0xe6, 0xa6, 0xa5, 0x0d ], &address.tag()[0..4]);
+    }
+
+    #[test]
+    fn test_bad_addresses() {
+        assert_eq!(Err(AddressError::BadChecksum), Address::from_str("BM-2cTfvWz6rmkyjDemoWTC2RDyK4Vb14fK3y"));
+        assert_eq!(Err(AddressError::BadEncoding), Address::from_str("BM-0OIl"));
+        assert_eq!(Err(AddressError::BadEncoding), Address::from_str(""));
+    }
+}
@@ -0,0 +1,75 @@
+use address::Address;
+use disk::{address_from_json,address_to_json,string_from_json};
+use persist::Persister;
+use rustc_serialize::json::{Json,ToJson};
+use std::collections::BTreeMap;
+
+// A labelled address of someone else, as kept in the address book and the sender filter lists
+#[derive(Clone,Debug,PartialEq)]
+pub struct Contact {
+    label: String,
+    address: Address
+}
+
+impl Contact {
+    pub fn new(label: &str, address: &Address) -> Contact {
+        Contact {
+            label: label.to_string(),
+            address: address.clone()
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    pub fn from_json(json: &Json) -> Option<Contact> {
+        Some(Contact {
+            label: return_none_on_none!(string_from_json(json.find("label"))),
+            address: return_none_on_none!(address_from_json(json.find("address")))
+        })
+    }
+}
+
+impl ToJson for Contact {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("label".to_string(), self.label.to_json());
+        object.insert("address".to_string(), address_to_json(&self.address));
+        Json::Object(object)
+    }
+}
+
+#[derive(Clone)]
+pub struct AddressBook {
+    persister: Persister
+}
+
+impl AddressBook {
+    pub fn new(persister: Persister) -> AddressBook {
+        AddressBook {
+            persister: persister
+        }
+    }
+
+    // Adding an address that is already there just changes its label
+    pub fn add(&mut self, contact: &Contact) {
+        self.persister.add_contact(contact);
+    }
+
+    pub fn remove(&mut self, address: &Address) -> bool {
+        self.persister.remove_contact(address)
+    }
+
+    pub fn get(&self, address: &Address) -> Option<Contact> {
+        self.list().into_iter().find(|contact| contact.address() == address)
+    }
+
+    pub fn list(&self) -> Vec<Contact> {
+        self.persister.get_contacts()
+    }
+}
@@ -0,0 +1,93 @@
+use BMClient;
+use api::{ApiError,ApiValue};
+use api::methods::call;
+use rustc_serialize::json::{Json,ToJson};
+use std::collections::BTreeMap;
+
+// JSON-RPC 2.0, with the params given by position as for XML-RPC
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const INVALID_PARAMS: i64 = -32602;
+
+pub fn handle(body: &[u8], client: &mut BMClient) -> String {
+    let request = match Json::from_str(&String::from_utf8_lossy(body)) {
+        Ok(request) => request,
+        Err(_) => return error_response(Json::Null, PARSE_ERROR, "Parse error")
+    };
+    let id = request.find("id").cloned().unwrap_or(Json::Null);
+
+    let method = match request.find("method").and_then(|method| method.as_string()) {
+        Some(method) => method,
+        None => return error_response(id, INVALID_REQUEST, "Invalid request")
+    };
+    let params = match request.find("params") {
+        None => vec![],
+        Some(&Json::Array(ref params)) => match params.iter().map(read_value).collect::<Option<Vec<ApiValue>>>() {
+            Some(params) => params,
+            None => return error_response(id, INVALID_PARAMS, "Params must be strings, integers, booleans or null")
+        },
+        Some(_) => return error_response(id, INVALID_PARAMS, "Params must be given as an array")
+    };
+
+    match call(client, method, &params) {
+        Ok(value) => response(id, "result", write_value(&value)),
+        Err(err) => api_error_response(id, &err)
+    }
+}
+
+fn read_value(json: &Json) -> Option<ApiValue> {
+    match json {
+        &Json::Null => Some(ApiValue::Nil),
+        &Json::Boolean(value) => Some(ApiValue::Bool(value)),
+        &Json::I64(value) => Some(ApiValue::Int(value)),
+        &Json::U64(value) if value <= i64::max_value() as u64 => Some(ApiValue::Int(value as i64)),
+        &Json::String(ref value) => Some(ApiValue::String(value.clone())),
+        _ => None
+    }
+}
+
+fn write_value(value: &ApiValue) -> Json {
+    match value {
+        &ApiValue::Nil => Json::Null,
+        &ApiValue::Bool(value) => Json::Boolean(value),
+        &ApiValue::Int(value) => Json::I64(value),
+        &ApiValue::String(ref value) => Json::String(value.clone())
+    }
+}
+
+fn response(id: Json, key: &str, value: Json) -> String {
+    let mut object = BTreeMap::new();
+    object.insert("jsonrpc".to_string(), "2.0".to_json());
+    object.insert("id".to_string(), id);
+    object.insert(key.to_string(), value);
+    Json::Object(object).to_string()
+}
+
+fn error_response(id: Json, code: i64, message: &str) -> String {
+    let mut error = BTreeMap::new();
+    error.insert("code".to_string(), code.to_json());
+    error.insert("message".to_string(), message.to_json());
+    response(id, "error", Json::Object(error))
+}
+
+fn api_error_response(id: Json, err: &ApiError) -> String {
+    error_response(id, err.code() as i64, &err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use BMClient;
+    use super::handle;
+
+    #[test]
+    fn test_errors() {
+        let mut client = BMClient::new().unwrap();
+
+        assert_eq!("{\"error\":{\"code\":-32700,\"message\":\"Parse error\"},\"id\":null,\"jsonrpc\":\"2.0\"}", handle(b"{", &mut client));
+        assert_eq!("{\"error\":{\"code\":-32602,\"message\":\"Params must be given as an array\"},\"id\":\"a\",\"jsonrpc\":\"2.0\"}",
+            handle(b"{\"id\":\"a\",\"method\":\"add\",\"params\":{\"a\":1}}", &mut client));
+        assert_eq!("{\"error\":{\"code\":20,\"message\":\"API Error 0020: Invalid method: nope\"},\"id\":2,\"jsonrpc\":\"2.0\"}",
+            handle(b"{\"id\":2,\"method\":\"nope\"}", &mut client));
+    }
+}
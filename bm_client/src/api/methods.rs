@@ -0,0 +1,456 @@
+use {BMClient,BMError,ConnectionState,Folder,MessageEncoding,MessageQuery,OutboxStatus,SendOptions,StoredMessage};
+use address::Address;
+use api::{ApiError,ApiValue};
+use rustc_serialize::base64::{FromBase64,STANDARD,ToBase64};
+use rustc_serialize::hex::{FromHex,ToHex};
+use rustc_serialize::json::{Json,ToJson};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration,UNIX_EPOCH};
+
+// PyBitmessage's API methods and error numbers. Subjects, bodies, labels and passphrases
+// are passed base64 encoded, and lists come back as JSON text. listConnections and
+// getObjectStats are our own additions.
+
+pub const ERROR_NO_PARAMS: u32 = 0;
+pub const ERROR_EMPTY_PASSPHRASE: u32 = 1;
+pub const ERROR_TOO_MANY_PARAMS: u32 = 3;
+pub const ERROR_BAD_ENCODING: u32 = 6;
+pub const ERROR_BAD_ADDRESS: u32 = 7;
+pub const ERROR_UNKNOWN_FROM_ADDRESS: u32 = 13;
+pub const ERROR_CHAN_MISMATCH: u32 = 18;
+pub const ERROR_INVALID_METHOD: u32 = 20;
+pub const ERROR_UNEXPECTED: u32 = 21;
+pub const ERROR_DECODE: u32 = 22;
+pub const ERROR_BAD_HEX: u32 = 23;
+pub const ERROR_BAD_PARAM: u32 = 25;
+pub const ERROR_MESSAGE_TOO_LONG: u32 = 27;
+
+const DEFAULT_ENCODING: i64 = 2;
+const DEFAULT_TTL_SECS: i64 = 345600;
+
+pub fn call(client: &mut BMClient, method: &str, params: &[ApiValue]) -> Result<ApiValue, ApiError> {
+    let params = Params(params);
+    match method {
+        "helloWorld" => {
+            try!(params.count(2, 2));
+            Ok(string(format!("{}-{}", try!(params.string(0)), try!(params.string(1)))))
+        },
+        "add" => {
+            try!(params.count(2, 2));
+            Ok(ApiValue::Int(try!(params.int(0)).wrapping_add(try!(params.int(1)))))
+        },
+        "listAddresses" | "listAddresses2" => {
+            try!(params.count(0, 0));
+            let addresses = client.identities().into_iter().chain(client.chans().into_iter()).map(|identity| object(vec![
+                ("label", label_to_json(identity.label(), method == "listAddresses2")),
+                ("address", identity.address().to_string().to_json()),
+                ("stream", identity.address().stream().to_json()),
+                ("enabled", identity.enabled().to_json()),
+                ("chan", identity.is_chan().to_json())
+            ])).collect();
+            Ok(json_list("addresses", addresses))
+        },
+        "createRandomAddress" => {
+            try!(params.count(1, 4));
+            let label = try!(params.base64(0));
+            Ok(string(client.create_identity(&label).to_string()))
+        },
+        "createChan" => {
+            try!(params.count(1, 1));
+            let passphrase = try!(params.base64(0));
+            client.create_chan(&passphrase).map(|address| string(address.to_string())).map_err(client_error)
+        },
+        "joinChan" => {
+            try!(params.count(2, 2));
+            let passphrase = try!(params.base64(0));
+            let address = try!(params.address(1));
+            try!(client.join_chan(&passphrase, &address).map_err(client_error));
+            Ok(string("success".to_string()))
+        },
+        "leaveChan" => {
+            try!(params.count(1, 1));
+            let address = try!(params.address(0));
+            client.leave_chan(&address);
+            Ok(string("success".to_string()))
+        },
+        "decodeAddress" => {
+            try!(params.count(1, 1));
+            let address = try!(params.address(0));
+            Ok(string(object(vec![
+                ("status", "success".to_json()),
+                ("addressVersion", address.version().to_json()),
+                ("streamNumber", address.stream().to_json()),
+                ("ripe", address.ripe().to_base64(STANDARD).to_json())
+            ]).to_string()))
+        },
+        "getAllInboxMessages" => {
+            try!(params.count(0, 0));
+            let messages = client.messages(&MessageQuery::new().folder(Folder::Inbox)).iter().map(inbox_message_to_json).collect();
+            Ok(json_list("inboxMessages", messages))
+        },
+        "getAllInboxMessageIds" | "getAllInboxMessageIDs" => {
+            try!(params.count(0, 0));
+            let ids = client.messages(&MessageQuery::new().folder(Folder::Inbox)).iter().map(|message| object(vec![ ("msgid", message.id().to_hex().to_json()) ])).collect();
+            Ok(json_list("inboxMessageIds", ids))
+        },
+        "getInboxMessageById" | "getInboxMessageByID" => {
+            try!(params.count(1, 2));
+            let msgid = try!(params.hex(0));
+            if params.0.len() == 2 {
+                client.mark_read(&msgid, try!(params.bool(1)));
+            }
+            let messages = client.message(&msgid).into_iter().filter(|message| !message.is_ours()).map(|message| inbox_message_to_json(&message)).collect();
+            Ok(json_list("inboxMessage", messages))
+        },
+        "getAllSentMessages" => {
+            try!(params.count(0, 0));
+            let messages = sent_messages(client).iter().map(sent_message_to_json).collect();
+            Ok(json_list("sentMessages", messages))
+        },
+        "getAllSentMessageIds" | "getAllSentMessageIDs" => {
+            try!(params.count(0, 0));
+            let ids = sent_messages(client).iter().map(|message| object(vec![ ("msgid", message.id().to_hex().to_json()) ])).collect();
+            Ok(json_list("sentMessageIds", ids))
+        },
+        "getSentMessageById" | "getSentMessageByID" | "getSentMessageByAckData" => {
+            try!(params.count(1, 1));
+            let ackdata = try!(params.hex(0));
+            let messages = client.message(&ackdata).into_iter().filter(|message| message.is_ours()).map(|message| sent_message_to_json(&message)).collect();
+            Ok(json_list("sentMessage", messages))
+        },
+        "getStatus" => {
+            try!(params.count(1, 1));
+            let ackdata = try!(params.hex(0));
+            let status = match client.outbox_message(&ackdata) {
+                Some(outbox_message) => status_name(outbox_message.status(), outbox_message.is_broadcast()),
+                None => "notfound"
+            };
+            Ok(string(status.to_string()))
+        },
+        "clientStatus" => {
+            try!(params.count(0, 0));
+            let established = client.connections().iter().filter(|&&(_, state)| is_established(state)).count();
+            let stats = client.object_stats();
+            // We don't accept incoming connections, so this is as connected as we get
+            let network_status = if established > 0 { "connectedButHaveNotReceivedIncomingConnections" } else { "notConnected" };
+            // Counted from the objects we hold, rather than those processed since startup
+            Ok(string(object(vec![
+                ("networkConnections", established.to_json()),
+                ("numberOfMessagesProcessed", stats.msgs().to_json()),
+                ("numberOfBroadcastsProcessed", stats.broadcasts().to_json()),
+                ("numberOfPubkeysProcessed", stats.pubkeys().to_json()),
+                ("networkStatus", network_status.to_json()),
+                ("softwareName", "rubbem".to_json()),
+                ("softwareVersion", env!("CARGO_PKG_VERSION").to_json())
+            ]).to_string()))
+        },
+        "listConnections" => {
+            try!(params.count(0, 0));
+            let connections = client.connections().iter().map(|&(peer_addr, state)| object(vec![
+                ("peer", peer_addr.to_string().to_json()),
+                ("state", state.to_string().to_json())
+            ])).collect();
+            Ok(json_list("connections", connections))
+        },
+        "getObjectStats" => {
+            try!(params.count(0, 0));
+            let stats = client.object_stats();
+            Ok(string(object(vec![
+                ("getpubkey", stats.getpubkeys().to_json()),
+                ("pubkey", stats.pubkeys().to_json()),
+                ("msg", stats.msgs().to_json()),
+                ("broadcast", stats.broadcasts().to_json()),
+                ("total", stats.total().to_json())
+            ]).to_string()))
+        },
+        "trashMessage" | "trashInboxMessage" | "trashSentMessage" | "trashSentMessageByAckData" => {
+            try!(params.count(1, 1));
+            client.trash_message(&try!(params.hex(0)));
+            Ok(string("Trashed message (assuming message existed).".to_string()))
+        },
+        "sendMessage" => {
+            try!(params.count(4, 6));
+            let to = try!(params.address(0));
+            let from = try!(params.address(1));
+            let (subject, body) = (try!(params.base64(2)), try!(params.base64(3)));
+            let options = try!(send_options(&params, 4));
+            client.send_message(&from, &to, &subject, &body, options).map(|ackdata| string(ackdata.to_hex())).map_err(client_error)
+        },
+        "sendBroadcast" => {
+            try!(params.count(3, 5));
+            let from = try!(params.address(0));
+            let (subject, body) = (try!(params.base64(1)), try!(params.base64(2)));
+            let options = try!(send_options(&params, 3));
+            client.send_broadcast(&from, &subject, &body, options).map(|ackdata| string(ackdata.to_hex())).map_err(client_error)
+        },
+        "listSubscriptions" => {
+            try!(params.count(0, 0));
+            let subscriptions = client.subscriptions().iter().map(|subscription| object(vec![
+                ("label", label_to_json(subscription.label(), true)),
+                ("address", subscription.address().to_string().to_json()),
+                ("enabled", true.to_json())
+            ])).collect();
+            Ok(json_list("subscriptions", subscriptions))
+        },
+        "addSubscription" => {
+            try!(params.count(1, 2));
+            let address = try!(params.address(0));
+            let label = if params.0.len() == 2 { try!(params.base64(1)) } else { String::new() };
+            client.add_subscription(&label, &address);
+            Ok(string("Added subscription.".to_string()))
+        },
+        "deleteSubscription" => {
+            try!(params.count(1, 1));
+            client.remove_subscription(&try!(params.address(0)));
+            Ok(string("Deleted subscription if it existed.".to_string()))
+        },
+        "listAddressBookEntries" | "listAddressbook" => {
+            try!(params.count(0, 0));
+            let contacts = client.contacts().iter().map(|contact| object(vec![
+                ("label", label_to_json(contact.label(), true)),
+                ("address", contact.address().to_string().to_json())
+            ])).collect();
+            Ok(json_list("addresses", contacts))
+        },
+        "addAddressBookEntry" | "addAddressbook" => {
+            try!(params.count(2, 2));
+            let address = try!(params.address(0));
+            client.add_contact(&try!(params.base64(1)), &address);
+            Ok(string(format!("Added address {} to address book", address)))
+        },
+        "deleteAddressBookEntry" | "deleteAddressbook" => {
+            try!(params.count(1, 1));
+            let address = try!(params.address(0));
+            client.remove_contact(&address);
+            Ok(string(format!("Deleted address book entry for {} if it existed", address)))
+        },
+        _ => Err(ApiError::new(ERROR_INVALID_METHOD, &format!("Invalid method: {}", method)))
+    }
+}
+
+struct Params<'a>(&'a [ApiValue]);
+
+impl<'a> Params<'a> {
+    fn count(&self, min: usize, max: usize) -> Result<(), ApiError> {
+        if self.0.len() < min {
+            Err(ApiError::new(ERROR_NO_PARAMS, "I need parameters!"))
+        } else if self.0.len() > max {
+            Err(ApiError::new(ERROR_TOO_MANY_PARAMS, "Too many parameters!"))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn string(&self, index: usize) -> Result<&'a str, ApiError> {
+        match self.0.get(index) {
+            Some(&ApiValue::String(ref value)) => Ok(value),
+            _ => Err(bad_param(index, "a string"))
+        }
+    }
+
+    fn int(&self, index: usize) -> Result<i64, ApiError> {
+        match self.0.get(index) {
+            Some(&ApiValue::Int(value)) => Ok(value),
+            Some(&ApiValue::String(ref value)) => value.trim().parse().map_err(|_| bad_param(index, "an integer")),
+            _ => Err(bad_param(index, "an integer"))
+        }
+    }
+
+    fn int_or(&self, index: usize, default: i64) -> Result<i64, ApiError> {
+        match self.0.get(index) {
+            Some(_) => self.int(index),
+            None => Ok(default)
+        }
+    }
+
+    fn bool(&self, index: usize) -> Result<bool, ApiError> {
+        match self.0.get(index) {
+            Some(&ApiValue::Bool(value)) => Ok(value),
+            Some(&ApiValue::Int(value)) => Ok(value != 0),
+            _ => Err(bad_param(index, "a boolean"))
+        }
+    }
+
+    fn base64(&self, index: usize) -> Result<String, ApiError> {
+        let bytes = try!(try!(self.string(index)).from_base64().map_err(|_| ApiError::new(ERROR_DECODE, "Decode error - the text is not base64 encoded")));
+        String::from_utf8(bytes).map_err(|_| ApiError::new(ERROR_DECODE, "Decode error - the text is not valid UTF-8"))
+    }
+
+    fn hex(&self, index: usize) -> Result<Vec<u8>, ApiError> {
+        try!(self.string(index)).from_hex().map_err(|_| ApiError::new(ERROR_BAD_HEX, "Decode error - the message id is not hex encoded"))
+    }
+
+    fn address(&self, index: usize) -> Result<Address, ApiError> {
+        let text = try!(self.string(index));
+        Address::from_str(text.trim()).map_err(|_| ApiError::new(ERROR_BAD_ADDRESS, &format!("Could not decode address: {}", text)))
+    }
+}
+
+fn bad_param(index: usize, expected: &str) -> ApiError {
+    ApiError::new(ERROR_BAD_PARAM, &format!("Parameter {} should be {}", index + 1, expected))
+}
+
+fn send_options(params: &Params, index: usize) -> Result<SendOptions, ApiError> {
+    let encoding = match try!(params.int_or(index, DEFAULT_ENCODING)) {
+        number if number == 2 || number == 3 => MessageEncoding::from_number(number as u64).unwrap(),
+        _ => return Err(ApiError::new(ERROR_BAD_ENCODING, "The encoding type must be 2 or 3."))
+    };
+    let ttl = try!(params.int_or(index + 1, DEFAULT_TTL_SECS));
+    Ok(SendOptions::new().encoding(encoding).ttl(Duration::from_secs(if ttl < 0 { 0 } else { ttl as u64 })))
+}
+
+fn client_error(err: BMError) -> ApiError {
+    match err {
+        BMError::UnknownIdentity(_) => ApiError::new(ERROR_UNKNOWN_FROM_ADDRESS, "Could not find your fromAddress in the keys.dat file."),
+        BMError::MessageTooLong => ApiError::new(ERROR_MESSAGE_TOO_LONG, "Message is too long."),
+        BMError::EmptyPassphrase => ApiError::new(ERROR_EMPTY_PASSPHRASE, "The specified passphrase is blank."),
+        BMError::ChanAddressMismatch(_) => ApiError::new(ERROR_CHAN_MISMATCH, "Chan name does not match address."),
+        err => ApiError::new(ERROR_UNEXPECTED, &format!("Unexpected API Failure - {}", err))
+    }
+}
+
+// Queued messages are in PyBitmessage's sent folder too
+fn sent_messages(client: &BMClient) -> Vec<StoredMessage> {
+    client.messages(&MessageQuery::new()).into_iter().filter(|message| message.folder() == Folder::Sent || message.folder() == Folder::Outbox).collect()
+}
+
+fn status_name(status: OutboxStatus, broadcast: bool) -> &'static str {
+    match (status, broadcast) {
+        (OutboxStatus::Queued, false) => "msgqueued",
+        (OutboxStatus::Queued, true) => "broadcastqueued",
+        (OutboxStatus::AwaitingPubKey, _) => "awaitingpubkey",
+        (OutboxStatus::DoingPow, false) => "doingmsgpow",
+        (OutboxStatus::DoingPow, true) => "doingbroadcastpow",
+        (OutboxStatus::Sent, false) => "msgsent",
+        (OutboxStatus::Sent, true) => "broadcastsent",
+        (OutboxStatus::AckReceived, _) => "ackreceived",
+        (OutboxStatus::Failed, _) => "failed",
+        (OutboxStatus::GaveUp, _) => "gaveup"
+    }
+}
+
+fn is_established(state: ConnectionState) -> bool {
+    match state {
+        ConnectionState::Established(_) => true,
+        _ => false
+    }
+}
+
+fn inbox_message_to_json(message: &StoredMessage) -> Json {
+    object(vec![
+        ("msgid", message.id().to_hex().to_json()),
+        ("toAddress", message.to().map(|to| to.to_string()).unwrap_or("[Broadcast subscribers]".to_string()).to_json()),
+        ("fromAddress", message.from().to_string().to_json()),
+        ("subject", message.subject().as_bytes().to_base64(STANDARD).to_json()),
+        ("message", message.body().as_bytes().to_base64(STANDARD).to_json()),
+        ("encodingType", DEFAULT_ENCODING.to_json()),
+        ("receivedTime", unix_time(message).to_string().to_json()),
+        ("read", (if message.is_read() { 1 } else { 0 }).to_json())
+    ])
+}
+
+fn sent_message_to_json(message: &StoredMessage) -> Json {
+    let broadcast = message.to().is_none();
+    object(vec![
+        ("msgid", message.id().to_hex().to_json()),
+        ("toAddress", message.to().map(|to| to.to_string()).unwrap_or("[Broadcast subscribers]".to_string()).to_json()),
+        ("fromAddress", message.from().to_string().to_json()),
+        ("subject", message.subject().as_bytes().to_base64(STANDARD).to_json()),
+        ("message", message.body().as_bytes().to_base64(STANDARD).to_json()),
+        ("encodingType", DEFAULT_ENCODING.to_json()),
+        ("lastActionTime", unix_time(message).to_string().to_json()),
+        ("status", message.status().map_or("notfound", |status| status_name(status, broadcast)).to_json()),
+        ("ackData", message.id().to_hex().to_json())
+    ])
+}
+
+fn unix_time(message: &StoredMessage) -> u64 {
+    message.timestamp().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0)
+}
+
+fn label_to_json(label: &str, base64: bool) -> Json {
+    match base64 {
+        true => label.as_bytes().to_base64(STANDARD).to_json(),
+        false => label.to_json()
+    }
+}
+
+fn object(members: Vec<(&str, Json)>) -> Json {
+    Json::Object(members.into_iter().map(|(key, value)| (key.to_string(), value)).collect::<BTreeMap<String, Json>>())
+}
+
+fn json_list(key: &str, entries: Vec<Json>) -> ApiValue {
+    string(object(vec![ (key, Json::Array(entries)) ]).to_string())
+}
+
+fn string(value: String) -> ApiValue {
+    ApiValue::String(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use {BMClient,Config};
+    use api::ApiValue;
+    use rustc_serialize::base64::{FromBase64,STANDARD,ToBase64};
+    use rustc_serialize::json::Json;
+    use super::{ERROR_BAD_ADDRESS,ERROR_BAD_ENCODING,ERROR_NO_PARAMS,ERROR_UNKNOWN_FROM_ADDRESS,call};
+
+    fn text(value: &str) -> ApiValue {
+        ApiValue::String(value.to_string())
+    }
+
+    fn encoded(value: &str) -> ApiValue {
+        ApiValue::String(value.as_bytes().to_base64(STANDARD))
+    }
+
+    fn result_json(client: &mut BMClient, method: &str, params: &[ApiValue]) -> Json {
+        match call(client, method, params) {
+            Ok(ApiValue::String(json)) => Json::from_str(&json).unwrap(),
+            other => panic!("Expected JSON text, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_addresses_and_sending() {
+        let config = Config::builder().seed_nodes(&[]).dns_seeds(&[]).build().unwrap();
+        let mut client = BMClient::with_config(config).unwrap();
+
+        let address = match call(&mut client, "createRandomAddress", &[ encoded("Me") ]) {
+            Ok(ApiValue::String(address)) => address,
+            other => panic!("Expected an address, got {:?}", other)
+        };
+        let addresses = result_json(&mut client, "listAddresses2", &[]);
+        let entry = &addresses.find("addresses").unwrap().as_array().unwrap()[0];
+        assert_eq!(Some(address.as_str()), entry.find("address").unwrap().as_string());
+        assert_eq!(b"Me".to_vec(), entry.find("label").unwrap().as_string().unwrap().from_base64().unwrap());
+
+        let ackdata = match call(&mut client, "sendMessage", &[ text(&address), text(&address), encoded("Hi"), encoded("Hello") ]) {
+            Ok(ApiValue::String(ackdata)) => ackdata,
+            other => panic!("Expected ackdata, got {:?}", other)
+        };
+        assert_eq!(Ok(text("msgqueued")), call(&mut client, "getStatus", &[ text(&ackdata) ]));
+        let sent = result_json(&mut client, "getAllSentMessages", &[]);
+        let entry = &sent.find("sentMessages").unwrap().as_array().unwrap()[0];
+        assert_eq!(Some(ackdata.as_str()), entry.find("ackData").unwrap().as_string());
+        assert_eq!(Some("SGVsbG8="), entry.find("message").unwrap().as_string());
+        assert_eq!(Ok(text("notfound")), call(&mut client, "getStatus", &[ text("00") ]));
+
+        let status = result_json(&mut client, "clientStatus", &[]);
+        assert_eq!(Some("notConnected"), status.find("networkStatus").unwrap().as_string());
+        assert_eq!(Some(0), result_json(&mut client, "getObjectStats", &[]).find("total").unwrap().as_u64());
+        assert!(result_json(&mut client, "listConnections", &[]).find("connections").unwrap().as_array().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut client = BMClient::new().unwrap();
+        let stranger = BMClient::new().unwrap().create_identity("Someone else").to_string();
+
+        let code = |result: Result<ApiValue, ::api::ApiError>| result.err().unwrap().code();
+        assert_eq!(ERROR_NO_PARAMS, code(call(&mut client, "sendMessage", &[])));
+        assert_eq!(ERROR_BAD_ADDRESS, code(call(&mut client, "decodeAddress", &[ text("BM-nope") ])));
+        assert_eq!(ERROR_UNKNOWN_FROM_ADDRESS, code(call(&mut client, "sendMessage", &[ text(&stranger), text(&stranger), encoded("Hi"), encoded("Hello") ])));
+        assert_eq!(ERROR_BAD_ENCODING, code(call(&mut client, "sendBroadcast", &[ text(&stranger), encoded("Hi"), encoded("Hello"), ApiValue::Int(1) ])));
+    }
+}
@@ -0,0 +1,266 @@
+mod jsonrpc;
+mod methods;
+mod xmlrpc;
+
+use BMClient;
+use config::Config;
+use rustc_serialize::base64::FromBase64;
+use std::fmt;
+use std::io::{self,BufRead,BufReader,ErrorKind,Write};
+use server::{PolledListener,secrets_match};
+use std::net::{SocketAddr,TcpStream};
+use std::sync::{Arc,Mutex};
+use std::thread::{Builder,JoinHandle};
+use std::time::{Duration,Instant};
+use stop::{StopSignal,join_until};
+
+// A local API server compatible with a subset of PyBitmessage's, so that scripts written for
+// it work against us. Requests are XML-RPC, or JSON-RPC when sent as application/json.
+
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+const MAX_HEADER_LINES: usize = 100;
+const MAX_BODY_LENGTH: usize = 1048576;
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum ApiValue {
+    Nil,
+    Bool(bool),
+    Int(i64),
+    String(String)
+}
+
+// Reported to the caller as an XML-RPC fault or JSON-RPC error
+#[derive(Clone,Debug,PartialEq)]
+pub struct ApiError {
+    code: u32,
+    message: String
+}
+
+impl ApiError {
+    pub fn new(code: u32, message: &str) -> ApiError {
+        ApiError {
+            code: code,
+            message: message.to_string()
+        }
+    }
+
+    pub fn code(&self) -> u32 {
+        self.code
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "API Error {:04}: {}", self.code, self.message)
+    }
+}
+
+pub struct ApiServer {
+    config: Config,
+    client: Arc<Mutex<BMClient>>,
+    local_addr: Option<SocketAddr>,
+    stop_signal: StopSignal,
+    thread: Option<JoinHandle<()>>
+}
+
+impl ApiServer {
+    pub fn new(config: &Config, client: Arc<Mutex<BMClient>>) -> ApiServer {
+        ApiServer {
+            config: config.clone(),
+            client: client,
+            local_addr: None,
+            stop_signal: StopSignal::new(),
+            thread: None
+        }
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        if self.thread.is_some() {
+            return Ok(());
+        }
+
+        let listener = try!(PolledListener::bind((self.config.api_listen_addr(), self.config.api_port())));
+        self.local_addr = Some(try!(listener.local_addr()));
+
+        let stop_signal = StopSignal::new();
+        self.stop_signal = stop_signal.clone();
+        let credentials = (self.config.api_username().to_string(), self.config.api_password().to_string());
+        let client = self.client.clone();
+
+        let thread = try!(Builder::new().name("API Server".to_string()).spawn(move || {
+            // Requests are handled one at a time, since each needs the client to itself anyway
+            listener.accept_until_stopped(&stop_signal, |stream| {
+                let _ = handle_connection(stream, &credentials, &client);
+            });
+        }));
+
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    pub fn stop(&mut self, deadline: Instant) {
+        self.stop_signal.stop(deadline);
+        if let Some(thread) = self.thread.take() {
+            join_until(thread, deadline);
+        }
+        self.local_addr = None;
+    }
+
+    // Where the server is listening while running
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        self.stop(Instant::now() + Duration::from_secs(REQUEST_TIMEOUT_SECS));
+    }
+}
+
+struct HttpRequest {
+    method: String,
+    authorization: Option<String>,
+    content_type: Option<String>,
+    body: Vec<u8>
+}
+
+fn handle_connection(stream: TcpStream, credentials: &(String, String), client: &Arc<Mutex<BMClient>>) -> io::Result<()> {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS))));
+    try!(stream.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS))));
+    let mut writer = try!(stream.try_clone());
+
+    let request = match read_request(&mut BufReader::new(stream)) {
+        Ok(request) => request,
+        Err(ref err) if err.kind() == ErrorKind::InvalidData => {
+            return write_response(&mut writer, "413 Payload Too Large", &[], "text/plain", b"Request too large\n");
+        },
+        Err(err) => return Err(err)
+    };
+
+    if request.method != "POST" {
+        return write_response(&mut writer, "405 Method Not Allowed", &[ "Allow: POST" ], "text/plain", b"Only POST is supported\n");
+    }
+    if !is_authorized(request.authorization.as_ref().map(|value| value.as_str()), credentials) {
+        return write_response(&mut writer, "401 Unauthorized", &[ "WWW-Authenticate: Basic realm=\"rubbem\"" ], "text/plain", b"Unauthorized\n");
+    }
+
+    let json = request.content_type.map_or(false, |content_type| content_type.contains("json"));
+    let mut client = match client.lock() {
+        Ok(client) => client,
+        Err(poisoned) => poisoned.into_inner()
+    };
+    let (content_type, body) = match json {
+        true => ("application/json", jsonrpc::handle(&request.body, &mut client)),
+        false => ("text/xml", xmlrpc::handle(&request.body, &mut client))
+    };
+    write_response(&mut writer, "200 OK", &[], content_type, body.as_bytes())
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<HttpRequest> {
+    let mut line = String::new();
+    try!(reader.read_line(&mut line));
+    let method = line.split_whitespace().next().unwrap_or("").to_string();
+
+    let (mut authorization, mut content_type, mut content_length) = (None, None, 0);
+    for _ in 0..MAX_HEADER_LINES {
+        line.clear();
+        if try!(reader.read_line(&mut line)) == 0 || line.trim().is_empty() {
+            break;
+        }
+
+        let mut parts = line.splitn(2, ':');
+        let name = parts.next().unwrap_or("").trim().to_lowercase();
+        let value = parts.next().unwrap_or("").trim().to_string();
+        match &name[..] {
+            "authorization" => authorization = Some(value),
+            "content-type" => content_type = Some(value.to_lowercase()),
+            "content-length" => content_length = value.parse().unwrap_or(0),
+            _ => {}
+        }
+    }
+
+    if content_length > MAX_BODY_LENGTH {
+        return Err(io::Error::new(ErrorKind::InvalidData, "body too long"));
+    }
+    let mut body = vec![0u8; content_length];
+    try!(reader.read_exact(&mut body));
+
+    Ok(HttpRequest {
+        method: method,
+        authorization: authorization,
+        content_type: content_type,
+        body: body
+    })
+}
+
+fn is_authorized(authorization: Option<&str>, credentials: &(String, String)) -> bool {
+    let encoded = match authorization {
+        Some(value) if value.starts_with("Basic ") => &value["Basic ".len()..],
+        _ => return false
+    };
+    let decoded = match encoded.trim().from_base64() {
+        Ok(decoded) => decoded,
+        Err(_) => return false
+    };
+
+    secrets_match(&decoded, format!("{}:{}", credentials.0, credentials.1).as_bytes())
+}
+
+fn write_response<W: Write>(writer: &mut W, status: &str, headers: &[&str], content_type: &str, body: &[u8]) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n", status, content_type, body.len());
+    for header in headers {
+        head.push_str(header);
+        head.push_str("\r\n");
+    }
+    head.push_str("\r\n");
+
+    try!(writer.write_all(head.as_bytes()));
+    try!(writer.write_all(body));
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use BMClient;
+    use config::Config;
+    use rustc_serialize::base64::{STANDARD,ToBase64};
+    use std::io::{Read,Write};
+    use std::net::TcpStream;
+    use std::sync::{Arc,Mutex};
+    use std::time::{Duration,Instant};
+    use super::ApiServer;
+
+    fn post(server: &ApiServer, credentials: &str, content_type: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        write!(stream, "POST / HTTP/1.1\r\nAuthorization: Basic {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n{}",
+            credentials.as_bytes().to_base64(STANDARD), content_type, body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_requests_need_credentials() {
+        let config = Config::builder().seed_nodes(&[]).api_enabled(true).api_port(0).api_credentials("user", "secret").build().unwrap();
+        let client = Arc::new(Mutex::new(BMClient::with_config(config.clone()).unwrap()));
+        let mut server = ApiServer::new(&config, client);
+        server.start().unwrap();
+
+        let call = "<?xml version=\"1.0\"?><methodCall><methodName>helloWorld</methodName><params>\
+            <param><value><string>a</string></value></param><param><value>b</value></param></params></methodCall>";
+        let response = post(&server, "user:secret", "text/xml", call);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("<value><string>a-b</string></value></param></params></methodResponse>"));
+
+        assert!(post(&server, "user:wrong", "text/xml", call).starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+
+        let response = post(&server, "user:secret", "application/json", "{\"jsonrpc\":\"2.0\",\"id\":1,\"method\":\"add\",\"params\":[2,3]}");
+        assert!(response.ends_with("{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":5}"));
+
+        let start = Instant::now();
+        server.stop(Instant::now() + Duration::from_secs(5));
+        assert!(start.elapsed() < Duration::from_secs(1));
+        assert!(server.local_addr().is_none());
+    }
+}
@@ -0,0 +1,252 @@
+use BMClient;
+use api::{ApiError,ApiValue};
+use api::methods::{ERROR_DECODE,call};
+use rustc_serialize::base64::FromBase64;
+
+// Just enough XML to read a methodCall and write a methodResponse
+
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug,PartialEq)]
+enum Node {
+    Element(Element),
+    Text(String)
+}
+
+#[derive(Debug,PartialEq)]
+struct Element {
+    name: String,
+    children: Vec<Node>
+}
+
+impl Element {
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|element| element.name == name)
+    }
+
+    fn elements<'a>(&'a self) -> impl Iterator<Item=&'a Element> + 'a {
+        self.children.iter().filter_map(|node| match node {
+            &Node::Element(ref element) => Some(element),
+            &Node::Text(_) => None
+        })
+    }
+
+    fn text(&self) -> String {
+        let mut text = String::new();
+        for node in &self.children {
+            if let &Node::Text(ref part) = node {
+                text.push_str(part);
+            }
+        }
+        text
+    }
+}
+
+pub fn handle(body: &[u8], client: &mut BMClient) -> String {
+    let result = match parse_call(&String::from_utf8_lossy(body)) {
+        Some((method, params)) => call(client, &method, &params),
+        None => Err(ApiError::new(ERROR_DECODE, "Decode error - could not read the XML-RPC request"))
+    };
+
+    match result {
+        Ok(value) => format!("<?xml version=\"1.0\"?><methodResponse><params><param>{}</param></params></methodResponse>", write_value(&value)),
+        Err(err) => format!("<?xml version=\"1.0\"?><methodResponse><fault><value><struct>\
+            <member><name>faultCode</name><value><int>{}</int></value></member>\
+            <member><name>faultString</name><value><string>{}</string></value></member>\
+            </struct></value></fault></methodResponse>", err.code(), escape(&err.to_string()))
+    }
+}
+
+fn parse_call(xml: &str) -> Option<(String, Vec<ApiValue>)> {
+    let mut parser = Parser { input: xml, position: 0 };
+    let root = return_none_on_none!(parser.document());
+    if root.name != "methodCall" {
+        return None;
+    }
+
+    let method = return_none_on_none!(root.child("methodName")).text().trim().to_string();
+    let mut params = vec![];
+    if let Some(param_list) = root.child("params") {
+        for param in param_list.elements().filter(|element| element.name == "param") {
+            params.push(return_none_on_none!(param.child("value").and_then(read_value)));
+        }
+    }
+    Some((method, params))
+}
+
+fn read_value(value: &Element) -> Option<ApiValue> {
+    let typed = match value.elements().next() {
+        Some(typed) => typed,
+        None => return Some(ApiValue::String(value.text()))
+    };
+
+    let text = typed.text();
+    match &typed.name[..] {
+        "string" => Some(ApiValue::String(text)),
+        "int" | "i4" | "i8" => text.trim().parse().ok().map(ApiValue::Int),
+        "boolean" => match text.trim() {
+            "1" => Some(ApiValue::Bool(true)),
+            "0" => Some(ApiValue::Bool(false)),
+            _ => None
+        },
+        "base64" => text.from_base64().ok().map(|bytes| ApiValue::String(String::from_utf8_lossy(&bytes).into_owned())),
+        "nil" => Some(ApiValue::Nil),
+        _ => None
+    }
+}
+
+fn write_value(value: &ApiValue) -> String {
+    match value {
+        &ApiValue::Nil => "<value><nil/></value>".to_string(),
+        &ApiValue::Bool(value) => format!("<value><boolean>{}</boolean></value>", if value { 1 } else { 0 }),
+        &ApiValue::Int(value) => format!("<value><int>{}</int></value>", value),
+        &ApiValue::String(ref value) => format!("<value><string>{}</string></value>", escape(value))
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn unescape(text: &str) -> Option<String> {
+    let mut unescaped = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        let end = return_none_on_none!(rest[start..].find(';')) + start;
+        let entity = &rest[start + 1..end];
+        let c = match entity {
+            "lt" => '<',
+            "gt" => '>',
+            "amp" => '&',
+            "quot" => '"',
+            "apos" => '\'',
+            _ if entity.starts_with("#x") => return_none_on_none!(u32::from_str_radix(&entity[2..], 16).ok().and_then(::std::char::from_u32)),
+            _ if entity.starts_with('#') => return_none_on_none!(entity[1..].parse().ok().and_then(::std::char::from_u32)),
+            _ => return None
+        };
+        unescaped.push(c);
+        rest = &rest[end + 1..];
+    }
+    unescaped.push_str(rest);
+    Some(unescaped)
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    position: usize
+}
+
+impl<'a> Parser<'a> {
+    fn document(&mut self) -> Option<Element> {
+        self.skip_prolog();
+        let root = return_none_on_none!(self.element(0));
+        self.skip_prolog();
+        match self.position == self.input.len() {
+            true => Some(root),
+            false => None
+        }
+    }
+
+    // Whitespace, the XML declaration and comments
+    fn skip_prolog(&mut self) {
+        loop {
+            let rest = self.rest();
+            let trimmed = rest.trim_start();
+            self.position += rest.len() - trimmed.len();
+            let end = if trimmed.starts_with("<?") {
+                trimmed.find("?>").map(|end| end + 2)
+            } else if trimmed.starts_with("<!--") {
+                trimmed.find("-->").map(|end| end + 3)
+            } else {
+                None
+            };
+            match end {
+                Some(end) => self.position += end,
+                None => return
+            }
+        }
+    }
+
+    fn element(&mut self, depth: usize) -> Option<Element> {
+        if depth > MAX_DEPTH || !self.rest().starts_with('<') {
+            return None;
+        }
+
+        let tag_end = return_none_on_none!(self.rest().find('>'));
+        let tag = &self.rest()[1..tag_end];
+        self.position += tag_end + 1;
+        // Attributes aren't used by XML-RPC, so they are skipped over
+        let name = return_none_on_none!(tag.trim_end_matches('/').split_whitespace().next()).to_string();
+        if tag.ends_with('/') {
+            return Some(Element { name: name, children: vec![] });
+        }
+
+        let mut children = vec![];
+        loop {
+            let rest = self.rest();
+            if rest.starts_with("</") {
+                let close_end = return_none_on_none!(rest.find('>'));
+                if rest[2..close_end].trim() != name {
+                    return None;
+                }
+                self.position += close_end + 1;
+                return Some(Element { name: name, children: children });
+            } else if rest.starts_with("<!--") {
+                self.position += return_none_on_none!(rest.find("-->")) + 3;
+            } else if rest.starts_with('<') {
+                children.push(Node::Element(return_none_on_none!(self.element(depth + 1))));
+            } else {
+                let text_end = return_none_on_none!(rest.find('<'));
+                children.push(Node::Text(return_none_on_none!(unescape(&rest[..text_end]))));
+                self.position += text_end;
+            }
+        }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.position..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api::ApiValue;
+    use super::{parse_call,write_value};
+
+    #[test]
+    fn test_parse_call() {
+        let xml = "<?xml version='1.0'?>\n<methodCall>\n<methodName>sendMessage</methodName>\n<params>\n\
+            <param><value><string>a &amp; b &#60;c&#x3e;</string></value></param>\n\
+            <param><value>plain</value></param>\n\
+            <param><value><int>2</int></value></param>\n\
+            <param><value><boolean>1</boolean></value></param>\n\
+            <param><value><base64>SGk=</base64></value></param>\n\
+            <param><value><string/></value></param>\n\
+            </params>\n</methodCall>\n";
+
+        assert_eq!(Some(("sendMessage".to_string(), vec![
+            ApiValue::String("a & b <c>".to_string()),
+            ApiValue::String("plain".to_string()),
+            ApiValue::Int(2),
+            ApiValue::Bool(true),
+            ApiValue::String("Hi".to_string()),
+            ApiValue::String("".to_string())
+        ])), parse_call(xml));
+    }
+
+    #[test]
+    fn test_bad_calls_are_rejected() {
+        assert_eq!(None, parse_call("<methodCall><methodName>a</methodName>"));
+        assert_eq!(None, parse_call("<methodCall><methodName>a</methodCall></methodName>"));
+        assert_eq!(None, parse_call("<methodResponse></methodResponse>"));
+        assert_eq!(None, parse_call("<methodCall><methodName>a</methodName><params><param><value><int>x</int></value></param></params></methodCall>"));
+        assert_eq!(None, parse_call(&"<a>".repeat(100)));
+    }
+
+    #[test]
+    fn test_values_are_escaped() {
+        assert_eq!("<value><string>&lt;b&gt; &amp;</string></value>", write_value(&ApiValue::String("<b> &".to_string())));
+        assert_eq!("<value><boolean>0</boolean></value>", write_value(&ApiValue::Bool(false)));
+    }
+}
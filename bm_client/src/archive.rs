@@ -0,0 +1,212 @@
+use address::Address;
+use checksum::sha256_hash;
+use events::OutboxStatus;
+use inbox::InboxMessage;
+use mail::{MAIL_DOMAIN,format_mail,mail_address,mbox_date,parse_mail,parse_mail_address,parse_mail_date};
+use messages::{Folder,StoredMessage};
+use outbox::{OutboxMessage,status_from_name,status_name};
+use rustc_serialize::hex::FromHex;
+use std::time::SystemTime;
+
+// Messages kept as internet mail, in mbox or .eml files, for archiving, moving between stores
+// and reading in other mail programs. A few extra headers keep what only Bitmessage knows.
+
+const FOLDER_HEADER: &'static str = "X-Bitmessage-Folder";
+const STATUS_HEADER: &'static str = "X-Bitmessage-Status";
+const MESSAGE_ID_LENGTH: usize = 32;
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct ImportStats {
+    imported: usize,
+    skipped: usize // already in the store, or not Bitmessage mail
+}
+
+impl ImportStats {
+    pub fn new() -> ImportStats {
+        ImportStats {
+            imported: 0,
+            skipped: 0
+        }
+    }
+
+    pub fn imported(&self) -> usize {
+        self.imported
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    pub fn count(&mut self, imported: bool) {
+        if imported {
+            self.imported += 1;
+        } else {
+            self.skipped += 1;
+        }
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub enum Archived {
+    Received(InboxMessage),
+    Sent(OutboxMessage)
+}
+
+// An RFC 5322 message with CRLF line endings, as .eml files are
+pub fn eml(message: &StoredMessage) -> String {
+    let mail = format_mail(message);
+    // Status is what mbox readers go by for read messages
+    let mut extra = format!("Status: {}\r\n{}: {}\r\n", if message.is_read() { "RO" } else { "O" }, FOLDER_HEADER, folder_name(message.folder()));
+    if let Some(status) = message.status() {
+        extra.push_str(&format!("{}: {}\r\n", STATUS_HEADER, status_name(status)));
+    }
+
+    let header_length = mail.header.len() - 2;
+    format!("{}{}\r\n{}", &mail.header[..header_length], extra, mail.text)
+}
+
+// mboxrd: each message starts with a From line, and lines that would look like one get another >
+pub fn mbox(messages: &[StoredMessage]) -> String {
+    let mut mbox = String::new();
+    for message in messages.iter() {
+        mbox.push_str(&format!("From {} {}\n", mail_address(message.from()), mbox_date(message.timestamp())));
+        for line in eml(message).lines() {
+            if line.trim_start_matches('>').starts_with("From ") {
+                mbox.push('>');
+            }
+            mbox.push_str(line);
+            mbox.push('\n');
+        }
+        mbox.push('\n');
+    }
+    mbox
+}
+
+pub fn is_mbox(text: &str) -> bool {
+    text.starts_with("From ")
+}
+
+pub fn split_mbox(text: &str) -> Vec<String> {
+    let mut messages = vec![];
+    let mut current: Option<String> = None;
+    for line in text.lines() {
+        if line.starts_with("From ") {
+            messages.extend(current.take());
+            current = Some(String::new());
+        } else if let Some(ref mut message) = current {
+            let quoted = line.starts_with('>') && line.trim_start_matches('>').starts_with("From ");
+            message.push_str(if quoted { &line[1..] } else { line });
+            message.push('\n');
+        }
+    }
+    messages.extend(current);
+    messages
+}
+
+// The message back as it was stored, and whether it was in the trash. Mail from elsewhere
+// goes by whether the sender is one of ours, and counts as unread unless its Status says so.
+pub fn read_mail<F: Fn(&Address) -> bool>(text: &str, is_ours: F) -> Option<(Archived, bool)> {
+    let mail = parse_mail(text);
+    let from = match mail.header("From").and_then(|from| parse_mail_address(&from)) {
+        Some(from) => from,
+        None => return None
+    };
+    let to = mail.header("To").and_then(|to| parse_mail_address(&to));
+    let timestamp = mail.header("Date").and_then(|date| parse_mail_date(&date)).unwrap_or(SystemTime::now());
+    // Hashing the message gives mail from elsewhere an id that's the same when imported again
+    let id = mail.header("Message-ID").and_then(|message_id| message_id_bytes(&message_id)).unwrap_or(sha256_hash(text.as_bytes()).to_vec());
+    let folder = mail.header(FOLDER_HEADER).and_then(|name| folder_from_name(&name));
+
+    let sent = match folder {
+        Some(Folder::Inbox) => false,
+        Some(Folder::Outbox) | Some(Folder::Sent) => true,
+        _ => is_ours(&from)
+    };
+    let archived = if sent {
+        // Messages that hadn't gone out come back as failed, rather than being sent again
+        let status = match mail.header(STATUS_HEADER).and_then(|name| status_from_name(&name)) {
+            Some(status) if status == OutboxStatus::Sent || status == OutboxStatus::AckReceived || status == OutboxStatus::GaveUp => status,
+            Some(_) => OutboxStatus::Failed,
+            None => OutboxStatus::Sent
+        };
+        Archived::Sent(OutboxMessage::restored(&id, &from, to.as_ref(), &mail.subject, &mail.body, status, timestamp))
+    } else {
+        let read = mail.header("Status").map_or(false, |status| status.contains('R'));
+        Archived::Received(InboxMessage::new(&id, &from, to.as_ref(), &mail.subject, &mail.body).restored(timestamp, read))
+    };
+    Some((archived, folder == Some(Folder::Trash)))
+}
+
+fn message_id_bytes(message_id: &str) -> Option<Vec<u8>> {
+    let message_id = message_id.trim().trim_start_matches('<').trim_end_matches('>');
+    let mut parts = message_id.splitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(hex), Some(domain)) if domain.eq_ignore_ascii_case(MAIL_DOMAIN) => {
+            hex.from_hex().ok().and_then(|id| if id.len() == MESSAGE_ID_LENGTH { Some(id) } else { None })
+        },
+        _ => None
+    }
+}
+
+fn folder_name(folder: Folder) -> &'static str {
+    match folder {
+        Folder::Inbox => "inbox",
+        Folder::Outbox => "outbox",
+        Folder::Sent => "sent",
+        Folder::Trash => "trash"
+    }
+}
+
+fn folder_from_name(name: &str) -> Option<Folder> {
+    match &name.trim().to_lowercase()[..] {
+        "inbox" => Some(Folder::Inbox),
+        "outbox" => Some(Folder::Outbox),
+        "sent" => Some(Folder::Sent),
+        "trash" => Some(Folder::Trash),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use address::Address;
+    use events::OutboxStatus;
+    use inbox::InboxMessage;
+    use std::str::FromStr;
+    use std::time::{Duration,UNIX_EPOCH};
+    use super::{Archived,mbox,read_mail,split_mbox};
+
+    #[test]
+    fn test_mbox_quoting() {
+        let text = "From a@bitmessage Tue Feb 29 12:34:00 2000\nSubject: One\n\n>From here\n>>From there\n\nFrom b@bitmessage Tue Feb 29 12:34:00 2000\nSubject: Two\n";
+        assert_eq!(vec![ "Subject: One\n\nFrom here\n>From there\n\n", "Subject: Two\n" ], split_mbox(text));
+        assert_eq!(0, split_mbox("").len());
+        assert_eq!(0, mbox(&[]).len());
+    }
+
+    #[test]
+    fn test_foreign_mail() {
+        let ours = Address::from_str("BM-2cXxfcSetKnbHJX2Y85rSkaVpsdNUZ5q9h").unwrap();
+        let text = "From: Someone <BM-2cTmU3oMh2CYijawr3P42nfwMJ6tJmxnuC@bitmessage>\nTo: BM-2cXxfcSetKnbHJX2Y85rSkaVpsdNUZ5q9h@bitmessage\n\
+            Date: Tue, 29 Feb 2000 12:34:00 +0000\nSubject: Hi\nStatus: RO\n\nHello\n";
+        let (archived, trashed) = read_mail(text, |address| address == &ours).unwrap();
+        let expected = InboxMessage::new(&archived_id(&archived), &Address::from_str("BM-2cTmU3oMh2CYijawr3P42nfwMJ6tJmxnuC").unwrap(), Some(&ours), "Hi", "Hello")
+            .restored(UNIX_EPOCH + Duration::from_secs(951827640), true);
+        assert_eq!((Archived::Received(expected), false), (archived, trashed));
+
+        // From one of ours, with no folder to say otherwise
+        let text = "From: BM-2cXxfcSetKnbHJX2Y85rSkaVpsdNUZ5q9h@bitmessage\nSubject: Hi\n\nHello\n";
+        match read_mail(text, |address| address == &ours) {
+            Some((Archived::Sent(outbox_message), false)) => assert_eq!(OutboxStatus::Sent, outbox_message.status()),
+            _ => panic!("not read as sent")
+        }
+        assert!(read_mail("From: someone@example.com\n\nHello\n", |_| false).is_none());
+    }
+
+    fn archived_id(archived: &Archived) -> Vec<u8> {
+        match archived {
+            &Archived::Received(ref inbox_message) => inbox_message.msgid().to_vec(),
+            &Archived::Sent(ref outbox_message) => outbox_message.ackdata().to_vec()
+        }
+    }
+}
@@ -0,0 +1,75 @@
+const ALPHABET: &'static [u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+// Leading zero bytes are written as '1's, as Bitcoin does
+pub fn encode(bytes: &[u8]) -> String {
+    let leading_zeros = bytes.iter().take_while(|&&b| b == 0).count();
+
+    // Repeated division of the big-endian number by 58, least significant digit first
+    let mut digits: Vec<u8> = vec![];
+    for &byte in &bytes[leading_zeros..] {
+        let mut carry = byte as u32;
+        for digit in digits.iter_mut() {
+            carry += (*digit as u32) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let mut encoded = String::with_capacity(leading_zeros + digits.len());
+    for _ in 0..leading_zeros {
+        encoded.push('1');
+    }
+    for &digit in digits.iter().rev() {
+        encoded.push(ALPHABET[digit as usize] as char);
+    }
+
+    encoded
+}
+
+pub fn decode(encoded: &str) -> Option<Vec<u8>> {
+    let leading_ones = encoded.bytes().take_while(|&c| c == b'1').count();
+
+    let mut bytes: Vec<u8> = vec![];
+    for c in encoded.bytes().skip(leading_ones) {
+        let mut carry = match ALPHABET.iter().position(|&a| a == c) {
+            Some(value) => value as u32,
+            None => return None
+        };
+        for byte in bytes.iter_mut() {
+            carry += (*byte as u32) * 58;
+            *byte = (carry & 0xff) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xff) as u8);
+            carry >>= 8;
+        }
+    }
+
+    let mut decoded = vec![0u8; leading_ones];
+    decoded.extend(bytes.iter().rev());
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode,encode};
+
+    #[test]
+    fn test_encode() {
+        assert_eq!("", encode(&[]));
+        assert_eq!("2NEpo7TZRRrLZSi2U", encode(b"Hello World!"));
+        assert_eq!("11233QC4", encode(&[ 0, 0, 0x28, 0x7f, 0xb4, 0xcd ]));
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Some(b"Hello World!".to_vec()), decode("2NEpo7TZRRrLZSi2U"));
+        assert_eq!(Some(vec![ 0, 0, 0x28, 0x7f, 0xb4, 0xcd ]), decode("11233QC4"));
+        assert_eq!(None, decode("0OIl"));
+    }
+}
@@ -0,0 +1,253 @@
+use config::Config;
+use events::{Event,Events};
+use known_nodes::KnownNodes;
+use message::KnownNode;
+use net::{PeerAddr,resolve_peer_addrs};
+use rustc_serialize::json::Json;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+
+const DEFAULT_PORT: u16 = 8444;
+const DEFAULT_STREAM: u32 = 1; // for known nodes files that don't say
+const MIN_BOOTSTRAP_INTERVAL_SECS: u64 = 300;
+
+pub struct Bootstrapper {
+    config: Config,
+    events: Events,
+    last_bootstrap: Option<Instant>
+}
+
+impl Bootstrapper {
+    pub fn new(config: &Config, events: &Events) -> Bootstrapper {
+        Bootstrapper {
+            config: config.clone(),
+            events: events.clone(),
+            last_bootstrap: None
+        }
+    }
+
+    // Each stream we're in needs enough nodes of its own
+    pub fn bootstrap_if_needed(&mut self, known_nodes: &mut KnownNodes) {
+        let threshold = self.config.bootstrap_threshold();
+        let streams: Vec<u32> = self.config.streams().iter().cloned()
+            .filter(|&stream| known_nodes.len_for_stream(stream) < threshold)
+            .collect();
+        if streams.is_empty() {
+            return;
+        }
+
+        // Don't hammer DNS or the seed nodes if bootstrapping didn't help last time
+        if let Some(last_bootstrap) = self.last_bootstrap {
+            if last_bootstrap.elapsed() < Duration::from_secs(MIN_BOOTSTRAP_INTERVAL_SECS) {
+                return;
+            }
+        }
+
+        self.last_bootstrap = Some(Instant::now());
+        self.bootstrap(known_nodes, &streams);
+    }
+
+    fn bootstrap(&self, known_nodes: &mut KnownNodes, streams: &[u32]) {
+        if let Some(path) = self.config.known_nodes_file() {
+            match import_known_nodes(path) {
+                Ok(imported) => for known_node in imported {
+                    known_nodes.add_known_node(&known_node);
+                },
+                Err(_) => self.events.emit(Event::Warning(format!("Could not import known nodes from {}", path.display())))
+            }
+        }
+
+        let seeds = self.config.seed_nodes().iter().chain(self.config.dns_seeds().iter());
+        for seed in seeds {
+            match resolve_peer_addrs(seed, DEFAULT_PORT) {
+                Ok(peer_addrs) => for peer_addr in peer_addrs {
+                    for &stream in streams {
+                        known_nodes.add_known_node(&seed_node(peer_addr, stream));
+                    }
+                },
+                Err(_) => self.events.emit(Event::Warning(format!("Could not resolve bootstrap node {}", seed)))
+            }
+        }
+    }
+}
+
+fn seed_node(peer_addr: PeerAddr, stream: u32) -> KnownNode {
+    KnownNode {
+        last_seen: SystemTime::now(),
+        stream: stream,
+        services: 1,
+        peer_addr: peer_addr
+    }
+}
+
+#[derive(Debug)]
+pub enum ImportError {
+    Io,
+    BadFormat
+}
+
+fn import_known_nodes(path: &Path) -> Result<Vec<KnownNode>,ImportError> {
+    let mut contents = String::new();
+    let mut file = try!(File::open(path).map_err(|_| ImportError::Io));
+    try!(file.read_to_string(&mut contents).map_err(|_| ImportError::Io));
+
+    parse_known_nodes(&contents)
+}
+
+// Accepts either PyBitmessage's JSON knownnodes.dat or one "host:port [stream]" per line
+fn parse_known_nodes(contents: &str) -> Result<Vec<KnownNode>,ImportError> {
+    match contents.trim_start().starts_with('[') {
+        true => parse_json_known_nodes(contents),
+        false => Ok(parse_line_known_nodes(contents))
+    }
+}
+
+fn parse_json_known_nodes(contents: &str) -> Result<Vec<KnownNode>,ImportError> {
+    let json = try!(Json::from_str(contents).map_err(|_| ImportError::BadFormat));
+    let entries = try!(json.as_array().ok_or(ImportError::BadFormat));
+
+    let mut known_nodes = vec![];
+    for entry in entries {
+        let stream = entry.find("stream").and_then(|s| s.as_u64()).unwrap_or(DEFAULT_STREAM as u64);
+        let host = match entry.find_path(&[ "peer", "host" ]).and_then(|h| h.as_string()) {
+            Some(host) => host,
+            None => continue
+        };
+        let port = entry.find_path(&[ "peer", "port" ]).and_then(|p| p.as_u64()).unwrap_or(DEFAULT_PORT as u64);
+        let last_seen = entry.find_path(&[ "info", "lastseen" ]).and_then(|l| l.as_f64());
+
+        if stream > u32::max_value() as u64 || port > u16::max_value() as u64 {
+            continue;
+        }
+
+        let peer_addrs = match resolve_peer_addrs(&format!("{}:{}", host, port), DEFAULT_PORT) {
+            Ok(peer_addrs) => peer_addrs,
+            Err(_) => continue
+        };
+
+        for peer_addr in peer_addrs {
+            let mut known_node = seed_node(peer_addr, stream as u32);
+            if let Some(secs) = last_seen {
+                if secs > 0.0 {
+                    known_node.last_seen = UNIX_EPOCH + Duration::from_secs(secs as u64);
+                }
+            }
+            known_nodes.push(known_node);
+        }
+    }
+
+    Ok(known_nodes)
+}
+
+fn parse_line_known_nodes(contents: &str) -> Vec<KnownNode> {
+    let mut known_nodes = vec![];
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut fields = line.split_whitespace();
+        let host_and_port = fields.next().unwrap();
+        let stream = match fields.next().map(|s| s.parse::<u32>()) {
+            None => DEFAULT_STREAM,
+            Some(Ok(stream)) => stream,
+            Some(Err(_)) => continue
+        };
+
+        if let Ok(peer_addrs) = resolve_peer_addrs(host_and_port, DEFAULT_PORT) {
+            for peer_addr in peer_addrs {
+                known_nodes.push(seed_node(peer_addr, stream));
+            }
+        }
+    }
+
+    known_nodes
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use events::{Event,Events};
+    use known_nodes::KnownNodes;
+    use net::{OnionAddr,PeerAddr,to_socket_addr};
+    use persist::Persister;
+    use std::time::{Duration,UNIX_EPOCH};
+    use super::{Bootstrapper,parse_known_nodes};
+
+    #[test]
+    fn test_parse_pybitmessage_json() {
+        let contents = r#"[
+            {"stream": 1, "peer": {"host": "5.45.99.75", "port": 8444}, "info": {"lastseen": 1500000000, "rating": 0, "self": false}},
+            {"stream": 2, "peer": {"host": "j5n7eiypq3u4mzar.onion", "port": 8448}, "info": {"lastseen": 1500000001.5}}
+        ]"#;
+
+        let known_nodes = parse_known_nodes(contents).unwrap();
+
+        assert_eq!(2, known_nodes.len());
+        assert_eq!(PeerAddr::Ip(to_socket_addr("5.45.99.75:8444")), known_nodes[0].peer_addr);
+        assert_eq!(1, known_nodes[0].stream);
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(1500000000), known_nodes[0].last_seen);
+        assert_eq!(PeerAddr::Onion(OnionAddr::from_hostname("j5n7eiypq3u4mzar.onion", 8448).unwrap()), known_nodes[1].peer_addr);
+        assert_eq!(2, known_nodes[1].stream);
+    }
+
+    #[test]
+    fn test_parse_lines() {
+        let contents = "# seeds\n5.45.99.75:8444\n\n75.167.159.54 2\nnot a port:x\n";
+
+        let known_nodes = parse_known_nodes(contents).unwrap();
+
+        assert_eq!(2, known_nodes.len());
+        assert_eq!(PeerAddr::Ip(to_socket_addr("5.45.99.75:8444")), known_nodes[0].peer_addr);
+        assert_eq!(1, known_nodes[0].stream);
+        assert_eq!(PeerAddr::Ip(to_socket_addr("75.167.159.54:8444")), known_nodes[1].peer_addr);
+        assert_eq!(2, known_nodes[1].stream);
+    }
+
+    #[test]
+    fn test_parse_bad_json() {
+        assert!(parse_known_nodes("[ {").is_err());
+    }
+
+    #[test]
+    fn test_bootstrap_only_below_threshold() {
+        let persister = Persister::new();
+        let mut known_nodes = KnownNodes::new(persister.clone());
+        let mut bootstrapper = Bootstrapper::new(&Config::new(), &Events::new());
+
+        bootstrapper.bootstrap_if_needed(&mut known_nodes);
+        assert_eq!(1, known_nodes.len_for_stream(1));
+
+        // Still above the threshold, and also too soon to try again
+        bootstrapper.bootstrap_if_needed(&mut known_nodes);
+        assert_eq!(1, known_nodes.len_for_stream(1));
+    }
+
+    #[test]
+    fn test_bootstrap_every_stream_without_duplicates() {
+        let mut known_nodes = KnownNodes::new(Persister::new());
+        let config = Config::builder().streams(&[ 1, 2 ]).seed_nodes(&[ "127.0.0.1:8444" ]).dns_seeds(&[]).bootstrap_threshold(2).build().unwrap();
+
+        for _ in 0..2 {
+            Bootstrapper::new(&config, &Events::new()).bootstrap_if_needed(&mut known_nodes);
+        }
+
+        // The one seed can't make up the threshold by being added again
+        assert_eq!(1, known_nodes.len_for_stream(1));
+        assert_eq!(1, known_nodes.len_for_stream(2));
+    }
+
+    #[test]
+    fn test_missing_known_nodes_file_is_reported() {
+        let events = Events::new();
+        let receiver = events.subscribe();
+        let config = Config::builder().seed_nodes(&[]).dns_seeds(&[]).known_nodes_file("/nonexistent/knownnodes.dat").build().unwrap();
+
+        Bootstrapper::new(&config, &events).bootstrap_if_needed(&mut KnownNodes::new(Persister::new()));
+
+        assert_eq!(Event::Warning("Could not import known nodes from /nonexistent/knownnodes.dat".to_string()), receiver.try_recv().unwrap());
+    }
+}
@@ -1,6 +1,8 @@
 use byteorder::{BigEndian,ReadBytesExt};
 use crypto::digest::Digest;
-use crypto::sha2::Sha512;
+use crypto::ripemd160::Ripemd160;
+use crypto::sha1::Sha1;
+use crypto::sha2::{Sha256,Sha512};
 use std::io::Cursor;
 
 pub fn sha512_hash(input: &[u8]) -> [u8; 64] {
@@ -14,6 +16,40 @@ pub fn sha512_hash(input: &[u8]) -> [u8; 64] {
     result
 }
 
+pub fn double_sha512_hash(input: &[u8]) -> [u8; 64] {
+    sha512_hash(&sha512_hash(input))
+}
+
+pub fn sha256_hash(input: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(input);
+
+    let mut result: [u8; 32] = [0; 32];
+    hasher.result(&mut result[..]);
+
+    result
+}
+
+pub fn sha1_hash(input: &[u8]) -> [u8; 20] {
+    let mut hasher = Sha1::new();
+    hasher.input(input);
+
+    let mut result: [u8; 20] = [0; 20];
+    hasher.result(&mut result[..]);
+
+    result
+}
+
+pub fn ripemd160_hash(input: &[u8]) -> [u8; 20] {
+    let mut hasher = Ripemd160::new();
+    hasher.input(input);
+
+    let mut result: [u8; 20] = [0; 20];
+    hasher.result(&mut result[..]);
+
+    result
+}
+
 pub fn sha512_checksum(input: &[u8]) -> u32 {
     let hash = sha512_hash(input);
 
@@ -25,6 +61,7 @@ pub fn sha512_checksum(input: &[u8]) -> u32 {
 
 #[cfg(test)]
 mod tests {
+    use super::ripemd160_hash;
     use super::sha512_checksum;
     use super::sha512_hash;
 
@@ -65,4 +102,10 @@ mod tests {
         let checksum = sha512_checksum(&bytes[..]);
         assert_eq!(3481526581, checksum);
     }
+
+    #[test]
+    fn test_ripemd160() {
+        let ripemd160 = ripemd160_hash(b"hello");
+        assert_eq!(&[ 0x10, 0x8f, 0x07, 0xb8 ], &ripemd160[0..4]);
+    }
 }
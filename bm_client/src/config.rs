@@ -1,11 +1,47 @@
+use ini::{IniError,parse_ini,parse_list};
+use message::{MAX_PAYLOAD_LENGTH_FOR_OBJECT,Message};
 use rand::{OsRng,Rng};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self,Read};
+use std::mem;
+use std::net::{IpAddr,Ipv4Addr,SocketAddr};
+use std::path::{Path,PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct Config {
     user_agent: String,
     nonce: u64,
+    data_dir: Option<PathBuf>,
+    listen_addr: IpAddr,
     port: u16,
-    concurrent_connection_attempts: u16
+    concurrent_connection_attempts: u16,
+    streams: Vec<u32>,
+    socks_proxy: Option<SocketAddr>,
+    local_discovery: bool,
+    seed_nodes: Vec<String>,
+    dns_seeds: Vec<String>,
+    known_nodes_file: Option<PathBuf>,
+    bootstrap_threshold: usize,
+    pow_threads: usize,
+    max_write_buffer: usize,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+    max_resends: u32,
+    api_enabled: bool,
+    api_listen_addr: IpAddr,
+    api_port: u16,
+    api_username: String,
+    api_password: String,
+    gateway_enabled: bool,
+    gateway_listen_addr: IpAddr,
+    smtp_port: u16,
+    imap_port: u16,
+    gateway_username: String,
+    gateway_password: String
 }
 
 impl Config {
@@ -13,11 +49,44 @@ impl Config {
         Config {
             user_agent: concat!("Rubbem ", env!("CARGO_PKG_VERSION")).to_string(),
             nonce: create_nonce(),
+            data_dir: None,
+            listen_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
             port: 8555,
-            concurrent_connection_attempts: 8
+            concurrent_connection_attempts: 8,
+            streams: vec![ 1 ],
+            socks_proxy: None,
+            local_discovery: false,
+            seed_nodes: vec![ "127.0.0.1:8444".to_string() ],
+            dns_seeds: vec![],
+            known_nodes_file: None,
+            bootstrap_threshold: 1,
+            pow_threads: 1,
+            max_write_buffer: 20_000_000,
+            handshake_timeout: Duration::from_secs(20),
+            idle_timeout: Duration::from_secs(10 * 60),
+            max_resends: 5,
+            api_enabled: false,
+            api_listen_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            api_port: 8442,
+            api_username: String::new(),
+            api_password: String::new(),
+            gateway_enabled: false,
+            gateway_listen_addr: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            smtp_port: 8425,
+            imap_port: 8143,
+            gateway_username: String::new(),
+            gateway_password: String::new()
         }
     }
 
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        try!(ConfigBuilder::from_file(path)).build()
+    }
+
     pub fn user_agent(&self) -> &str {
         &self.user_agent
     }
@@ -26,6 +95,14 @@ impl Config {
         self.nonce
     }
 
+    pub fn data_dir(&self) -> Option<&Path> {
+        self.data_dir.as_ref().map(|path| path.as_path())
+    }
+
+    pub fn listen_addr(&self) -> IpAddr {
+        self.listen_addr
+    }
+
     pub fn port(&self) -> u16 {
         self.port
     }
@@ -33,9 +110,572 @@ impl Config {
     pub fn concurrent_connection_attempts(&self) -> u16 {
         self.concurrent_connection_attempts
     }
+
+    pub fn streams(&self) -> &[u32] {
+        &self.streams
+    }
+
+    pub fn socks_proxy(&self) -> Option<SocketAddr> {
+        self.socks_proxy
+    }
+
+    pub fn local_discovery(&self) -> bool {
+        self.local_discovery
+    }
+
+    pub fn seed_nodes(&self) -> &[String] {
+        &self.seed_nodes
+    }
+
+    pub fn dns_seeds(&self) -> &[String] {
+        &self.dns_seeds
+    }
+
+    pub fn known_nodes_file(&self) -> Option<&Path> {
+        self.known_nodes_file.as_ref().map(|path| path.as_path())
+    }
+
+    pub fn bootstrap_threshold(&self) -> usize {
+        self.bootstrap_threshold
+    }
+
+    pub fn pow_threads(&self) -> usize {
+        self.pow_threads
+    }
+
+    pub fn max_write_buffer(&self) -> usize {
+        self.max_write_buffer
+    }
+
+    pub fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout
+    }
+
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    // How many times an unacknowledged message is sent again before giving up
+    pub fn max_resends(&self) -> u32 {
+        self.max_resends
+    }
+
+    pub fn api_enabled(&self) -> bool {
+        self.api_enabled
+    }
+
+    pub fn api_listen_addr(&self) -> IpAddr {
+        self.api_listen_addr
+    }
+
+    // Port 0 lets the system pick a free port
+    pub fn api_port(&self) -> u16 {
+        self.api_port
+    }
+
+    pub fn api_username(&self) -> &str {
+        &self.api_username
+    }
+
+    pub fn api_password(&self) -> &str {
+        &self.api_password
+    }
+
+    pub fn gateway_enabled(&self) -> bool {
+        self.gateway_enabled
+    }
+
+    pub fn gateway_listen_addr(&self) -> IpAddr {
+        self.gateway_listen_addr
+    }
+
+    // Port 0 lets the system pick a free port
+    pub fn smtp_port(&self) -> u16 {
+        self.smtp_port
+    }
+
+    pub fn imap_port(&self) -> u16 {
+        self.imap_port
+    }
+
+    pub fn gateway_username(&self) -> &str {
+        &self.gateway_username
+    }
+
+    pub fn gateway_password(&self) -> &str {
+        &self.gateway_password
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Syntax(PathBuf, IniError),
+    UnknownSetting { line: usize, section: String, key: String },
+    BadValue { setting: &'static str, value: String },
+    Invalid { setting: &'static str, reason: &'static str }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ConfigError::Io(ref path, ref err) => write!(f, "Cannot read config file {}: {}", path.display(), err),
+            &ConfigError::Syntax(ref path, ref err) => write!(f, "Syntax error in config file {} at {}", path.display(), err),
+            &ConfigError::UnknownSetting { line, ref section, ref key } => match section.is_empty() {
+                true => write!(f, "Unknown setting '{}' at line {}", key, line),
+                false => write!(f, "Unknown setting '{}' in section [{}] at line {}", key, section, line)
+            },
+            &ConfigError::BadValue { setting, ref value } => write!(f, "Cannot understand '{}' as a value for {}", value, setting),
+            &ConfigError::Invalid { setting, reason } => write!(f, "Invalid {}: {}", setting, reason)
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            &ConfigError::Io(_, ref err) => Some(err),
+            &ConfigError::Syntax(_, ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+pub struct ConfigBuilder {
+    config: Config
+}
+
+impl ConfigBuilder {
+    pub fn new() -> ConfigBuilder {
+        ConfigBuilder {
+            config: Config::new()
+        }
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<ConfigBuilder, ConfigError> {
+        let path = path.as_ref();
+        let mut contents = String::new();
+        let mut file = try!(File::open(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e)));
+        try!(file.read_to_string(&mut contents).map_err(|e| ConfigError::Io(path.to_path_buf(), e)));
+
+        let mut builder = ConfigBuilder::new();
+        try!(builder.apply_file_contents(&contents).map_err(|e| match e {
+            FileError::Syntax(err) => ConfigError::Syntax(path.to_path_buf(), err),
+            FileError::Config(err) => err
+        }));
+
+        Ok(builder)
+    }
+
+    fn apply_file_contents(&mut self, contents: &str) -> Result<(), FileError> {
+        let entries = try!(parse_ini(contents).map_err(FileError::Syntax));
+
+        for entry in entries {
+            let value = &entry.value;
+            let config = &mut self.config;
+            match (entry.section.as_str(), entry.key.as_str()) {
+                ("", "data_dir") => config.data_dir = Some(PathBuf::from(value)),
+                ("", "user_agent") => config.user_agent = value.clone(),
+                ("network", "listen_address") => config.listen_addr = try!(parse_value("network.listen_address", value)),
+                ("network", "port") => config.port = try!(parse_value("network.port", value)),
+                ("network", "max_connections") => config.concurrent_connection_attempts = try!(parse_value("network.max_connections", value)),
+                ("network", "streams") => config.streams = try!(parse_list_values("network.streams", value)),
+                ("network", "socks_proxy") => config.socks_proxy = try!(parse_optional_value("network.socks_proxy", value)),
+                ("network", "local_discovery") => config.local_discovery = try!(parse_value("network.local_discovery", value)),
+                ("network", "handshake_timeout_secs") => config.handshake_timeout = Duration::from_secs(try!(parse_value("network.handshake_timeout_secs", value))),
+                ("network", "idle_timeout_secs") => config.idle_timeout = Duration::from_secs(try!(parse_value("network.idle_timeout_secs", value))),
+                ("network", "max_write_buffer") => config.max_write_buffer = try!(parse_value("network.max_write_buffer", value)),
+                ("bootstrap", "seed_nodes") => config.seed_nodes = parse_list(value),
+                ("bootstrap", "dns_seeds") => config.dns_seeds = parse_list(value),
+                ("bootstrap", "known_nodes_file") => config.known_nodes_file = Some(PathBuf::from(value)),
+                ("bootstrap", "threshold") => config.bootstrap_threshold = try!(parse_value("bootstrap.threshold", value)),
+                ("pow", "threads") => config.pow_threads = try!(parse_value("pow.threads", value)),
+                ("messages", "max_resends") => config.max_resends = try!(parse_value("messages.max_resends", value)),
+                ("api", "enabled") => config.api_enabled = try!(parse_value("api.enabled", value)),
+                ("api", "listen_address") => config.api_listen_addr = try!(parse_value("api.listen_address", value)),
+                ("api", "port") => config.api_port = try!(parse_value("api.port", value)),
+                ("api", "username") => config.api_username = value.clone(),
+                ("api", "password") => config.api_password = value.clone(),
+                ("gateway", "enabled") => config.gateway_enabled = try!(parse_value("gateway.enabled", value)),
+                ("gateway", "listen_address") => config.gateway_listen_addr = try!(parse_value("gateway.listen_address", value)),
+                ("gateway", "smtp_port") => config.smtp_port = try!(parse_value("gateway.smtp_port", value)),
+                ("gateway", "imap_port") => config.imap_port = try!(parse_value("gateway.imap_port", value)),
+                ("gateway", "username") => config.gateway_username = value.clone(),
+                ("gateway", "password") => config.gateway_password = value.clone(),
+                _ => return Err(FileError::Config(ConfigError::UnknownSetting {
+                    line: entry.line,
+                    section: entry.section.clone(),
+                    key: entry.key.clone()
+                }))
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn data_dir<P: AsRef<Path>>(mut self, data_dir: P) -> ConfigBuilder {
+        self.config.data_dir = Some(data_dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> ConfigBuilder {
+        self.config.user_agent = user_agent.to_string();
+        self
+    }
+
+    pub fn listen_addr(mut self, listen_addr: IpAddr) -> ConfigBuilder {
+        self.config.listen_addr = listen_addr;
+        self
+    }
+
+    pub fn port(mut self, port: u16) -> ConfigBuilder {
+        self.config.port = port;
+        self
+    }
+
+    pub fn max_connections(mut self, max_connections: u16) -> ConfigBuilder {
+        self.config.concurrent_connection_attempts = max_connections;
+        self
+    }
+
+    pub fn streams(mut self, streams: &[u32]) -> ConfigBuilder {
+        self.config.streams = streams.to_vec();
+        self
+    }
+
+    pub fn socks_proxy(mut self, socks_proxy: Option<SocketAddr>) -> ConfigBuilder {
+        self.config.socks_proxy = socks_proxy;
+        self
+    }
+
+    pub fn local_discovery(mut self, local_discovery: bool) -> ConfigBuilder {
+        self.config.local_discovery = local_discovery;
+        self
+    }
+
+    pub fn seed_nodes(mut self, seed_nodes: &[&str]) -> ConfigBuilder {
+        self.config.seed_nodes = seed_nodes.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn dns_seeds(mut self, dns_seeds: &[&str]) -> ConfigBuilder {
+        self.config.dns_seeds = dns_seeds.iter().map(|s| s.to_string()).collect();
+        self
+    }
+
+    pub fn known_nodes_file<P: AsRef<Path>>(mut self, known_nodes_file: P) -> ConfigBuilder {
+        self.config.known_nodes_file = Some(known_nodes_file.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn bootstrap_threshold(mut self, bootstrap_threshold: usize) -> ConfigBuilder {
+        self.config.bootstrap_threshold = bootstrap_threshold;
+        self
+    }
+
+    pub fn pow_threads(mut self, pow_threads: usize) -> ConfigBuilder {
+        self.config.pow_threads = pow_threads;
+        self
+    }
+
+    pub fn max_write_buffer(mut self, max_write_buffer: usize) -> ConfigBuilder {
+        self.config.max_write_buffer = max_write_buffer;
+        self
+    }
+
+    pub fn handshake_timeout(mut self, handshake_timeout: Duration) -> ConfigBuilder {
+        self.config.handshake_timeout = handshake_timeout;
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> ConfigBuilder {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn max_resends(mut self, max_resends: u32) -> ConfigBuilder {
+        self.config.max_resends = max_resends;
+        self
+    }
+
+    pub fn api_enabled(mut self, api_enabled: bool) -> ConfigBuilder {
+        self.config.api_enabled = api_enabled;
+        self
+    }
+
+    // Anything other than localhost exposes the API, and the password, to the network
+    pub fn api_listen_addr(mut self, api_listen_addr: IpAddr) -> ConfigBuilder {
+        self.config.api_listen_addr = api_listen_addr;
+        self
+    }
+
+    pub fn api_port(mut self, api_port: u16) -> ConfigBuilder {
+        self.config.api_port = api_port;
+        self
+    }
+
+    pub fn api_credentials(mut self, username: &str, password: &str) -> ConfigBuilder {
+        self.config.api_username = username.to_string();
+        self.config.api_password = password.to_string();
+        self
+    }
+
+    pub fn gateway_enabled(mut self, gateway_enabled: bool) -> ConfigBuilder {
+        self.config.gateway_enabled = gateway_enabled;
+        self
+    }
+
+    // Mail clients send the password in the clear, so this should stay on localhost
+    pub fn gateway_listen_addr(mut self, gateway_listen_addr: IpAddr) -> ConfigBuilder {
+        self.config.gateway_listen_addr = gateway_listen_addr;
+        self
+    }
+
+    pub fn gateway_ports(mut self, smtp_port: u16, imap_port: u16) -> ConfigBuilder {
+        self.config.smtp_port = smtp_port;
+        self.config.imap_port = imap_port;
+        self
+    }
+
+    pub fn gateway_credentials(mut self, username: &str, password: &str) -> ConfigBuilder {
+        self.config.gateway_username = username.to_string();
+        self.config.gateway_password = password.to_string();
+        self
+    }
+
+    pub fn build(self) -> Result<Config, ConfigError> {
+        let config = self.config;
+
+        if config.port == 0 {
+            return Err(invalid("port", "must be between 1 and 65535"));
+        }
+
+        if config.concurrent_connection_attempts == 0 {
+            return Err(invalid("max_connections", "must be at least 1"));
+        }
+
+        if config.streams.is_empty() {
+            return Err(invalid("streams", "at least one stream is needed"));
+        }
+
+        if config.streams.contains(&0) {
+            return Err(invalid("streams", "stream numbers start at 1"));
+        }
+
+        if config.pow_threads == 0 {
+            return Err(invalid("pow threads", "must be at least 1"));
+        }
+
+        // The write buffer must at least be able to hold a single object message, which is
+        // counted at its largest possible size
+        if config.max_write_buffer < MAX_PAYLOAD_LENGTH_FOR_OBJECT as usize + mem::size_of::<Message>() {
+            return Err(invalid("max_write_buffer", "must be large enough for one object message"));
+        }
+
+        if config.handshake_timeout == Duration::from_secs(0) {
+            return Err(invalid("handshake_timeout", "must be longer than zero"));
+        }
+
+        if config.idle_timeout < config.handshake_timeout {
+            return Err(invalid("idle_timeout", "must not be shorter than the handshake timeout"));
+        }
+
+        if config.user_agent.is_empty() || !config.user_agent.is_ascii() {
+            return Err(invalid("user_agent", "must be non-empty ASCII"));
+        }
+
+        if config.api_enabled && (config.api_username.is_empty() || config.api_password.is_empty()) {
+            return Err(invalid("api", "needs a username and password"));
+        }
+
+        if config.gateway_enabled && (config.gateway_username.is_empty() || config.gateway_password.is_empty()) {
+            return Err(invalid("gateway", "needs a username and password"));
+        }
+
+        if let Some(ref data_dir) = config.data_dir {
+            if data_dir.exists() && !data_dir.is_dir() {
+                return Err(invalid("data_dir", "exists but is not a directory"));
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+enum FileError {
+    Syntax(IniError),
+    Config(ConfigError)
+}
+
+fn invalid(setting: &'static str, reason: &'static str) -> ConfigError {
+    ConfigError::Invalid {
+        setting: setting,
+        reason: reason
+    }
+}
+
+fn parse_value<T: FromStr>(setting: &'static str, value: &str) -> Result<T, FileError> {
+    value.parse::<T>().map_err(|_| FileError::Config(ConfigError::BadValue {
+        setting: setting,
+        value: value.to_string()
+    }))
+}
+
+fn parse_optional_value<T: FromStr>(setting: &'static str, value: &str) -> Result<Option<T>, FileError> {
+    match value.is_empty() || value == "none" {
+        true => Ok(None),
+        false => parse_value(setting, value).map(Some)
+    }
+}
+
+fn parse_list_values<T: FromStr>(setting: &'static str, value: &str) -> Result<Vec<T>, FileError> {
+    let mut values = vec![];
+    for item in parse_list(value) {
+        values.push(try!(parse_value(setting, &item)));
+    }
+    Ok(values)
 }
 
 fn create_nonce() -> u64 {
     let mut rng = OsRng::new().unwrap();
     rng.next_u64()
 }
+
+#[cfg(test)]
+mod tests {
+    use message::{MAX_PAYLOAD_LENGTH_FOR_OBJECT,Message};
+    use net::to_socket_addr;
+    use std::mem;
+    use std::path::Path;
+    use std::time::Duration;
+    use super::{Config,ConfigBuilder,ConfigError,FileError};
+
+    fn from_contents(contents: &str) -> Result<Config, ConfigError> {
+        let mut builder = ConfigBuilder::new();
+        match builder.apply_file_contents(contents) {
+            Ok(()) => builder.build(),
+            Err(FileError::Config(err)) => Err(err),
+            Err(FileError::Syntax(err)) => panic!("Unexpected syntax error {}", err)
+        }
+    }
+
+    #[test]
+    fn test_defaults_are_valid() {
+        let config = Config::builder().build().unwrap();
+        assert_eq!(8555, config.port());
+        assert_eq!(&[ 1 ], config.streams());
+        assert_eq!(Duration::from_secs(20), config.handshake_timeout());
+        assert_eq!(Duration::from_secs(600), config.idle_timeout());
+    }
+
+    #[test]
+    fn test_builder() {
+        let config = Config::builder()
+            .port(8444)
+            .streams(&[ 1, 2 ])
+            .socks_proxy(Some(to_socket_addr("127.0.0.1:9050")))
+            .pow_threads(4)
+            .data_dir("/tmp/rubbem")
+            .build().unwrap();
+
+        assert_eq!(8444, config.port());
+        assert_eq!(&[ 1, 2 ], config.streams());
+        assert_eq!(Some(to_socket_addr("127.0.0.1:9050")), config.socks_proxy());
+        assert_eq!(4, config.pow_threads());
+        assert_eq!(Some(Path::new("/tmp/rubbem")), config.data_dir());
+    }
+
+    #[test]
+    fn test_file_contents() {
+        let contents = "data_dir = \"/var/lib/rubbem\"\n\
+            [network]\n\
+            listen_address = \"0.0.0.0\"\n\
+            port = 8444\n\
+            max_connections = 4\n\
+            streams = [ 1 ]\n\
+            socks_proxy = \"127.0.0.1:9050\"\n\
+            local_discovery = true\n\
+            handshake_timeout_secs = 30\n\
+            idle_timeout_secs = 300\n\
+            max_write_buffer = 1000000\n\
+            [bootstrap]\n\
+            seed_nodes = [ \"5.45.99.75:8444\", \"75.167.159.54:8444\" ]\n\
+            dns_seeds = bootstrap8444.bitmessage.org\n\
+            threshold = 10\n\
+            [pow]\n\
+            threads = 2\n\
+            [messages]\n\
+            max_resends = 3\n\
+            [api]\n\
+            enabled = true\n\
+            port = 8443\n\
+            username = \"user\"\n\
+            password = \"secret\"\n\
+            [gateway]\n\
+            enabled = true\n\
+            smtp_port = 2525\n\
+            imap_port = 1143\n\
+            username = \"mail\"\n\
+            password = \"hunter2\"\n";
+
+        let config = from_contents(contents).unwrap();
+
+        assert_eq!(Some(Path::new("/var/lib/rubbem")), config.data_dir());
+        assert_eq!("0.0.0.0".parse::<::std::net::IpAddr>().unwrap(), config.listen_addr());
+        assert_eq!(8444, config.port());
+        assert_eq!(4, config.concurrent_connection_attempts());
+        assert_eq!(Some(to_socket_addr("127.0.0.1:9050")), config.socks_proxy());
+        assert!(config.local_discovery());
+        assert_eq!(Duration::from_secs(30), config.handshake_timeout());
+        assert_eq!(Duration::from_secs(300), config.idle_timeout());
+        assert_eq!(1000000, config.max_write_buffer());
+        assert_eq!(2, config.seed_nodes().len());
+        assert_eq!(&[ "bootstrap8444.bitmessage.org".to_string() ], config.dns_seeds());
+        assert_eq!(10, config.bootstrap_threshold());
+        assert_eq!(2, config.pow_threads());
+        assert_eq!(3, config.max_resends());
+        assert!(config.api_enabled());
+        assert_eq!("127.0.0.1".parse::<::std::net::IpAddr>().unwrap(), config.api_listen_addr());
+        assert_eq!(8443, config.api_port());
+        assert_eq!("user", config.api_username());
+        assert_eq!("secret", config.api_password());
+        assert!(config.gateway_enabled());
+        assert_eq!(2525, config.smtp_port());
+        assert_eq!(1143, config.imap_port());
+        assert_eq!("mail", config.gateway_username());
+        assert_eq!("hunter2", config.gateway_password());
+    }
+
+    #[test]
+    fn test_unknown_setting_is_reported() {
+        let error = from_contents("[network]\nportt = 8444\n").err().unwrap();
+        assert_eq!("Unknown setting 'portt' in section [network] at line 2", format!("{}", error));
+    }
+
+    #[test]
+    fn test_bad_value_is_reported() {
+        let error = from_contents("[network]\nport = 70000\n").err().unwrap();
+        assert_eq!("Cannot understand '70000' as a value for network.port", format!("{}", error));
+    }
+
+    #[test]
+    fn test_invalid_configurations() {
+        let zero_threads = Config::builder().pow_threads(0).build().err().unwrap();
+        assert_eq!("Invalid pow threads: must be at least 1", format!("{}", zero_threads));
+
+        assert!(Config::builder().streams(&[]).build().is_err());
+        assert!(Config::builder().port(0).build().is_err());
+        assert!(Config::builder().max_write_buffer(1000).build().is_err());
+        assert!(Config::builder().max_write_buffer(MAX_PAYLOAD_LENGTH_FOR_OBJECT as usize).build().is_err());
+        assert!(Config::builder().max_write_buffer(MAX_PAYLOAD_LENGTH_FOR_OBJECT as usize + mem::size_of::<Message>()).build().is_ok());
+        assert!(Config::builder().idle_timeout(Duration::from_secs(5)).build().is_err());
+        assert!(Config::builder().api_enabled(true).api_credentials("user", "").build().is_err());
+        assert!(Config::builder().gateway_enabled(true).build().is_err());
+    }
+
+    #[test]
+    fn test_missing_file_is_reported() {
+        let error = Config::from_file("/nonexistent/rubbem.conf").err().unwrap();
+        assert!(format!("{}", error).starts_with("Cannot read config file /nonexistent/rubbem.conf"));
+    }
+}
@@ -1,14 +1,17 @@
 use channel::{ConstrainedReceiver,ConstrainedSender,constrained_channel};
-use message::{Message,MessageHandler,ParseError,read_message,write_message,VersionData};
+use config::Config;
+use inventory::calculate_inventory_vector;
+use message::{MAX_INV_COUNT,Message,MessageHandler,ParseError,read_message,write_message,VersionData};
+use net::{PeerAddr,connect};
+use stop::{StopSignal,join_until};
+use std::fmt;
 use std::io::{Error,Write};
-use std::net::{Shutdown,SocketAddr,TcpStream};
+use std::net::{Shutdown,TcpStream};
 use std::sync::{Arc,RwLock};
-use std::sync::mpsc::{Receiver,SyncSender,TryRecvError,sync_channel};
+use std::sync::mpsc::{Receiver,RecvTimeoutError,SyncSender,TryRecvError,sync_channel};
 use std::time::{Duration,Instant};
 use std::thread::{Builder,JoinHandle,sleep};
 
-const MAX_WRITE_BUFFER: usize = 20_000_000;
-
 #[derive(Debug,Clone,Copy,PartialEq)]
 pub enum ConnectionState {
     Fresh(Instant),
@@ -19,6 +22,18 @@ pub enum ConnectionState {
     Error
 }
 
+impl fmt::Display for ConnectionState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ConnectionState::Fresh(_) => write!(f, "connecting"),
+            &ConnectionState::GotVerackAwaitingVersion(_) | &ConnectionState::GotVersionAwaitingVerack(_) => write!(f, "handshaking"),
+            &ConnectionState::Established(_) => write!(f, "established"),
+            &ConnectionState::Stale => write!(f, "stale"),
+            &ConnectionState::Error => write!(f, "error")
+        }
+    }
+}
+
 #[derive(Debug,Clone)]
 pub struct StateHolder {
     state: Arc<RwLock<ConnectionState>>
@@ -43,88 +58,130 @@ impl StateHolder {
 
 pub struct Connection {
     state: StateHolder,
-    tcp_stream: Option<TcpStream>
+    peer_addr: PeerAddr,
+    tcp_stream: Option<TcpStream>,
+    stop_signal: StopSignal,
+    write_thread: Option<JoinHandle<()>>,
+    other_threads: Vec<JoinHandle<()>>
 }
 
 impl Connection {
-    pub fn new(message_handler: MessageHandler, socket_addr: SocketAddr) -> Connection {
-        match TcpStream::connect(&socket_addr) {
-            Ok(tcp_stream) => new_from_stream(message_handler, tcp_stream),
-            Err(_) => error_connection(None)
+    pub fn new(config: &Config, message_handler: MessageHandler, peer_addr: PeerAddr) -> Connection {
+        match connect(&peer_addr, config.socks_proxy()) {
+            Ok(tcp_stream) => new_from_stream(config, message_handler, peer_addr, tcp_stream),
+            Err(_) => error_connection(peer_addr, None)
         }
     }
 
-    pub fn peer_addr(&self) -> Option<SocketAddr> {
+    pub fn peer_addr(&self) -> Option<PeerAddr> {
         match self.tcp_stream {
             None => None,
-            Some(ref s) => Some(s.peer_addr().unwrap())
+            Some(_) => Some(self.peer_addr)
         }
     }
 
     pub fn state(&self) -> ConnectionState {
         self.state.get_state()
     }
-}
 
-impl Drop for Connection {
-    fn drop(&mut self) {
+    // Stopping the state thread winds down the handler thread, then the write thread once
+    // it has written everything queued. Only then is the socket closed, which frees the read thread.
+    pub fn stop(&mut self, deadline: Instant) {
+        self.stop_signal.stop(deadline);
+
+        if let Some(write_thread) = self.write_thread.take() {
+            join_until(write_thread, deadline);
+        }
+
         for tcp_stream in self.tcp_stream.iter() {
             let _ = tcp_stream.shutdown(Shutdown::Both);
         }
+
+        for thread in self.other_threads.drain(..) {
+            join_until(thread, deadline);
+        }
     }
 }
 
-fn new_from_stream(message_handler: MessageHandler, tcp_stream: TcpStream) -> Connection {
-    let socket_addr = tcp_stream.peer_addr().unwrap();
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.stop(Instant::now());
+    }
+}
+
+fn new_from_stream(config: &Config, message_handler: MessageHandler, peer_addr: PeerAddr, tcp_stream: TcpStream) -> Connection {
     let state = StateHolder::new(ConnectionState::Fresh(Instant::now()));
+    let stop_signal = StopSignal::new();
+
+    let (read_tcp_stream, write_tcp_stream) = match (tcp_stream.try_clone(), tcp_stream.try_clone()) {
+        (Ok(read_tcp_stream), Ok(write_tcp_stream)) => (read_tcp_stream, write_tcp_stream),
+        _ => return error_connection(peer_addr, Some(tcp_stream))
+    };
 
     // Make channels for thread communication
     let (read_state_tx, read_state_rx) = sync_channel(0);
-    let (state_handler_tx, state_handler_rx) = constrained_channel(MAX_WRITE_BUFFER);
+    let (state_handler_tx, state_handler_rx) = constrained_channel(config.max_write_buffer());
     let (handler_write_tx, handler_write_rx) = sync_channel(0);
+    let announce_write_tx = handler_write_tx.clone();
+    // Watching from before the handshake, so no new object slips between the inventory sent after verack and the first announcement
+    let inventory_watcher = message_handler.watch_inventory();
 
     // Make thread to read messages from the peer
-    let read_name = format!("Connection {} - read", socket_addr);
-    let read_tcp_stream = clone_stream(&tcp_stream);
+    let read_name = format!("Connection {} - read", peer_addr);
     let read_thread = create_thread(read_name, state.clone(),
         || read_thread_body(read_tcp_stream, read_state_tx));
 
     // Make thread to manage the state of this connnection
-    let state_name = format!("Connection {} - state", socket_addr);
+    let state_name = format!("Connection {} - state", peer_addr);
     let state_thread_state = state.clone();
+    let state_thread_stop_signal = stop_signal.clone();
+    let timeouts = (config.handshake_timeout(), config.idle_timeout());
     let state_thread = create_thread(state_name, state.clone(),
-        || state_thread_body(state_thread_state, read_state_rx, state_handler_tx));
+        move || state_thread_body(state_thread_state, state_thread_stop_signal, timeouts, read_state_rx, state_handler_tx));
 
     // Make thread to handle the messages - verifying them and creating appropriate response messages
-    let handler_name = format!("Connection {} - verify/response", socket_addr);
+    let handler_name = format!("Connection {} - verify/response", peer_addr);
     let handler_thread = create_thread(handler_name, state.clone(),
         || handler_thread_body(message_handler, state_handler_rx, handler_write_tx));
 
+    // Make thread to offer the peer each object that reaches the inventory while we're connected
+    let announce_name = format!("Connection {} - announce", peer_addr);
+    let announce_thread_state = state.clone();
+    let announce_thread_stop_signal = stop_signal.clone();
+    let announce_thread = create_thread(announce_name, state.clone(),
+        move || announce_thread_body(announce_thread_state, announce_thread_stop_signal, inventory_watcher, announce_write_tx));
+
     // Make thread to write messages to the peer
-    let write_name = format!("Connection {} - write", socket_addr);
-    let write_tcp_stream = clone_stream(&tcp_stream);
+    let write_name = format!("Connection {} - write", peer_addr);
     let write_thread = create_thread(write_name, state.clone(),
         || write_thread_body(write_tcp_stream, handler_write_rx));
 
-    if read_thread.is_err() || state_thread.is_err() || handler_thread.is_err() || write_thread.is_err() {
-        state.set_state(ConnectionState::Error);
-        return error_connection(Some(tcp_stream));
-    }
-
-    Connection {
-        state: state,
-        tcp_stream: Some(tcp_stream)
+    match (read_thread, state_thread, handler_thread, announce_thread, write_thread) {
+        (Ok(read_thread), Ok(state_thread), Ok(handler_thread), Ok(announce_thread), Ok(write_thread)) => Connection {
+            state: state,
+            peer_addr: peer_addr,
+            tcp_stream: Some(tcp_stream),
+            stop_signal: stop_signal,
+            write_thread: Some(write_thread),
+            other_threads: vec![ state_thread, handler_thread, announce_thread, read_thread ]
+        },
+        _ => {
+            // Any threads that did start will finish once the stop signal and socket shutdown reach them
+            stop_signal.stop(Instant::now());
+            state.set_state(ConnectionState::Error);
+            error_connection(peer_addr, Some(tcp_stream))
+        }
     }
 }
 
-fn clone_stream(stream: &TcpStream) -> TcpStream {
-    stream.try_clone().unwrap()
-}
-
-fn error_connection(tcp_stream: Option<TcpStream>) -> Connection {
+fn error_connection(peer_addr: PeerAddr, tcp_stream: Option<TcpStream>) -> Connection {
     Connection {
         state: StateHolder::new(ConnectionState::Error),
-        tcp_stream: tcp_stream
+        peer_addr: peer_addr,
+        tcp_stream: tcp_stream,
+        stop_signal: StopSignal::new(),
+        write_thread: None,
+        other_threads: vec![]
     }
 }
 
@@ -141,7 +198,7 @@ fn read_thread_body(mut stream: TcpStream, state_chan: SyncSender<Result<Message
         let message: Result<Message,ParseError> = read_message(&mut stream);
         let parse_error = message.is_err();
 
-        state_chan.send(message).unwrap();
+        break_on_err!(state_chan.send(message));
 
         if parse_error {
             break;
@@ -149,8 +206,12 @@ fn read_thread_body(mut stream: TcpStream, state_chan: SyncSender<Result<Message
     }
 }
 
-fn state_thread_body(state_holder: StateHolder, read_chan: Receiver<Result<Message,ParseError>>, handler_chan: ConstrainedSender<Message>) -> () {
+fn state_thread_body(state_holder: StateHolder, stop_signal: StopSignal, (handshake_timeout, idle_timeout): (Duration, Duration), read_chan: Receiver<Result<Message,ParseError>>, handler_chan: ConstrainedSender<Message>) -> () {
     loop {
+        if stop_signal.is_stopped() {
+            break;
+        }
+
         let current_state = state_holder.get_state();
 
         let (new_state, forward_messages) = match (current_state, read_chan.try_recv()) {
@@ -170,14 +231,17 @@ fn state_thread_body(state_holder: StateHolder, read_chan: Receiver<Result<Messa
 
         state_holder.set_state(new_state);
         for forward_message in forward_messages.into_iter() {
-            handler_chan.send(forward_message).unwrap();
+            if handler_chan.send(forward_message).is_err() {
+                state_holder.set_state(ConnectionState::Error);
+                break;
+            }
         }
 
         match new_state {
-            ConnectionState::Fresh(time) => check_staleness(&state_holder, time, Duration::from_secs(20)),
-            ConnectionState::GotVersionAwaitingVerack(time) => check_staleness(&state_holder, time, Duration::from_secs(20)),
-            ConnectionState::GotVerackAwaitingVersion(time) => check_staleness(&state_holder, time, Duration::from_secs(20)),
-            ConnectionState::Established(time) => check_staleness(&state_holder, time, Duration::from_secs(10 * 60)),
+            ConnectionState::Fresh(time) => check_staleness(&state_holder, time, handshake_timeout),
+            ConnectionState::GotVersionAwaitingVerack(time) => check_staleness(&state_holder, time, handshake_timeout),
+            ConnectionState::GotVerackAwaitingVersion(time) => check_staleness(&state_holder, time, handshake_timeout),
+            ConnectionState::Established(time) => check_staleness(&state_holder, time, idle_timeout),
             _ => {}
         }
 
@@ -207,6 +271,35 @@ fn handler_thread_body(mut message_handler: MessageHandler, state_chan: Constrai
     }
 }
 
+// Objects that arrive during the handshake wait in the watcher until the connection is established
+fn announce_thread_body(state_holder: StateHolder, stop_signal: StopSignal, inventory_watcher: Receiver<Message>, write_chan: SyncSender<Message>) -> () {
+    while !stop_signal.is_stopped() {
+        match state_holder.get_state() {
+            ConnectionState::Established(_) => {},
+            ConnectionState::Stale | ConnectionState::Error => break,
+            _ => {
+                sleep(Duration::from_millis(100));
+                continue;
+            }
+        }
+
+        let object_message = match inventory_watcher.recv_timeout(Duration::from_millis(100)) {
+            Ok(object_message) => object_message,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break
+        };
+        let mut inventory = vec![ calculate_inventory_vector(&object_message) ];
+        while inventory.len() < MAX_INV_COUNT {
+            match inventory_watcher.try_recv() {
+                Ok(object_message) => inventory.push(calculate_inventory_vector(&object_message)),
+                Err(_) => break
+            }
+        }
+
+        break_on_err!(write_chan.send(Message::Inv { inventory: inventory }));
+    }
+}
+
 fn write_thread_body(mut stream: TcpStream, handler_chan: Receiver<Message>) -> () {
     loop {
         let message = break_on_err!(handler_chan.recv());
@@ -216,4 +309,90 @@ fn write_thread_body(mut stream: TcpStream, handler_chan: Receiver<Message>) ->
 
         break_on_err!(stream.write_all(&message_bytes));
     }
+
+    let _ = stream.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use events::Events;
+    use inventory::{Inventory,calculate_inventory_vector};
+    use known_nodes::KnownNodes;
+    use message::{GetPubKey,Message,MessageHandler,MessageResponder,MessageVerifier,Object,ObjectData,read_message,write_message};
+    use net::PeerAddr;
+    use persist::Persister;
+    use std::io::{Read,Write};
+    use std::net::{TcpListener,TcpStream};
+    use std::time::{Duration,Instant,SystemTime};
+    use super::{Connection,ConnectionState};
+    use timegen::TimeType;
+
+    #[test]
+    fn test_stop_flushes_and_closes_socket() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = PeerAddr::Ip(listener.local_addr().unwrap());
+        let config = Config::new();
+        let persister = Persister::new();
+        let message_handler = MessageHandler::new(
+            MessageVerifier::new(&config, TimeType::Real),
+            MessageResponder::new(&config, &KnownNodes::new(persister.clone()), &Inventory::new(persister, &Events::new()), peer_addr)
+        );
+
+        let mut connection = Connection::new(&config, message_handler, peer_addr);
+        let (mut stream, _) = listener.accept().unwrap();
+        connection.stop(Instant::now() + Duration::from_secs(5));
+
+        match read_message(&mut stream) {
+            Ok(Message::Version(_)) => {},
+            other => panic!("Expected a version message, got {:?}", other)
+        }
+        assert_eq!(0, stream.read(&mut [0u8; 1]).unwrap());
+        assert_eq!(ConnectionState::Error, connection.state());
+    }
+
+    #[test]
+    fn test_new_objects_are_offered_to_connected_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer_addr = PeerAddr::Ip(listener.local_addr().unwrap());
+        let config = Config::new();
+        let persister = Persister::new();
+        let mut inventory = Inventory::new(persister.clone(), &Events::new());
+        let message_handler = MessageHandler::new(
+            MessageVerifier::new(&config, TimeType::Real),
+            MessageResponder::new(&config, &KnownNodes::new(persister), &inventory, peer_addr)
+        );
+
+        let mut connection = Connection::new(&config, message_handler, peer_addr);
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        // A client of its own, so its version has another nonce
+        let their_responder = MessageResponder::new(&Config::new(), &KnownNodes::new(Persister::new()), &Inventory::new(Persister::new(), &Events::new()), peer_addr);
+        their_responder.send_version(|message| { send(&mut stream.try_clone().unwrap(), message); Ok(()) }).unwrap();
+        send(&mut stream, Message::Verack);
+        // Our version, the verack for theirs and the addr that only follows a finished handshake
+        for _ in 0..3 {
+            read_message(&mut stream).unwrap();
+        }
+        match connection.state() {
+            ConnectionState::Established(_) => {},
+            state => panic!("Expected an established connection, got {:?}", state)
+        }
+
+        // Queued after the handshake, as our own messages are
+        let object_message = Message::Object(ObjectData::new(SystemTime::now(), 3, 1, Object::GetPubKey(GetPubKey::V3 { ripe: vec![ 4; 20 ] })));
+        inventory.add_object_message(&object_message);
+
+        match read_message(&mut stream) {
+            Ok(Message::Inv { inventory }) => assert_eq!(vec![ calculate_inventory_vector(&object_message) ], inventory),
+            other => panic!("Expected an inv message, got {:?}", other)
+        }
+        connection.stop(Instant::now() + Duration::from_secs(5));
+    }
+
+    fn send(stream: &mut TcpStream, message: Message) {
+        let mut bytes = vec![];
+        write_message(&mut bytes, &message);
+        stream.write_all(&bytes).unwrap();
+    }
 }
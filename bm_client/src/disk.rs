@@ -0,0 +1,368 @@
+use address::Address;
+use addressbook::Contact;
+use filter::FilterMode;
+use identity::Identity;
+use inbox::InboxMessage;
+use outbox::OutboxMessage;
+use subscriptions::Subscription;
+use rustc_serialize::hex::{FromHex,ToHex};
+use rustc_serialize::json::{Json,ToJson};
+use std::collections::BTreeMap;
+use std::fs::{File,rename};
+use std::io;
+use std::io::{ErrorKind,Read,Write};
+use std::path::{Path,PathBuf};
+use std::str::FromStr;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+const MESSAGES_FILE: &'static str = "messages.json";
+const IDENTITIES_FILE: &'static str = "identities.json";
+const ADDRESS_BOOK_FILE: &'static str = "addressbook.json";
+const FORMAT_VERSION: u64 = 1;
+
+// The inbox and outbox, kept as JSON in the data directory and rewritten whole on each change
+pub struct MessageFile {
+    path: PathBuf
+}
+
+impl MessageFile {
+    pub fn new(data_dir: &Path) -> MessageFile {
+        MessageFile {
+            path: data_dir.join(MESSAGES_FILE)
+        }
+    }
+
+    // A missing file is a new data directory, so there are no messages yet
+    pub fn load(&self) -> io::Result<(Vec<InboxMessage>, Vec<OutboxMessage>)> {
+        let json = match try!(read_json(&self.path, MESSAGES_FILE)) {
+            Some(json) => json,
+            None => return Ok((vec![], vec![]))
+        };
+
+        let inbox = try!(read_list(&json, "inbox", MESSAGES_FILE, InboxMessage::from_json));
+        let outbox = try!(read_list(&json, "outbox", MESSAGES_FILE, OutboxMessage::from_json));
+        Ok((inbox, outbox))
+    }
+
+    pub fn save(&self, inbox: &[InboxMessage], outbox: &[OutboxMessage]) -> io::Result<()> {
+        let mut object = BTreeMap::new();
+        object.insert("inbox".to_string(), Json::Array(inbox.iter().map(|message| message.to_json()).collect()));
+        object.insert("outbox".to_string(), Json::Array(outbox.iter().map(|message| message.to_json()).collect()));
+        write_json(&self.path, object)
+    }
+}
+
+// Our identities and chans with their private keys, so only the owner may read the file,
+// and the addresses we're subscribed to
+pub struct IdentityFile {
+    path: PathBuf
+}
+
+impl IdentityFile {
+    pub fn new(data_dir: &Path) -> IdentityFile {
+        IdentityFile {
+            path: data_dir.join(IDENTITIES_FILE)
+        }
+    }
+
+    pub fn load(&self) -> io::Result<(Vec<Identity>, Vec<Identity>, Vec<Subscription>)> {
+        let json = match try!(read_json(&self.path, IDENTITIES_FILE)) {
+            Some(json) => json,
+            None => return Ok((vec![], vec![], vec![]))
+        };
+
+        let identities = try!(read_list(&json, "identities", IDENTITIES_FILE, Identity::from_json));
+        let chans = try!(read_list(&json, "chans", IDENTITIES_FILE, Identity::from_json));
+        // Files saved before subscriptions were kept have none
+        let subscriptions = match json.find("subscriptions") {
+            Some(_) => try!(read_list(&json, "subscriptions", IDENTITIES_FILE, Subscription::from_json)),
+            None => vec![]
+        };
+        Ok((identities, chans, subscriptions))
+    }
+
+    pub fn save(&self, identities: &[Identity], chans: &[Identity], subscriptions: &[Subscription]) -> io::Result<()> {
+        let mut object = BTreeMap::new();
+        object.insert("identities".to_string(), Json::Array(identities.iter().map(|identity| identity.to_json()).collect()));
+        object.insert("chans".to_string(), Json::Array(chans.iter().map(|chan| chan.to_json()).collect()));
+        object.insert("subscriptions".to_string(), Json::Array(subscriptions.iter().map(|subscription| subscription.to_json()).collect()));
+        write_json(&self.path, object)
+    }
+}
+
+// The address book, and the sender filter with both of its lists
+pub struct AddressBookFile {
+    path: PathBuf
+}
+
+impl AddressBookFile {
+    pub fn new(data_dir: &Path) -> AddressBookFile {
+        AddressBookFile {
+            path: data_dir.join(ADDRESS_BOOK_FILE)
+        }
+    }
+
+    pub fn load(&self) -> io::Result<(Vec<Contact>, FilterMode, Vec<Contact>, Vec<Contact>)> {
+        let json = match try!(read_json(&self.path, ADDRESS_BOOK_FILE)) {
+            Some(json) => json,
+            None => return Ok((vec![], FilterMode::Blacklist, vec![], vec![]))
+        };
+
+        let contacts = try!(read_list(&json, "contacts", ADDRESS_BOOK_FILE, Contact::from_json));
+        let filter_mode = try!(json.find("filter_mode").and_then(FilterMode::from_json).ok_or(bad_data(ADDRESS_BOOK_FILE, "bad filter_mode")));
+        let blacklist = try!(read_list(&json, "blacklist", ADDRESS_BOOK_FILE, Contact::from_json));
+        let whitelist = try!(read_list(&json, "whitelist", ADDRESS_BOOK_FILE, Contact::from_json));
+        Ok((contacts, filter_mode, blacklist, whitelist))
+    }
+
+    pub fn save(&self, contacts: &[Contact], filter_mode: FilterMode, blacklist: &[Contact], whitelist: &[Contact]) -> io::Result<()> {
+        let mut object = BTreeMap::new();
+        object.insert("contacts".to_string(), Json::Array(contacts.iter().map(|contact| contact.to_json()).collect()));
+        object.insert("filter_mode".to_string(), filter_mode.to_json());
+        object.insert("blacklist".to_string(), Json::Array(blacklist.iter().map(|contact| contact.to_json()).collect()));
+        object.insert("whitelist".to_string(), Json::Array(whitelist.iter().map(|contact| contact.to_json()).collect()));
+        write_json(&self.path, object)
+    }
+}
+
+fn read_json(path: &Path, name: &str) -> io::Result<Option<Json>> {
+    let mut contents = String::new();
+    match File::open(path) {
+        Ok(mut file) => try!(file.read_to_string(&mut contents)),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err)
+    };
+
+    let json = try!(Json::from_str(&contents).map_err(|_| bad_data(name, "not valid JSON")));
+    if json.find("version").and_then(|version| version.as_u64()) != Some(FORMAT_VERSION) {
+        return Err(bad_data(name, "unknown format version"));
+    }
+    Ok(Some(json))
+}
+
+// Written to a temporary file first, so a crash part way through leaves the old file in place
+fn write_json(path: &Path, mut object: BTreeMap<String, Json>) -> io::Result<()> {
+    object.insert("version".to_string(), FORMAT_VERSION.to_json());
+
+    let temp_path = path.with_extension("json.tmp");
+    {
+        let mut file = try!(create_private(&temp_path));
+        try!(file.write_all(Json::Object(object).to_string().as_bytes()));
+        try!(file.sync_all());
+    }
+    rename(&temp_path, path)
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    ::std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)
+}
+
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> io::Result<File> {
+    File::create(path)
+}
+
+fn read_list<T, F>(json: &Json, key: &str, name: &str, from_json: F) -> io::Result<Vec<T>>
+    where F: Fn(&Json) -> Option<T>
+{
+    let entries = try!(json.find(key).and_then(|entries| entries.as_array()).ok_or(bad_data(name, &format!("missing {} list", key))));
+    let mut list = vec![];
+    for entry in entries {
+        list.push(try!(from_json(entry).ok_or(bad_data(name, &format!("bad {} entry", key)))));
+    }
+    Ok(list)
+}
+
+fn bad_data(name: &str, reason: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("{} is damaged: {}", name, reason))
+}
+
+pub fn bytes_to_json(bytes: &[u8]) -> Json {
+    Json::String(bytes.to_hex())
+}
+
+pub fn bytes_from_json(json: Option<&Json>) -> Option<Vec<u8>> {
+    json.and_then(|json| json.as_string()).and_then(|hex| hex.from_hex().ok())
+}
+
+pub fn time_to_json(time: SystemTime) -> Json {
+    Json::U64(time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0))
+}
+
+pub fn time_from_json(json: Option<&Json>) -> Option<SystemTime> {
+    json.and_then(|json| json.as_u64()).map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+pub fn address_to_json(address: &Address) -> Json {
+    Json::String(address.to_string())
+}
+
+pub fn address_from_json(json: Option<&Json>) -> Option<Address> {
+    json.and_then(|json| json.as_string()).and_then(|address| Address::from_str(address).ok())
+}
+
+pub fn string_from_json(json: Option<&Json>) -> Option<String> {
+    json.and_then(|json| json.as_string()).map(|string| string.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use addressbook::{AddressBook,Contact};
+    use events::{Event,Events,OutboxStatus};
+    use filter::{FilterMode,SenderFilter};
+    use inbox::{Inbox,InboxMessage};
+    use identity::{Identities,Identity};
+    use outbox::{Outbox,SendOptions};
+    use persist::Persister;
+    use rand::{OsRng,Rng};
+    use rustc_serialize::hex::ToHex;
+    use subscriptions::{Subscription,Subscriptions};
+    use std::env::temp_dir;
+    use std::fs::{File,remove_dir_all};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use super::{ADDRESS_BOOK_FILE,IDENTITIES_FILE,MESSAGES_FILE};
+
+    fn temp_data_dir() -> PathBuf {
+        let mut name = [0u8; 8];
+        OsRng::new().unwrap().fill_bytes(&mut name);
+        temp_dir().join(format!("bm_client_test_{}", name.to_hex()))
+    }
+
+    #[test]
+    fn test_messages_survive_reopening() {
+        let data_dir = temp_data_dir();
+        let from = Identity::random("", 1).address().clone();
+        let to = Identity::random("", 1).address().clone();
+        let events = Events::new();
+
+        let inbox_message = InboxMessage::new(&[ 1; 32 ], &from, Some(&to), "Hi", "Hello");
+        let ackdata = {
+            let persister = Persister::open(&data_dir, &Events::new()).unwrap();
+            let mut inbox = Inbox::new(persister.clone(), &events);
+            inbox.add(&inbox_message);
+            inbox.set_read(&[ 1; 32 ], true);
+            let mut outbox = Outbox::new(persister, &events);
+            let ackdata = outbox.queue_broadcast(&to, "Hello", "everyone", &SendOptions::new());
+            outbox.set_status(&ackdata, OutboxStatus::DoingPow);
+            ackdata
+        };
+
+        let persister = Persister::open(&data_dir, &Events::new()).unwrap();
+        let inbox_messages = Inbox::new(persister.clone(), &events).list();
+        assert_eq!(1, inbox_messages.len());
+        assert!(inbox_messages[0].is_read());
+        assert_eq!(inbox_message.subject(), inbox_messages[0].subject());
+        assert_eq!(Some(&to), inbox_messages[0].to());
+        let outbox_message = Outbox::new(persister, &events).get(&ackdata).unwrap();
+        assert!(outbox_message.is_broadcast());
+        assert_eq!("everyone", outbox_message.body());
+        // The proof of work was cut short, so it has to be done again
+        assert_eq!(OutboxStatus::Queued, outbox_message.status());
+
+        File::create(data_dir.join(MESSAGES_FILE)).unwrap().write_all(b"{\"version\":2}").unwrap();
+        assert!(Persister::open(&data_dir, &Events::new()).is_err());
+        remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_save_is_reported() {
+        let data_dir = temp_data_dir();
+        let events = Events::new();
+        let receiver = events.subscribe();
+        let persister = Persister::open(&data_dir, &events).unwrap();
+        remove_dir_all(&data_dir).unwrap();
+
+        let from = Identity::random("", 1).address().clone();
+        Inbox::new(persister.clone(), &events).add(&InboxMessage::new(&[ 1; 32 ], &from, None, "Hi", "Hello"));
+        AddressBook::new(persister.clone()).add(&Contact::new("Bob", &from));
+        Identities::new(persister).add(&Identity::random("Me", 1));
+        let warnings: Vec<String> = receiver.try_iter().filter_map(|event| match event {
+            Event::Warning(warning) => Some(warning),
+            _ => None
+        }).collect();
+        assert!(warnings[0].starts_with("Could not save messages: "));
+        assert!(warnings[1].starts_with("Could not save the address book: "));
+        assert!(warnings[2].starts_with("Could not save identities, they will be lost on exit: "));
+    }
+
+    #[test]
+    fn test_identities_survive_reopening() {
+        let data_dir = temp_data_dir();
+        let identity = Identity::random("Me", 1);
+        let chan = Identity::chan("general", 1);
+        {
+            let mut identities = Identities::new(Persister::open(&data_dir, &Events::new()).unwrap());
+            identities.add(&identity);
+            identities.add(&chan);
+            identities.add(&Identity::chan("other", 1));
+            identities.remove_chan(Identity::chan("other", 1).address());
+        }
+
+        let identities = Identities::new(Persister::open(&data_dir, &Events::new()).unwrap());
+        assert_eq!(vec![ identity ], identities.personal());
+        assert_eq!(vec![ chan ], identities.chans());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(0o600, ::std::fs::metadata(data_dir.join(IDENTITIES_FILE)).unwrap().permissions().mode() & 0o777);
+        }
+        remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_subscriptions_survive_reopening() {
+        let data_dir = temp_data_dir();
+        let news = Subscription::new("News", Identity::random("", 1).address());
+        let other = Identity::random("", 1).address().clone();
+        {
+            let mut subscriptions = Subscriptions::new(Persister::open(&data_dir, &Events::new()).unwrap());
+            subscriptions.add(&Subscription::new("Old name", news.address()));
+            subscriptions.add(&news);
+            subscriptions.add(&Subscription::new("Other", &other));
+            subscriptions.remove(&other);
+        }
+
+        assert_eq!(vec![ news ], Subscriptions::new(Persister::open(&data_dir, &Events::new()).unwrap()).list());
+
+        // Identity files saved before subscriptions were kept still open
+        File::create(data_dir.join(IDENTITIES_FILE)).unwrap().write_all(b"{\"version\":1,\"identities\":[],\"chans\":[]}").unwrap();
+        assert!(Subscriptions::new(Persister::open(&data_dir, &Events::new()).unwrap()).list().is_empty());
+        remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn test_address_book_survives_reopening() {
+        let data_dir = temp_data_dir();
+        let friend = Contact::new("Friend", Identity::random("", 1).address());
+        let spammer = Contact::new("Spammer", Identity::random("", 1).address());
+        let other = Identity::random("", 1).address().clone();
+        {
+            let persister = Persister::open(&data_dir, &Events::new()).unwrap();
+            let mut address_book = AddressBook::new(persister.clone());
+            address_book.add(&friend);
+            address_book.add(&Contact::new("Other", &other));
+            address_book.remove(&other);
+            let mut filter = SenderFilter::new(persister);
+            filter.add(FilterMode::Blacklist, &spammer);
+            filter.add(FilterMode::Whitelist, &friend);
+            filter.add(FilterMode::Whitelist, &Contact::new("Other", &other));
+            filter.remove(FilterMode::Whitelist, &other);
+            filter.set_mode(FilterMode::Whitelist);
+        }
+
+        let persister = Persister::open(&data_dir, &Events::new()).unwrap();
+        assert_eq!(vec![ friend.clone() ], AddressBook::new(persister.clone()).list());
+        let filter = SenderFilter::new(persister);
+        assert_eq!(FilterMode::Whitelist, filter.mode());
+        assert_eq!(vec![ spammer ], filter.list(FilterMode::Blacklist));
+        assert_eq!(vec![ friend ], filter.list(FilterMode::Whitelist));
+
+        File::create(data_dir.join(ADDRESS_BOOK_FILE)).unwrap().write_all(b"{\"version\":1,\"contacts\":[],\"filter_mode\":\"greylist\",\"blacklist\":[],\"whitelist\":[]}").unwrap();
+        assert!(Persister::open(&data_dir, &Events::new()).is_err());
+        remove_dir_all(&data_dir).unwrap();
+    }
+}
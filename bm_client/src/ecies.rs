@@ -0,0 +1,182 @@
+use byteorder::{BigEndian,ReadBytesExt,WriteBytesExt};
+use checksum::sha512_hash;
+use crypto::aes::{KeySize,cbc_decryptor,cbc_encryptor};
+use crypto::blockmodes::PkcsPadding;
+use crypto::buffer::{BufferResult,ReadBuffer,RefReadBuffer,RefWriteBuffer,WriteBuffer};
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac,MacResult};
+use crypto::sha2::Sha256;
+use keys::{parse_public_key,public_key,random_private_key};
+use rand::{OsRng,Rng};
+use secp256k1::Secp256k1;
+use std::io::{Cursor,Read};
+
+// The format PyBitmessage uses (from pyelliptic): IV, curve id, ephemeral public key X and Y,
+// AES-256-CBC ciphertext, then an HMAC-SHA256 over everything before it
+const CURVE_SECP256K1: u16 = 0x02ca;
+const IV_LENGTH: usize = 16;
+const MAC_LENGTH: usize = 32;
+
+#[derive(Debug,PartialEq)]
+pub enum EciesError {
+    BadPublicKey,
+    TooShort,
+    UnknownCurve,
+    MacMismatch,
+    BadCiphertext
+}
+
+pub fn encrypt(public_key_bytes: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, EciesError> {
+    let ephemeral_private_key = random_private_key();
+    let mut iv = [0u8; IV_LENGTH];
+    OsRng::new().unwrap().fill_bytes(&mut iv);
+
+    encrypt_with(public_key_bytes, plaintext, &ephemeral_private_key, &iv)
+}
+
+fn encrypt_with(public_key_bytes: &[u8], plaintext: &[u8], ephemeral_private_key: &[u8; 32], iv: &[u8; IV_LENGTH]) -> Result<Vec<u8>, EciesError> {
+    let (key_e, key_m) = try!(derive_keys(public_key_bytes, ephemeral_private_key));
+    let ephemeral_public_key = public_key(ephemeral_private_key);
+
+    let mut output = Vec::with_capacity(IV_LENGTH + 70 + plaintext.len() + 16 + MAC_LENGTH);
+    output.extend(iv);
+    output.write_u16::<BigEndian>(CURVE_SECP256K1).unwrap();
+    output.write_u16::<BigEndian>(32).unwrap();
+    output.extend(&ephemeral_public_key[0..32]);
+    output.write_u16::<BigEndian>(32).unwrap();
+    output.extend(&ephemeral_public_key[32..64]);
+
+    let mut encryptor = cbc_encryptor(KeySize::KeySize256, &key_e, iv, PkcsPadding);
+    let ciphertext = try!(run_cipher(|input, output, eof| encryptor.encrypt(input, output, eof), plaintext));
+    output.extend(ciphertext);
+
+    let mac = hmac_sha256(&key_m, &output);
+    output.extend(&mac);
+
+    Ok(output)
+}
+
+pub fn decrypt(private_key: &[u8; 32], data: &[u8]) -> Result<Vec<u8>, EciesError> {
+    if data.len() < IV_LENGTH + 6 + MAC_LENGTH {
+        return Err(EciesError::TooShort);
+    }
+
+    let (authenticated, mac) = data.split_at(data.len() - MAC_LENGTH);
+    let mut cursor = Cursor::new(authenticated);
+
+    let mut iv = [0u8; IV_LENGTH];
+    try!(cursor.read_exact(&mut iv).map_err(|_| EciesError::TooShort));
+    let curve = try!(cursor.read_u16::<BigEndian>().map_err(|_| EciesError::TooShort));
+    if curve != CURVE_SECP256K1 {
+        return Err(EciesError::UnknownCurve);
+    }
+    let x = try!(read_coordinate(&mut cursor));
+    let y = try!(read_coordinate(&mut cursor));
+    let ciphertext = &authenticated[cursor.position() as usize..];
+
+    let mut ephemeral_public_key = x;
+    ephemeral_public_key.extend(y);
+    let (key_e, key_m) = try!(derive_keys(&ephemeral_public_key, private_key));
+
+    if !constant_time_eq(&hmac_sha256(&key_m, authenticated), mac) {
+        return Err(EciesError::MacMismatch);
+    }
+
+    let mut decryptor = cbc_decryptor(KeySize::KeySize256, &key_e, &iv, PkcsPadding);
+    run_cipher(|input, output, eof| decryptor.decrypt(input, output, eof), ciphertext)
+}
+
+// OpenSSL writes coordinates without leading zero bytes, so they can be shorter than 32 bytes
+fn read_coordinate(cursor: &mut Cursor<&[u8]>) -> Result<Vec<u8>, EciesError> {
+    let length = try!(cursor.read_u16::<BigEndian>().map_err(|_| EciesError::TooShort)) as usize;
+    if length > 32 {
+        return Err(EciesError::BadPublicKey);
+    }
+
+    let mut coordinate = vec![0u8; 32];
+    try!(cursor.read_exact(&mut coordinate[32 - length..]).map_err(|_| EciesError::TooShort));
+    Ok(coordinate)
+}
+
+// The shared secret is the X coordinate of the ECDH point, hashed to give the AES and MAC keys
+fn derive_keys(public_key_bytes: &[u8], private_key: &[u8; 32]) -> Result<([u8; 32], [u8; 32]), EciesError> {
+    let mut point = try!(parse_public_key(public_key_bytes).ok_or(EciesError::BadPublicKey));
+    try!(point.mul_assign(&Secp256k1::verification_only(), private_key).map_err(|_| EciesError::BadPublicKey));
+    let shared_x = &point.serialize_uncompressed()[1..33];
+
+    let hash = sha512_hash(shared_x);
+    let mut key_e = [0u8; 32];
+    let mut key_m = [0u8; 32];
+    key_e.copy_from_slice(&hash[0..32]);
+    key_m.copy_from_slice(&hash[32..64]);
+    Ok((key_e, key_m))
+}
+
+fn run_cipher<F>(mut step: F, input: &[u8]) -> Result<Vec<u8>, EciesError>
+    where F: FnMut(&mut RefReadBuffer, &mut RefWriteBuffer, bool) -> Result<BufferResult, ::crypto::symmetriccipher::SymmetricCipherError>
+{
+    let mut output = Vec::with_capacity(input.len() + 16);
+    let mut read_buffer = RefReadBuffer::new(input);
+    let mut buffer = [0u8; 4096];
+
+    loop {
+        let mut write_buffer = RefWriteBuffer::new(&mut buffer);
+        let result = try!(step(&mut read_buffer, &mut write_buffer, true).map_err(|_| EciesError::BadCiphertext));
+        output.extend(write_buffer.take_read_buffer().take_remaining());
+
+        if let BufferResult::BufferUnderflow = result {
+            break;
+        }
+    }
+
+    Ok(output)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(data);
+    hmac.result().code().to_vec()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && MacResult::new(a) == MacResult::new(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use keys::{public_key,random_private_key};
+    use super::{EciesError,decrypt,encrypt,encrypt_with};
+
+    #[test]
+    fn test_roundtrip() {
+        let private_key = random_private_key();
+        let plaintext = b"The quick brown fox jumps over the lazy dog".to_vec();
+
+        let encrypted = encrypt(&public_key(&private_key), &plaintext).unwrap();
+
+        assert_eq!(16 + 70 + 48 + 32, encrypted.len());
+        assert_eq!(Ok(plaintext), decrypt(&private_key, &encrypted));
+    }
+
+    #[test]
+    fn test_wrong_key_fails_mac() {
+        let encrypted = encrypt(&public_key(&random_private_key()), b"secret").unwrap();
+
+        assert_eq!(Err(EciesError::MacMismatch), decrypt(&random_private_key(), &encrypted));
+    }
+
+    #[test]
+    fn test_known_layout() {
+        let mut private_key = [0u8; 32];
+        private_key[31] = 2;
+        let mut ephemeral_private_key = [0u8; 32];
+        ephemeral_private_key[31] = 1;
+
+        let encrypted = encrypt_with(&public_key(&private_key), b"", &ephemeral_private_key, &[ 7; 16 ]).unwrap();
+
+        assert_eq!(&[ 7; 16 ], &encrypted[0..16]);
+        assert_eq!(&[ 0x02, 0xca, 0x00, 0x20, 0x79, 0xbe ], &encrypted[16..22]);
+        assert_eq!(Ok(vec![]), decrypt(&private_key, &encrypted));
+        assert_eq!(Err(EciesError::TooShort), decrypt(&private_key, &encrypted[0..40]));
+    }
+}
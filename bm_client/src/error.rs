@@ -0,0 +1,105 @@
+use address::Address;
+use config::ConfigError;
+use keys_dat::KeysDatError;
+use message::MessageSendError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum BMError {
+    UnsupportedPlatform,
+    NoDiskAccess(io::Error),
+    Archive(io::Error),
+    Network(io::Error),
+    ThreadSpawn(io::Error),
+    Config(ConfigError),
+    Send(MessageSendError),
+    UnknownIdentity(Address),
+    MessageTooLong,
+    EmptyPassphrase,
+    ChanAddressMismatch(Address),
+    KeysDat(KeysDatError)
+}
+
+impl fmt::Display for BMError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &BMError::UnsupportedPlatform => write!(f, "Rubbem needs at least a 32-bit system"),
+            &BMError::NoDiskAccess(ref err) => write!(f, "Cannot access the data directory: {}", err),
+            &BMError::Archive(ref err) => write!(f, "Archive error: {}", err),
+            &BMError::Network(ref err) => write!(f, "Network error: {}", err),
+            &BMError::ThreadSpawn(ref err) => write!(f, "Cannot start a thread: {}", err),
+            &BMError::Config(ref err) => write!(f, "Configuration error: {}", err),
+            &BMError::Send(ref err) => write!(f, "Cannot send message: {}", err),
+            &BMError::UnknownIdentity(ref address) => write!(f, "{} is not one of our identities", address),
+            &BMError::MessageTooLong => write!(f, "Message is too long to send"),
+            &BMError::EmptyPassphrase => write!(f, "The passphrase cannot be empty"),
+            &BMError::ChanAddressMismatch(ref address) => write!(f, "The passphrase does not give the chan address {}", address),
+            &BMError::KeysDat(ref err) => write!(f, "Bad keys.dat: {}", err)
+        }
+    }
+}
+
+impl Error for BMError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            &BMError::UnsupportedPlatform => None,
+            &BMError::NoDiskAccess(ref err) => Some(err),
+            &BMError::Archive(ref err) => Some(err),
+            &BMError::Network(ref err) => Some(err),
+            &BMError::ThreadSpawn(ref err) => Some(err),
+            &BMError::Config(ref err) => Some(err),
+            &BMError::Send(ref err) => Some(err),
+            &BMError::UnknownIdentity(_) => None,
+            &BMError::MessageTooLong => None,
+            &BMError::EmptyPassphrase => None,
+            &BMError::ChanAddressMismatch(_) => None,
+            &BMError::KeysDat(ref err) => Some(err)
+        }
+    }
+}
+
+impl From<ConfigError> for BMError {
+    fn from(err: ConfigError) -> BMError {
+        BMError::Config(err)
+    }
+}
+
+impl From<MessageSendError> for BMError {
+    fn from(err: MessageSendError) -> BMError {
+        BMError::Send(err)
+    }
+}
+
+impl From<KeysDatError> for BMError {
+    fn from(err: KeysDatError) -> BMError {
+        BMError::KeysDat(err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use message::{GenerateError,MessageSendError};
+    use std::error::Error;
+    use super::BMError;
+
+    #[test]
+    fn test_config_error_is_carried_as_source() {
+        let config_error = Config::builder().port(0).build().err().unwrap();
+        let error = BMError::from(config_error);
+
+        assert_eq!("Configuration error: Invalid port: must be between 1 and 65535", error.to_string());
+        assert_eq!("Invalid port: must be between 1 and 65535", error.source().unwrap().to_string());
+    }
+
+    #[test]
+    fn test_send_error_chain() {
+        let error = BMError::from(MessageSendError::UnableToCreatePow(GenerateError::ObjectLivesTooLong));
+
+        let source = error.source().unwrap();
+        assert_eq!("Unable to create proof of work: object lives too long", source.to_string());
+        assert_eq!("object lives too long", source.source().unwrap().to_string());
+    }
+}
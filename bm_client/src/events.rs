@@ -0,0 +1,82 @@
+use connection::ConnectionState;
+use net::PeerAddr;
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::{Receiver,Sender,channel};
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum OutboxStatus {
+    Queued,
+    AwaitingPubKey,
+    DoingPow,
+    Sent,
+    AckReceived,
+    Failed,
+    GaveUp // resent as many times as allowed without an ack
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub enum Event {
+    InboxMessage { msgid: Vec<u8> },
+    AckReceived { ackdata: Vec<u8> },
+    OutboxStatusChanged { ackdata: Vec<u8>, status: OutboxStatus },
+    PeerConnected(PeerAddr),
+    PeerDisconnected(PeerAddr),
+    ConnectionStateChanged(PeerAddr, ConnectionState), // a connection ends as Stale or Error
+    PowProgress { trials: u64, expected_trials: u64 },
+    ObjectCount(usize),
+    Warning(String) // something went wrong that the client carries on past
+}
+
+// Fans events out to every subscriber; subscribers that have gone away are dropped
+#[derive(Clone)]
+pub struct Events {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>
+}
+
+impl Events {
+    pub fn new() -> Events {
+        Events {
+            subscribers: Arc::new(Mutex::new(vec![]))
+        }
+    }
+
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    pub fn emit(&self, event: Event) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event,Events};
+
+    #[test]
+    fn test_every_subscriber_gets_events() {
+        let events = Events::new();
+        let first = events.subscribe();
+        let second = events.subscribe();
+
+        events.emit(Event::ObjectCount(3));
+
+        assert_eq!(Event::ObjectCount(3), first.try_recv().unwrap());
+        assert_eq!(Event::ObjectCount(3), second.try_recv().unwrap());
+    }
+
+    #[test]
+    fn test_dropped_subscriber_is_forgotten() {
+        let events = Events::new();
+        drop(events.subscribe());
+        let remaining = events.subscribe();
+
+        events.emit(Event::ObjectCount(1));
+
+        assert_eq!(1, events.subscribers.lock().unwrap().len());
+        assert_eq!(Event::ObjectCount(1), remaining.try_recv().unwrap());
+    }
+}
@@ -0,0 +1,103 @@
+use address::Address;
+use addressbook::Contact;
+use persist::Persister;
+use rustc_serialize::json::{Json,ToJson};
+
+// Which of the two lists decides who can send us msgs. Both lists are kept, so switching
+// mode doesn't lose the other one.
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum FilterMode {
+    Blacklist, // everyone but those listed
+    Whitelist // only those listed
+}
+
+impl FilterMode {
+    pub fn from_json(json: &Json) -> Option<FilterMode> {
+        match json.as_string() {
+            Some("blacklist") => Some(FilterMode::Blacklist),
+            Some("whitelist") => Some(FilterMode::Whitelist),
+            _ => None
+        }
+    }
+}
+
+impl ToJson for FilterMode {
+    fn to_json(&self) -> Json {
+        match self {
+            &FilterMode::Blacklist => "blacklist".to_json(),
+            &FilterMode::Whitelist => "whitelist".to_json()
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SenderFilter {
+    persister: Persister
+}
+
+impl SenderFilter {
+    pub fn new(persister: Persister) -> SenderFilter {
+        SenderFilter {
+            persister: persister
+        }
+    }
+
+    pub fn mode(&self) -> FilterMode {
+        self.persister.get_filter_mode()
+    }
+
+    pub fn set_mode(&mut self, mode: FilterMode) {
+        self.persister.set_filter_mode(mode);
+    }
+
+    pub fn add(&mut self, list: FilterMode, contact: &Contact) {
+        self.persister.add_filter_entry(list, contact);
+    }
+
+    pub fn remove(&mut self, list: FilterMode, address: &Address) -> bool {
+        self.persister.remove_filter_entry(list, address)
+    }
+
+    pub fn list(&self, list: FilterMode) -> Vec<Contact> {
+        self.persister.get_filter_entries(list)
+    }
+
+    pub fn allows(&self, address: &Address) -> bool {
+        let mode = self.mode();
+        let listed = self.list(mode).iter().any(|contact| contact.address() == address);
+        match mode {
+            FilterMode::Blacklist => !listed,
+            FilterMode::Whitelist => listed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use addressbook::Contact;
+    use identity::Identity;
+    use persist::Persister;
+    use super::{FilterMode,SenderFilter};
+
+    #[test]
+    fn test_modes_use_their_own_list() {
+        let mut filter = SenderFilter::new(Persister::new());
+        let blocked = Identity::random("", 1).address().clone();
+        let friend = Identity::random("", 1).address().clone();
+        filter.add(FilterMode::Blacklist, &Contact::new("Spammer", &blocked));
+        filter.add(FilterMode::Whitelist, &Contact::new("Friend", &friend));
+
+        assert_eq!(FilterMode::Blacklist, filter.mode());
+        assert!(!filter.allows(&blocked));
+        assert!(filter.allows(&friend));
+
+        filter.set_mode(FilterMode::Whitelist);
+        assert!(!filter.allows(&blocked));
+        assert!(filter.allows(&friend));
+
+        assert!(filter.remove(FilterMode::Whitelist, &friend));
+        assert!(!filter.remove(FilterMode::Whitelist, &friend));
+        assert!(!filter.allows(&friend));
+        assert_eq!(vec![ Contact::new("Spammer", &blocked) ], filter.list(FilterMode::Blacklist));
+    }
+}
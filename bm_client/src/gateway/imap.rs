@@ -0,0 +1,1006 @@
+use {BMClient,Folder,MessageQuery,StoredMessage};
+use address::Address;
+use mail::{FormattedMail,MAIL_DOMAIN,encode_words,format_mail,internal_date,mail_address,mail_date,message_id};
+use std::collections::{HashMap,HashSet};
+use std::io::{self,Read,Write};
+use std::sync::{Arc,Mutex};
+use std::time::{SystemTime,UNIX_EPOCH};
+use super::{Lines,credentials_match,plain_credentials,write_line};
+
+// IMAP4rev1 (RFC 3501) over two mailboxes: INBOX for received messages and Sent for our own.
+// Messages are read-only apart from \Seen, and \Deleted, which trashes them on EXPUNGE.
+
+const CAPABILITIES: &'static str = "IMAP4rev1 LITERAL+ AUTH=PLAIN UNSELECT";
+const MAX_LITERAL_LENGTH: usize = 1 << 20;
+const MAX_DEPTH: usize = 32;
+
+// Shared by all sessions, so a message keeps its uid whichever session sees it first
+pub struct Uids {
+    validity: u32,
+    next: u32,
+    assigned: HashMap<Vec<u8>, u32>
+}
+
+impl Uids {
+    // Uids are only kept in memory, so each run starts a new validity
+    pub fn new() -> Uids {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(1);
+        Uids {
+            validity: secs as u32,
+            next: 1,
+            assigned: HashMap::new()
+        }
+    }
+
+    fn uid(&mut self, id: &[u8]) -> u32 {
+        if let Some(&uid) = self.assigned.get(id) {
+            return uid;
+        }
+        let uid = self.next;
+        self.next += 1;
+        self.assigned.insert(id.to_vec(), uid);
+        uid
+    }
+}
+
+#[derive(Clone,Copy,PartialEq)]
+enum Mailbox {
+    Inbox,
+    Sent
+}
+
+impl Mailbox {
+    fn from_name(name: &str) -> Option<Mailbox> {
+        if name.eq_ignore_ascii_case("INBOX") {
+            Some(Mailbox::Inbox)
+        } else if name.eq_ignore_ascii_case("Sent") {
+            Some(Mailbox::Sent)
+        } else {
+            None
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            &Mailbox::Inbox => "INBOX",
+            &Mailbox::Sent => "Sent"
+        }
+    }
+
+    fn holds(&self, folder: Folder) -> bool {
+        match self {
+            &Mailbox::Inbox => folder == Folder::Inbox,
+            &Mailbox::Sent => folder == Folder::Outbox || folder == Folder::Sent
+        }
+    }
+}
+
+#[derive(Debug,PartialEq)]
+enum Token {
+    Atom(String),
+    Text(String), // quoted, or a literal
+    List(Vec<Token>)
+}
+
+struct Selected {
+    mailbox: Mailbox,
+    read_only: bool,
+    uids: Vec<u32>, // in sequence number order
+    deleted: HashSet<u32>
+}
+
+enum Section {
+    Full,
+    Header,
+    HeaderFields(Vec<String>, bool), // true for HEADER.FIELDS.NOT
+    Text
+}
+
+enum FetchItem {
+    Uid,
+    Flags,
+    InternalDate,
+    Size,
+    Envelope,
+    Structure,
+    Body { section: Section, label: String, peek: bool, partial: Option<(usize, usize)> }
+}
+
+#[derive(Clone,Copy)]
+enum FlagChange {
+    Replace,
+    Add,
+    Remove
+}
+
+enum SearchField {
+    From,
+    To,
+    Subject,
+    Body,
+    Text
+}
+
+enum Criterion {
+    All,
+    Seen,
+    Deleted,
+    Sequence(Vec<(u32, u32)>),
+    Uid(Vec<(u32, u32)>),
+    Contains(SearchField, String),
+    Not(Box<Criterion>),
+    Or(Box<Criterion>, Box<Criterion>),
+    And(Vec<Criterion>)
+}
+
+pub fn serve<R: Read, W: Write>(lines: &mut Lines<R>, writer: &mut W, credentials: &(String, String), client: &Arc<Mutex<BMClient>>, uids: &Arc<Mutex<Uids>>) -> io::Result<()> {
+    let mut session = Session {
+        credentials: credentials,
+        client: client,
+        uids: uids,
+        authenticated: false,
+        selected: None
+    };
+
+    try!(write_line(writer, &format!("* OK [CAPABILITY {}] Rubbem mail gateway ready", CAPABILITIES)));
+    while let Some(command) = try!(read_command(lines, writer)) {
+        let (tag, rest) = match command.find(' ') {
+            Some(space) => (&command[..space], &command[space + 1..]),
+            None => (&command[..], "")
+        };
+        let mut arguments = match tokenize(rest) {
+            Ok(arguments) => arguments,
+            Err(err) => {
+                try!(write_line(writer, &format!("{} BAD {}", tag, err)));
+                continue;
+            }
+        };
+        let name = match arguments.first().and_then(text) {
+            Some(name) => name.to_uppercase(),
+            None => {
+                try!(write_line(writer, &format!("{} BAD Missing command", tag)));
+                continue;
+            }
+        };
+        arguments.remove(0);
+
+        if name == "LOGOUT" {
+            try!(write_line(writer, "* BYE Logging out"));
+            return write_line(writer, &format!("{} OK LOGOUT completed", tag));
+        }
+        let completion = try!(session.command(lines, writer, &name, &arguments));
+        try!(write_line(writer, &format!("{} {}", tag, completion)));
+    }
+    Ok(())
+}
+
+// A whole command, with any literals in it turned into quoted strings
+fn read_command<R: Read, W: Write>(lines: &mut Lines<R>, writer: &mut W) -> io::Result<Option<String>> {
+    let mut command = String::new();
+    loop {
+        let line = match try!(lines.next_line()) {
+            Some(line) => line,
+            None => return Ok(None)
+        };
+        match literal_length(&line) {
+            Some((_, length, _)) if length > MAX_LITERAL_LENGTH => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("literal of {} bytes is too long", length)));
+            },
+            Some((start, length, synchronizing)) => {
+                command.push_str(&line[..start]);
+                if synchronizing {
+                    try!(write_line(writer, "+ Ready"));
+                }
+                let bytes = try!(lines.read_bytes(length));
+                command.push_str(&quote(&String::from_utf8_lossy(&bytes)));
+            },
+            None => {
+                command.push_str(&line);
+                return Ok(Some(command));
+            }
+        }
+    }
+}
+
+// Where a line ends in {length} or {length+}, and whether the client waits to be told to go on
+fn literal_length(line: &str) -> Option<(usize, usize, bool)> {
+    if !line.ends_with('}') {
+        return None;
+    }
+    let start = match line.rfind('{') {
+        Some(start) => start,
+        None => return None
+    };
+    let inside = &line[start + 1..line.len() - 1];
+    let (digits, synchronizing) = if inside.ends_with('+') { (&inside[..inside.len() - 1], false) } else { (inside, true) };
+    digits.parse().ok().map(|length| (start, length, synchronizing))
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut index = 0;
+    tokens(&chars, &mut index, 0)
+}
+
+// Lists nest no deeper than any real command needs, so a flood of ( can't exhaust the stack
+fn tokens(chars: &[char], index: &mut usize, depth: usize) -> Result<Vec<Token>, String> {
+    if depth > MAX_DEPTH {
+        return Err("Lists nested too deeply".to_string());
+    }
+    let in_list = depth > 0;
+    let mut tokens = vec![];
+    while *index < chars.len() {
+        match chars[*index] {
+            ' ' => *index += 1,
+            '(' => {
+                *index += 1;
+                tokens.push(Token::List(try!(self::tokens(chars, index, depth + 1))));
+            },
+            ')' if in_list => {
+                *index += 1;
+                return Ok(tokens);
+            },
+            ')' => return Err("Unexpected )".to_string()),
+            '"' => {
+                *index += 1;
+                let mut quoted = String::new();
+                loop {
+                    let c = match chars.get(*index) {
+                        Some(&c) => c,
+                        None => return Err("Unterminated string".to_string())
+                    };
+                    *index += 1;
+                    match c {
+                        '"' => break,
+                        '\\' => match chars.get(*index) {
+                            Some(&escaped) => {
+                                quoted.push(escaped);
+                                *index += 1;
+                            },
+                            None => return Err("Unterminated string".to_string())
+                        },
+                        _ => quoted.push(c)
+                    }
+                }
+                tokens.push(Token::Text(quoted));
+            },
+            _ => {
+                // Fetch items such as BODY[HEADER.FIELDS (FROM)] stay one atom
+                let mut atom = String::new();
+                let mut depth = 0;
+                while let Some(&c) = chars.get(*index) {
+                    if depth == 0 && (c == ' ' || c == '(' || c == ')' || c == '"') {
+                        break;
+                    }
+                    if c == '[' {
+                        depth += 1;
+                    } else if c == ']' && depth > 0 {
+                        depth -= 1;
+                    }
+                    atom.push(c);
+                    *index += 1;
+                }
+                tokens.push(Token::Atom(atom));
+            }
+        }
+    }
+    if in_list { Err("Unterminated list".to_string()) } else { Ok(tokens) }
+}
+
+fn text(token: &Token) -> Option<&str> {
+    match token {
+        &Token::Atom(ref text) | &Token::Text(ref text) => Some(text),
+        &Token::List(_) => None
+    }
+}
+
+struct Session<'a> {
+    credentials: &'a (String, String),
+    client: &'a Arc<Mutex<BMClient>>,
+    uids: &'a Arc<Mutex<Uids>>,
+    authenticated: bool,
+    selected: Option<Selected>
+}
+
+impl<'a> Session<'a> {
+    // Answers with the text of the tagged response
+    fn command<R: Read, W: Write>(&mut self, lines: &mut Lines<R>, writer: &mut W, name: &str, arguments: &[Token]) -> io::Result<String> {
+        match name {
+            "CAPABILITY" => {
+                try!(write_line(writer, &format!("* CAPABILITY {}", CAPABILITIES)));
+                Ok("OK CAPABILITY completed".to_string())
+            },
+            "NOOP" | "CHECK" => {
+                try!(self.refresh(writer));
+                Ok(format!("OK {} completed", name))
+            },
+            "LOGIN" => match (arguments.get(0).and_then(text), arguments.get(1).and_then(text)) {
+                (Some(username), Some(password)) => Ok(self.log_in(username, password)),
+                _ => Ok("BAD LOGIN needs a username and password".to_string())
+            },
+            "AUTHENTICATE" => self.authenticate(lines, writer, arguments),
+            _ if !self.authenticated => Ok("NO Log in first".to_string()),
+            "LIST" | "LSUB" => self.list(writer, name, arguments),
+            "STATUS" => self.status(writer, arguments),
+            "SELECT" | "EXAMINE" => self.select(writer, name, arguments),
+            "CREATE" | "DELETE" | "RENAME" => Ok("NO Only INBOX and Sent are available".to_string()),
+            "SUBSCRIBE" | "UNSUBSCRIBE" => Ok(format!("OK {} completed", name)),
+            "APPEND" => Ok(match arguments.get(0).and_then(text).and_then(Mailbox::from_name) {
+                // Mail clients keep a copy of what they send, but the gateway has it already
+                Some(Mailbox::Sent) => "OK APPEND completed".to_string(),
+                Some(Mailbox::Inbox) => "NO Messages only arrive over Bitmessage".to_string(),
+                None => "NO [TRYCREATE] No such mailbox".to_string()
+            }),
+            _ if self.selected.is_none() => Ok("BAD No mailbox selected".to_string()),
+            "CLOSE" => {
+                try!(self.expunge(writer, false));
+                self.selected = None;
+                Ok("OK CLOSE completed".to_string())
+            },
+            "UNSELECT" => {
+                self.selected = None;
+                Ok("OK UNSELECT completed".to_string())
+            },
+            "EXPUNGE" => {
+                try!(self.expunge(writer, true));
+                Ok("OK EXPUNGE completed".to_string())
+            },
+            "SEARCH" => self.search(writer, false, arguments),
+            "FETCH" => self.fetch(writer, false, arguments),
+            "STORE" => self.store(writer, false, arguments),
+            "COPY" => Ok("NO Messages cannot be copied".to_string()),
+            "UID" => match arguments.first().and_then(text).map(|name| name.to_uppercase()) {
+                Some(ref name) if name == "SEARCH" => self.search(writer, true, &arguments[1..]),
+                Some(ref name) if name == "FETCH" => self.fetch(writer, true, &arguments[1..]),
+                Some(ref name) if name == "STORE" => self.store(writer, true, &arguments[1..]),
+                Some(ref name) if name == "COPY" => Ok("NO Messages cannot be copied".to_string()),
+                _ => Ok("BAD Unknown UID command".to_string())
+            },
+            _ => Ok("BAD Unknown command".to_string())
+        }
+    }
+
+    fn log_in(&mut self, username: &str, password: &str) -> String {
+        if credentials_match(username, password, self.credentials) {
+            self.authenticated = true;
+            "OK Logged in".to_string()
+        } else {
+            "NO [AUTHENTICATIONFAILED] Wrong username or password".to_string()
+        }
+    }
+
+    fn authenticate<R: Read, W: Write>(&mut self, lines: &mut Lines<R>, writer: &mut W, arguments: &[Token]) -> io::Result<String> {
+        match arguments.get(0).and_then(text) {
+            Some(mechanism) if mechanism.eq_ignore_ascii_case("PLAIN") => {},
+            _ => return Ok("NO Only PLAIN authentication is supported".to_string())
+        }
+        let response = match arguments.get(1).and_then(text) {
+            Some(initial) => initial.to_string(),
+            None => {
+                try!(write_line(writer, "+ "));
+                try!(lines.next_line()).unwrap_or(String::new())
+            }
+        };
+        if response.trim() == "*" {
+            return Ok("BAD Authentication cancelled".to_string());
+        }
+        let (username, password) = plain_credentials(&response);
+        Ok(self.log_in(&username, &password))
+    }
+
+    fn list<W: Write>(&self, writer: &mut W, name: &str, arguments: &[Token]) -> io::Result<String> {
+        let (reference, pattern) = match (arguments.get(0).and_then(text), arguments.get(1).and_then(text)) {
+            (Some(reference), Some(pattern)) => (reference, pattern),
+            _ => return Ok(format!("BAD {} needs a reference and a mailbox pattern", name))
+        };
+
+        // An empty pattern asks for the hierarchy delimiter
+        if pattern.is_empty() {
+            try!(write_line(writer, &format!("* {} (\\Noselect) \"/\" \"\"", name)));
+        } else {
+            let pattern: Vec<char> = format!("{}{}", reference, pattern).to_uppercase().chars().collect();
+            for mailbox in [ Mailbox::Inbox, Mailbox::Sent ].iter() {
+                let mailbox_name: Vec<char> = mailbox.name().to_uppercase().chars().collect();
+                if matches_pattern(&pattern, &mailbox_name) {
+                    let attributes = if *mailbox == Mailbox::Sent { "\\HasNoChildren \\Sent" } else { "\\HasNoChildren" };
+                    try!(write_line(writer, &format!("* {} ({}) \"/\" {}", name, attributes, quote(mailbox.name()))));
+                }
+            }
+        }
+        Ok(format!("OK {} completed", name))
+    }
+
+    fn status<W: Write>(&self, writer: &mut W, arguments: &[Token]) -> io::Result<String> {
+        let mailbox = match arguments.get(0).and_then(text).and_then(Mailbox::from_name) {
+            Some(mailbox) => mailbox,
+            None => return Ok("NO No such mailbox".to_string())
+        };
+        let items = match arguments.get(1) {
+            Some(&Token::List(ref items)) => items,
+            _ => return Ok("BAD STATUS needs a list of items".to_string())
+        };
+
+        let messages = self.messages(mailbox);
+        let (validity, next) = {
+            let uids = self.uids.lock().unwrap();
+            (uids.validity, uids.next)
+        };
+        let mut values = vec![];
+        for item in items.iter() {
+            let item = text(item).unwrap_or("").to_uppercase();
+            let value = match &item[..] {
+                "MESSAGES" => messages.len() as u32,
+                "RECENT" => 0,
+                "UIDNEXT" => next,
+                "UIDVALIDITY" => validity,
+                "UNSEEN" => messages.iter().filter(|&&(_, ref message)| !message.is_read()).count() as u32,
+                _ => return Ok(format!("BAD Unknown status item {}", item))
+            };
+            values.push(format!("{} {}", item, value));
+        }
+        try!(write_line(writer, &format!("* STATUS {} ({})", quote(mailbox.name()), values.join(" "))));
+        Ok("OK STATUS completed".to_string())
+    }
+
+    fn select<W: Write>(&mut self, writer: &mut W, name: &str, arguments: &[Token]) -> io::Result<String> {
+        self.selected = None;
+        let mailbox = match arguments.get(0).and_then(text).and_then(Mailbox::from_name) {
+            Some(mailbox) => mailbox,
+            None => return Ok("NO No such mailbox".to_string())
+        };
+
+        let messages = self.messages(mailbox);
+        let (validity, next) = {
+            let uids = self.uids.lock().unwrap();
+            (uids.validity, uids.next)
+        };
+        try!(write_line(writer, "* FLAGS (\\Seen \\Deleted)"));
+        try!(write_line(writer, "* OK [PERMANENTFLAGS (\\Seen \\Deleted)] Limited"));
+        try!(write_line(writer, &format!("* {} EXISTS", messages.len())));
+        try!(write_line(writer, "* 0 RECENT"));
+        if let Some(first_unseen) = messages.iter().position(|&(_, ref message)| !message.is_read()) {
+            try!(write_line(writer, &format!("* OK [UNSEEN {}] First unseen", first_unseen + 1)));
+        }
+        try!(write_line(writer, &format!("* OK [UIDVALIDITY {}] UIDs valid", validity)));
+        try!(write_line(writer, &format!("* OK [UIDNEXT {}] Predicted next UID", next)));
+
+        let read_only = name == "EXAMINE";
+        self.selected = Some(Selected {
+            mailbox: mailbox,
+            read_only: read_only,
+            uids: messages.iter().map(|&(uid, _)| uid).collect(),
+            deleted: HashSet::new()
+        });
+        Ok(format!("OK [{}] {} completed", if read_only { "READ-ONLY" } else { "READ-WRITE" }, name))
+    }
+
+    // The mailbox's messages in uid order, which new messages always come last in
+    fn messages(&self, mailbox: Mailbox) -> Vec<(u32, StoredMessage)> {
+        let mut messages: Vec<StoredMessage> = self.client.lock().unwrap().messages(&MessageQuery::new())
+            .into_iter().filter(|message| mailbox.holds(message.folder())).collect();
+        // Oldest first, so uids are handed out in the order the messages came
+        messages.reverse();
+        let mut uids = self.uids.lock().unwrap();
+        let mut numbered: Vec<(u32, StoredMessage)> = messages.into_iter().map(|message| (uids.uid(message.id()), message)).collect();
+        numbered.sort_by_key(|&(uid, _)| uid);
+        numbered
+    }
+
+    fn selected_messages(&self) -> HashMap<u32, StoredMessage> {
+        match self.selected {
+            Some(ref selected) => self.messages(selected.mailbox).into_iter().collect(),
+            None => HashMap::new()
+        }
+    }
+
+    // Tells the client about messages that have arrived or gone since it last looked
+    fn refresh<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let current: Vec<u32> = match self.selected {
+            Some(ref selected) => self.messages(selected.mailbox).into_iter().map(|(uid, _)| uid).collect(),
+            None => return Ok(())
+        };
+        let selected = self.selected.as_mut().unwrap();
+
+        for sequence in (1..selected.uids.len() + 1).rev() {
+            let uid = selected.uids[sequence - 1];
+            if !current.contains(&uid) {
+                selected.uids.remove(sequence - 1);
+                selected.deleted.remove(&uid);
+                try!(write_line(writer, &format!("* {} EXPUNGE", sequence)));
+            }
+        }
+        let last = selected.uids.last().cloned().unwrap_or(0);
+        let known = selected.uids.len();
+        selected.uids.extend(current.into_iter().filter(|&uid| uid > last));
+        if selected.uids.len() != known {
+            try!(write_line(writer, &format!("* {} EXISTS", selected.uids.len())));
+        }
+        Ok(())
+    }
+
+    // Messages marked \Deleted go to the trash, where the other clients can still restore them
+    fn expunge<W: Write>(&mut self, writer: &mut W, report: bool) -> io::Result<()> {
+        let messages = self.selected_messages();
+        let selected = self.selected.as_mut().unwrap();
+        if selected.read_only {
+            return Ok(());
+        }
+
+        for sequence in (1..selected.uids.len() + 1).rev() {
+            let uid = selected.uids[sequence - 1];
+            if !selected.deleted.contains(&uid) {
+                continue;
+            }
+            if let Some(message) = messages.get(&uid) {
+                self.client.lock().unwrap().trash_message(message.id());
+            }
+            selected.uids.remove(sequence - 1);
+            selected.deleted.remove(&uid);
+            if report {
+                try!(write_line(writer, &format!("* {} EXPUNGE", sequence)));
+            }
+        }
+        Ok(())
+    }
+
+    // The sequence numbers and uids of the selected messages in the set
+    fn targets(&self, by_uid: bool, set: &str) -> Result<Vec<(usize, u32)>, String> {
+        let selected = self.selected.as_ref().unwrap();
+        let largest = if by_uid { selected.uids.last().cloned().unwrap_or(0) } else { selected.uids.len() as u32 };
+        let ranges = try!(sequence_set(set, largest));
+        Ok(selected.uids.iter().enumerate().map(|(index, &uid)| (index + 1, uid))
+            .filter(|&(sequence, uid)| in_set(&ranges, if by_uid { uid } else { sequence as u32 })).collect())
+    }
+
+    fn fetch<W: Write>(&mut self, writer: &mut W, by_uid: bool, arguments: &[Token]) -> io::Result<String> {
+        let items = match (arguments.get(1), arguments.len()) {
+            (Some(&Token::List(ref tokens)), 2) => fetch_items(tokens),
+            (Some(token), 2) => fetch_items(::std::slice::from_ref(token)),
+            _ => Err("FETCH needs a sequence set and items".to_string())
+        };
+        let mut items = match items {
+            Ok(items) => items,
+            Err(err) => return Ok(format!("BAD {}", err))
+        };
+        let targets = match self.targets(by_uid, arguments.get(0).and_then(text).unwrap_or("")) {
+            Ok(targets) => targets,
+            Err(err) => return Ok(format!("BAD {}", err))
+        };
+        if by_uid && !items.iter().any(|item| match item { &FetchItem::Uid => true, _ => false }) {
+            items.insert(0, FetchItem::Uid);
+        }
+        let reads = items.iter().any(|item| match item { &FetchItem::Body { peek, .. } => !peek, _ => false });
+        let has_flags = items.iter().any(|item| match item { &FetchItem::Flags => true, _ => false });
+
+        let messages = self.selected_messages();
+        let selected = self.selected.as_ref().unwrap();
+        for (sequence, uid) in targets {
+            let message = match messages.get(&uid) {
+                Some(message) => message,
+                None => continue
+            };
+            let mut seen = message.is_read();
+            if reads && !seen && !selected.read_only {
+                seen = self.client.lock().unwrap().mark_read(message.id(), true);
+            }
+            let deleted = selected.deleted.contains(&uid);
+
+            let mail = format_mail(message);
+            let mut parts: Vec<Vec<u8>> = items.iter().map(|item| fetch_item(item, uid, seen, deleted, message, &mail)).collect();
+            if seen != message.is_read() && !has_flags {
+                parts.push(format!("FLAGS {}", flags(seen, deleted)).into_bytes());
+            }
+
+            let mut response = format!("* {} FETCH (", sequence).into_bytes();
+            for (index, part) in parts.iter().enumerate() {
+                if index > 0 {
+                    response.push(b' ');
+                }
+                response.extend_from_slice(part);
+            }
+            response.extend_from_slice(b")\r\n");
+            try!(writer.write_all(&response));
+        }
+        try!(writer.flush());
+        Ok(format!("OK {}FETCH completed", if by_uid { "UID " } else { "" }))
+    }
+
+    fn store<W: Write>(&mut self, writer: &mut W, by_uid: bool, arguments: &[Token]) -> io::Result<String> {
+        if self.selected.as_ref().unwrap().read_only {
+            return Ok("NO The mailbox is read-only".to_string());
+        }
+        let (set, action) = match (arguments.get(0).and_then(text), arguments.get(1).and_then(text)) {
+            (Some(set), Some(action)) => (set, action.to_uppercase()),
+            _ => return Ok("BAD STORE needs a sequence set, an action and flags".to_string())
+        };
+        let silent = action.ends_with(".SILENT");
+        let change = match action.trim_end_matches(".SILENT") {
+            "FLAGS" => FlagChange::Replace,
+            "+FLAGS" => FlagChange::Add,
+            "-FLAGS" => FlagChange::Remove,
+            _ => return Ok(format!("BAD Unknown STORE action {}", action))
+        };
+        let flag_tokens = match arguments.get(2) {
+            Some(&Token::List(ref tokens)) => &tokens[..],
+            _ => &arguments[2..]
+        };
+        let flag_names: Vec<String> = flag_tokens.iter().filter_map(text).map(|flag| flag.to_uppercase()).collect();
+        let (seen_flag, deleted_flag) = (flag_names.iter().any(|flag| flag == "\\SEEN"), flag_names.iter().any(|flag| flag == "\\DELETED"));
+
+        let targets = match self.targets(by_uid, set) {
+            Ok(targets) => targets,
+            Err(err) => return Ok(format!("BAD {}", err))
+        };
+        let messages = self.selected_messages();
+        let selected = self.selected.as_mut().unwrap();
+        for (sequence, uid) in targets {
+            let message = match messages.get(&uid) {
+                Some(message) => message,
+                None => continue
+            };
+            let (mut seen, mut deleted) = (message.is_read(), selected.deleted.contains(&uid));
+            match change {
+                FlagChange::Replace => {
+                    seen = seen_flag;
+                    deleted = deleted_flag;
+                },
+                FlagChange::Add => {
+                    seen = seen || seen_flag;
+                    deleted = deleted || deleted_flag;
+                },
+                FlagChange::Remove => {
+                    seen = seen && !seen_flag;
+                    deleted = deleted && !deleted_flag;
+                }
+            }
+
+            // Our own messages are always read
+            if seen != message.is_read() && !self.client.lock().unwrap().mark_read(message.id(), seen) {
+                seen = message.is_read();
+            }
+            if deleted {
+                selected.deleted.insert(uid);
+            } else {
+                selected.deleted.remove(&uid);
+            }
+            if !silent {
+                let uid_part = if by_uid { format!(" UID {}", uid) } else { String::new() };
+                try!(write_line(writer, &format!("* {} FETCH (FLAGS {}{})", sequence, flags(seen, deleted), uid_part)));
+            }
+        }
+        Ok(format!("OK {}STORE completed", if by_uid { "UID " } else { "" }))
+    }
+
+    fn search<W: Write>(&mut self, writer: &mut W, by_uid: bool, arguments: &[Token]) -> io::Result<String> {
+        let mut arguments = arguments;
+        if arguments.first().and_then(text).map_or(false, |first| first.eq_ignore_ascii_case("CHARSET")) {
+            match arguments.get(1).and_then(text) {
+                Some(charset) if charset.eq_ignore_ascii_case("UTF-8") || charset.eq_ignore_ascii_case("US-ASCII") => arguments = &arguments[2..],
+                _ => return Ok("NO [BADCHARSET (UTF-8 US-ASCII)] Unsupported charset".to_string())
+            }
+        }
+
+        let selected = self.selected.as_ref().unwrap();
+        let largest = (selected.uids.len() as u32, selected.uids.last().cloned().unwrap_or(0));
+        let mut criteria = vec![];
+        let mut index = 0;
+        while index < arguments.len() {
+            match criterion(arguments, &mut index, largest) {
+                Ok(criterion) => criteria.push(criterion),
+                Err(err) => return Ok(format!("BAD {}", err))
+            }
+        }
+        let criterion = Criterion::And(criteria);
+
+        let messages = self.selected_messages();
+        let found: Vec<String> = selected.uids.iter().enumerate().filter_map(|(index, &uid)| {
+            let sequence = index as u32 + 1;
+            match messages.get(&uid) {
+                Some(message) if matches(&criterion, sequence, uid, message, selected.deleted.contains(&uid)) => {
+                    Some(if by_uid { uid } else { sequence }.to_string())
+                },
+                _ => None
+            }
+        }).collect();
+
+        let mut response = "* SEARCH".to_string();
+        for number in found.iter() {
+            response.push(' ');
+            response.push_str(number);
+        }
+        try!(write_line(writer, &response));
+        Ok(format!("OK {}SEARCH completed", if by_uid { "UID " } else { "" }))
+    }
+}
+
+// Mailbox names are flat, so % matches as much as * does
+fn matches_pattern(pattern: &[char], name: &[char]) -> bool {
+    match pattern.first() {
+        None => name.is_empty(),
+        Some(&'*') | Some(&'%') => (0..name.len() + 1).any(|skip| matches_pattern(&pattern[1..], &name[skip..])),
+        Some(&c) => name.first() == Some(&c) && matches_pattern(&pattern[1..], &name[1..])
+    }
+}
+
+// Ranges such as 1:4,7,9:* where * is the largest number there is
+fn sequence_set(set: &str, largest: u32) -> Result<Vec<(u32, u32)>, String> {
+    let number = |text: &str| if text == "*" {
+        Ok(largest)
+    } else {
+        text.parse::<u32>().map_err(|_| format!("Bad sequence set {}", set))
+    };
+
+    let mut ranges = vec![];
+    for part in set.split(',') {
+        let mut ends = part.splitn(2, ':');
+        let first = try!(number(ends.next().unwrap_or("")));
+        let last = match ends.next() {
+            Some(end) => try!(number(end)),
+            None => first
+        };
+        ranges.push((first.min(last), first.max(last)));
+    }
+    Ok(ranges)
+}
+
+fn in_set(ranges: &[(u32, u32)], number: u32) -> bool {
+    ranges.iter().any(|&(first, last)| number >= first && number <= last)
+}
+
+fn fetch_items(tokens: &[Token]) -> Result<Vec<FetchItem>, String> {
+    let mut items = vec![];
+    for token in tokens.iter() {
+        let name = match text(token) {
+            Some(name) => name.to_uppercase(),
+            None => return Err("Bad fetch item".to_string())
+        };
+        match &name[..] {
+            "ALL" => items.extend(vec![ FetchItem::Flags, FetchItem::InternalDate, FetchItem::Size, FetchItem::Envelope ]),
+            "FAST" => items.extend(vec![ FetchItem::Flags, FetchItem::InternalDate, FetchItem::Size ]),
+            "FULL" => items.extend(vec![ FetchItem::Flags, FetchItem::InternalDate, FetchItem::Size, FetchItem::Envelope, FetchItem::Structure ]),
+            "UID" => items.push(FetchItem::Uid),
+            "FLAGS" => items.push(FetchItem::Flags),
+            "INTERNALDATE" => items.push(FetchItem::InternalDate),
+            "RFC822.SIZE" => items.push(FetchItem::Size),
+            "ENVELOPE" => items.push(FetchItem::Envelope),
+            "BODY" | "BODYSTRUCTURE" => items.push(FetchItem::Structure),
+            "RFC822" => items.push(FetchItem::Body { section: Section::Full, label: name.clone(), peek: false, partial: None }),
+            "RFC822.HEADER" => items.push(FetchItem::Body { section: Section::Header, label: name.clone(), peek: true, partial: None }),
+            "RFC822.TEXT" => items.push(FetchItem::Body { section: Section::Text, label: name.clone(), peek: false, partial: None }),
+            _ if name.starts_with("BODY[") || name.starts_with("BODY.PEEK[") => items.push(try!(body_item(&name))),
+            _ => return Err(format!("Unknown fetch item {}", name))
+        }
+    }
+    Ok(items)
+}
+
+// BODY[section]<start.length>, where the section is all there is to a single text part
+fn body_item(name: &str) -> Result<FetchItem, String> {
+    let bad = || format!("Bad fetch item {}", name);
+    let open = name.find('[').unwrap();
+    let close = match name.rfind(']') {
+        Some(close) => close,
+        None => return Err(bad())
+    };
+    let section_name = &name[open + 1..close];
+
+    let partial = match &name[close + 1..] {
+        "" => None,
+        range if range.starts_with('<') && range.ends_with('>') => {
+            let mut numbers = range[1..range.len() - 1].splitn(2, '.').map(|number| number.parse::<usize>());
+            match (numbers.next(), numbers.next()) {
+                (Some(Ok(start)), Some(Ok(length))) => Some((start, length)),
+                _ => return Err(bad())
+            }
+        },
+        _ => return Err(bad())
+    };
+
+    let section = match section_name {
+        "" => Section::Full,
+        "HEADER" => Section::Header,
+        "TEXT" | "1" => Section::Text,
+        "1.MIME" => Section::HeaderFields(vec![ "CONTENT-TYPE".to_string(), "CONTENT-TRANSFER-ENCODING".to_string() ], false),
+        _ if section_name.starts_with("HEADER.FIELDS") => {
+            let fields = match (section_name.find('('), section_name.rfind(')')) {
+                (Some(start), Some(end)) if start < end => section_name[start + 1..end].split_whitespace().map(|field| field.trim_matches('"').to_string()).collect(),
+                _ => return Err(bad())
+            };
+            Section::HeaderFields(fields, section_name.starts_with("HEADER.FIELDS.NOT"))
+        },
+        _ => return Err(bad())
+    };
+
+    let label = match partial {
+        Some((start, _)) => format!("BODY[{}]<{}>", section_name, start),
+        None => format!("BODY[{}]", section_name)
+    };
+    Ok(FetchItem::Body {
+        section: section,
+        label: label,
+        peek: name.starts_with("BODY.PEEK["),
+        partial: partial
+    })
+}
+
+fn fetch_item(item: &FetchItem, uid: u32, seen: bool, deleted: bool, message: &StoredMessage, mail: &FormattedMail) -> Vec<u8> {
+    match item {
+        &FetchItem::Uid => format!("UID {}", uid).into_bytes(),
+        &FetchItem::Flags => format!("FLAGS {}", flags(seen, deleted)).into_bytes(),
+        &FetchItem::InternalDate => format!("INTERNALDATE {}", quote(&internal_date(message.timestamp()))).into_bytes(),
+        &FetchItem::Size => format!("RFC822.SIZE {}", mail.size()).into_bytes(),
+        &FetchItem::Envelope => format!("ENVELOPE {}", envelope(message)).into_bytes(),
+        &FetchItem::Structure => format!("BODYSTRUCTURE (\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"8BIT\" {} {})",
+            mail.text.len(), mail.lines()).into_bytes(),
+        &FetchItem::Body { ref section, ref label, partial, .. } => {
+            let content = section_content(mail, section);
+            let bytes = content.as_bytes();
+            let bytes = match partial {
+                Some((start, length)) => {
+                    let start = start.min(bytes.len());
+                    &bytes[start..start.saturating_add(length).min(bytes.len())]
+                },
+                None => bytes
+            };
+            let mut item = format!("{} {{{}}}\r\n", label, bytes.len()).into_bytes();
+            item.extend_from_slice(bytes);
+            item
+        }
+    }
+}
+
+fn section_content(mail: &FormattedMail, section: &Section) -> String {
+    match section {
+        &Section::Full => mail.full(),
+        &Section::Header => mail.header.clone(),
+        &Section::Text => mail.text.clone(),
+        &Section::HeaderFields(ref fields, exclude) => {
+            // The header is written unfolded, so each field is on a line of its own
+            let mut content: String = mail.header.split("\r\n").filter(|line| !line.is_empty()).filter(|line| {
+                let field = line.split(':').next().unwrap_or("").to_uppercase();
+                fields.contains(&field) != exclude
+            }).map(|line| format!("{}\r\n", line)).collect();
+            content.push_str("\r\n");
+            content
+        }
+    }
+}
+
+fn envelope(message: &StoredMessage) -> String {
+    let from = address_list(message.from());
+    let to = message.to().map(address_list).unwrap_or("NIL".to_string());
+    format!("({} {} {} {} {} {} NIL NIL NIL {})", quote(&mail_date(message.timestamp())), quote(&encode_words(message.subject())),
+        from, from, from, to, quote(&message_id(message)))
+}
+
+fn address_list(address: &Address) -> String {
+    format!("((NIL NIL {} {}))", quote(&address.to_string()), quote(MAIL_DOMAIN))
+}
+
+fn flags(seen: bool, deleted: bool) -> String {
+    let mut flags = vec![];
+    if seen {
+        flags.push("\\Seen");
+    }
+    if deleted {
+        flags.push("\\Deleted");
+    }
+    format!("({})", flags.join(" "))
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn criterion(tokens: &[Token], index: &mut usize, largest: (u32, u32)) -> Result<Criterion, String> {
+    let token = match tokens.get(*index) {
+        Some(token) => token,
+        None => return Err("Missing search key".to_string())
+    };
+    *index += 1;
+
+    let key = match token {
+        &Token::List(ref inner) => {
+            let mut criteria = vec![];
+            let mut inner_index = 0;
+            while inner_index < inner.len() {
+                criteria.push(try!(criterion(inner, &mut inner_index, largest)));
+            }
+            return Ok(Criterion::And(criteria));
+        },
+        _ => text(token).unwrap_or("").to_uppercase()
+    };
+    let argument = |index: &mut usize| match tokens.get(*index).and_then(text) {
+        Some(argument) => {
+            *index += 1;
+            Ok(argument.to_string())
+        },
+        None => Err(format!("{} needs an argument", key))
+    };
+
+    let field = match &key[..] {
+        "FROM" => Some(SearchField::From),
+        "TO" => Some(SearchField::To),
+        "SUBJECT" => Some(SearchField::Subject),
+        "BODY" => Some(SearchField::Body),
+        "TEXT" => Some(SearchField::Text),
+        _ => None
+    };
+    if let Some(field) = field {
+        return Ok(Criterion::Contains(field, try!(argument(index)).to_lowercase()));
+    }
+
+    // There are no flags besides \Seen and \Deleted, so searches for the others have simple answers
+    Ok(match &key[..] {
+        "ALL" | "OLD" | "UNANSWERED" | "UNDRAFT" | "UNFLAGGED" => Criterion::All,
+        "ANSWERED" | "DRAFT" | "FLAGGED" | "NEW" | "RECENT" => Criterion::Not(Box::new(Criterion::All)),
+        "SEEN" => Criterion::Seen,
+        "UNSEEN" => Criterion::Not(Box::new(Criterion::Seen)),
+        "DELETED" => Criterion::Deleted,
+        "UNDELETED" => Criterion::Not(Box::new(Criterion::Deleted)),
+        "KEYWORD" | "CC" | "BCC" => {
+            try!(argument(index));
+            Criterion::Not(Box::new(Criterion::All))
+        },
+        "UNKEYWORD" => {
+            try!(argument(index));
+            Criterion::All
+        },
+        "UID" => Criterion::Uid(try!(sequence_set(&try!(argument(index)), largest.1))),
+        "NOT" => Criterion::Not(Box::new(try!(criterion(tokens, index, largest)))),
+        "OR" => {
+            let first = try!(criterion(tokens, index, largest));
+            Criterion::Or(Box::new(first), Box::new(try!(criterion(tokens, index, largest))))
+        },
+        _ if key.starts_with(|c: char| c.is_ascii_digit() || c == '*') => Criterion::Sequence(try!(sequence_set(&key, largest.0))),
+        _ => return Err(format!("Unsupported search key {}", key))
+    })
+}
+
+fn matches(criterion: &Criterion, sequence: u32, uid: u32, message: &StoredMessage, deleted: bool) -> bool {
+    match criterion {
+        &Criterion::All => true,
+        &Criterion::Seen => message.is_read(),
+        &Criterion::Deleted => deleted,
+        &Criterion::Sequence(ref ranges) => in_set(ranges, sequence),
+        &Criterion::Uid(ref ranges) => in_set(ranges, uid),
+        &Criterion::Contains(ref field, ref wanted) => {
+            let from = mail_address(message.from());
+            let to = message.to().map(mail_address).unwrap_or(String::new());
+            let haystack = match field {
+                &SearchField::From => from,
+                &SearchField::To => to,
+                &SearchField::Subject => message.subject().to_string(),
+                &SearchField::Body => message.body().to_string(),
+                &SearchField::Text => format!("{}\n{}\n{}\n{}", from, to, message.subject(), message.body())
+            };
+            haystack.to_lowercase().contains(wanted)
+        },
+        &Criterion::Not(ref inner) => !matches(inner, sequence, uid, message, deleted),
+        &Criterion::Or(ref first, ref second) => matches(first, sequence, uid, message, deleted) || matches(second, sequence, uid, message, deleted),
+        &Criterion::And(ref criteria) => criteria.iter().all(|criterion| matches(criterion, sequence, uid, message, deleted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Token,literal_length,sequence_set,tokenize};
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(vec![ Token::Atom("FETCH".to_string()), Token::Atom("1:*".to_string()),
+            Token::List(vec![ Token::Atom("UID".to_string()), Token::Atom("BODY.PEEK[HEADER.FIELDS (FROM TO)]<0.100>".to_string()) ]) ],
+            tokenize("FETCH 1:* (UID BODY.PEEK[HEADER.FIELDS (FROM TO)]<0.100>)").unwrap());
+        assert_eq!(vec![ Token::Text("a \"b\"".to_string()), Token::Atom("NIL".to_string()) ], tokenize("\"a \\\"b\\\"\" NIL").unwrap());
+        assert!(tokenize("(UID").is_err());
+        assert_eq!(Err("Lists nested too deeply".to_string()), tokenize(&"(".repeat(60000)));
+        assert_eq!(Some((6, 12, true)), literal_length("LOGIN {12}"));
+        assert_eq!(Some((6, 3, false)), literal_length("LOGIN {3+}"));
+    }
+
+    #[test]
+    fn test_sequence_set() {
+        assert_eq!(vec![ (1, 3), (5, 5), (7, 9) ], sequence_set("1:3,5,9:*", 7).unwrap());
+        assert!(sequence_set("1:x", 7).is_err());
+    }
+}
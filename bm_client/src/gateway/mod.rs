@@ -0,0 +1,347 @@
+mod imap;
+mod smtp;
+
+use BMClient;
+use config::Config;
+use rustc_serialize::base64::FromBase64;
+use self::imap::Uids;
+use server::{PolledListener,secrets_match};
+use std::io::{self,BufRead,BufReader,ErrorKind,Read,Write};
+use std::net::{SocketAddr,TcpStream};
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicUsize,Ordering};
+use std::thread::{Builder,JoinHandle};
+use std::time::{Duration,Instant};
+use stop::{StopSignal,join_until};
+
+// Lets ordinary mail clients use our identities: mail submitted over SMTP to BM-...@bitmessage
+// is sent as a Bitmessage msg, and the inbox and sent messages can be read over IMAP
+
+const READ_POLL_MILLIS: u64 = 500;
+const WRITE_TIMEOUT_SECS: u64 = 30;
+const SESSION_IDLE_SECS: u64 = 30 * 60;
+const MAX_SESSIONS: usize = 16;
+const MAX_LINE_LENGTH: usize = 65536;
+
+#[derive(Clone,Copy,PartialEq)]
+enum Protocol {
+    Smtp,
+    Imap
+}
+
+pub struct MailGateway {
+    config: Config,
+    client: Arc<Mutex<BMClient>>,
+    smtp_addr: Option<SocketAddr>,
+    imap_addr: Option<SocketAddr>,
+    stop_signal: StopSignal,
+    threads: Vec<JoinHandle<()>>
+}
+
+impl MailGateway {
+    pub fn new(config: &Config, client: Arc<Mutex<BMClient>>) -> MailGateway {
+        MailGateway {
+            config: config.clone(),
+            client: client,
+            smtp_addr: None,
+            imap_addr: None,
+            stop_signal: StopSignal::new(),
+            threads: vec![]
+        }
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        if !self.threads.is_empty() {
+            return Ok(());
+        }
+
+        let smtp_listener = try!(PolledListener::bind((self.config.gateway_listen_addr(), self.config.smtp_port())));
+        let imap_listener = try!(PolledListener::bind((self.config.gateway_listen_addr(), self.config.imap_port())));
+        self.smtp_addr = Some(try!(smtp_listener.local_addr()));
+        self.imap_addr = Some(try!(imap_listener.local_addr()));
+
+        let stop_signal = StopSignal::new();
+        self.stop_signal = stop_signal.clone();
+        let credentials = Arc::new((self.config.gateway_username().to_string(), self.config.gateway_password().to_string()));
+        let uids = Arc::new(Mutex::new(Uids::new()));
+        let sessions = Arc::new(AtomicUsize::new(0));
+
+        for (listener, protocol) in vec![ (smtp_listener, Protocol::Smtp), (imap_listener, Protocol::Imap) ] {
+            let (stop_signal, credentials, client, uids, sessions) = (stop_signal.clone(), credentials.clone(), self.client.clone(), uids.clone(), sessions.clone());
+            let name = if protocol == Protocol::Smtp { "SMTP Gateway" } else { "IMAP Gateway" };
+
+            let thread = try!(Builder::new().name(name.to_string()).spawn(move || {
+                listener.accept_until_stopped(&stop_signal, |stream| {
+                    if sessions.load(Ordering::SeqCst) >= MAX_SESSIONS {
+                        return;
+                    }
+
+                    // Sessions last as long as the mail client likes, so each gets its own thread
+                    sessions.fetch_add(1, Ordering::SeqCst);
+                    let (stop_signal, credentials, client, uids, open) = (stop_signal.clone(), credentials.clone(), client.clone(), uids.clone(), sessions.clone());
+                    let spawned = Builder::new().name(format!("{} Session", name)).spawn(move || {
+                        let _slot = SessionSlot { open: open };
+                        let _ = serve(stream, protocol, &stop_signal, &credentials, &client, &uids);
+                    });
+                    if spawned.is_err() {
+                        sessions.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }));
+            self.threads.push(thread);
+        }
+
+        Ok(())
+    }
+
+    // Open sessions notice the stop the next time they wait for the mail client
+    pub fn stop(&mut self, deadline: Instant) {
+        self.stop_signal.stop(deadline);
+        for thread in self.threads.drain(..) {
+            join_until(thread, deadline);
+        }
+        self.smtp_addr = None;
+        self.imap_addr = None;
+    }
+
+    // Where the gateway is listening while running
+    pub fn smtp_addr(&self) -> Option<SocketAddr> {
+        self.smtp_addr
+    }
+
+    pub fn imap_addr(&self) -> Option<SocketAddr> {
+        self.imap_addr
+    }
+}
+
+impl Drop for MailGateway {
+    fn drop(&mut self) {
+        self.stop(Instant::now() + Duration::from_secs(1));
+    }
+}
+
+// Gives the session's place back however the session ends, even by panicking
+struct SessionSlot {
+    open: Arc<AtomicUsize>
+}
+
+impl Drop for SessionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve(stream: TcpStream, protocol: Protocol, stop_signal: &StopSignal, credentials: &(String, String), client: &Arc<Mutex<BMClient>>, uids: &Arc<Mutex<Uids>>) -> io::Result<()> {
+    try!(stream.set_read_timeout(Some(Duration::from_millis(READ_POLL_MILLIS))));
+    try!(stream.set_write_timeout(Some(Duration::from_secs(WRITE_TIMEOUT_SECS))));
+    let mut writer = try!(stream.try_clone());
+    let mut lines = Lines::new(stream, stop_signal);
+
+    match protocol {
+        Protocol::Smtp => smtp::serve(&mut lines, &mut writer, credentials, client),
+        Protocol::Imap => imap::serve(&mut lines, &mut writer, credentials, client, uids)
+    }
+}
+
+// Reads what the mail client sends, giving up when the gateway stops or the client goes quiet
+pub struct Lines<R> {
+    reader: BufReader<R>,
+    stop_signal: StopSignal
+}
+
+impl<R: Read> Lines<R> {
+    pub fn new(reader: R, stop_signal: &StopSignal) -> Lines<R> {
+        Lines {
+            reader: BufReader::new(reader),
+            stop_signal: stop_signal.clone()
+        }
+    }
+
+    // The line without its line ending, or None once there's nothing more to read
+    pub fn next_line(&mut self) -> io::Result<Option<String>> {
+        let idle_until = Instant::now() + Duration::from_secs(SESSION_IDLE_SECS);
+        let mut line = vec![];
+        loop {
+            match (&mut self.reader).take((MAX_LINE_LENGTH - line.len()) as u64).read_until(b'\n', &mut line) {
+                Ok(0) if line.is_empty() => return Ok(None),
+                Ok(_) if line.ends_with(b"\n") || line.len() < MAX_LINE_LENGTH => {
+                    while line.ends_with(b"\n") || line.ends_with(b"\r") {
+                        line.pop();
+                    }
+                    return Ok(Some(String::from_utf8_lossy(&line).into_owned()));
+                },
+                Ok(_) => return Err(io::Error::new(ErrorKind::InvalidData, "line too long")),
+                Err(ref err) if is_timeout(err) => if self.stop_signal.is_stopped() || Instant::now() >= idle_until {
+                    return Ok(None);
+                },
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0u8; length];
+        let mut filled = 0;
+        while filled < length {
+            match self.reader.read(&mut bytes[filled..]) {
+                Ok(0) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed")),
+                Ok(count) => filled += count,
+                Err(ref err) if is_timeout(err) => if self.stop_signal.is_stopped() {
+                    return Err(io::Error::new(ErrorKind::Interrupted, "gateway stopped"));
+                },
+                Err(err) => return Err(err)
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+    err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut
+}
+
+fn credentials_match(username: &str, password: &str, credentials: &(String, String)) -> bool {
+    secrets_match(format!("{}\0{}", username, password).as_bytes(), format!("{}\0{}", credentials.0, credentials.1).as_bytes())
+}
+
+// The username and password from a SASL PLAIN response, which also starts with an authorization identity
+fn plain_credentials(response: &str) -> (String, String) {
+    let decoded = response.trim().from_base64().map(|bytes| String::from_utf8_lossy(&bytes).into_owned()).unwrap_or(String::new());
+    let mut fields = decoded.splitn(3, '\0').skip(1);
+    (fields.next().unwrap_or("").to_string(), fields.next().unwrap_or("").to_string())
+}
+
+fn write_line<W: Write>(writer: &mut W, line: &str) -> io::Result<()> {
+    try!(writer.write_all(line.as_bytes()));
+    try!(writer.write_all(b"\r\n"));
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use BMClient;
+    use config::Config;
+    use inbox::InboxMessage;
+    use msgcoding::MessageEncoding;
+    use std::io::{BufRead,BufReader,Write};
+    use std::net::{SocketAddr,TcpStream};
+    use std::sync::{Arc,Mutex};
+    use std::sync::atomic::{AtomicUsize,Ordering};
+    use std::thread;
+    use super::{MailGateway,SessionSlot};
+
+    struct Conversation {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream
+    }
+
+    impl Conversation {
+        fn open(addr: SocketAddr) -> Conversation {
+            let stream = TcpStream::connect(addr).unwrap();
+            Conversation {
+                writer: stream.try_clone().unwrap(),
+                reader: BufReader::new(stream)
+            }
+        }
+
+        // Everything the gateway says up to the line starting with the prefix
+        fn until(&mut self, prefix: &str) -> String {
+            let mut response = String::new();
+            loop {
+                let mut line = String::new();
+                assert!(self.reader.read_line(&mut line).unwrap() > 0, "connection closed waiting for {}", prefix);
+                response.push_str(&line);
+                if line.starts_with(prefix) {
+                    return response;
+                }
+            }
+        }
+
+        fn send(&mut self, line: &str, prefix: &str) -> String {
+            write!(self.writer, "{}\r\n", line).unwrap();
+            self.until(prefix)
+        }
+    }
+
+    #[test]
+    fn test_mail_clients() {
+        let config = Config::builder().seed_nodes(&[]).dns_seeds(&[]).gateway_enabled(true).gateway_ports(0, 0)
+            .gateway_credentials("mail", "secret").build().unwrap();
+        let client = Arc::new(Mutex::new(BMClient::with_config(config.clone()).unwrap()));
+        let (ours, theirs) = {
+            let mut client = client.lock().unwrap();
+            (client.create_identity("Me"), client.create_identity("Friend"))
+        };
+        let mut gateway = MailGateway::new(&config, client.clone());
+        gateway.start().unwrap();
+
+        let mut smtp = Conversation::open(gateway.smtp_addr().unwrap());
+        smtp.until("220 ");
+        assert!(smtp.send("EHLO test", "250 ").contains("AUTH PLAIN LOGIN"));
+        assert!(smtp.send(&format!("MAIL FROM:<{}@bitmessage>", ours), "5").starts_with("530 "));
+        assert!(smtp.send("AUTH PLAIN AG1haWwAd3Jvbmc=", "5").starts_with("535 "));
+        assert!(smtp.send("AUTH PLAIN AG1haWwAc2VjcmV0", "2").starts_with("235 "));
+        assert!(smtp.send("MAIL FRO\u{FFFD}:<x>", "5").starts_with("501 "));
+        assert!(smtp.send("MAIL FROM:<BM-2cXxfcSetKnbHJX2Y85rSkaVpsdNUZ5q9h@bitmessage>", "5").starts_with("553 "));
+        assert!(smtp.send(&format!("MAIL FROM:<{}@bitmessage>", ours), "2").starts_with("250 "));
+        assert!(smtp.send("RCPT TO:<someone@example.com>", "5").starts_with("550 "));
+        assert!(smtp.send(&format!("RCPT TO:<{}@Bitmessage>", theirs), "2").starts_with("250 "));
+        assert!(smtp.send("DATA", "3").starts_with("354 "));
+        smtp.send(&format!("From: {}@bitmessage\r\nSubject: Lunch\r\n\r\nPizza?\r\n..and more\r\n.", ours), "250 Queued as ");
+
+        // Too long to send, so it goes to neither recipient
+        smtp.send(&format!("MAIL FROM:<{}@bitmessage>", ours), "250 ");
+        smtp.send(&format!("RCPT TO:<{}@bitmessage>", theirs), "250 ");
+        smtp.send(&format!("RCPT TO:<{}@bitmessage>", ours), "250 ");
+        smtp.send("DATA", "354 ");
+        let line = "x".repeat(60000);
+        assert!(smtp.send(&format!("Subject: Long\r\n\r\n{0}\r\n{0}\r\n{0}\r\n{0}\r\n{0}\r\n.", line), "5").starts_with("554 "));
+        smtp.send("QUIT", "221 ");
+
+        let sent = client.lock().unwrap().outbox_messages();
+        assert_eq!(1, sent.len());
+        assert_eq!((&ours, Some(&theirs), "Lunch", "Pizza?\n.and more", MessageEncoding::Simple),
+            (sent[0].from(), sent[0].to(), sent[0].subject(), sent[0].body(), sent[0].encoding()));
+
+        client.lock().unwrap().inbox.add(&InboxMessage::new(&[ 1; 32 ], &theirs, Some(&ours), "Re: Lunch", "Sure"));
+
+        let mut imap = Conversation::open(gateway.imap_addr().unwrap());
+        imap.until("* OK ");
+        assert!(imap.send("a1 SELECT INBOX", "a1 ").contains("a1 NO "));
+        assert!(imap.send("a2 LOGIN mail {6}", "+ ").starts_with("+ "));
+        assert!(imap.send("secret", "a2 ").contains("a2 OK "));
+        let response = imap.send("a3 LIST \"\" *", "a3 ");
+        assert!(response.contains("* LIST (\\HasNoChildren) \"/\" \"INBOX\"\r\n") && response.contains("\"Sent\"\r\n"));
+
+        let response = imap.send("a4 SELECT INBOX", "a4 ");
+        assert!(response.contains("* 1 EXISTS\r\n") && response.contains("a4 OK [READ-WRITE]"));
+        assert!(imap.send(&format!("b1 NOOP {}", "(".repeat(60000)), "b1 ").starts_with("b1 BAD "));
+        let response = imap.send("a5 FETCH 1 (ENVELOPE BODY[TEXT])", "a5 ");
+        assert!(response.starts_with("* 1 FETCH (ENVELOPE (\""));
+        assert!(response.contains(&format!(" \"Re: Lunch\" ((NIL NIL \"{}\" \"bitmessage\"))", theirs)));
+        assert!(response.contains("BODY[TEXT] {6}\r\nSure\r\n FLAGS (\\Seen))\r\n"));
+        assert!(client.lock().unwrap().inbox_message(&[ 1; 32 ]).unwrap().is_read());
+
+        imap.send("a6 SELECT Sent", "a6 OK ");
+        let response = imap.send("a7 UID FETCH 1:* (BODY.PEEK[HEADER.FIELDS (SUBJECT)])", "a7 ");
+        assert!(response.contains("BODY[HEADER.FIELDS (SUBJECT)] {18}\r\nSubject: Lunch\r\n\r\n)"), "{}", response);
+        let response = imap.send("b2 UID FETCH 1:* (BODY.PEEK[TEXT]<1.18446744073709551615>)", "b2 ");
+        assert!(response.contains("BODY[TEXT]<1> {") && response.contains("b2 OK "), "{}", response);
+        assert!(imap.send("a8 SEARCH UNDELETED BODY pizza", "a8 ").starts_with("* SEARCH 1\r\n"));
+        assert!(imap.send("a9 STORE 1 +FLAGS (\\Deleted)", "a9 ").starts_with("* 1 FETCH (FLAGS (\\Seen \\Deleted))"));
+        assert!(imap.send("a10 EXPUNGE", "a10 ").starts_with("* 1 EXPUNGE\r\n"));
+        assert!(client.lock().unwrap().outbox_messages()[0].is_trashed());
+        assert!(imap.send("a11 LOGOUT", "a11 ").starts_with("* BYE"));
+    }
+
+    #[test]
+    fn test_session_slot_is_given_back_when_the_session_panics() {
+        let open = Arc::new(AtomicUsize::new(1));
+        let slot_open = open.clone();
+        assert!(thread::spawn(move || {
+            let _slot = SessionSlot { open: slot_open };
+            panic!("session failed");
+        }).join().is_err());
+        assert_eq!(0, open.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,195 @@
+use {BMClient,SendOptions};
+use address::Address;
+use mail::{parse_mail,parse_mail_address};
+use rustc_serialize::base64::{FromBase64,STANDARD,ToBase64};
+use rustc_serialize::hex::ToHex;
+use std::io::{self,Read,Write};
+use std::sync::{Arc,Mutex};
+use super::{Lines,credentials_match,plain_credentials,write_line};
+
+// Message submission (RFC 6409), only as far as mail clients need it. Every message is
+// sent on as a msg to each of its recipients, with the subject and text body.
+
+const MAX_RECIPIENTS: usize = 100;
+
+pub fn serve<R: Read, W: Write>(lines: &mut Lines<R>, writer: &mut W, credentials: &(String, String), client: &Arc<Mutex<BMClient>>) -> io::Result<()> {
+    let mut session = Session {
+        authenticated: false,
+        from: None,
+        recipients: vec![]
+    };
+
+    try!(write_line(writer, "220 localhost Rubbem mail gateway ready"));
+    while let Some(line) = try!(lines.next_line()) {
+        let (verb, argument) = match line.find(' ') {
+            Some(space) => (line[..space].to_uppercase(), line[space + 1..].trim().to_string()),
+            None => (line.trim().to_uppercase(), String::new())
+        };
+
+        match &verb[..] {
+            "EHLO" => try!(write_line(writer, "250-localhost\r\n250-AUTH PLAIN LOGIN\r\n250 8BITMIME")),
+            "HELO" => try!(write_line(writer, "250 localhost")),
+            "AUTH" => try!(session.authenticate(lines, writer, &argument, credentials)),
+            "MAIL" if !session.authenticated => try!(write_line(writer, "530 Authentication required")),
+            "MAIL" => try!(session.mail_from(writer, &argument, client)),
+            "RCPT" if session.from.is_none() => try!(write_line(writer, "503 MAIL first")),
+            "RCPT" => try!(session.recipient(writer, &argument)),
+            "DATA" if session.recipients.is_empty() => try!(write_line(writer, "503 RCPT first")),
+            "DATA" => try!(session.data(lines, writer, client)),
+            "RSET" => {
+                session.reset();
+                try!(write_line(writer, "250 OK"));
+            },
+            "NOOP" => try!(write_line(writer, "250 OK")),
+            "VRFY" => try!(write_line(writer, "252 Cannot verify, but will try")),
+            "QUIT" => return write_line(writer, "221 Bye"),
+            _ => try!(write_line(writer, "502 Command not implemented"))
+        }
+    }
+    Ok(())
+}
+
+struct Session {
+    authenticated: bool,
+    from: Option<Address>,
+    recipients: Vec<Address>
+}
+
+impl Session {
+    fn reset(&mut self) {
+        self.from = None;
+        self.recipients.clear();
+    }
+
+    fn authenticate<R: Read, W: Write>(&mut self, lines: &mut Lines<R>, writer: &mut W, argument: &str, credentials: &(String, String)) -> io::Result<()> {
+        if self.authenticated {
+            return write_line(writer, "503 Already authenticated");
+        }
+
+        let mut parts = argument.splitn(2, ' ');
+        let mechanism = parts.next().unwrap_or("").to_uppercase();
+        let initial = parts.next().map(|initial| initial.trim().to_string());
+        let (username, password) = match &mechanism[..] {
+            "PLAIN" => {
+                let response = match initial {
+                    Some(response) => response,
+                    None => {
+                        try!(write_line(writer, "334 "));
+                        try!(lines.next_line()).unwrap_or(String::new())
+                    }
+                };
+                plain_credentials(&response)
+            },
+            "LOGIN" => {
+                let username = match initial {
+                    Some(username) => decode(&username),
+                    None => {
+                        try!(write_line(writer, &format!("334 {}", b"Username:".to_base64(STANDARD))));
+                        decode(&try!(lines.next_line()).unwrap_or(String::new()))
+                    }
+                };
+                try!(write_line(writer, &format!("334 {}", b"Password:".to_base64(STANDARD))));
+                (username, decode(&try!(lines.next_line()).unwrap_or(String::new())))
+            },
+            _ => return write_line(writer, "504 Unrecognized authentication type")
+        };
+
+        if credentials_match(&username, &password, credentials) {
+            self.authenticated = true;
+            write_line(writer, "235 Authentication successful")
+        } else {
+            write_line(writer, "535 Authentication credentials invalid")
+        }
+    }
+
+    fn mail_from<W: Write>(&mut self, writer: &mut W, argument: &str, client: &Arc<Mutex<BMClient>>) -> io::Result<()> {
+        let address = match path(argument, "FROM:") {
+            Some(path) => parse_mail_address(&path),
+            None => return write_line(writer, "501 Syntax: MAIL FROM:<address>")
+        };
+        let address = match address {
+            Some(address) => address,
+            None => return write_line(writer, "553 Send from a BM-...@bitmessage address")
+        };
+
+        let ours = {
+            let client = client.lock().unwrap();
+            client.identities().iter().chain(client.chans().iter()).any(|identity| identity.address() == &address)
+        };
+        if !ours {
+            return write_line(writer, &format!("553 {} is not one of our identities", address));
+        }
+
+        self.reset();
+        self.from = Some(address);
+        write_line(writer, "250 OK")
+    }
+
+    fn recipient<W: Write>(&mut self, writer: &mut W, argument: &str) -> io::Result<()> {
+        let address = match path(argument, "TO:") {
+            Some(path) => parse_mail_address(&path),
+            None => return write_line(writer, "501 Syntax: RCPT TO:<address>")
+        };
+        match address {
+            Some(_) if self.recipients.len() >= MAX_RECIPIENTS => write_line(writer, "452 Too many recipients"),
+            Some(address) => {
+                if !self.recipients.contains(&address) {
+                    self.recipients.push(address);
+                }
+                write_line(writer, "250 OK")
+            },
+            None => write_line(writer, "550 Only BM-...@bitmessage addresses can be reached")
+        }
+    }
+
+    fn data<R: Read, W: Write>(&mut self, lines: &mut Lines<R>, writer: &mut W, client: &Arc<Mutex<BMClient>>) -> io::Result<()> {
+        try!(write_line(writer, "354 End data with <CR><LF>.<CR><LF>"));
+        let mut data = String::new();
+        loop {
+            let line = match try!(lines.next_line()) {
+                Some(line) => line,
+                None => return Ok(())
+            };
+            if line == "." {
+                break;
+            }
+            // Dots at the start of a line were doubled by the client
+            data.push_str(if line.starts_with('.') { &line[1..] } else { &line });
+            data.push('\n');
+        }
+
+        let mail = parse_mail(&data);
+        let from = self.from.take().unwrap();
+        let recipients: Vec<Address> = self.recipients.drain(..).collect();
+        let queued: Vec<String> = {
+            let mut client = client.lock().unwrap();
+            // Checked once for all the recipients, so a failure never leaves some of them queued
+            // and the mail client sending to them again when it retries
+            if let Err(err) = client.check_sendable(&from, &mail.subject, &mail.body) {
+                return write_line(writer, &format!("554 {}", err));
+            }
+            recipients.iter()
+                .filter_map(|to| client.send_message(&from, to, &mail.subject, &mail.body, SendOptions::new()).ok())
+                .map(|ackdata| ackdata.to_hex())
+                .collect()
+        };
+        write_line(writer, &format!("250 Queued as {}", queued.join(" ")))
+    }
+}
+
+// The address out of FROM:<address> or TO:<address>, ignoring any parameters after it
+fn path(argument: &str, prefix: &str) -> Option<String> {
+    // Lines can hold any character, so the prefix's end may not fall on a character boundary
+    if !argument.get(..prefix.len()).map_or(false, |start| start.eq_ignore_ascii_case(prefix)) {
+        return None;
+    }
+    let rest = argument[prefix.len()..].trim();
+    match (rest.find('<'), rest.find('>')) {
+        (Some(start), Some(end)) if start < end => Some(rest[start + 1..end].to_string()),
+        _ => rest.split(' ').next().map(|path| path.to_string())
+    }
+}
+
+fn decode(text: &str) -> String {
+    text.trim().from_base64().map(|bytes| String::from_utf8_lossy(&bytes).into_owned()).unwrap_or(String::new())
+}
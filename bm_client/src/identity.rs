@@ -0,0 +1,405 @@
+use address::Address;
+use checksum::sha512_hash;
+use disk::{address_from_json,address_to_json,bytes_from_json,bytes_to_json,string_from_json};
+use ecies;
+use keys::{is_valid_private_key,private_key_to_wif,public_key,random_private_key,ripe,sign};
+use message::{Broadcast,BroadcastContent,NETWORK_EXTRA_BYTES,NETWORK_TRIALS_PER_BYTE,OBJECT_BROADCAST,OBJECT_PUBKEY,Object,ObjectData,PubKey,PubKeyContent};
+use message::{write_broadcast_content,write_object_header,write_pubkey_content,write_unsigned_broadcast_content,write_unsigned_pubkey_content,write_var_int_64};
+use persist::Persister;
+use rustc_serialize::json::{Json,ToJson};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+// The recipient will send an acknowledgement for each message
+pub const BEHAVIOUR_DOES_ACK: u32 = 1;
+
+const ADDRESS_VERSION: u64 = 4;
+
+// One of our own addresses, with the private keys needed to receive and sign for it
+#[derive(Clone,Debug,PartialEq)]
+pub struct Identity {
+    label: String,
+    address: Address,
+    private_signing_key: [u8; 32],
+    private_encryption_key: [u8; 32],
+    nonce_trials_per_byte: u64,
+    extra_bytes: u64,
+    enabled: bool,
+    chan: bool // shared by everyone who knows the passphrase
+}
+
+impl Identity {
+    // Keeps generating encryption keys until the ripe starts with a zero byte, which makes the address shorter
+    pub fn random(label: &str, stream: u32) -> Identity {
+        let private_signing_key = random_private_key();
+        let public_signing_key = public_key(&private_signing_key);
+        loop {
+            let private_encryption_key = random_private_key();
+            let ripe = ripe(&public_signing_key, &public_key(&private_encryption_key));
+            if ripe[0] == 0 {
+                let address = Address::new(ADDRESS_VERSION, stream, &ripe);
+                return Identity::new(label, address, private_signing_key, private_encryption_key);
+            }
+        }
+    }
+
+    // The same passphrase always gives the same keys, as in PyBitmessage: alternate nonces for the signing
+    // and encryption keys, until the ripe starts with a zero byte
+    pub fn deterministic(label: &str, passphrase: &str, stream: u32) -> Identity {
+        let mut nonce = 0;
+        loop {
+            let private_signing_key = deterministic_private_key(passphrase, nonce);
+            let private_encryption_key = deterministic_private_key(passphrase, nonce + 1);
+            nonce += 2;
+            if !is_valid_private_key(&private_signing_key) || !is_valid_private_key(&private_encryption_key) {
+                continue;
+            }
+
+            let ripe = ripe(&public_key(&private_signing_key), &public_key(&private_encryption_key));
+            if ripe[0] == 0 {
+                let address = Address::new(ADDRESS_VERSION, stream, &ripe);
+                return Identity::new(label, address, private_signing_key, private_encryption_key);
+            }
+        }
+    }
+
+    // A deterministic address that everyone who knows the passphrase shares
+    pub fn chan(passphrase: &str, stream: u32) -> Identity {
+        let mut identity = Identity::deterministic(&format!("[chan] {}", passphrase), passphrase, stream);
+        identity.chan = true;
+        identity
+    }
+
+    // Only if the keys really belong to the address, so that a mistyped key can't take over an address
+    pub fn from_private_keys(label: &str, address: &Address, private_signing_key: &[u8], private_encryption_key: &[u8]) -> Option<Identity> {
+        let private_signing_key = return_none_on_none!(to_private_key(private_signing_key));
+        let private_encryption_key = return_none_on_none!(to_private_key(private_encryption_key));
+        if ripe(&public_key(&private_signing_key), &public_key(&private_encryption_key)) != address.ripe() {
+            return None;
+        }
+        Some(Identity::new(label, address.clone(), private_signing_key, private_encryption_key))
+    }
+
+    pub fn from_json(json: &Json) -> Option<Identity> {
+        let address = return_none_on_none!(address_from_json(json.find("address")));
+        let mut identity = return_none_on_none!(Identity::from_private_keys(
+            &return_none_on_none!(string_from_json(json.find("label"))),
+            &address,
+            &return_none_on_none!(bytes_from_json(json.find("private_signing_key"))),
+            &return_none_on_none!(bytes_from_json(json.find("private_encryption_key")))));
+        identity.nonce_trials_per_byte = return_none_on_none!(json.find("nonce_trials_per_byte").and_then(|trials| trials.as_u64()));
+        identity.extra_bytes = return_none_on_none!(json.find("extra_bytes").and_then(|extra| extra.as_u64()));
+        identity.enabled = return_none_on_none!(json.find("enabled").and_then(|enabled| enabled.as_boolean()));
+        identity.chan = return_none_on_none!(json.find("chan").and_then(|chan| chan.as_boolean()));
+        Some(identity)
+    }
+
+    // With the settings that came with the keys, as when they're imported from elsewhere
+    pub fn restored(mut self, enabled: bool, chan: bool, nonce_trials_per_byte: u64, extra_bytes: u64) -> Identity {
+        self.enabled = enabled;
+        self.chan = chan;
+        self.nonce_trials_per_byte = nonce_trials_per_byte;
+        self.extra_bytes = extra_bytes;
+        self
+    }
+
+    fn new(label: &str, address: Address, private_signing_key: [u8; 32], private_encryption_key: [u8; 32]) -> Identity {
+        Identity {
+            label: label.to_string(),
+            address: address,
+            private_signing_key: private_signing_key,
+            private_encryption_key: private_encryption_key,
+            nonce_trials_per_byte: NETWORK_TRIALS_PER_BYTE,
+            extra_bytes: NETWORK_EXTRA_BYTES,
+            enabled: true,
+            chan: false
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    pub fn private_signing_key(&self) -> &[u8; 32] {
+        &self.private_signing_key
+    }
+
+    pub fn private_encryption_key(&self) -> &[u8; 32] {
+        &self.private_encryption_key
+    }
+
+    // As PyBitmessage writes them in keys.dat
+    pub fn private_signing_key_wif(&self) -> String {
+        private_key_to_wif(&self.private_signing_key)
+    }
+
+    pub fn private_encryption_key_wif(&self) -> String {
+        private_key_to_wif(&self.private_encryption_key)
+    }
+
+    pub fn public_signing_key(&self) -> Vec<u8> {
+        public_key(&self.private_signing_key)
+    }
+
+    pub fn public_encryption_key(&self) -> Vec<u8> {
+        public_key(&self.private_encryption_key)
+    }
+
+    pub fn nonce_trials_per_byte(&self) -> u64 {
+        self.nonce_trials_per_byte
+    }
+
+    pub fn extra_bytes(&self) -> u64 {
+        self.extra_bytes
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_chan(&self) -> bool {
+        self.chan
+    }
+
+    // Nobody acks messages to a chan, so senders shouldn't wait for one
+    pub fn behaviour_bitfield(&self) -> u32 {
+        if self.chan { 0 } else { BEHAVIOUR_DOES_ACK }
+    }
+
+    // A signed v4 pubkey, encrypted so that only those who know the address can read it
+    pub fn pubkey_object_data(&self, expiry: SystemTime) -> ObjectData {
+        let mut content = PubKeyContent {
+            behaviour_bitfield: self.behaviour_bitfield(),
+            public_signing_key: self.public_signing_key(),
+            public_encryption_key: self.public_encryption_key(),
+            nonce_trials_per_byte: self.nonce_trials_per_byte,
+            extra_bytes: self.extra_bytes,
+            signature: vec![]
+        };
+        let tag = self.address.tag();
+
+        let mut signed = vec![];
+        write_object_header(&mut signed, &expiry, OBJECT_PUBKEY, self.address.version(), self.address.stream());
+        signed.extend(&tag);
+        write_unsigned_pubkey_content(&mut signed, &content);
+        content.signature = sign(&self.private_signing_key, &signed);
+
+        let mut plaintext = vec![];
+        write_pubkey_content(&mut plaintext, &content);
+
+        let tag_public_key = public_key(&self.address.tag_private_key());
+        let encrypted = ecies::encrypt(&tag_public_key, &plaintext).expect("tag keys are always valid");
+
+        ObjectData::new(expiry, self.address.version(), self.address.stream(), Object::PubKey(PubKey::V4 {
+            tag: tag,
+            encrypted: encrypted
+        }))
+    }
+
+    // A signed broadcast that anyone who knows the address can decrypt; v4 addresses send v5 broadcasts, with a tag
+    pub fn broadcast_object_data(&self, expiry: SystemTime, encoding: u64, message: Vec<u8>) -> ObjectData {
+        let mut content = BroadcastContent {
+            address_version: self.address.version(),
+            stream: self.address.stream(),
+            behaviour_bitfield: self.behaviour_bitfield(),
+            public_signing_key: self.public_signing_key(),
+            public_encryption_key: self.public_encryption_key(),
+            nonce_trials_per_byte: self.nonce_trials_per_byte,
+            extra_bytes: self.extra_bytes,
+            encoding: encoding,
+            message: message,
+            signature: vec![]
+        };
+        let tag = match self.address.version() {
+            4 => Some(self.address.tag()),
+            _ => None
+        };
+        let version = if tag.is_some() { 5 } else { 4 };
+
+        let mut signed = vec![];
+        write_object_header(&mut signed, &expiry, OBJECT_BROADCAST, version, self.address.stream());
+        if let Some(ref tag) = tag {
+            signed.extend(tag);
+        }
+        write_unsigned_broadcast_content(&mut signed, &content);
+        content.signature = sign(&self.private_signing_key, &signed);
+
+        let mut plaintext = vec![];
+        write_broadcast_content(&mut plaintext, &content);
+
+        let broadcast_public_key = public_key(&self.address.broadcast_private_key());
+        let encrypted = ecies::encrypt(&broadcast_public_key, &plaintext).expect("broadcast keys are always valid");
+
+        let broadcast = match tag {
+            Some(tag) => Broadcast::V5 { tag: tag, encrypted: encrypted },
+            None => Broadcast::V4 { encrypted: encrypted }
+        };
+        ObjectData::new(expiry, version, self.address.stream(), Object::Broadcast(broadcast))
+    }
+}
+
+impl ToJson for Identity {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("label".to_string(), self.label.to_json());
+        object.insert("address".to_string(), address_to_json(&self.address));
+        object.insert("private_signing_key".to_string(), bytes_to_json(&self.private_signing_key));
+        object.insert("private_encryption_key".to_string(), bytes_to_json(&self.private_encryption_key));
+        object.insert("nonce_trials_per_byte".to_string(), self.nonce_trials_per_byte.to_json());
+        object.insert("extra_bytes".to_string(), self.extra_bytes.to_json());
+        object.insert("enabled".to_string(), self.enabled.to_json());
+        object.insert("chan".to_string(), self.chan.to_json());
+        Json::Object(object)
+    }
+}
+
+#[derive(Clone)]
+pub struct Identities {
+    persister: Persister
+}
+
+impl Identities {
+    pub fn new(persister: Persister) -> Identities {
+        Identities {
+            persister: persister
+        }
+    }
+
+    pub fn add(&mut self, identity: &Identity) {
+        if identity.is_chan() {
+            self.persister.add_chan(identity);
+        } else {
+            self.persister.add_identity(identity);
+        }
+    }
+
+    pub fn remove_chan(&mut self, address: &Address) -> bool {
+        self.persister.remove_chan(address)
+    }
+
+    // Chans as well as personal identities
+    pub fn list(&self) -> Vec<Identity> {
+        let mut identities = self.persister.get_identities();
+        identities.extend(self.persister.get_chans());
+        identities
+    }
+
+    pub fn personal(&self) -> Vec<Identity> {
+        self.persister.get_identities()
+    }
+
+    pub fn chans(&self) -> Vec<Identity> {
+        self.persister.get_chans()
+    }
+
+    pub fn get(&self, address: &Address) -> Option<Identity> {
+        self.list().into_iter().find(|identity| identity.address() == address)
+    }
+}
+
+fn to_private_key(bytes: &[u8]) -> Option<[u8; 32]> {
+    if bytes.len() != 32 {
+        return None;
+    }
+    let mut private_key = [0u8; 32];
+    private_key.copy_from_slice(bytes);
+    match is_valid_private_key(&private_key) {
+        true => Some(private_key),
+        false => None
+    }
+}
+
+fn deterministic_private_key(passphrase: &str, nonce: u64) -> [u8; 32] {
+    let mut input = passphrase.as_bytes().to_vec();
+    write_var_int_64(&mut input, nonce);
+
+    let mut private_key = [0u8; 32];
+    private_key.copy_from_slice(&sha512_hash(&input)[0..32]);
+    private_key
+}
+
+#[cfg(test)]
+mod tests {
+    use address::Address;
+    use ecies;
+    use message::{Object,PubKey,read_pubkey_content};
+    use persist::Persister;
+    use rustc_serialize::json::ToJson;
+    use std::str::FromStr;
+    use std::time::SystemTime;
+    use super::{Identities,Identity};
+
+    #[test]
+    fn test_random_identity() {
+        let identity = Identity::random("Test", 1);
+
+        assert_eq!(4, identity.address().version());
+        assert_eq!(1, identity.address().stream());
+        assert_eq!(0, identity.address().ripe()[0]);
+        assert!(identity.enabled());
+    }
+
+    #[test]
+    fn test_pubkey_object_can_be_read_with_the_address() {
+        let identity = Identity::random("Test", 1);
+        let object_data = identity.pubkey_object_data(SystemTime::now());
+
+        let (tag, encrypted) = match object_data.object() {
+            &Object::PubKey(PubKey::V4 { ref tag, ref encrypted }) => (tag.clone(), encrypted.clone()),
+            _ => panic!("Expected a v4 pubkey")
+        };
+        assert_eq!(identity.address().tag(), tag);
+
+        let plaintext = ecies::decrypt(&identity.address().tag_private_key(), &encrypted).unwrap();
+        let content = read_pubkey_content(&plaintext).unwrap();
+        assert_eq!(identity.public_encryption_key(), content.public_encryption_key);
+    }
+
+    #[test]
+    fn test_chan_matches_pybitmessage() {
+        let chan = Identity::chan("general", 1);
+
+        assert_eq!(Address::from_str("BM-2cW67GEKkHGonXKZLCzouLLxnLym3azS8r").unwrap(), *chan.address());
+        assert_eq!("[chan] general", chan.label());
+        assert!(chan.is_chan());
+        assert_eq!(0, chan.behaviour_bitfield());
+
+        let deterministic = Identity::deterministic("Mine", "general", 1);
+        assert_eq!(chan.address(), deterministic.address());
+        assert!(!deterministic.is_chan());
+    }
+
+    #[test]
+    fn test_keys_must_match_the_address() {
+        let identity = Identity::random("Test", 1);
+        let other = Identity::random("Other", 1);
+
+        let restored = Identity::from_private_keys("Test", identity.address(), identity.private_signing_key(), identity.private_encryption_key());
+        assert_eq!(Some(identity.clone()), restored);
+        assert_eq!(None, Identity::from_private_keys("Test", identity.address(), identity.private_signing_key(), other.private_encryption_key()));
+        assert_eq!(None, Identity::from_private_keys("Test", identity.address(), &[ 0; 32 ], identity.private_encryption_key()));
+
+        let chan = Identity::chan("general", 1);
+        assert_eq!(Some(chan.clone()), Identity::from_json(&chan.to_json()));
+    }
+
+    #[test]
+    fn test_chans_are_kept_apart() {
+        let mut identities = Identities::new(Persister::new());
+        let personal = Identity::random("Me", 1);
+        let chan = Identity::chan("general", 1);
+        identities.add(&personal);
+        identities.add(&chan);
+        identities.add(&chan);
+
+        assert_eq!(vec![ personal.clone() ], identities.personal());
+        assert_eq!(vec![ chan.clone() ], identities.chans());
+        assert_eq!(vec![ personal, chan.clone() ], identities.list());
+        assert!(identities.remove_chan(chan.address()));
+        assert!(identities.chans().is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+use address::Address;
+use disk::{address_from_json,address_to_json,bytes_from_json,bytes_to_json,string_from_json,time_from_json,time_to_json};
+use events::{Event,Events};
+use persist::Persister;
+use rustc_serialize::json::{Json,ToJson};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct InboxMessage {
+    msgid: Vec<u8>, // the inventory vector hash of the object it came in
+    from: Address,
+    to: Option<Address>, // None for broadcasts
+    subject: String,
+    body: String,
+    received: SystemTime,
+    read: bool,
+    trashed: bool
+}
+
+impl InboxMessage {
+    pub fn new(msgid: &[u8], from: &Address, to: Option<&Address>, subject: &str, body: &str) -> InboxMessage {
+        InboxMessage {
+            msgid: msgid.to_vec(),
+            from: from.clone(),
+            to: to.cloned(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            received: SystemTime::now(),
+            read: false,
+            trashed: false
+        }
+    }
+
+    // For a message brought back from an archive, rather than just received
+    pub fn restored(mut self, received: SystemTime, read: bool) -> InboxMessage {
+        self.received = received;
+        self.read = read;
+        self
+    }
+
+    pub fn from_json(json: &Json) -> Option<InboxMessage> {
+        Some(InboxMessage {
+            msgid: return_none_on_none!(bytes_from_json(json.find("msgid"))),
+            from: return_none_on_none!(address_from_json(json.find("from"))),
+            to: address_from_json(json.find("to")),
+            subject: return_none_on_none!(string_from_json(json.find("subject"))),
+            body: return_none_on_none!(string_from_json(json.find("body"))),
+            received: return_none_on_none!(time_from_json(json.find("received"))),
+            read: return_none_on_none!(json.find("read").and_then(|read| read.as_boolean())),
+            trashed: return_none_on_none!(json.find("trashed").and_then(|trashed| trashed.as_boolean()))
+        })
+    }
+
+    pub fn msgid(&self) -> &[u8] {
+        &self.msgid
+    }
+
+    pub fn from(&self) -> &Address {
+        &self.from
+    }
+
+    pub fn to(&self) -> Option<&Address> {
+        self.to.as_ref()
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.to.is_none()
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    pub fn received(&self) -> SystemTime {
+        self.received
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.read
+    }
+
+    pub fn is_trashed(&self) -> bool {
+        self.trashed
+    }
+}
+
+impl ToJson for InboxMessage {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("msgid".to_string(), bytes_to_json(&self.msgid));
+        object.insert("from".to_string(), address_to_json(&self.from));
+        if let Some(ref to) = self.to {
+            object.insert("to".to_string(), address_to_json(to));
+        }
+        object.insert("subject".to_string(), self.subject.to_json());
+        object.insert("body".to_string(), self.body.to_json());
+        object.insert("received".to_string(), time_to_json(self.received));
+        object.insert("read".to_string(), self.read.to_json());
+        object.insert("trashed".to_string(), self.trashed.to_json());
+        Json::Object(object)
+    }
+}
+
+#[derive(Clone)]
+pub struct Inbox {
+    persister: Persister,
+    events: Events
+}
+
+impl Inbox {
+    pub fn new(persister: Persister, events: &Events) -> Inbox {
+        Inbox {
+            persister: persister,
+            events: events.clone()
+        }
+    }
+
+    // The same object can arrive more than once, but only the first is delivered
+    pub fn add(&mut self, inbox_message: &InboxMessage) -> bool {
+        if self.get(inbox_message.msgid()).is_some() {
+            return false;
+        }
+
+        self.persister.add_inbox_message(inbox_message);
+        self.events.emit(Event::InboxMessage { msgid: inbox_message.msgid().to_vec() });
+        true
+    }
+
+    pub fn get(&self, msgid: &[u8]) -> Option<InboxMessage> {
+        self.list().into_iter().find(|inbox_message| inbox_message.msgid() == msgid)
+    }
+
+    pub fn list(&self) -> Vec<InboxMessage> {
+        self.persister.get_inbox_messages()
+    }
+
+    pub fn set_read(&mut self, msgid: &[u8], read: bool) -> bool {
+        self.persister.update_inbox_message(msgid, |inbox_message| inbox_message.read = read)
+    }
+
+    pub fn set_trashed(&mut self, msgid: &[u8], trashed: bool) -> bool {
+        self.persister.update_inbox_message(msgid, |inbox_message| inbox_message.trashed = trashed)
+    }
+
+    pub fn remove(&mut self, msgid: &[u8]) -> bool {
+        self.persister.remove_inbox_message(msgid)
+    }
+}
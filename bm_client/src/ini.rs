@@ -0,0 +1,122 @@
+use std::error::Error;
+use std::fmt;
+
+// A small reader for INI-style files, which also accepts the simple subset of TOML
+// that our own config files use (quoted strings, bare numbers/booleans and flat lists).
+
+#[derive(Debug,PartialEq)]
+pub struct IniError {
+    pub line: usize,
+    pub reason: &'static str
+}
+
+impl fmt::Display for IniError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl Error for IniError {}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct IniEntry {
+    pub section: String,
+    pub key: String,
+    pub value: String,
+    pub line: usize
+}
+
+pub fn parse_ini(contents: &str) -> Result<Vec<IniEntry>,IniError> {
+    let mut section = String::new();
+    let mut entries = vec![];
+
+    for (index, raw_line) in contents.lines().enumerate() {
+        let line_number = index + 1;
+        let line = raw_line.trim();
+
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') {
+            if !line.ends_with(']') || line.len() < 3 {
+                return Err(IniError { line: line_number, reason: "malformed section header" });
+            }
+            section = line[1..line.len() - 1].trim().to_string();
+            continue;
+        }
+
+        let separator = match line.find('=') {
+            Some(separator) => separator,
+            None => return Err(IniError { line: line_number, reason: "expected 'key = value'" })
+        };
+
+        let key = line[..separator].trim();
+        if key.is_empty() {
+            return Err(IniError { line: line_number, reason: "missing key before '='" });
+        }
+
+        entries.push(IniEntry {
+            section: section.clone(),
+            key: key.to_string(),
+            value: unquote(line[separator + 1..].trim()).to_string(),
+            line: line_number
+        });
+    }
+
+    Ok(entries)
+}
+
+pub fn parse_list(value: &str) -> Vec<String> {
+    let trimmed = value.trim();
+    let inner = match trimmed.starts_with('[') && trimmed.ends_with(']') {
+        true => &trimmed[1..trimmed.len() - 1],
+        false => trimmed
+    };
+
+    inner.split(',')
+        .map(|item| unquote(item.trim()).to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+fn unquote(value: &str) -> &str {
+    let quoted = value.len() >= 2 &&
+        ((value.starts_with('"') && value.ends_with('"')) || (value.starts_with('\'') && value.ends_with('\'')));
+
+    match quoted {
+        true => &value[1..value.len() - 1],
+        false => value
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IniEntry,IniError,parse_ini,parse_list};
+
+    #[test]
+    fn test_parse_sections_and_values() {
+        let contents = "data_dir = \"/tmp/bm\"\n\n# comment\n[network]\nport = 8444\n; other comment\nproxy='127.0.0.1:9050'\n";
+        let entries = parse_ini(contents).unwrap();
+
+        assert_eq!(vec![
+            IniEntry { section: "".to_string(), key: "data_dir".to_string(), value: "/tmp/bm".to_string(), line: 1 },
+            IniEntry { section: "network".to_string(), key: "port".to_string(), value: "8444".to_string(), line: 5 },
+            IniEntry { section: "network".to_string(), key: "proxy".to_string(), value: "127.0.0.1:9050".to_string(), line: 7 }
+        ], entries);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Err(IniError { line: 2, reason: "expected 'key = value'" }), parse_ini("[a]\nnonsense\n"));
+        assert_eq!(Err(IniError { line: 1, reason: "malformed section header" }), parse_ini("[a\n"));
+        assert_eq!(Err(IniError { line: 1, reason: "missing key before '='" }), parse_ini(" = 3\n"));
+    }
+
+    #[test]
+    fn test_parse_list() {
+        assert_eq!(vec![ "1".to_string(), "2".to_string() ], parse_list("[ 1, 2 ]"));
+        assert_eq!(vec![ "a:1".to_string(), "b".to_string() ], parse_list("\"a:1\", 'b',"));
+        assert!(parse_list("[]").is_empty());
+    }
+}
@@ -1,23 +1,53 @@
 use checksum::sha512_hash;
-use message::{InventoryVector,Message,write_message};
+use events::{Event,Events};
+use message::{InventoryVector,Message,Object,write_message};
 use persist::{InventoryIterator,Persister};
+use std::sync::{Arc,Mutex};
+use std::sync::mpsc::{Receiver,Sender,channel};
 
 #[derive(Clone)]
 pub struct Inventory {
-    persister: Persister
+    persister: Persister,
+    events: Events,
+    watchers: Arc<Mutex<Vec<Sender<Message>>>>
 }
 
 impl Inventory {
-    pub fn new(persister: Persister) -> Inventory {
+    pub fn new(persister: Persister, events: &Events) -> Inventory {
         Inventory {
-            persister: persister.clone()
+            persister: persister.clone(),
+            events: events.clone(),
+            watchers: Arc::new(Mutex::new(vec![]))
         }
     }
 
+    // Each new object is sent to the receiver until it is dropped
+    pub fn watch(&self) -> Receiver<Message> {
+        let (tx, rx) = channel();
+        self.watchers.lock().unwrap().push(tx);
+        rx
+    }
+
     pub fn iterator(&self) -> InventoryIterator {
         self.persister.inventory_iterator()
     }
 
+    // Walks the whole inventory, so it takes a while when there are many objects
+    pub fn stats(&self) -> ObjectStats {
+        let mut stats = ObjectStats { getpubkeys: 0, pubkeys: 0, msgs: 0, broadcasts: 0 };
+        for inventory_vector in self.iterator() {
+            if let Some(Message::Object(object_data)) = self.persister.get_object_message(&inventory_vector) {
+                match object_data.object() {
+                    &Object::GetPubKey(_) => stats.getpubkeys += 1,
+                    &Object::PubKey(_) => stats.pubkeys += 1,
+                    &Object::Msg { .. } => stats.msgs += 1,
+                    &Object::Broadcast(_) => stats.broadcasts += 1
+                }
+            }
+        }
+        stats
+    }
+
     pub fn unknown(&self, inventory_chunk: Vec<InventoryVector>) -> Vec<InventoryVector> {
         let mut unknown = Vec::with_capacity(inventory_chunk.len());
 
@@ -38,7 +68,45 @@ impl Inventory {
 
     pub fn add_object_message(&mut self, object_message: &Message) {
         let inventory_vector = calculate_inventory_vector(object_message);
+        if self.persister.get_object_message(&inventory_vector).is_some() {
+            return;
+        }
+
         self.persister.add_object_message(&inventory_vector, object_message);
+        self.events.emit(Event::ObjectCount(self.persister.object_count()));
+
+        let mut watchers = self.watchers.lock().unwrap();
+        watchers.retain(|watcher| watcher.send(object_message.clone()).is_ok());
+    }
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct ObjectStats {
+    getpubkeys: usize,
+    pubkeys: usize,
+    msgs: usize,
+    broadcasts: usize
+}
+
+impl ObjectStats {
+    pub fn getpubkeys(&self) -> usize {
+        self.getpubkeys
+    }
+
+    pub fn pubkeys(&self) -> usize {
+        self.pubkeys
+    }
+
+    pub fn msgs(&self) -> usize {
+        self.msgs
+    }
+
+    pub fn broadcasts(&self) -> usize {
+        self.broadcasts
+    }
+
+    pub fn total(&self) -> usize {
+        self.getpubkeys + self.pubkeys + self.msgs + self.broadcasts
     }
 }
 
@@ -54,3 +122,44 @@ pub fn calculate_inventory_vector(object_message: &Message) -> InventoryVector {
         hash: hash.to_vec()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use events::{Event,Events};
+    use message::{GetPubKey,Message,Object,ObjectData};
+    use persist::Persister;
+    use std::time::SystemTime;
+    use super::Inventory;
+
+    #[test]
+    fn test_new_objects_are_counted_once() {
+        let events = Events::new();
+        let receiver = events.subscribe();
+        let mut inventory = Inventory::new(Persister::new(), &events);
+        let watcher = inventory.watch();
+        // The inventory only hashes what it is given, so any message will do
+        let object_message = Message::Verack;
+
+        inventory.add_object_message(&object_message);
+        inventory.add_object_message(&object_message);
+
+        assert_eq!(Event::ObjectCount(1), receiver.try_recv().unwrap());
+        assert!(receiver.try_recv().is_err());
+        assert_eq!(Message::Verack, watcher.try_recv().unwrap());
+        assert!(watcher.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_stats_count_each_object_type() {
+        let mut inventory = Inventory::new(Persister::new(), &Events::new());
+        let object = |object| Message::Object(ObjectData::new(SystemTime::now(), 4, 1, object));
+        inventory.add_object_message(&object(Object::Msg { encrypted: vec![ 1 ] }));
+        inventory.add_object_message(&object(Object::Msg { encrypted: vec![ 2 ] }));
+        inventory.add_object_message(&object(Object::GetPubKey(GetPubKey::V4 { tag: vec![ 0; 32 ] })));
+        inventory.add_object_message(&Message::Verack);
+
+        let stats = inventory.stats();
+        assert_eq!((1, 0, 2, 0), (stats.getpubkeys(), stats.pubkeys(), stats.msgs(), stats.broadcasts()));
+        assert_eq!(3, stats.total());
+    }
+}
@@ -0,0 +1,156 @@
+use base58;
+use checksum::{ripemd160_hash,sha1_hash,sha256_hash,sha512_hash};
+use rand::{OsRng,Rng};
+use secp256k1::{Message,PublicKey,Secp256k1,SecretKey,Signature};
+
+// Public keys travel without their leading 0x04 byte, so they're always 64 bytes on the wire
+
+pub fn random_private_key() -> [u8; 32] {
+    let mut rng = OsRng::new().unwrap();
+    loop {
+        let mut private_key = [0u8; 32];
+        rng.fill_bytes(&mut private_key);
+        if is_valid_private_key(&private_key) {
+            return private_key;
+        }
+    }
+}
+
+pub fn is_valid_private_key(private_key: &[u8; 32]) -> bool {
+    SecretKey::from_slice(private_key).is_ok()
+}
+
+pub fn public_key(private_key: &[u8; 32]) -> Vec<u8> {
+    let secp = Secp256k1::signing_only();
+    let secret_key = SecretKey::from_slice(private_key).expect("private keys are validated on creation");
+    let public_key = PublicKey::from_secret_key(&secp, &secret_key);
+    public_key.serialize_uncompressed()[1..].to_vec()
+}
+
+// Wallet import format, which PyBitmessage's keys.dat uses for private keys
+pub fn private_key_to_wif(private_key: &[u8; 32]) -> String {
+    let mut data = vec![ 0x80 ];
+    data.extend(private_key);
+    let checksum = sha256_hash(&sha256_hash(&data));
+    data.extend(&checksum[0..4]);
+    base58::encode(&data)
+}
+
+// None unless the checksum is right and the key is usable
+pub fn private_key_from_wif(wif: &str) -> Option<[u8; 32]> {
+    let data = return_none_on_none!(base58::decode(wif.trim()));
+    if data.len() != 37 || data[0] != 0x80 || sha256_hash(&sha256_hash(&data[..33]))[0..4] != data[33..] {
+        return None;
+    }
+    let mut private_key = [0u8; 32];
+    private_key.copy_from_slice(&data[1..33]);
+    match is_valid_private_key(&private_key) {
+        true => Some(private_key),
+        false => None
+    }
+}
+
+pub fn parse_public_key(bytes: &[u8]) -> Option<PublicKey> {
+    let mut uncompressed = [0u8; 65];
+    uncompressed[0] = 0x04;
+    match bytes.len() {
+        64 => uncompressed[1..].copy_from_slice(bytes),
+        65 => uncompressed.copy_from_slice(bytes),
+        _ => return None
+    }
+
+    PublicKey::from_slice(&uncompressed).ok()
+}
+
+pub fn ripe(public_signing_key: &[u8], public_encryption_key: &[u8]) -> Vec<u8> {
+    assert!(public_signing_key.len() == 64 && public_encryption_key.len() == 64);
+
+    let mut input = Vec::with_capacity(130);
+    input.push(0x04);
+    input.extend(public_signing_key);
+    input.push(0x04);
+    input.extend(public_encryption_key);
+
+    ripemd160_hash(&sha512_hash(&input)).to_vec()
+}
+
+// DER-encoded ECDSA over the SHA-256 of the data
+pub fn sign(private_key: &[u8; 32], data: &[u8]) -> Vec<u8> {
+    let secp = Secp256k1::signing_only();
+    let secret_key = SecretKey::from_slice(private_key).expect("private keys are validated on creation");
+    let message = Message::from_slice(&sha256_hash(data)).expect("hashes are 32 bytes");
+    secp.sign(&message, &secret_key).serialize_der().to_vec()
+}
+
+// Older clients signed the SHA-1 of the data, so that is accepted too
+pub fn verify(public_signing_key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let public_key = match parse_public_key(public_signing_key) {
+        Some(public_key) => public_key,
+        None => return false
+    };
+    let mut signature = match Signature::from_der_lax(signature) {
+        Ok(signature) => signature,
+        Err(_) => return false
+    };
+    // OpenSSL doesn't normalise signatures, but libsecp256k1 only accepts low S values
+    signature.normalize_s();
+
+    // A hash shorter than the curve order is used as it is, which is the same as padding it at the front
+    let mut sha1 = [0u8; 32];
+    sha1[12..].copy_from_slice(&sha1_hash(data));
+
+    let secp = Secp256k1::verification_only();
+    [ sha256_hash(data), sha1 ].iter().any(|hash| {
+        let message = Message::from_slice(hash).expect("hashes are 32 bytes");
+        secp.verify(&message, &signature, &public_key).is_ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::hex::FromHex;
+    use super::{parse_public_key,private_key_from_wif,private_key_to_wif,public_key,random_private_key,ripe,sign,verify};
+
+    #[test]
+    fn test_public_key_of_one_is_generator() {
+        let mut private_key = [0u8; 32];
+        private_key[31] = 1;
+
+        let public_key = public_key(&private_key);
+
+        assert_eq!(64, public_key.len());
+        assert_eq!(&[ 0x79, 0xbe, 0x66, 0x7e ], &public_key[0..4]);
+        assert!(parse_public_key(&public_key).is_some());
+    }
+
+    #[test]
+    fn test_wif() {
+        let mut private_key = [0u8; 32];
+        private_key.copy_from_slice(&"0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d".from_hex().unwrap());
+
+        assert_eq!("5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ", private_key_to_wif(&private_key));
+        assert_eq!(Some(private_key), private_key_from_wif("5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTJ"));
+        assert_eq!(None, private_key_from_wif("5HueCGU8rMjxEXxiPuD5BDku4MkFqeZyd4dZ1jvhTVqvbTLvyTK"));
+        assert_eq!(None, private_key_from_wif("not base58"));
+    }
+
+    #[test]
+    fn test_ripe_length() {
+        let signing = public_key(&random_private_key());
+        let encryption = public_key(&random_private_key());
+
+        assert_eq!(20, ripe(&signing, &encryption).len());
+        assert!(parse_public_key(&signing[1..]).is_none());
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let private_key = random_private_key();
+        let signature = sign(&private_key, b"hello");
+
+        assert!(verify(&public_key(&private_key), b"hello", &signature));
+        assert!(!verify(&public_key(&private_key), b"hellp", &signature));
+        assert!(!verify(&public_key(&random_private_key()), b"hello", &signature));
+        assert!(!verify(&public_key(&private_key), b"hello", &signature[1..]));
+    }
+}
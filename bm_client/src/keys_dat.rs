@@ -0,0 +1,132 @@
+use address::Address;
+use identity::Identity;
+use ini::{IniEntry,IniError,parse_ini};
+use keys::private_key_from_wif;
+use message::{NETWORK_EXTRA_BYTES,NETWORK_TRIALS_PER_BYTE};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+// PyBitmessage's keys.dat: an INI file with a section named after each address, holding its
+// private keys in wallet import format and a few settings. Other sections, such as
+// [bitmessagesettings], and keys we have no use for are ignored.
+
+#[derive(Debug,PartialEq)]
+pub enum KeysDatError {
+    Syntax(IniError),
+    Invalid { section: String, reason: &'static str }
+}
+
+impl fmt::Display for KeysDatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &KeysDatError::Syntax(ref err) => write!(f, "Syntax error in keys.dat at {}", err),
+            &KeysDatError::Invalid { ref section, reason } => write!(f, "Cannot import [{}]: {}", section, reason)
+        }
+    }
+}
+
+impl Error for KeysDatError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            &KeysDatError::Syntax(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+pub fn format_keys_dat(identities: &[Identity]) -> String {
+    let mut keys_dat = String::new();
+    for identity in identities.iter() {
+        keys_dat.push_str(&format!("[{}]\nlabel = {}\nenabled = {}\ndecoy = false\nchan = {}\nnoncetrialsperbyte = {}\npayloadlengthextrabytes = {}\nprivsigningkey = {}\nprivencryptionkey = {}\n\n",
+            identity.address(), identity.label(), identity.enabled(), identity.is_chan(), identity.nonce_trials_per_byte(),
+            identity.extra_bytes(), identity.private_signing_key_wif(), identity.private_encryption_key_wif()));
+    }
+    keys_dat
+}
+
+// Every identity in the file, or the first thing wrong with it. The keys in each section
+// have to give its address, so nothing is imported from a damaged file.
+pub fn read_keys_dat(contents: &str) -> Result<Vec<Identity>, KeysDatError> {
+    let entries = try!(parse_ini(contents).map_err(KeysDatError::Syntax));
+
+    let mut sections: Vec<String> = vec![];
+    for entry in entries.iter() {
+        if entry.section.starts_with("BM-") && !sections.contains(&entry.section) {
+            sections.push(entry.section.clone());
+        }
+    }
+
+    let mut identities = vec![];
+    for section in sections.iter() {
+        let section_entries: Vec<&IniEntry> = entries.iter().filter(|entry| &entry.section == section).collect();
+        identities.push(try!(read_identity(section, &section_entries)));
+    }
+    Ok(identities)
+}
+
+fn read_identity(section: &str, entries: &[&IniEntry]) -> Result<Identity, KeysDatError> {
+    let invalid = |reason| KeysDatError::Invalid { section: section.to_string(), reason: reason };
+    let value = |key: &str| entries.iter().rev().find(|entry| entry.key.eq_ignore_ascii_case(key)).map(|entry| entry.value.as_str());
+
+    let address = try!(Address::from_str(section).map_err(|_| invalid("not a valid address")));
+    let private_signing_key = try!(value("privsigningkey").ok_or(invalid("privsigningkey is missing")));
+    let private_signing_key = try!(private_key_from_wif(private_signing_key).ok_or(invalid("privsigningkey is not a valid private key")));
+    let private_encryption_key = try!(value("privencryptionkey").ok_or(invalid("privencryptionkey is missing")));
+    let private_encryption_key = try!(private_key_from_wif(private_encryption_key).ok_or(invalid("privencryptionkey is not a valid private key")));
+
+    let enabled = try!(parse_bool(value("enabled"), true).ok_or(invalid("enabled must be true or false")));
+    let chan = try!(parse_bool(value("chan"), false).ok_or(invalid("chan must be true or false")));
+    let nonce_trials_per_byte = try!(parse_number(value("noncetrialsperbyte"), NETWORK_TRIALS_PER_BYTE).ok_or(invalid("noncetrialsperbyte must be a number")));
+    let extra_bytes = try!(parse_number(value("payloadlengthextrabytes"), NETWORK_EXTRA_BYTES).ok_or(invalid("payloadlengthextrabytes must be a number")));
+
+    let identity = try!(Identity::from_private_keys(value("label").unwrap_or(""), &address, &private_signing_key, &private_encryption_key)
+        .ok_or(invalid("the private keys do not belong to this address")));
+    // Anything easier than the network's minimum would only get our messages dropped
+    Ok(identity.restored(enabled, chan, nonce_trials_per_byte.max(NETWORK_TRIALS_PER_BYTE), extra_bytes.max(NETWORK_EXTRA_BYTES)))
+}
+
+// Python writes True and False, PyBitmessage itself true and false
+fn parse_bool(value: Option<&str>, default: bool) -> Option<bool> {
+    match value.map(|value| value.to_lowercase()) {
+        Some(ref value) if value == "true" => Some(true),
+        Some(ref value) if value == "false" => Some(false),
+        Some(_) => None,
+        None => Some(default)
+    }
+}
+
+fn parse_number(value: Option<&str>, default: u64) -> Option<u64> {
+    match value {
+        Some(value) => value.parse().ok(),
+        None => Some(default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use identity::Identity;
+    use message::NETWORK_TRIALS_PER_BYTE;
+    use super::{KeysDatError,format_keys_dat,read_keys_dat};
+
+    #[test]
+    fn test_keys_dat_round_trip() {
+        let identity = Identity::random("Me = myself", 1).restored(false, false, NETWORK_TRIALS_PER_BYTE * 2, 1000);
+        let chan = Identity::chan("general", 1);
+        let keys_dat = format!("[bitmessagesettings]\nsettingsversion = 10\n\n{}", format_keys_dat(&[ identity.clone(), chan.clone() ]));
+
+        assert_eq!(Ok(vec![ identity, chan ]), read_keys_dat(&keys_dat));
+    }
+
+    #[test]
+    fn test_keys_must_give_the_address() {
+        let identity = Identity::random("Me", 1);
+        let other = Identity::random("Other", 1);
+        let keys_dat = format!("[{}]\nlabel = Me\nprivsigningkey = {}\nprivencryptionkey = {}\n",
+            identity.address(), identity.private_signing_key_wif(), other.private_encryption_key_wif());
+
+        assert_eq!(Err(KeysDatError::Invalid { section: identity.address().to_string(), reason: "the private keys do not belong to this address" }), read_keys_dat(&keys_dat));
+        assert_eq!(Err(KeysDatError::Invalid { section: "BM-nonsense".to_string(), reason: "not a valid address" }), read_keys_dat("[BM-nonsense]\nlabel = x\n"));
+        assert!(read_keys_dat("privsigningkey\n").is_err());
+    }
+}
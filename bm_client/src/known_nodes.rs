@@ -1,9 +1,9 @@
 use message::KnownNode;
+use net::PeerAddr;
 use persist::Persister;
 use rand::OsRng;
 use rand::Rng;
 use std::cmp::min;
-use std::net::SocketAddr;
 
 #[derive(Clone)]
 pub struct KnownNodes {
@@ -17,36 +17,57 @@ impl KnownNodes {
         }
     }
 
-    pub fn len(&self) -> usize {
-        self.persister.get_known_nodes().len()
+    pub fn len_for_stream(&self, stream: u32) -> usize {
+        self.persister.get_known_nodes().iter().filter(|known_node| known_node.stream == stream).count()
     }
 
-    pub fn get_random_but_not(&self, exclude: Vec<SocketAddr>) -> Option<KnownNode> {
-        let mut single_selection = self.get_random_selection_but_not(1, exclude);
+    pub fn get_random_connectable_but_not(&self, exclude: Vec<PeerAddr>, onion_reachable: bool) -> Option<KnownNode> {
+        let mut known_nodes: Vec<KnownNode> = self.persister.get_known_nodes();
+        known_nodes.retain(|known_node| onion_reachable || !known_node.peer_addr.is_onion());
+
+        let mut single_selection = select_random_but_not(known_nodes, 1, exclude);
         single_selection.pop()
     }
 
-    pub fn get_random_selection_but_not(&self, at_most: usize, exclude: Vec<SocketAddr>) -> Vec<KnownNode> {
-        let mut known_nodes: Vec<KnownNode> = self.persister.get_known_nodes();
-        let mut rng = OsRng::new().unwrap();
-
-        let capacity = min(at_most, known_nodes.len());
-
-        let mut random_nodes: Vec<KnownNode> = Vec::with_capacity(capacity);
-        while random_nodes.len() < at_most && !known_nodes.is_empty() {
-            let index: usize = rng.gen_range(0, known_nodes.len());
-            let random_node = known_nodes.swap_remove(index);
-            let node_socket_addr = random_node.socket_addr;
-            if !exclude.contains(&node_socket_addr) {
-                random_nodes.push(random_node);
-            }
-        }
-
-        random_nodes
+    pub fn get_random_selection_but_not(&self, at_most: usize, exclude: Vec<PeerAddr>) -> Vec<KnownNode> {
+        let known_nodes: Vec<KnownNode> = self.persister.get_known_nodes();
+        select_random_but_not(known_nodes, at_most, exclude)
     }
 
     pub fn add_known_node(&mut self, known_node: &KnownNode)
     {
         self.persister.add_known_node(known_node);
     }
+
+    pub fn is_local(&self, peer_addr: &PeerAddr) -> bool {
+        self.persister.get_local_nodes().iter().any(|local_node| &local_node.peer_addr == peer_addr)
+    }
+
+    // Local nodes announce themselves repeatedly, so only the first announcement is recorded
+    pub fn add_local_node(&mut self, known_node: &KnownNode) {
+        if self.is_local(&known_node.peer_addr) {
+            return;
+        }
+
+        self.persister.add_known_node(known_node);
+        self.persister.add_local_node(known_node);
+    }
+}
+
+fn select_random_but_not(mut known_nodes: Vec<KnownNode>, at_most: usize, exclude: Vec<PeerAddr>) -> Vec<KnownNode> {
+    let mut rng = OsRng::new().unwrap();
+
+    let capacity = min(at_most, known_nodes.len());
+
+    let mut random_nodes: Vec<KnownNode> = Vec::with_capacity(capacity);
+    while random_nodes.len() < at_most && !known_nodes.is_empty() {
+        let index: usize = rng.gen_range(0, known_nodes.len());
+        let random_node = known_nodes.swap_remove(index);
+        let node_peer_addr = random_node.peer_addr;
+        if !exclude.contains(&node_peer_addr) {
+            random_nodes.push(random_node);
+        }
+    }
+
+    random_nodes
 }
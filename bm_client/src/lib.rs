@@ -1,92 +1,724 @@
 extern crate byteorder;
 extern crate crypto;
 extern crate encoding;
+extern crate flate2;
 extern crate rand;
+extern crate rmp;
+extern crate rustc_serialize;
+extern crate secp256k1;
 
 mod macros;
 
+mod address;
+mod api;
+mod addressbook;
+mod archive;
+mod base58;
+mod bootstrap;
 mod channel;
 mod checksum;
 mod chunk;
 mod config;
+mod disk;
 mod connection;
+mod ecies;
+mod error;
+mod events;
+mod filter;
+mod gateway;
+mod identity;
+mod inbox;
+mod ini;
+mod keys;
+mod keys_dat;
 mod inventory;
 mod known_nodes;
+mod local_discovery;
+mod mail;
 mod message;
+mod messages;
+mod msgcoding;
 mod net;
+mod outbox;
 mod peer;
 mod persist;
+mod processor;
+mod pubkeys;
+mod search;
+mod server;
+mod stop;
+mod subscriptions;
 mod timegen;
 
-use config::Config;
+pub use address::{Address,AddressError};
+pub use api::ApiServer;
+pub use addressbook::Contact;
+pub use archive::ImportStats;
+pub use config::{Config,ConfigBuilder,ConfigError};
+pub use connection::ConnectionState;
+pub use error::BMError;
+pub use events::{Event,OutboxStatus};
+pub use filter::FilterMode;
+pub use gateway::MailGateway;
+pub use identity::Identity;
+pub use inbox::InboxMessage;
+pub use inventory::ObjectStats;
+pub use keys_dat::KeysDatError;
+pub use mail::short_date;
+pub use message::{GenerateError,MessageSendError,PowBenchmark,benchmark_pow};
+pub use messages::{Folder,MessageQuery,StoredMessage};
+pub use msgcoding::MessageEncoding;
+pub use net::{OnionAddr,PeerAddr};
+pub use outbox::{OutboxMessage,SendOptions};
+pub use subscriptions::Subscription;
+
+use addressbook::AddressBook;
+use archive::Archived;
+use events::Events;
+use filter::SenderFilter;
+use identity::Identities;
+use inbox::Inbox;
 use inventory::Inventory;
 use known_nodes::KnownNodes;
-use message::KnownNode;
-use message::{Sender,MessageSendError};
-use net::to_socket_addr;
+use local_discovery::LocalDiscovery;
+use message::Sender;
+use messages::Messages;
+use outbox::{MAX_MESSAGE_LENGTH,Outbox,OutboxWorker};
 use peer::PeerConnector;
 use persist::Persister;
-use std::time::SystemTime;
+use processor::ObjectProcessor;
+use pubkeys::PubKeys;
+use rustc_serialize::hex::ToHex;
+use std::fs::{self,File};
+use std::io::{self,Read,Write};
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration,Instant};
+use subscriptions::Subscriptions;
 
-pub enum BMError {
-    NoDiskAccess,
-    Network
-}
+const SHUTDOWN_TIMEOUT_SECS: u64 = 5;
 
 pub struct BMClient {
-    known_nodes: KnownNodes,
-    sender: Sender,
-    peer_connector: PeerConnector
+    config: Config,
+    identities: Identities,
+    subscriptions: Subscriptions,
+    address_book: AddressBook,
+    filter: SenderFilter,
+    inbox: Inbox,
+    outbox: Outbox,
+    messages: Messages,
+    inventory: Inventory,
+    outbox_worker: OutboxWorker,
+    object_processor: ObjectProcessor,
+    peer_connector: PeerConnector,
+    local_discovery: LocalDiscovery,
+    events: Events,
+    running: bool
 }
 
 impl BMClient {
-    pub fn new() -> BMClient {
-        // TODO - Move this to a Result returned from new()
-        // assert!(usize::max_value() >= u32::max_value(), "You must use at least a 32-bit system");
+    pub fn new() -> Result<BMClient, BMError> {
+        BMClient::with_config(Config::new())
+    }
+
+    pub fn with_config(config: Config) -> Result<BMClient, BMError> {
+        if (usize::max_value() as u64) < (u32::max_value() as u64) {
+            return Err(BMError::UnsupportedPlatform);
+        }
+
+        let events = Events::new();
+        let persister = match config.data_dir() {
+            Some(data_dir) => try!(Persister::open(data_dir, &events).map_err(BMError::NoDiskAccess)),
+            None => Persister::new()
+        };
+
+        let known_nodes = KnownNodes::new(persister.clone());
+        let identities = Identities::new(persister.clone());
+        let pubkeys = PubKeys::new(persister.clone());
+        let subscriptions = Subscriptions::new(persister.clone());
+        let address_book = AddressBook::new(persister.clone());
+        let filter = SenderFilter::new(persister.clone());
+        let inbox = Inbox::new(persister.clone(), &events);
+        let outbox = Outbox::new(persister.clone(), &events);
+        let messages = Messages::new(persister.clone(), &inbox, &outbox);
+
+        let inventory = Inventory::new(persister, &events);
+        let sender = Sender::new(&config, inventory.clone(), &events);
+        let outbox_worker = OutboxWorker::new(&config, &outbox, &identities, &pubkeys, &inventory, &sender);
+        let object_processor = ObjectProcessor::new(&identities, &pubkeys, &subscriptions, &filter, &inbox, &outbox, &inventory, &sender);
+        let peer_connector = PeerConnector::new(&config, &known_nodes, &inventory, &events);
+        let local_discovery = LocalDiscovery::new(&config, &known_nodes, &events);
+
+        Ok(BMClient {
+            config: config,
+            identities: identities,
+            subscriptions: subscriptions,
+            address_book: address_book,
+            filter: filter,
+            inbox: inbox,
+            outbox: outbox,
+            messages: messages,
+            inventory: inventory,
+            outbox_worker: outbox_worker,
+            object_processor: object_processor,
+            peer_connector: peer_connector,
+            local_discovery: local_discovery,
+            events: events,
+            running: false
+        })
+    }
+
+    pub fn start(&mut self) -> Result<(), BMError> {
+        if self.running {
+            return Ok(());
+        }
+
+        if self.config.local_discovery() {
+            try!(self.local_discovery.start().map_err(BMError::Network));
+        }
+        if let Err(err) = self.start_threads() {
+            self.stop_threads(Instant::now());
+            return Err(BMError::ThreadSpawn(err));
+        }
+
+        self.running = true;
+        Ok(())
+    }
+
+    // Waits up to SHUTDOWN_TIMEOUT_SECS for queued writes to be flushed and threads to finish.
+    // The client can be started again afterwards.
+    pub fn stop(&mut self) {
+        if !self.running {
+            return;
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(SHUTDOWN_TIMEOUT_SECS);
+        self.stop_threads(deadline);
+        self.running = false;
+    }
 
-        let config = Config::new();
-        let persister = Persister::new();
+    fn start_threads(&mut self) -> io::Result<()> {
+        try!(self.object_processor.start());
+        try!(self.outbox_worker.start());
+        self.peer_connector.start()
+    }
+
+    fn stop_threads(&mut self, deadline: Instant) {
+        self.peer_connector.stop(deadline);
+        self.outbox_worker.stop(deadline);
+        self.object_processor.stop(deadline);
+        self.local_discovery.stop(deadline);
+    }
 
-        let mut known_nodes = KnownNodes::new(persister.clone());
-        bootstrap_known_nodes(&mut known_nodes);
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    // The peers we are connected or connecting to, with how far each handshake has got
+    pub fn connections(&self) -> Vec<(PeerAddr, ConnectionState)> {
+        self.peer_connector.connections()
+    }
 
-        let inventory = Inventory::new(persister);
-        let sender = Sender::new(inventory.clone());
-        let peer_connector = PeerConnector::new(&config, &known_nodes, &inventory);
+    pub fn object_stats(&self) -> ObjectStats {
+        self.inventory.stats()
+    }
+
+    // Each call gives a new receiver which sees every event from then on
+    pub fn subscribe(&self) -> Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    // New identities get a v4 address in the first stream we connect to
+    pub fn create_identity(&mut self, label: &str) -> Address {
+        let identity = Identity::random(label, self.config.streams()[0]);
+        self.identities.add(&identity);
+        identity.address().clone()
+    }
 
-        BMClient {
-            known_nodes: known_nodes,
-            sender: sender,
-            peer_connector: peer_connector
+    // Personal identities only; chans are listed by chans()
+    pub fn identities(&self) -> Vec<Identity> {
+        self.identities.personal()
+    }
+
+    // Like a chan, the passphrase gives the keys, so the identity can be recreated anywhere from it
+    pub fn create_deterministic_identity(&mut self, label: &str, passphrase: &str) -> Result<Address, BMError> {
+        if passphrase.is_empty() {
+            return Err(BMError::EmptyPassphrase);
+        }
+
+        let identity = Identity::deterministic(label, passphrase, self.config.streams()[0]);
+        self.identities.add(&identity);
+        Ok(identity.address().clone())
+    }
+
+    // Gives the address of the chan for the passphrase, joining it if we haven't already
+    pub fn create_chan(&mut self, passphrase: &str) -> Result<Address, BMError> {
+        if passphrase.is_empty() {
+            return Err(BMError::EmptyPassphrase);
         }
+
+        let chan = Identity::chan(passphrase, self.config.streams()[0]);
+        self.identities.add(&chan);
+        Ok(chan.address().clone())
     }
 
-    pub fn start(&mut self) {
-        bootstrap_known_nodes(&mut self.known_nodes);
-        self.peer_connector.start();
+    // Checks the passphrase against the chan's address before joining, to catch typing mistakes
+    pub fn join_chan(&mut self, passphrase: &str, address: &Address) -> Result<(), BMError> {
+        if passphrase.is_empty() {
+            return Err(BMError::EmptyPassphrase);
+        }
+
+        let chan = Identity::chan(passphrase, address.stream());
+        if chan.address() != address {
+            return Err(BMError::ChanAddressMismatch(address.clone()));
+        }
+        self.identities.add(&chan);
+        Ok(())
     }
 
-    pub fn send_message(&mut self, text: &str) -> Result<(), MessageSendError> {
-        self.sender.send_message(text)
+    pub fn leave_chan(&mut self, address: &Address) -> bool {
+        self.identities.remove_chan(address)
+    }
+
+    pub fn chans(&self) -> Vec<Identity> {
+        self.identities.chans()
+    }
+
+    // In PyBitmessage's keys.dat format: just the given identity or chan, or all of them
+    pub fn export_keys_dat(&self, address: Option<&Address>) -> Result<String, BMError> {
+        let identities = match address {
+            Some(address) => vec![ try!(self.identities.get(address).ok_or(BMError::UnknownIdentity(address.clone()))) ],
+            None => self.identities.list()
+        };
+        Ok(keys_dat::format_keys_dat(&identities))
+    }
+
+    // Identities and chans from PyBitmessage's keys.dat. Ones we already have are left as they are.
+    pub fn import_keys_dat(&mut self, contents: &str) -> Result<ImportStats, BMError> {
+        let mut stats = ImportStats::new();
+        for identity in try!(keys_dat::read_keys_dat(contents)) {
+            let known = self.identities.get(identity.address()).is_some();
+            if !known {
+                self.identities.add(&identity);
+            }
+            stats.count(!known);
+        }
+        Ok(stats)
+    }
+
+    // Queues the message and returns straight away with its id. Progress is reported
+    // through OutboxStatusChanged events, or can be polled with outbox_message().
+    pub fn send_message(&mut self, from: &Address, to: &Address, subject: &str, body: &str, options: SendOptions) -> Result<Vec<u8>, BMError> {
+        try!(self.check_sendable(from, subject, body));
+        Ok(self.outbox.queue(from, to, subject, body, &options))
+    }
+
+    // Like send_message, but readable by anyone subscribed to the from address
+    pub fn send_broadcast(&mut self, from: &Address, subject: &str, body: &str, options: SendOptions) -> Result<Vec<u8>, BMError> {
+        try!(self.check_sendable(from, subject, body));
+        Ok(self.outbox.queue_broadcast(from, subject, body, &options))
+    }
+
+    // Whether send_message would take the message, without queuing it
+    pub fn check_sendable(&self, from: &Address, subject: &str, body: &str) -> Result<(), BMError> {
+        if self.identities.get(from).is_none() {
+            return Err(BMError::UnknownIdentity(from.clone()));
+        }
+        if subject.len() + body.len() > MAX_MESSAGE_LENGTH {
+            return Err(BMError::MessageTooLong);
+        }
+        Ok(())
+    }
+
+    pub fn outbox_message(&self, ackdata: &[u8]) -> Option<OutboxMessage> {
+        self.outbox.get(ackdata)
+    }
+
+    pub fn outbox_messages(&self) -> Vec<OutboxMessage> {
+        self.outbox.list()
+    }
+
+    // Returns false if the message is unknown or already sent
+    pub fn cancel_message(&mut self, ackdata: &[u8]) -> bool {
+        self.outbox.cancel(ackdata)
+    }
+
+    pub fn inbox_message(&self, msgid: &[u8]) -> Option<InboxMessage> {
+        self.inbox.get(msgid)
+    }
+
+    pub fn inbox_messages(&self) -> Vec<InboxMessage> {
+        self.inbox.list()
+    }
+
+    // Received and sent messages together, filtered by folder, address, date or words they contain
+    pub fn messages(&self, query: &MessageQuery) -> Vec<StoredMessage> {
+        self.messages.query(query)
+    }
+
+    pub fn message(&self, id: &[u8]) -> Option<StoredMessage> {
+        self.messages.get(id)
+    }
+
+    pub fn mark_read(&mut self, id: &[u8], read: bool) -> bool {
+        self.messages.mark_read(id, read)
+    }
+
+    // Trashed messages stay in the store until deleted, and unsent ones aren't sent until restored
+    pub fn trash_message(&mut self, id: &[u8]) -> bool {
+        self.messages.trash(id)
+    }
+
+    pub fn restore_message(&mut self, id: &[u8]) -> bool {
+        self.messages.restore(id)
+    }
+
+    pub fn delete_message(&mut self, id: &[u8]) -> bool {
+        self.messages.delete(id)
+    }
+
+    // Writes the messages in the folders to an mbox file, oldest first, returning how many there were
+    pub fn export_mbox<P: AsRef<Path>>(&self, folders: &[Folder], path: P) -> Result<usize, BMError> {
+        let messages = self.messages_in(folders);
+        try!(write_text(path.as_ref(), &archive::mbox(&messages)).map_err(BMError::Archive));
+        Ok(messages.len())
+    }
+
+    // One .eml file per message, named after its id
+    pub fn export_eml<P: AsRef<Path>>(&self, folders: &[Folder], dir: P) -> Result<usize, BMError> {
+        let messages = self.messages_in(folders);
+        try!(fs::create_dir_all(&dir).map_err(BMError::Archive));
+        for message in messages.iter() {
+            let path = dir.as_ref().join(format!("{}.eml", message.id().to_hex()));
+            try!(write_text(&path, &archive::eml(message)).map_err(BMError::Archive));
+        }
+        Ok(messages.len())
+    }
+
+    // From an mbox file, an .eml file or a directory of them. Messages already in the store
+    // are skipped, so importing the same archive twice does no harm.
+    pub fn import_messages<P: AsRef<Path>>(&mut self, path: P) -> Result<ImportStats, BMError> {
+        let texts = try!(read_archive(path.as_ref()).map_err(BMError::Archive));
+        let mut stats = ImportStats::new();
+        for text in texts.iter() {
+            let identities = &self.identities;
+            let imported = match archive::read_mail(text, |address| identities.get(address).is_some()) {
+                Some((Archived::Received(inbox_message), trashed)) => {
+                    self.inbox.add(&inbox_message) && (!trashed || self.inbox.set_trashed(inbox_message.msgid(), true))
+                },
+                Some((Archived::Sent(outbox_message), trashed)) => {
+                    self.outbox.restore(&outbox_message) && (!trashed || self.outbox.set_trashed(outbox_message.ackdata(), true))
+                },
+                None => false
+            };
+            stats.count(imported);
+        }
+        Ok(stats)
+    }
+
+    fn messages_in(&self, folders: &[Folder]) -> Vec<StoredMessage> {
+        let mut messages: Vec<StoredMessage> = self.messages.query(&MessageQuery::new()).into_iter()
+            .filter(|message| folders.contains(&message.folder())).collect();
+        messages.reverse();
+        messages
+    }
+
+    // Broadcasts from the address arriving from then on are delivered to the inbox
+    pub fn add_subscription(&mut self, label: &str, address: &Address) {
+        self.subscriptions.add(&Subscription::new(label, address));
+    }
+
+    pub fn remove_subscription(&mut self, address: &Address) -> bool {
+        self.subscriptions.remove(address)
+    }
+
+    pub fn subscriptions(&self) -> Vec<Subscription> {
+        self.subscriptions.list()
+    }
+
+    // Adding an address that is already in the address book changes its label
+    pub fn add_contact(&mut self, label: &str, address: &Address) {
+        self.address_book.add(&Contact::new(label, address));
+    }
+
+    pub fn remove_contact(&mut self, address: &Address) -> bool {
+        self.address_book.remove(address)
+    }
+
+    pub fn contact(&self, address: &Address) -> Option<Contact> {
+        self.address_book.get(address)
+    }
+
+    pub fn contacts(&self) -> Vec<Contact> {
+        self.address_book.list()
+    }
+
+    // Msgs from senders the filter doesn't allow are dropped without an ack. Starts in blacklist mode.
+    pub fn set_filter_mode(&mut self, mode: FilterMode) {
+        self.filter.set_mode(mode);
+    }
+
+    pub fn filter_mode(&self) -> FilterMode {
+        self.filter.mode()
+    }
+
+    pub fn add_filter_entry(&mut self, list: FilterMode, label: &str, address: &Address) {
+        self.filter.add(list, &Contact::new(label, address));
+    }
+
+    pub fn remove_filter_entry(&mut self, list: FilterMode, address: &Address) -> bool {
+        self.filter.remove(list, address)
+    }
+
+    pub fn filter_entries(&self, list: FilterMode) -> Vec<Contact> {
+        self.filter.list(list)
+    }
+}
+
+impl Drop for BMClient {
+    fn drop(&mut self) {
+        self.stop();
     }
 }
 
-fn bootstrap_known_nodes(known_nodes: &mut KnownNodes) {
-    if known_nodes.len() == 0 {
-        for known_node in bootstrap_nodes() {
-            known_nodes.add_known_node(&known_node);
+// The mails in an mbox file, an .eml file or a directory of .eml files
+fn read_archive(path: &Path) -> io::Result<Vec<String>> {
+    let mut texts = vec![];
+    if path.is_dir() {
+        let mut paths = vec![];
+        for entry in try!(fs::read_dir(path)) {
+            let entry_path = try!(entry).path();
+            if entry_path.extension().map_or(false, |extension| extension.eq_ignore_ascii_case("eml")) {
+                paths.push(entry_path);
+            }
+        }
+        paths.sort();
+        for eml_path in paths.iter() {
+            texts.push(try!(read_text(eml_path)));
+        }
+    } else {
+        let text = try!(read_text(path));
+        if archive::is_mbox(&text) {
+            texts.extend(archive::split_mbox(&text));
+        } else if !text.trim().is_empty() {
+            texts.push(text);
         }
     }
+    Ok(texts)
+}
+
+// Mail in other character sets keeps what it can
+fn read_text(path: &Path) -> io::Result<String> {
+    let mut bytes = vec![];
+    try!(try!(File::open(path)).read_to_end(&mut bytes));
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn write_text(path: &Path, text: &str) -> io::Result<()> {
+    try!(File::create(path)).write_all(text.as_bytes())
 }
 
-fn bootstrap_nodes() -> Vec<KnownNode> {
-    vec![
-        // "5.45.99.75:8444"
-        KnownNode {
-            last_seen: SystemTime::now(),
-            stream: 1,
-            services: 1,
-            socket_addr: to_socket_addr("127.0.0.1:8444")
+#[cfg(test)]
+mod tests {
+    use inbox::InboxMessage;
+    use rand::{OsRng,Rng};
+    use rustc_serialize::hex::ToHex;
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+    use std::net::TcpListener;
+    use std::time::{Duration,Instant,UNIX_EPOCH};
+    use super::{BMClient,BMError,Config,ConnectionState,Event,Folder,MessageQuery,OutboxStatus,SendOptions};
+
+    #[test]
+    fn test_stop_and_restart() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let seed = listener.local_addr().unwrap().to_string();
+        let config = Config::builder().seed_nodes(&[ &seed ]).max_connections(1).build().unwrap();
+        let mut bm_client = BMClient::with_config(config).unwrap();
+
+        for _ in 0..2 {
+            bm_client.start().unwrap();
+            assert!(bm_client.is_running());
+            let (_stream, _) = listener.accept().unwrap();
+
+            let start = Instant::now();
+            bm_client.stop();
+            assert!(!bm_client.is_running());
+            assert!(start.elapsed() < Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn test_connection_states_are_reported() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let seed = listener.local_addr().unwrap().to_string();
+        let config = Config::builder().seed_nodes(&[ &seed ]).max_connections(1).build().unwrap();
+        let mut bm_client = BMClient::with_config(config).unwrap();
+        let events = bm_client.subscribe();
+        bm_client.start().unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let next_state = || loop {
+            match events.recv_timeout(Duration::from_secs(5)).unwrap() {
+                Event::ConnectionStateChanged(_, state) => return state,
+                _ => {}
+            }
+        };
+        match next_state() {
+            ConnectionState::Fresh(_) => {},
+            state => panic!("Expected a fresh connection, got {:?}", state)
         }
-    ]
+        assert_eq!(1, bm_client.connections().len());
+
+        drop(stream);
+        match next_state() {
+            ConnectionState::Error | ConnectionState::Stale => {},
+            state => panic!("Expected the connection to end, got {:?}", state)
+        }
+    }
+
+    #[test]
+    fn test_send_message_is_queued() {
+        let config = Config::builder().seed_nodes(&[]).dns_seeds(&[]).build().unwrap();
+        let mut bm_client = BMClient::with_config(config).unwrap();
+        let from = bm_client.create_identity("Me");
+        let to = BMClient::new().unwrap().create_identity("Someone else");
+
+        let ackdata = bm_client.send_message(&from, &to, "Hi", "Hello", SendOptions::new()).unwrap();
+
+        assert_eq!(OutboxStatus::Queued, bm_client.outbox_message(&ackdata).unwrap().status());
+        assert!(bm_client.cancel_message(&ackdata));
+        assert!(bm_client.outbox_message(&ackdata).is_none());
+        match bm_client.send_message(&to, &from, "Hi", "Hello", SendOptions::new()) {
+            Err(BMError::UnknownIdentity(address)) => assert_eq!(to, address),
+            _ => panic!("Expected UnknownIdentity")
+        }
+    }
+
+    #[test]
+    fn test_chans() {
+        let mut bm_client = BMClient::new().unwrap();
+        let address = bm_client.create_chan("general").unwrap();
+
+        assert_eq!("BM-2cW67GEKkHGonXKZLCzouLLxnLym3azS8r", address.to_string());
+        assert!(bm_client.join_chan("general", &address).is_ok());
+        assert_eq!(1, bm_client.chans().len());
+        assert!(bm_client.identities().is_empty());
+        assert!(bm_client.send_message(&address, &address, "Hi", "Hello", SendOptions::new()).is_ok());
+
+        match bm_client.join_chan("genera1", &address) {
+            Err(BMError::ChanAddressMismatch(mismatched)) => assert_eq!(address, mismatched),
+            _ => panic!("Expected ChanAddressMismatch")
+        }
+        assert!(bm_client.create_chan("").is_err());
+        assert!(bm_client.leave_chan(&address));
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let mut bm_client = BMClient::new().unwrap();
+        let address = BMClient::new().unwrap().create_identity("Someone else");
+
+        bm_client.add_subscription("News", &address);
+        assert_eq!(address, *bm_client.subscriptions()[0].address());
+        assert!(bm_client.remove_subscription(&address));
+        assert!(bm_client.subscriptions().is_empty());
+        assert!(bm_client.send_broadcast(&address, "Hi", "Hello", SendOptions::new()).is_err());
+    }
+
+    #[test]
+    fn test_address_book() {
+        let mut bm_client = BMClient::new().unwrap();
+        let address = BMClient::new().unwrap().create_identity("Someone else");
+
+        bm_client.add_contact("Bob", &address);
+        bm_client.add_contact("Robert", &address);
+        assert_eq!(1, bm_client.contacts().len());
+        assert_eq!("Robert", bm_client.contact(&address).unwrap().label());
+        assert!(bm_client.remove_contact(&address));
+        assert!(bm_client.contact(&address).is_none());
+    }
+
+    #[test]
+    fn test_deterministic_identity() {
+        let mut bm_client = BMClient::new().unwrap();
+
+        let address = bm_client.create_deterministic_identity("Mine", "correct horse").unwrap();
+        assert_eq!(address, bm_client.create_deterministic_identity("Again", "correct horse").unwrap());
+        assert_eq!(1, bm_client.identities().len());
+        assert_eq!("Mine", bm_client.identities()[0].label());
+        match bm_client.create_deterministic_identity("Mine", "") {
+            Err(BMError::EmptyPassphrase) => {},
+            _ => panic!("Expected EmptyPassphrase")
+        }
+    }
+
+    #[test]
+    fn test_export_and_import_messages() {
+        let mut bm_client = BMClient::new().unwrap();
+        let me = bm_client.create_identity("Me");
+        let someone = BMClient::new().unwrap().create_identity("Someone else");
+        let received = UNIX_EPOCH + Duration::from_secs(1500000000);
+        bm_client.inbox.add(&InboxMessage::new(&[ 1; 32 ], &someone, Some(&me), "Hi", "From >From\nFrom here").restored(received, true));
+        bm_client.inbox.add(&InboxMessage::new(&[ 2; 32 ], &someone, Some(&me), "Spam", "").restored(received, false));
+        bm_client.trash_message(&[ 2; 32 ]);
+        let ackdata = bm_client.send_message(&me, &someone, "Re: Hi", "Hello", SendOptions::new()).unwrap();
+
+        let mut name = [0u8; 8];
+        OsRng::new().unwrap().fill_bytes(&mut name);
+        let dir = temp_dir().join(format!("bm_client_test_{}", name.to_hex()));
+        let all = [ Folder::Inbox, Folder::Outbox, Folder::Sent, Folder::Trash ];
+        assert_eq!(3, bm_client.export_eml(&all, &dir).unwrap());
+        assert_eq!(2, bm_client.export_mbox(&[ Folder::Inbox, Folder::Outbox ], dir.join("messages.mbox")).unwrap());
+
+        let mut other = BMClient::new().unwrap();
+        other.identities.add(&bm_client.identities.get(&me).unwrap());
+        let stats = other.import_messages(dir.join("messages.mbox")).unwrap();
+        assert_eq!((2, 0), (stats.imported(), stats.skipped()));
+        let stats = other.import_messages(&dir).unwrap();
+        remove_dir_all(&dir).unwrap();
+        assert_eq!((1, 2), (stats.imported(), stats.skipped()));
+
+        let message = other.message(&[ 1; 32 ]).unwrap();
+        assert_eq!((Folder::Inbox, true, received, "From >From\nFrom here"), (message.folder(), message.is_read(), message.timestamp(), message.body()));
+        let message = other.message(&[ 2; 32 ]).unwrap();
+        assert_eq!((Folder::Trash, false), (message.folder(), message.is_read()));
+        // Queued when exported, so it isn't sent a second time
+        let message = other.message(&ackdata).unwrap();
+        assert_eq!((Some(OutboxStatus::Failed), "Re: Hi"), (message.status(), message.subject()));
+        assert_eq!(3, other.messages(&MessageQuery::new()).len());
+
+        match other.import_messages(&dir) {
+            Err(BMError::Archive(_)) => {},
+            _ => panic!("Expected Archive")
+        }
+    }
+
+    #[test]
+    fn test_import_keys_dat() {
+        let mut bm_client = BMClient::new().unwrap();
+        let mine = bm_client.create_identity("Mine");
+        bm_client.create_chan("general").unwrap();
+        let keys_dat = bm_client.export_keys_dat(None).unwrap();
+
+        let mut other = BMClient::new().unwrap();
+        other.create_chan("general").unwrap();
+        let stats = other.import_keys_dat(&keys_dat).unwrap();
+        assert_eq!((1, 1), (stats.imported(), stats.skipped()));
+        assert_eq!(bm_client.identities(), other.identities());
+        assert_eq!(mine, *other.identities()[0].address());
+        match other.import_keys_dat("[BM-2cW67GEKkHGonXKZLCzouLLxnLym3azS8r]\nlabel = general\n") {
+            Err(BMError::KeysDat(_)) => {},
+            _ => panic!("Expected KeysDat")
+        }
+
+        let keys_dat = bm_client.export_keys_dat(Some(&mine)).unwrap();
+        assert!(keys_dat.starts_with(&format!("[{}]\n", mine)));
+        assert_eq!(1, keys_dat.matches("privsigningkey").count());
+        let unknown = other.create_identity("Unknown");
+        match bm_client.export_keys_dat(Some(&unknown)) {
+            Err(BMError::UnknownIdentity(address)) => assert_eq!(unknown, address),
+            _ => panic!("Expected UnknownIdentity")
+        }
+    }
 }
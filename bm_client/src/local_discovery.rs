@@ -0,0 +1,199 @@
+use byteorder::{BigEndian,ReadBytesExt,WriteBytesExt};
+use config::Config;
+use events::{Event,Events};
+use known_nodes::KnownNodes;
+use message::{KnownNode,Message,read_message,write_message};
+use net::PeerAddr;
+use std::io::{self,Cursor,ErrorKind};
+use std::net::{Ipv4Addr,SocketAddr,SocketAddrV4,UdpSocket};
+use std::thread::{Builder,JoinHandle};
+use std::time::{Duration,Instant,SystemTime};
+use stop::{StopSignal,join_until};
+
+const DISCOVERY_PORT: u16 = 8444;
+const ANNOUNCE_INTERVAL_SECS: u64 = 60;
+const MAX_DATAGRAM_SIZE: usize = 1500;
+const LISTEN_TIMEOUT_MILLIS: u64 = 200;
+
+pub struct LocalDiscovery {
+    config: Config,
+    known_nodes: KnownNodes,
+    events: Events,
+    stop_signal: StopSignal,
+    threads: Vec<JoinHandle<()>>
+}
+
+impl LocalDiscovery {
+    pub fn new(config: &Config, known_nodes: &KnownNodes, events: &Events) -> LocalDiscovery {
+        LocalDiscovery {
+            config: config.clone(),
+            known_nodes: known_nodes.clone(),
+            events: events.clone(),
+            stop_signal: StopSignal::new(),
+            threads: vec![]
+        }
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        let announce_socket = try!(UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 0)));
+
+        let stop_signal = StopSignal::new();
+        self.stop_signal = stop_signal.clone();
+
+        let nonce = self.config.nonce();
+        let announcement = create_announcement(self.config.port(), self.config.streams(), nonce);
+        let announce_stop_signal = stop_signal.clone();
+        let announce_thread = try!(Builder::new().name("Local Discovery - announce".to_string()).spawn(move || {
+            if announce_socket.set_broadcast(true).is_err() {
+                return;
+            }
+
+            let broadcast_addr = SocketAddrV4::new(Ipv4Addr::new(255, 255, 255, 255), DISCOVERY_PORT);
+            loop {
+                let _ = announce_socket.send_to(&announcement, broadcast_addr);
+                if !announce_stop_signal.sleep(Duration::from_secs(ANNOUNCE_INTERVAL_SECS)) {
+                    break;
+                }
+            }
+        }));
+        self.threads.push(announce_thread);
+
+        let listen_socket = match UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), DISCOVERY_PORT)) {
+            Ok(socket) => socket,
+            Err(_) => {
+                // Another client on this machine is already listening, so we only announce
+                self.events.emit(Event::Warning(format!("Local discovery not listening - UDP port {} is in use", DISCOVERY_PORT)));
+                return Ok(());
+            }
+        };
+
+        // Wake up regularly to check whether we've been stopped
+        try!(listen_socket.set_read_timeout(Some(Duration::from_millis(LISTEN_TIMEOUT_MILLIS))));
+
+        let mut known_nodes = self.known_nodes.clone();
+        let listen_thread = try!(Builder::new().name("Local Discovery - listen".to_string()).spawn(move || {
+            let mut buffer = [0u8; MAX_DATAGRAM_SIZE];
+            while !stop_signal.is_stopped() {
+                let (length, source) = match listen_socket.recv_from(&mut buffer) {
+                    Ok(received) => received,
+                    Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut => continue,
+                    Err(_) => break
+                };
+
+                for local_node in local_nodes_from_announcement(&buffer[..length], source, nonce) {
+                    known_nodes.add_local_node(&local_node);
+                }
+            }
+        }));
+        self.threads.push(listen_thread);
+
+        Ok(())
+    }
+
+    pub fn stop(&mut self, deadline: Instant) {
+        self.stop_signal.stop(deadline);
+        for thread in self.threads.drain(..) {
+            join_until(thread, deadline);
+        }
+    }
+}
+
+// Our own broadcasts come straight back to us, so the addr message is followed by the nonce
+// that also tells us when we've connected to ourselves
+fn create_announcement(port: u16, streams: &[u32], nonce: u64) -> Vec<u8> {
+    // The address itself is ignored by the receiver, which uses the datagram's source instead
+    let our_nodes = streams.iter().map(|&stream| KnownNode {
+        last_seen: SystemTime::now(),
+        stream: stream,
+        services: 1,
+        peer_addr: PeerAddr::Ip(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port)))
+    }).collect();
+
+    let mut bytes = vec![];
+    write_message(&mut bytes, &Message::Addr { addr_list: our_nodes });
+    bytes.write_u64::<BigEndian>(nonce).unwrap();
+    bytes
+}
+
+// Announcements without a nonce are accepted, as they can only have come from someone else
+fn local_nodes_from_announcement(bytes: &[u8], source: SocketAddr, our_nonce: u64) -> Vec<KnownNode> {
+    let mut cursor = Cursor::new(bytes);
+    let addr_list = match read_message(&mut cursor) {
+        Ok(Message::Addr { addr_list }) => addr_list,
+        _ => return vec![]
+    };
+    if cursor.read_u64::<BigEndian>().ok() == Some(our_nonce) {
+        return vec![];
+    }
+
+    addr_list.into_iter()
+        .filter(|announced| !announced.peer_addr.is_onion())
+        .map(|announced| KnownNode {
+            last_seen: SystemTime::now(),
+            stream: announced.stream,
+            services: announced.services,
+            peer_addr: PeerAddr::Ip(SocketAddr::new(source.ip(), announced.peer_addr.port()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use events::{Event,Events};
+    use known_nodes::KnownNodes;
+    use message::{Message,write_message};
+    use net::{PeerAddr,to_socket_addr};
+    use persist::Persister;
+    use std::net::{Ipv4Addr,SocketAddrV4,UdpSocket};
+    use std::time::{Duration,Instant};
+    use super::{DISCOVERY_PORT,LocalDiscovery,create_announcement,local_nodes_from_announcement};
+
+    #[test]
+    fn test_port_in_use_is_reported() {
+        // If something else already has the port, it's just as much in use
+        let _socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), DISCOVERY_PORT));
+        let events = Events::new();
+        let receiver = events.subscribe();
+        let mut local_discovery = LocalDiscovery::new(&Config::new(), &KnownNodes::new(Persister::new()), &events);
+
+        local_discovery.start().unwrap();
+        local_discovery.stop(Instant::now() + Duration::from_secs(1));
+
+        assert_eq!(Event::Warning("Local discovery not listening - UDP port 8444 is in use".to_string()), receiver.try_recv().unwrap());
+    }
+
+    #[test]
+    fn test_announcement_uses_source_ip_and_announced_port() {
+        let announcement = create_announcement(8555, &[ 1 ], 1);
+        let source = to_socket_addr("192.168.1.20:41234");
+
+        let local_nodes = local_nodes_from_announcement(&announcement, source, 2);
+
+        assert_eq!(1, local_nodes.len());
+        assert_eq!(PeerAddr::Ip(to_socket_addr("192.168.1.20:8555")), local_nodes[0].peer_addr);
+        assert_eq!(1, local_nodes[0].stream);
+        assert_eq!(1, local_nodes[0].services);
+    }
+
+    #[test]
+    fn test_own_announcement_is_ignored() {
+        let announcement = create_announcement(8555, &[ 1 ], 7);
+        let source = to_socket_addr("192.168.1.20:41234");
+
+        assert!(local_nodes_from_announcement(&announcement, source, 7).is_empty());
+        assert_eq!(1, local_nodes_from_announcement(&announcement, source, 8).len());
+        // Clients that don't send a nonce are never us
+        assert_eq!(1, local_nodes_from_announcement(&announcement[..announcement.len() - 8], source, 7).len());
+    }
+
+    #[test]
+    fn test_non_addr_datagram_is_ignored() {
+        let mut bytes = vec![];
+        write_message(&mut bytes, &Message::Verack);
+        let source = to_socket_addr("192.168.1.20:41234");
+
+        assert!(local_nodes_from_announcement(&bytes, source, 0).is_empty());
+        assert!(local_nodes_from_announcement(&[ 1, 2, 3 ], source, 0).is_empty());
+    }
+}
@@ -25,3 +25,12 @@ macro_rules! break_on_none (
         }
     })
 );
+
+macro_rules! return_none_on_none (
+    ($expr:expr) => ({
+        match $expr {
+            Some(val) => val,
+            None => return None
+        }
+    })
+);
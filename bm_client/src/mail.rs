@@ -0,0 +1,425 @@
+use address::Address;
+use messages::StoredMessage;
+use rustc_serialize::base64::{FromBase64,STANDARD,ToBase64};
+use rustc_serialize::hex::ToHex;
+use std::str::FromStr;
+use std::time::{Duration,SystemTime,UNIX_EPOCH};
+
+// Between Bitmessage messages and internet mail. Addresses become BM-...@bitmessage, and only
+// the subject and a plain text body carry over.
+
+pub const MAIL_DOMAIN: &'static str = "bitmessage";
+const MONTHS: [&'static str; 12] = [ "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec" ];
+const WEEKDAYS: [&'static str; 7] = [ "Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat" ];
+const MAX_YEAR: i64 = 9999;
+
+pub fn mail_address(address: &Address) -> String {
+    format!("{}@{}", address, MAIL_DOMAIN)
+}
+
+// From a bare address, <address> or Name <address>
+pub fn parse_mail_address(text: &str) -> Option<Address> {
+    let text = text.trim();
+    let address = match (text.rfind('<'), text.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &text[start + 1..end],
+        _ => text
+    };
+
+    let mut parts = address.trim().rsplitn(2, '@');
+    match (parts.next(), parts.next()) {
+        (Some(domain), Some(local)) if domain.eq_ignore_ascii_case(MAIL_DOMAIN) => Address::from_str(local).ok(),
+        _ => None
+    }
+}
+
+// A message as written by a mail client, reduced to what a msg can carry
+#[derive(Debug,PartialEq)]
+pub struct Mail {
+    pub subject: String,
+    pub body: String,
+    headers: Vec<(String, String)> // with lowercase names
+}
+
+impl Mail {
+    pub fn header(&self, name: &str) -> Option<String> {
+        header(&self.headers, &name.to_lowercase())
+    }
+}
+
+pub fn parse_mail(data: &str) -> Mail {
+    let data = data.replace("\r\n", "\n");
+    let (headers, body) = split_headers(&data);
+    let subject = header(&headers, "subject").map(|subject| decode_words(&subject)).unwrap_or(String::new());
+    Mail {
+        subject: subject,
+        body: text_body(&headers, body).trim_end().to_string(),
+        headers: headers
+    }
+}
+
+pub struct FormattedMail {
+    pub header: String, // with the blank line that ends it
+    pub text: String
+}
+
+impl FormattedMail {
+    pub fn full(&self) -> String {
+        format!("{}{}", self.header, self.text)
+    }
+
+    pub fn size(&self) -> usize {
+        self.header.len() + self.text.len()
+    }
+
+    pub fn lines(&self) -> usize {
+        self.text.matches("\r\n").count()
+    }
+}
+
+pub fn format_mail(message: &StoredMessage) -> FormattedMail {
+    let to = message.to().map(mail_address).unwrap_or("undisclosed-recipients:;".to_string());
+    let header = format!("Message-ID: {}\r\nDate: {}\r\nFrom: {}\r\nTo: {}\r\nSubject: {}\r\n\
+        MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+        message_id(message), mail_date(message.timestamp()), mail_address(message.from()), to, encode_words(message.subject()));
+
+    let mut text = message.body().replace("\r\n", "\n").replace('\n', "\r\n");
+    if !text.ends_with("\r\n") {
+        text.push_str("\r\n");
+    }
+    FormattedMail {
+        header: header,
+        text: text
+    }
+}
+
+pub fn message_id(message: &StoredMessage) -> String {
+    format!("<{}@{}>", message.id().to_hex(), MAIL_DOMAIN)
+}
+
+// As in RFC 5322, always in UTC
+pub fn mail_date(time: SystemTime) -> String {
+    let (year, month, day, days, secs) = civil_time(time);
+    format!("{}, {} {} {} {:02}:{:02}:{:02} +0000", WEEKDAYS[((days + 4) % 7) as usize], day, MONTHS[month - 1], year, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+// As IMAP's INTERNALDATE
+pub fn internal_date(time: SystemTime) -> String {
+    let (year, month, day, _, secs) = civil_time(time);
+    format!("{:02}-{}-{} {:02}:{:02}:{:02} +0000", day, MONTHS[month - 1], year, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+// The date line of an mbox file, as asctime() writes it
+pub fn mbox_date(time: SystemTime) -> String {
+    let (year, month, day, days, secs) = civil_time(time);
+    format!("{} {} {:2} {:02}:{:02}:{:02} {}", WEEKDAYS[((days + 4) % 7) as usize], MONTHS[month - 1], day, secs / 3600, secs % 3600 / 60, secs % 60, year)
+}
+
+// For people to read, in UTC so it's the same wherever it's shown
+pub fn short_date(time: SystemTime) -> String {
+    let (year, month, day, _, secs) = civil_time(time);
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, secs / 3600, secs % 3600 / 60)
+}
+
+// Accepts what RFC 5322 allows: an optional weekday, two digit years and optional seconds.
+// Zones other than numeric offsets are taken as UTC.
+pub fn parse_mail_date(text: &str) -> Option<SystemTime> {
+    let text = match text.find(',') {
+        Some(comma) => &text[comma + 1..],
+        None => text
+    };
+    let parts: Vec<&str> = text.split_whitespace().collect();
+    if parts.len() < 4 {
+        return None;
+    }
+
+    let day: i64 = match parts[0].parse() {
+        Ok(day) => day,
+        Err(_) => return None
+    };
+    let month = match MONTHS.iter().position(|month| month.eq_ignore_ascii_case(parts[1])) {
+        Some(index) => index as i64 + 1,
+        None => return None
+    };
+    let year: i64 = match parts[2].parse() {
+        Ok(year) if year < 0 || year > MAX_YEAR => return None,
+        Ok(year) if year < 50 => year + 2000,
+        Ok(year) if year < 1000 => year + 1900,
+        Ok(year) => year,
+        Err(_) => return None
+    };
+
+    let clock: Vec<i64> = parts[3].split(':').filter_map(|number| number.parse().ok()).collect();
+    let (hours, minutes, seconds) = match clock.len() {
+        2 => (clock[0], clock[1], 0),
+        3 => (clock[0], clock[1], clock[2]),
+        _ => return None
+    };
+    // Numbers out of range could overflow working out the time
+    if day < 1 || day > 31 || hours < 0 || hours > 23 || minutes < 0 || minutes > 59 || seconds < 0 || seconds > 60 {
+        return None;
+    }
+    let offset = match parts.get(4) {
+        Some(zone) if zone.len() == 5 && (zone.starts_with('+') || zone.starts_with('-')) => match zone[1..].parse::<i64>() {
+            Ok(offset) => (offset / 100 * 3600 + offset % 100 * 60) * if zone.starts_with('-') { -1 } else { 1 },
+            Err(_) => return None
+        },
+        _ => 0
+    };
+
+    let unix_secs = days_from_civil(year, month, day) * 86400 + hours * 3600 + minutes * 60 + seconds - offset;
+    if unix_secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(unix_secs as u64))
+}
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    // Mail is never dated before year 0, so eras can be counted with plain division
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// Year, month, day, days since the epoch and seconds into the day
+fn civil_time(time: SystemTime) -> (i64, usize, i64, i64, i64) {
+    let unix_secs = time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0) as i64;
+    let (days, secs) = (unix_secs / 86400, unix_secs % 86400);
+
+    // Counting years from March puts the leap day last
+    let shifted = days + 719468;
+    let era = shifted / 146097;
+    let day_of_era = shifted - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as usize, day, days, secs)
+}
+
+fn split_headers(data: &str) -> (Vec<(String, String)>, &str) {
+    let (head, body) = match data.find("\n\n") {
+        Some(end) => (&data[..end], &data[end + 2..]),
+        None if data.starts_with('\n') => ("", &data[1..]),
+        None => (data, "")
+    };
+
+    // Folded lines carry on the header above
+    let mut headers: Vec<(String, String)> = vec![];
+    for line in head.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(last) = headers.last_mut() {
+                last.1.push(' ');
+                last.1.push_str(line.trim());
+            }
+        } else if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].trim().to_lowercase(), line[colon + 1..].trim().to_string()));
+        }
+    }
+    (headers, body)
+}
+
+fn header(headers: &[(String, String)], name: &str) -> Option<String> {
+    headers.iter().find(|&&(ref header_name, _)| header_name == name).map(|&(_, ref value)| value.clone())
+}
+
+// A parameter of a header such as Content-Type
+fn parameter(value: &str, name: &str) -> Option<String> {
+    value.split(';').skip(1).filter_map(|part| {
+        let mut pair = part.splitn(2, '=');
+        match (pair.next(), pair.next()) {
+            (Some(key), Some(value)) if key.trim().eq_ignore_ascii_case(name) => Some(value.trim().trim_matches('"').to_string()),
+            _ => None
+        }
+    }).next()
+}
+
+// The first plain text part, since a msg has nowhere to put anything else
+fn text_body(headers: &[(String, String)], body: &str) -> String {
+    let content_type = header(headers, "content-type").unwrap_or("text/plain".to_string());
+    let media_type = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+
+    if media_type.starts_with("multipart/") {
+        let boundary = match parameter(&content_type, "boundary") {
+            Some(boundary) => format!("--{}", boundary),
+            None => return String::new()
+        };
+        let mut parts = vec![];
+        for part in body.split(&boundary[..]).skip(1) {
+            if part.starts_with("--") {
+                break;
+            }
+            let part = if part.starts_with('\n') { &part[1..] } else { part };
+            let (part_headers, part_body) = split_headers(part);
+            parts.push((part_headers, part_body));
+        }
+        let is_text = |part_headers: &[(String, String)]| header(part_headers, "content-type")
+            .map_or(true, |part_type| part_type.to_lowercase().starts_with("text/plain"));
+        return match parts.iter().find(|&&(ref part_headers, _)| is_text(part_headers)) {
+            Some(&(ref part_headers, part_body)) => text_body(part_headers, part_body),
+            None => parts.iter().next().map(|&(ref part_headers, part_body)| text_body(part_headers, part_body)).unwrap_or(String::new())
+        };
+    }
+
+    let encoding = header(headers, "content-transfer-encoding").unwrap_or(String::new()).to_lowercase();
+    let decoded = match &encoding[..] {
+        "quoted-printable" => String::from_utf8_lossy(&decode_quoted_printable(body)).into_owned(),
+        "base64" => body.replace('\n', "").from_base64().map(|bytes| String::from_utf8_lossy(&bytes).into_owned()).unwrap_or(String::new()),
+        _ => body.to_string()
+    };
+    match parameter(&content_type, "format").map(|format| format.to_lowercase()) {
+        Some(ref format) if format == "flowed" => unflow(&decoded, parameter(&content_type, "delsp").map_or(false, |delsp| delsp.eq_ignore_ascii_case("yes"))),
+        _ => decoded
+    }
+}
+
+fn decode_quoted_printable(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut decoded = vec![];
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'=' if bytes.get(index + 1) == Some(&b'\n') => index += 2,
+            b'=' if index + 2 < bytes.len() => match (hex_value(bytes[index + 1]), hex_value(bytes[index + 2])) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high * 16 + low);
+                    index += 3;
+                },
+                _ => {
+                    decoded.push(b'=');
+                    index += 1;
+                }
+            },
+            byte => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    decoded
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|value| value as u8)
+}
+
+// Lines ending in a space were wrapped by the mail client, and join up again (RFC 3676)
+fn unflow(text: &str, delete_space: bool) -> String {
+    let mut unflowed = String::new();
+    for line in text.split('\n') {
+        let line = if line.starts_with(' ') { &line[1..] } else { line };
+        if line.ends_with(' ') && line != "-- " {
+            unflowed.push_str(if delete_space { &line[..line.len() - 1] } else { line });
+        } else {
+            unflowed.push_str(line);
+            unflowed.push('\n');
+        }
+    }
+    unflowed
+}
+
+// RFC 2047 encoded words, as mail clients send non-ASCII subjects
+fn decode_words(text: &str) -> String {
+    let mut decoded = String::new();
+    let mut rest = text;
+    let mut after_word = false;
+    while let Some(start) = rest.find("=?") {
+        let word = &rest[start..];
+        let parts: Vec<&str> = word[2..].splitn(4, '?').collect();
+        let decoded_word = match parts.len() {
+            4 if parts[3].starts_with('=') => match &parts[1].to_lowercase()[..] {
+                "b" => parts[2].from_base64().ok(),
+                "q" => Some(decode_quoted_printable(&parts[2].replace('_', " "))),
+                _ => None
+            },
+            _ => None
+        };
+        match decoded_word {
+            Some(bytes) => {
+                // Whitespace between encoded words is only there to separate them
+                let between = &rest[..start];
+                if !(after_word && between.trim().is_empty()) {
+                    decoded.push_str(between);
+                }
+                decoded.push_str(&String::from_utf8_lossy(&bytes));
+                let length = 2 + parts[0].len() + 1 + parts[1].len() + 1 + parts[2].len() + 2;
+                rest = &rest[start + length..];
+                after_word = true;
+            },
+            None => {
+                decoded.push_str(&rest[..start + 2]);
+                rest = &rest[start + 2..];
+                after_word = false;
+            }
+        }
+    }
+    decoded.push_str(rest);
+    decoded
+}
+
+pub fn encode_words(text: &str) -> String {
+    if text.chars().all(|c| c.is_ascii() && !c.is_control()) {
+        text.to_string()
+    } else {
+        format!("=?utf-8?B?{}?=", text.as_bytes().to_base64(STANDARD))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use address::Address;
+    use std::str::FromStr;
+    use std::time::{Duration,UNIX_EPOCH};
+    use super::{decode_words,encode_words,internal_date,mail_date,mbox_date,parse_mail,parse_mail_address,parse_mail_date,short_date};
+
+    #[test]
+    fn test_addresses() {
+        let address = Address::from_str("BM-2cXxfcSetKnbHJX2Y85rSkaVpsdNUZ5q9h").unwrap();
+        assert_eq!(Some(address.clone()), parse_mail_address("BM-2cXxfcSetKnbHJX2Y85rSkaVpsdNUZ5q9h@bitmessage"));
+        assert_eq!(Some(address), parse_mail_address("Friend <BM-2cXxfcSetKnbHJX2Y85rSkaVpsdNUZ5q9h@Bitmessage>"));
+        assert_eq!(None, parse_mail_address("BM-2cXxfcSetKnbHJX2Y85rSkaVpsdNUZ5q9h@example.com"));
+        assert_eq!(None, parse_mail_address("someone@bitmessage"));
+    }
+
+    #[test]
+    fn test_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(951827640);
+        assert_eq!("Tue, 29 Feb 2000 12:34:00 +0000", mail_date(time));
+        assert_eq!("29-Feb-2000 12:34:00 +0000", internal_date(time));
+        assert_eq!("Tue Feb 29 12:34:00 2000", mbox_date(time));
+        assert_eq!("2000-02-29 12:34", short_date(time));
+        assert_eq!(Some(time), parse_mail_date(&mail_date(time)));
+        assert_eq!(Some(time), parse_mail_date("29 Feb 00 14:34 +0200"));
+        assert_eq!(Some(time), parse_mail_date("Tue, 29 Feb 2000 07:34:00 -0500 (EST)"));
+        assert_eq!(None, parse_mail_date("yesterday"));
+        assert_eq!(None, parse_mail_date("1 Jan 99999999999999999 00:00:00 +0000"));
+        assert_eq!(None, parse_mail_date("1 Jan -99999999999999999 00:00:00 +0000"));
+        assert_eq!(None, parse_mail_date("99999999999999999 Jan 2000 00:00:00 +0000"));
+        assert_eq!(None, parse_mail_date("1 Jan 2000 99999999999999999:00:00 +0000"));
+    }
+
+    #[test]
+    fn test_encoded_words() {
+        assert_eq!("Grüße aus Köln", decode_words("=?UTF-8?Q?Gr=C3=BC=C3=9Fe?= =?UTF-8?B?IGF1cyBLw7Zsbg==?="));
+        assert_eq!("Grüße", decode_words(&encode_words("Grüße")));
+        assert_eq!("Plain =? text", decode_words("Plain =? text"));
+    }
+
+    #[test]
+    fn test_parse_mail() {
+        let plain = "From: a@bitmessage\r\nSubject: Hello\r\n there\r\nContent-Type: text/plain; charset=utf-8; format=flowed\r\n\
+            Content-Transfer-Encoding: quoted-printable\r\n\r\nA long line that was =\r\nwrapped and a flowed \r\nline, caf=C3=A9\r\n";
+        let mail = parse_mail(plain);
+        assert_eq!(("Hello there", "A long line that was wrapped and a flowed line, café"), (&mail.subject[..], &mail.body[..]));
+        assert_eq!(Some("a@bitmessage".to_string()), mail.header("From"));
+
+        let multipart = "Subject: Parts\r\nContent-Type: multipart/alternative; boundary=\"xyz\"\r\n\r\nPreamble\r\n--xyz\r\n\
+            Content-Type: text/html\r\n\r\n<p>Hi</p>\r\n--xyz\r\nContent-Type: text/plain\r\nContent-Transfer-Encoding: base64\r\n\r\nSGk=\r\n--xyz--\r\n";
+        let mail = parse_mail(multipart);
+        assert_eq!(("Parts", "Hi"), (&mail.subject[..], &mail.body[..]));
+    }
+}
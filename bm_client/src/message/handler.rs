@@ -1,7 +1,7 @@
 use super::Message;
 use super::responder::{MessageResponder,ResponderError};
 use super::verify::{MessageVerifier,MessageVerifierError};
-use std::sync::mpsc::SendError;
+use std::sync::mpsc::{Receiver,SendError};
 
 pub enum MessageHandlingError {
     VerificationError,
@@ -39,6 +39,10 @@ impl MessageHandler {
         self.message_responder.send_version(f)
     }
 
+    pub fn watch_inventory(&self) -> Receiver<Message> {
+        self.message_responder.watch_inventory()
+    }
+
     pub fn handle<F>(&mut self, message: Message, send: F) -> Result<(), MessageHandlingError>
         where F : Fn(Message) -> Result<(), SendError<Message>>
     {
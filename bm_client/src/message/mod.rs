@@ -10,22 +10,29 @@ pub use self::read::ParseError;
 pub use self::handler::MessageHandler;
 pub use self::responder::MessageResponder;
 pub use self::verify::MessageVerifier;
-pub use self::read::read_message;
-pub use self::write::write_message;
+pub use self::read::{read_broadcast_content,read_message,read_msg_content,read_pubkey_content,read_var_int};
+pub use self::write::{write_broadcast_content,write_message,write_msg_content,write_object_header,write_pubkey_content,write_var_int_64};
+pub use self::write::{write_unsigned_broadcast_content,write_unsigned_msg_content,write_unsigned_pubkey_content};
 pub use self::sender::Sender;
 pub use self::sender::MessageSendError;
+pub use self::pow::{GenerateError,NETWORK_EXTRA_BYTES,NETWORK_TRIALS_PER_BYTE,PowBenchmark,benchmark_pow};
 
 use channel::MemorySize;
+use net::PeerAddr;
 use std::mem;
-use std::net::SocketAddr;
 use std::time::SystemTime;
 
 const MAGIC: u32 = 0xe9beb4d9;
 const MAX_PAYLOAD_LENGTH: u32 = 1600003;
 const MAX_NODES_COUNT: usize = 1000;
 const MAX_GETDATA_COUNT: usize = 50000;
-const MAX_INV_COUNT: usize = 50000;
-const MAX_PAYLOAD_LENGTH_FOR_OBJECT: u32 = 262144; // 2^18 - maximum object length
+pub const MAX_INV_COUNT: usize = 50000;
+pub const MAX_PAYLOAD_LENGTH_FOR_OBJECT: u32 = 262144; // 2^18 - maximum object length
+
+pub const OBJECT_GETPUBKEY: u32 = 0;
+pub const OBJECT_PUBKEY: u32 = 1;
+pub const OBJECT_MSG: u32 = 2;
+pub const OBJECT_BROADCAST: u32 = 3;
 // const MAX_TTL: u32 = 2430000; // 28 days and 3 hours
 // const OBJECT_EXPIRY_CUTOFF: i64 = -3600; // 1 hour ago
 
@@ -39,7 +46,7 @@ pub struct KnownNode {
     pub last_seen: SystemTime,
     pub stream: u32,
     pub services: u64,
-    pub socket_addr: SocketAddr
+    pub peer_addr: PeerAddr
 }
 
 #[derive(Clone,Debug,PartialEq)]
@@ -97,8 +104,8 @@ pub struct VersionData {
     version: u32,
     services: u64,
     timestamp: SystemTime,
-    addr_recv: SocketAddr,
-    addr_from: SocketAddr,
+    addr_recv: PeerAddr,
+    addr_from: PeerAddr,
     nonce: u64,
     user_agent: String,
     streams: Vec<u64>
@@ -113,6 +120,78 @@ pub struct ObjectData {
     object: Object
 }
 
+impl ObjectData {
+    // The nonce is filled in when the proof of work is done
+    pub fn new(expiry: SystemTime, version: u64, stream: u32, object: Object) -> ObjectData {
+        ObjectData {
+            nonce: 0,
+            expiry: expiry,
+            version: version,
+            stream: stream,
+            object: object
+        }
+    }
+
+    pub fn expiry(&self) -> SystemTime {
+        self.expiry
+    }
+
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn stream(&self) -> u32 {
+        self.stream
+    }
+
+    pub fn object(&self) -> &Object {
+        &self.object
+    }
+}
+
+// The plaintext inside a v4 pubkey object, once decrypted with the address tag key
+#[derive(Clone,Debug,PartialEq)]
+pub struct PubKeyContent {
+    pub behaviour_bitfield: u32,
+    pub public_signing_key: Vec<u8>, // 64 bytes
+    pub public_encryption_key: Vec<u8>, // 64 bytes
+    pub nonce_trials_per_byte: u64,
+    pub extra_bytes: u64,
+    pub signature: Vec<u8>
+}
+
+// The plaintext inside a msg object, once decrypted by the recipient
+#[derive(Clone,Debug,PartialEq)]
+pub struct MsgContent {
+    pub address_version: u64,
+    pub stream: u32,
+    pub behaviour_bitfield: u32,
+    pub public_signing_key: Vec<u8>, // 64 bytes
+    pub public_encryption_key: Vec<u8>, // 64 bytes
+    pub nonce_trials_per_byte: u64, // not sent by v2 addresses
+    pub extra_bytes: u64, // not sent by v2 addresses
+    pub destination_ripe: Vec<u8>, // 20 bytes
+    pub encoding: u64,
+    pub message: Vec<u8>,
+    pub ack_data: Vec<u8>,
+    pub signature: Vec<u8>
+}
+
+// The plaintext inside a broadcast object, once decrypted with the sender's address
+#[derive(Clone,Debug,PartialEq)]
+pub struct BroadcastContent {
+    pub address_version: u64,
+    pub stream: u32,
+    pub behaviour_bitfield: u32,
+    pub public_signing_key: Vec<u8>, // 64 bytes
+    pub public_encryption_key: Vec<u8>, // 64 bytes
+    pub nonce_trials_per_byte: u64, // not sent by v2 addresses
+    pub extra_bytes: u64, // not sent by v2 addresses
+    pub encoding: u64,
+    pub message: Vec<u8>,
+    pub signature: Vec<u8>
+}
+
 #[derive(Clone,Debug,PartialEq)]
 pub enum Message {
     Addr {
@@ -146,12 +225,12 @@ impl MemorySize for Message {
 
 #[cfg(test)]
 mod tests {
-    use net::to_socket_addr;
+    use net::{OnionAddr,PeerAddr,to_socket_addr};
     use rand::{Rng,SeedableRng,XorShiftRng};
     use std::io::Cursor;
     use std::time::{Duration,UNIX_EPOCH};
-    use super::{InventoryVector,KnownNode,Message,Object,GetPubKey,ObjectData,VersionData};
-    use super::{read_message,write_message};
+    use super::{InventoryVector,KnownNode,Message,Object,GetPubKey,ObjectData,VersionData,BroadcastContent,MsgContent};
+    use super::{read_broadcast_content,read_message,read_msg_content,write_broadcast_content,write_message,write_msg_content};
 
     #[test]
     fn test_addr() {
@@ -161,13 +240,13 @@ mod tests {
                     last_seen: UNIX_EPOCH + Duration::from_secs(0x908070605),
                     stream: 2,
                     services: 3,
-                    socket_addr: to_socket_addr("12.13.14.15:1617")
+                    peer_addr: PeerAddr::Ip(to_socket_addr("12.13.14.15:1617"))
                 },
                 KnownNode {
                     last_seen: UNIX_EPOCH + Duration::from_secs(0x1918171615),
                     stream: 4,
                     services: 5,
-                    socket_addr: to_socket_addr("22.23.24.25:2627")
+                    peer_addr: PeerAddr::Ip(to_socket_addr("22.23.24.25:2627"))
                 }
             ]
         };
@@ -194,6 +273,38 @@ mod tests {
         run_message_read_write_test(message, expected);
     }
 
+    #[test]
+    fn test_addr_onion() {
+        let onion_id = [ 0x4f, 0x5b, 0xf2, 0x23, 0x0f, 0x86, 0xe9, 0xc6, 0x64, 0x11 ];
+        let message = Message::Addr {
+            addr_list: vec![
+                KnownNode {
+                    last_seen: UNIX_EPOCH + Duration::from_secs(0x908070605),
+                    stream: 1,
+                    services: 1,
+                    peer_addr: PeerAddr::Onion(OnionAddr::new(onion_id, 8444))
+                }
+            ]
+        };
+
+        let expected = vec![
+            0xe9, 0xbe, 0xb4, 0xd9, // magic
+            97, 100, 100, 114, // "addr"
+            0, 0, 0, 0, 0, 0, 0, 0, // command padding
+            0, 0, 0, 39, // payload length
+            15, 51, 191, 127, // checksum
+            1, // count
+            0, 0, 0, 9, 8, 7, 6, 5, // last_seen
+            0, 0, 0, 1, // stream
+            0, 0, 0, 0, 0, 0, 0, 1, // services
+            0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43, // OnionCat prefix
+            0x4f, 0x5b, 0xf2, 0x23, 0x0f, 0x86, 0xe9, 0xc6, 0x64, 0x11, // onion id
+            32, 252 // port
+        ];
+
+        run_message_read_write_test(message, expected);
+    }
+
     #[test]
     fn test_getdata() {
         let mut rng: XorShiftRng = SeedableRng::from_seed([0, 0, 0, 1]);
@@ -262,8 +373,8 @@ mod tests {
             version: 3,
             services: 1,
             timestamp: UNIX_EPOCH + Duration::from_secs(0x504030201),
-            addr_recv: to_socket_addr("127.0.0.1:8444"),
-            addr_from: to_socket_addr("11.22.33.44:8555"),
+            addr_recv: PeerAddr::Ip(to_socket_addr("127.0.0.1:8444")),
+            addr_from: PeerAddr::Ip(to_socket_addr("11.22.33.44:8555")),
             nonce: 0x12345678,
             user_agent: "Rubbem".to_string(),
             streams: vec![ 1 ]
@@ -341,6 +452,55 @@ mod tests {
         run_message_read_write_test(message, expected);
     }
 
+    #[test]
+    fn test_msg_content() {
+        let content = MsgContent {
+            address_version: 4,
+            stream: 1,
+            behaviour_bitfield: 1,
+            public_signing_key: vec![ 2; 64 ],
+            public_encryption_key: vec![ 3; 64 ],
+            nonce_trials_per_byte: 1000,
+            extra_bytes: 1000,
+            destination_ripe: vec![ 4; 20 ],
+            encoding: 2,
+            message: b"Subject:Hi\nBody:Hello".to_vec(),
+            ack_data: vec![],
+            signature: vec![ 5; 3 ]
+        };
+
+        let mut output = vec![];
+        write_msg_content(&mut output, &content);
+
+        assert_eq!(&[ 4, 1, 0, 0, 0, 1, 2 ], &output[0..7]);
+        assert_eq!(&[ 0xfd, 0x03, 0xe8, 0xfd, 0x03, 0xe8, 4 ], &output[134..141]);
+        assert_eq!(content, read_msg_content(&output).unwrap());
+        assert!(read_msg_content(&output[0..output.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_broadcast_content_from_v2_address() {
+        let content = BroadcastContent {
+            address_version: 2,
+            stream: 1,
+            behaviour_bitfield: 1,
+            public_signing_key: vec![ 2; 64 ],
+            public_encryption_key: vec![ 3; 64 ],
+            nonce_trials_per_byte: 0,
+            extra_bytes: 0,
+            encoding: 2,
+            message: b"Subject:Hi\nBody:Hello".to_vec(),
+            signature: vec![ 5; 3 ]
+        };
+
+        let mut output = vec![];
+        write_broadcast_content(&mut output, &content);
+
+        assert_eq!(&[ 2, 21 ], &output[134..136]);
+        assert_eq!(content, read_broadcast_content(&output).unwrap());
+        assert!(read_broadcast_content(&output[0..output.len() - 1]).is_err());
+    }
+
     fn run_message_read_write_test(message: Message, expected: Vec<u8>) {
         let mut output = vec![];
         write_message(&mut output, &message);
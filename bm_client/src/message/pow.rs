@@ -1,12 +1,23 @@
 use byteorder::{BigEndian,ReadBytesExt,WriteBytesExt};
 use checksum::sha512_hash;
-use message::{ObjectData,MAX_PAYLOAD_LENGTH_FOR_OBJECT};
+use message::{Object,ObjectData,MAX_PAYLOAD_LENGTH_FOR_OBJECT};
 use message::write::write_object_message_data;
 use std::cmp::max;
+use std::error::Error;
+use std::fmt;
 use std::io::Cursor;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool,AtomicU64,Ordering};
+use std::sync::mpsc::{RecvTimeoutError,channel};
+use std::thread;
+use std::time::{Duration,Instant,SystemTime};
 use timegen::{TimeType,get_time};
 
+pub const NETWORK_TRIALS_PER_BYTE: u64 = 1000;
+pub const NETWORK_EXTRA_BYTES: u64 = 1000;
+const PROGRESS_BATCH: u64 = 0x10000;
+const PROGRESS_INTERVAL_MILLIS: u64 = 500;
+
 #[derive(Debug,PartialEq)]
 pub enum TimeToLiveError {
     ObjectAlreadyDied,
@@ -27,6 +38,30 @@ pub enum VerifyError {
     UnacceptableProof
 }
 
+impl fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &GenerateError::ObjectAlreadyDied => write!(f, "object has already expired"),
+            &GenerateError::ObjectLivesTooLong => write!(f, "object lives too long"),
+            &GenerateError::NoProofFound => write!(f, "no nonce meets the target")
+        }
+    }
+}
+
+impl Error for GenerateError {}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &VerifyError::ObjectAlreadyDied => write!(f, "object has already expired"),
+            &VerifyError::ObjectLivesTooLong => write!(f, "object lives too long"),
+            &VerifyError::UnacceptableProof => write!(f, "proof of work does not meet the target")
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
 impl From<TimeToLiveError> for GenerateError {
     fn from(err: TimeToLiveError) -> GenerateError {
         match err {
@@ -45,6 +80,7 @@ impl From<TimeToLiveError> for VerifyError {
     }
 }
 
+#[derive(Clone)]
 pub struct ProofOfWorkConfig {
     trials_per_byte: u64,
     extra_bytes: u64,
@@ -55,26 +91,45 @@ pub struct ProofOfWorkConfig {
 
 pub fn network_pow_config() -> ProofOfWorkConfig {
     ProofOfWorkConfig {
-        trials_per_byte: 1000,
-        extra_bytes: 1000,
+        trials_per_byte: NETWORK_TRIALS_PER_BYTE,
+        extra_bytes: NETWORK_EXTRA_BYTES,
         minimum_ttl: -3600, // 1 hour ago
         maximum_ttl: 2430000, // 28 days and 3 hours
         tide_ttl: 300 // 5 minutes
     }
 }
 
+// The network TTL limits, with the difficulty demanded by a recipient
+pub fn pow_config_with_difficulty(trials_per_byte: u64, extra_bytes: u64) -> ProofOfWorkConfig {
+    ProofOfWorkConfig {
+        trials_per_byte: trials_per_byte,
+        extra_bytes: extra_bytes,
+        .. network_pow_config()
+    }
+}
+
 pub struct ProofOfWork {
-    time_type: TimeType
+    time_type: TimeType,
+    thread_count: usize
 }
 
 impl ProofOfWork {
     pub fn new(time_type: TimeType) -> ProofOfWork {
+        ProofOfWork::with_threads(time_type, 1)
+    }
+
+    pub fn with_threads(time_type: TimeType, thread_count: usize) -> ProofOfWork {
+        assert!(thread_count > 0);
         ProofOfWork {
-            time_type: time_type
+            time_type: time_type,
+            thread_count: thread_count
         }
     }
 
-    pub fn generate(&self, object_data: &ObjectData, pow_config: ProofOfWorkConfig) -> Result<u64, GenerateError> {
+    // Progress is reported as (trials so far, expected number of trials)
+    pub fn generate<F>(&self, object_data: &ObjectData, pow_config: ProofOfWorkConfig, mut progress: F) -> Result<u64, GenerateError>
+        where F: FnMut(u64, u64)
+    {
         assert!(pow_config.tide_ttl > 0);
         assert!(pow_config.trials_per_byte > 0);
 
@@ -87,7 +142,12 @@ impl ProofOfWork {
         let expiry = object_data.expiry;
         let target = try!(self.target(payload_with_nonce_length, expiry, pow_config));
 
-        generate_pow_given_target(payload_without_nonce, target)
+        let expected_trials = u64::max_value() / max(target, 1);
+        let mut report = |trials| progress(trials, expected_trials);
+        match self.thread_count {
+            1 => generate_pow_given_target(payload_without_nonce, target, &mut report),
+            thread_count => generate_pow_in_parallel(payload_without_nonce, target, thread_count, &mut report)
+        }
     }
 
     pub fn verify(&self, object_data: &ObjectData, pow_config: ProofOfWorkConfig) -> Result<(), VerifyError> {
@@ -154,14 +214,104 @@ impl ProofOfWork {
     }
 }
 
+// How long the proof of work for a msg of the given size and TTL takes on this machine
+#[derive(Clone,Debug)]
+pub struct PowBenchmark {
+    trials: u64,
+    expected_trials: u64,
+    elapsed: Duration
+}
+
+impl PowBenchmark {
+    // Counted in batches, so slightly fewer than were really made
+    pub fn trials(&self) -> u64 {
+        self.trials
+    }
+
+    pub fn expected_trials(&self) -> u64 {
+        self.expected_trials
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    pub fn trials_per_second(&self) -> u64 {
+        let millis = self.elapsed.as_secs() * 1000 + self.elapsed.subsec_millis() as u64;
+        self.trials * 1000 / max(millis, 1)
+    }
+}
+
+pub fn benchmark_pow(thread_count: usize, payload_length: usize, ttl: Duration) -> Result<PowBenchmark, GenerateError> {
+    run_benchmark(thread_count, payload_length, ttl, network_pow_config())
+}
+
+fn run_benchmark(thread_count: usize, payload_length: usize, ttl: Duration, pow_config: ProofOfWorkConfig) -> Result<PowBenchmark, GenerateError> {
+    let object_data = ObjectData::new(SystemTime::now() + ttl, 1, 1, Object::Msg { encrypted: vec![0; payload_length] });
+    let pow = ProofOfWork::with_threads(TimeType::Real, thread_count);
+    let target = try!(pow.target(payload_with_nonce(&object_data).len() as u32, object_data.expiry, pow_config.clone()));
+
+    let mut trials = 0;
+    let start = Instant::now();
+    try!(pow.generate(&object_data, pow_config, |so_far, _| trials = so_far));
+
+    Ok(PowBenchmark {
+        trials: trials,
+        expected_trials: u64::max_value() / max(target, 1),
+        elapsed: start.elapsed()
+    })
+}
+
 fn payload_with_nonce(object_data: &ObjectData) -> Vec<u8> {
     let mut output = vec![];
     write_object_message_data(&mut output, object_data);
     output
 }
 
-fn generate_pow_given_target(payload: &[u8], target: u64) -> Result<u64, GenerateError> {
+fn generate_pow_given_target(payload: &[u8], target: u64, progress: &mut dyn FnMut(u64)) -> Result<u64, GenerateError> {
     let initial_hash = sha512_hash(payload);
+    let mut trials = 0;
+    search_nonces(&initial_hash, target, 0, 1, &AtomicBool::new(false), &mut |batch| {
+        trials += batch;
+        progress(trials);
+    })
+}
+
+// Each thread tries every thread_count-th nonce; the first to succeed stops the others
+fn generate_pow_in_parallel(payload: &[u8], target: u64, thread_count: usize, progress: &mut dyn FnMut(u64)) -> Result<u64, GenerateError> {
+    let initial_hash = Arc::new(sha512_hash(payload));
+    let found = Arc::new(AtomicBool::new(false));
+    let trials = Arc::new(AtomicU64::new(0));
+    let (result_tx, result_rx) = channel();
+
+    for first_nonce in 0..thread_count {
+        let initial_hash = initial_hash.clone();
+        let found = found.clone();
+        let trials = trials.clone();
+        let result_tx = result_tx.clone();
+        thread::spawn(move || {
+            let result = search_nonces(&initial_hash[..], target, first_nonce as u64, thread_count as u64, &found,
+                &mut |batch| { trials.fetch_add(batch, Ordering::Relaxed); });
+            let _ = result_tx.send(result);
+        });
+    }
+    drop(result_tx);
+
+    loop {
+        match result_rx.recv_timeout(Duration::from_millis(PROGRESS_INTERVAL_MILLIS)) {
+            Ok(Ok(nonce)) => {
+                found.store(true, Ordering::Relaxed);
+                return Ok(nonce);
+            },
+            Ok(Err(_)) => {},
+            Err(RecvTimeoutError::Timeout) => progress(trials.load(Ordering::Relaxed)),
+            Err(RecvTimeoutError::Disconnected) => return Err(GenerateError::NoProofFound)
+        }
+    }
+}
+
+// Calls report with the number of trials made every PROGRESS_BATCH trials
+fn search_nonces(initial_hash: &[u8], target: u64, first_nonce: u64, step: u64, found: &AtomicBool, report: &mut dyn FnMut(u64)) -> Result<u64, GenerateError> {
     assert!(initial_hash.len() == 64);
 
     let mut input = [0u8; 72];
@@ -171,7 +321,18 @@ fn generate_pow_given_target(payload: &[u8], target: u64) -> Result<u64, Generat
 
     let mut nonce_cursor = Cursor::new(Vec::<u8>::with_capacity(8));
 
-    for nonce in 0u64..u64::max_value() {
+    let mut nonce = first_nonce;
+    let mut batch_trials = 0;
+    while nonce < u64::max_value() - step {
+        if batch_trials == PROGRESS_BATCH {
+            if found.load(Ordering::Relaxed) {
+                break;
+            }
+            report(batch_trials);
+            batch_trials = 0;
+        }
+        batch_trials += 1;
+
         nonce_cursor.set_position(0);
         nonce_cursor.write_u64::<BigEndian>(nonce).unwrap();
         let nonce_bytes: &Vec<u8> = nonce_cursor.get_ref();
@@ -182,8 +343,11 @@ fn generate_pow_given_target(payload: &[u8], target: u64) -> Result<u64, Generat
         let trial_value = first_8_of_double_digest(&input);
 
         if trial_value <= target {
+            found.store(true, Ordering::Relaxed);
             return Ok(nonce);
         }
+
+        nonce += step;
     }
 
     return Err(GenerateError::NoProofFound);
@@ -221,23 +385,52 @@ fn fit_in_i64(value: u64, error: TimeToLiveError) -> Result<i64, TimeToLiveError
 
 #[cfg(test)]
 mod tests {
-    use super::generate_pow_given_target;
-    use super::target_from_ttl;
+    use super::{generate_pow_given_target,generate_pow_in_parallel,first_8_of_double_digest,search_nonces};
+    use super::PROGRESS_BATCH;
+    use super::{pow_config_with_difficulty,run_benchmark,target_from_ttl};
+    use byteorder::{BigEndian,WriteBytesExt};
+    use checksum::sha512_hash;
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
 
     #[test]
     fn test_generate_pow_given_target() {
         let payload = [ 0, 1, 2, 3 ];
-        let pow = generate_pow_given_target(&payload, 100000000000000000).unwrap();
+        let pow = generate_pow_given_target(&payload, 100000000000000000, &mut |_| {}).unwrap();
         assert_eq!(290, pow);
     }
 
     #[test]
     fn test_generate_pow_given_smaller_target() {
         let payload = [ 0, 1, 2, 3 ];
-        let pow = generate_pow_given_target(&payload, 1000000000000000).unwrap();
+        let pow = generate_pow_given_target(&payload, 1000000000000000, &mut |_| {}).unwrap();
         assert_eq!(2904, pow);
     }
 
+    #[test]
+    fn test_generate_pow_in_parallel_meets_target() {
+        let payload = [ 0, 1, 2, 3 ];
+        let target = 1000000000000000;
+        let nonce = generate_pow_in_parallel(&payload, target, 4, &mut |_| {}).unwrap();
+
+        let mut input = vec![];
+        input.write_u64::<BigEndian>(nonce).unwrap();
+        input.extend(sha512_hash(&payload).to_vec());
+        assert!(first_8_of_double_digest(&input) <= target);
+    }
+
+    #[test]
+    fn test_search_reports_progress() {
+        let initial_hash = sha512_hash(&[ 0, 1, 2, 3 ]);
+        let first_nonce = 840501 - PROGRESS_BATCH - 5;
+        let mut reported = vec![];
+        let nonce = search_nonces(&initial_hash, 100000000000000, first_nonce, 1, &AtomicBool::new(false),
+            &mut |trials| reported.push(trials)).unwrap();
+
+        assert_eq!(840501, nonce);
+        assert_eq!(vec![ PROGRESS_BATCH ], reported);
+    }
+
     #[test]
     fn test_get_target_100_bytes() {
         let target = target_from_ttl(100, 345600, 1000, 2000);
@@ -253,4 +446,12 @@ mod tests {
 
         assert_eq!(expected, target);
     }
+
+    #[test]
+    fn test_benchmark() {
+        let benchmark = run_benchmark(2, 100, Duration::from_secs(3600), pow_config_with_difficulty(10, 10)).unwrap();
+
+        assert!(benchmark.expected_trials() > 0);
+        assert!(benchmark.elapsed() < Duration::from_secs(10));
+    }
 }
@@ -3,12 +3,16 @@ use byteorder::ReadBytesExt;
 use checksum::sha512_checksum;
 use encoding::{DecoderTrap,Encoding};
 use encoding::all::ASCII;
+use net::{ONION_PREFIX,OnionAddr,PeerAddr};
+use std::error::Error;
+use std::fmt;
 use std::io::{Cursor,Read};
 use std::net::{Ipv6Addr,SocketAddr,SocketAddrV4,SocketAddrV6};
 use std::time::{Duration,SystemTime,UNIX_EPOCH};
 
-use super::{InventoryVector,KnownNode,GetPubKey,PubKey,Broadcast,Object,Message,ObjectData,VersionData};
-use super::{MAGIC,MAX_GETDATA_COUNT,MAX_INV_COUNT,MAX_NODES_COUNT,MAX_PAYLOAD_LENGTH};
+use super::{InventoryVector,KnownNode,GetPubKey,PubKey,Broadcast,Object,Message,ObjectData,VersionData,BroadcastContent,MsgContent,PubKeyContent};
+use super::{MAGIC,MAX_GETDATA_COUNT,MAX_INV_COUNT,MAX_NODES_COUNT,MAX_PAYLOAD_LENGTH,MAX_PAYLOAD_LENGTH_FOR_OBJECT};
+use super::{OBJECT_BROADCAST,OBJECT_GETPUBKEY,OBJECT_MSG,OBJECT_PUBKEY};
 
 #[derive(Debug,PartialEq)]
 pub enum ParseError {
@@ -29,6 +33,28 @@ pub enum ParseError {
     // UnacceptablePow
 }
 
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let description = match self {
+            &ParseError::FailedMagic => "bad magic number",
+            &ParseError::PayloadLength => "payload is too long",
+            &ParseError::ChecksumMismatch => "payload checksum does not match",
+            &ParseError::AsciiDecode => "command is not ASCII",
+            &ParseError::NonZeroPadding => "command padding is not zero",
+            &ParseError::UnknownCommand => "unknown command",
+            &ParseError::BadAscii => "string is not ASCII",
+            &ParseError::PayloadWrongSize => "payload has the wrong size",
+            &ParseError::MaxExceeded => "too many entries",
+            &ParseError::UnexpectedPayloadEnd => "payload ended unexpectedly",
+            &ParseError::UnknownObjectType => "unknown object type",
+            &ParseError::UnknownObjectVersion => "unknown object version"
+        };
+        write!(f, "Cannot parse message: {}", description)
+    }
+}
+
+impl Error for ParseError {}
+
 pub fn read_message<A: Read>(source: &mut A) -> Result<Message,ParseError> {
     let magic = try!(read_u32(source));
     if magic != MAGIC {
@@ -100,13 +126,13 @@ fn read_known_node<A: Read>(source: &mut A) -> Result<KnownNode,ParseError> {
     let last_seen = try!(read_timestamp(source));
     let stream = try!(read_u32(source));
     let services = try!(read_u64(source));
-    let socket_addr = try!(read_address_and_port(source));
+    let peer_addr = try!(read_address_and_port(source));
 
     Ok(KnownNode {
         last_seen: last_seen,
         stream: stream,
         services: services,
-        socket_addr: socket_addr
+        peer_addr: peer_addr
     })
 }
 
@@ -208,10 +234,10 @@ fn read_object_message(bytes: &[u8]) -> Result<Message,ParseError> {
 
 fn read_object(object_type: u32, version: u64, bytes: &[u8]) -> Result<Object,ParseError> {
     match object_type {
-        0 => read_getpubkey(version, bytes),
-        1 => read_pubkey(version, bytes),
-        2 => read_msg(bytes),
-        3 => read_broadcast(version, bytes),
+        OBJECT_GETPUBKEY => read_getpubkey(version, bytes),
+        OBJECT_PUBKEY => read_pubkey(version, bytes),
+        OBJECT_MSG => read_msg(bytes),
+        OBJECT_BROADCAST => read_broadcast(version, bytes),
         _ => Err(ParseError::UnknownObjectType)
     }
 }
@@ -339,28 +365,119 @@ fn read_broadcast_v5(bytes: &[u8]) -> Result<Broadcast,ParseError> {
     })
 }
 
+// Content lengths can't be more than the object that carried them
+const MAX_CONTENT_LENGTH: usize = MAX_PAYLOAD_LENGTH_FOR_OBJECT as usize;
+
+pub fn read_pubkey_content(bytes: &[u8]) -> Result<PubKeyContent,ParseError> {
+    let mut cursor = Cursor::new(bytes);
+    let behaviour_bitfield = try!(read_u32(&mut cursor));
+    let public_signing_key = try!(read_bytes(&mut cursor, 64));
+    let public_encryption_key = try!(read_bytes(&mut cursor, 64));
+    let nonce_trials_per_byte = try!(read_var_int(&mut cursor, u64::max_value()));
+    let extra_bytes = try!(read_var_int(&mut cursor, u64::max_value()));
+    let signature = try!(read_var_int_bytes_max(&mut cursor, MAX_CONTENT_LENGTH));
+
+    Ok(PubKeyContent {
+        behaviour_bitfield: behaviour_bitfield,
+        public_signing_key: public_signing_key,
+        public_encryption_key: public_encryption_key,
+        nonce_trials_per_byte: nonce_trials_per_byte,
+        extra_bytes: extra_bytes,
+        signature: signature
+    })
+}
+
+pub fn read_msg_content(bytes: &[u8]) -> Result<MsgContent,ParseError> {
+    let mut cursor = Cursor::new(bytes);
+    let address_version = try!(read_var_int(&mut cursor, u64::max_value()));
+    let stream = try!(read_var_int(&mut cursor, u32::max_value() as u64)) as u32;
+    let behaviour_bitfield = try!(read_u32(&mut cursor));
+    let public_signing_key = try!(read_bytes(&mut cursor, 64));
+    let public_encryption_key = try!(read_bytes(&mut cursor, 64));
+    let (nonce_trials_per_byte, extra_bytes) = if address_version >= 3 {
+        (try!(read_var_int(&mut cursor, u64::max_value())), try!(read_var_int(&mut cursor, u64::max_value())))
+    } else {
+        (0, 0)
+    };
+    let destination_ripe = try!(read_bytes(&mut cursor, 20));
+    let encoding = try!(read_var_int(&mut cursor, u64::max_value()));
+    let message = try!(read_var_int_bytes_max(&mut cursor, MAX_CONTENT_LENGTH));
+    let ack_data = try!(read_var_int_bytes_max(&mut cursor, MAX_CONTENT_LENGTH));
+    let signature = try!(read_var_int_bytes_max(&mut cursor, MAX_CONTENT_LENGTH));
+
+    Ok(MsgContent {
+        address_version: address_version,
+        stream: stream,
+        behaviour_bitfield: behaviour_bitfield,
+        public_signing_key: public_signing_key,
+        public_encryption_key: public_encryption_key,
+        nonce_trials_per_byte: nonce_trials_per_byte,
+        extra_bytes: extra_bytes,
+        destination_ripe: destination_ripe,
+        encoding: encoding,
+        message: message,
+        ack_data: ack_data,
+        signature: signature
+    })
+}
+
+pub fn read_broadcast_content(bytes: &[u8]) -> Result<BroadcastContent,ParseError> {
+    let mut cursor = Cursor::new(bytes);
+    let address_version = try!(read_var_int(&mut cursor, u64::max_value()));
+    let stream = try!(read_var_int(&mut cursor, u32::max_value() as u64)) as u32;
+    let behaviour_bitfield = try!(read_u32(&mut cursor));
+    let public_signing_key = try!(read_bytes(&mut cursor, 64));
+    let public_encryption_key = try!(read_bytes(&mut cursor, 64));
+    let (nonce_trials_per_byte, extra_bytes) = if address_version >= 3 {
+        (try!(read_var_int(&mut cursor, u64::max_value())), try!(read_var_int(&mut cursor, u64::max_value())))
+    } else {
+        (0, 0)
+    };
+    let encoding = try!(read_var_int(&mut cursor, u64::max_value()));
+    let message = try!(read_var_int_bytes_max(&mut cursor, MAX_CONTENT_LENGTH));
+    let signature = try!(read_var_int_bytes_max(&mut cursor, MAX_CONTENT_LENGTH));
+
+    Ok(BroadcastContent {
+        address_version: address_version,
+        stream: stream,
+        behaviour_bitfield: behaviour_bitfield,
+        public_signing_key: public_signing_key,
+        public_encryption_key: public_encryption_key,
+        nonce_trials_per_byte: nonce_trials_per_byte,
+        extra_bytes: extra_bytes,
+        encoding: encoding,
+        message: message,
+        signature: signature
+    })
+}
+
 const NO_FLOW: u32 = 0;
 const GLOBAL_SCOPE: u32 = 0xe;
 
-fn read_address_and_port<A: Read>(source: &mut A) -> Result<SocketAddr,ParseError> {
-    let a = try!(read_u16(source));
-    let b = try!(read_u16(source));
-    let c = try!(read_u16(source));
-    let d = try!(read_u16(source));
-    let e = try!(read_u16(source));
-    let f = try!(read_u16(source));
-    let g = try!(read_u16(source));
-    let h = try!(read_u16(source));
+fn read_address_and_port<A: Read>(source: &mut A) -> Result<PeerAddr,ParseError> {
+    let ip_bytes = try!(read_bytes(source, 16));
     let port = try!(read_u16(source));
 
-    let v6_ip = Ipv6Addr::new(a, b, c, d, e, f, g, h);
+    if ip_bytes[0..6] == ONION_PREFIX {
+        let mut onion_id = [0u8; 10];
+        onion_id.copy_from_slice(&ip_bytes[6..16]);
+        return Ok(PeerAddr::Onion(OnionAddr::new(onion_id, port)));
+    }
+
+    let mut segments = [0u16; 8];
+    let mut cursor = Cursor::new(ip_bytes);
+    for segment in segments.iter_mut() {
+        *segment = try!(read_u16(&mut cursor));
+    }
+
+    let v6_ip = Ipv6Addr::new(segments[0], segments[1], segments[2], segments[3], segments[4], segments[5], segments[6], segments[7]);
 
     let socket_addr = match v6_ip.to_ipv4() {
         None => SocketAddr::V6(SocketAddrV6::new(v6_ip, port, NO_FLOW, GLOBAL_SCOPE)),
         Some(v4_ip) => SocketAddr::V4(SocketAddrV4::new(v4_ip, port))
     };
 
-    Ok(socket_addr)
+    Ok(PeerAddr::Ip(socket_addr))
 }
 
 fn read_timestamp<A: Read>(source: &mut A) -> Result<SystemTime,ParseError> {
@@ -381,6 +498,11 @@ fn read_var_int_bytes<A: Read>(source: &mut A) -> Result<Vec<u8>,ParseError> {
     Ok(try!(read_bytes(source, byte_count)))
 }
 
+fn read_var_int_bytes_max<A: Read>(source: &mut A, max_length: usize) -> Result<Vec<u8>,ParseError> {
+    let byte_count = try!(read_var_int_usize(source, max_length));
+    Ok(try!(read_bytes(source, byte_count)))
+}
+
 fn read_var_str<A: Read>(source: &mut A, max_length: usize) -> Result<String,ParseError> {
     let length = try!(read_var_int_usize(source, max_length));
 
@@ -404,7 +526,7 @@ fn read_var_int_usize<A: Read>(source: &mut A, max_value: usize) -> Result<usize
     read_var_int(source, max_value as u64).map(|v| v as usize)
 }
 
-fn read_var_int<A: Read>(source: &mut A, max_value: u64) -> Result<u64,ParseError> {
+pub fn read_var_int<A: Read>(source: &mut A, max_value: u64) -> Result<u64,ParseError> {
     let first_byte: u8 = try!(read_u8(source));
 
     let value = match first_byte {
@@ -456,6 +578,7 @@ fn read_u8<A: Read>(source: &mut A) -> Result<u8,ParseError> {
 
 #[cfg(test)]
 mod tests {
+    use net::PeerAddr;
     use std::io::Cursor;
     use std::net::SocketAddr;
     use std::time::UNIX_EPOCH;
@@ -474,8 +597,8 @@ mod tests {
         let mut source = Cursor::new(bytes);
 
         let socket_addr = match read_address_and_port(&mut source).unwrap() {
-            SocketAddr::V6(_) => panic!("Expected V4"),
-            SocketAddr::V4(v4) => v4
+            PeerAddr::Ip(SocketAddr::V4(v4)) => v4,
+            _ => panic!("Expected V4")
         };
 
         assert_eq!([11, 12, 13, 14], socket_addr.ip().octets());
@@ -488,14 +611,28 @@ mod tests {
         let mut source = Cursor::new(bytes);
 
         let socket_addr = match read_address_and_port(&mut source).unwrap() {
-            SocketAddr::V4(_) => panic!("Expected V6"),
-            SocketAddr::V6(v6) => v6
+            PeerAddr::Ip(SocketAddr::V6(v6)) => v6,
+            _ => panic!("Expected V6")
         };
 
         assert_eq!([0x102, 0x304, 0x506, 0x708, 0x90a, 0xb0c, 0xd0e, 0xf00], socket_addr.ip().segments());
         assert_eq!(6655, socket_addr.port());
     }
 
+    #[test]
+    fn test_read_address_and_port_for_onion() {
+        let bytes: Vec<u8> = vec![ 0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43, 0x4f, 0x5b, 0xf2, 0x23, 0x0f, 0x86, 0xe9, 0xc6, 0x64, 0x11, 32, 252 ];
+        let mut source = Cursor::new(bytes);
+
+        let onion_addr = match read_address_and_port(&mut source).unwrap() {
+            PeerAddr::Onion(onion_addr) => onion_addr,
+            _ => panic!("Expected onion")
+        };
+
+        assert_eq!("j5n7eiypq3u4mzar.onion", onion_addr.hostname());
+        assert_eq!(8444, PeerAddr::Onion(onion_addr).port());
+    }
+
     #[test]
     fn test_read_timestamp() {
         let bytes: Vec<u8> = vec![ 8, 7, 6, 5, 4, 3, 2, 1 ];
@@ -3,10 +3,12 @@ use config::Config;
 use inventory::Inventory;
 use known_nodes::KnownNodes;
 use message::{InventoryVector,KnownNode,Message,ObjectData,VersionData};
-use net::to_socket_addr;
+use net::PeerAddr;
 use std::collections::HashSet;
-use std::sync::mpsc::SendError;
-use std::net::{Ipv4Addr,SocketAddr,SocketAddrV4};
+use std::error::Error;
+use std::fmt;
+use std::sync::mpsc::{Receiver,SendError};
+use std::net::{IpAddr,Ipv4Addr,SocketAddr};
 use std::time::SystemTime;
 
 use super::{MAX_INV_COUNT,MAX_NODES_COUNT};
@@ -17,6 +19,24 @@ pub enum ResponderError {
     UnacceptableMessage,
 }
 
+impl fmt::Display for ResponderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &ResponderError::ThreadDown(_) => write!(f, "Connection thread has stopped"),
+            &ResponderError::UnacceptableMessage => write!(f, "Message is not acceptable in this connection state")
+        }
+    }
+}
+
+impl Error for ResponderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            &ResponderError::ThreadDown(ref err) => Some(err),
+            &ResponderError::UnacceptableMessage => None
+        }
+    }
+}
+
 impl From<SendError<Message>> for ResponderError {
     fn from(err: SendError<Message>) -> ResponderError {
         ResponderError::ThreadDown(err)
@@ -27,11 +47,11 @@ pub struct MessageResponder {
     config: Config,
     known_nodes: KnownNodes,
     inventory: Inventory,
-    peer_addr: SocketAddr
+    peer_addr: PeerAddr
 }
 
 impl MessageResponder {
-    pub fn new(config: &Config, known_nodes: &KnownNodes, inventory: &Inventory, peer_addr: SocketAddr) -> MessageResponder {
+    pub fn new(config: &Config, known_nodes: &KnownNodes, inventory: &Inventory, peer_addr: PeerAddr) -> MessageResponder {
         MessageResponder {
             config: config.clone(),
             known_nodes: known_nodes.clone(),
@@ -46,6 +66,11 @@ impl MessageResponder {
         f(self.create_version_message())
     }
 
+    // Objects added to the inventory from now on, which the peer hasn't been told about yet
+    pub fn watch_inventory(&self) -> Receiver<Message> {
+        self.inventory.watch()
+    }
+
     pub fn respond<F>(&mut self, message: Message, send: F) -> Result<(), ResponderError>
         where F : Fn(Message) -> Result<(), SendError<Message>>
     {
@@ -87,7 +112,7 @@ impl MessageResponder {
         Ok(())
     }
 
-    fn add_known_node(&mut self, streams: Vec<u64>, services: u64, addr_from: SocketAddr) -> Result<(), ResponderError> {
+    fn add_known_node(&mut self, streams: Vec<u64>, services: u64, addr_from: PeerAddr) -> Result<(), ResponderError> {
         let streams_of_interest = self.get_streams_of_interest();
 
         let mut stream_count = 0;
@@ -102,7 +127,7 @@ impl MessageResponder {
                     last_seen: SystemTime::now(),
                     stream: stream as u32,
                     services: services,
-                    socket_addr: addr_from
+                    peer_addr: addr_from
                 };
 
                 self.known_nodes.add_known_node(&peer_node);
@@ -118,18 +143,19 @@ impl MessageResponder {
     }
 
     fn get_streams_of_interest(&self) -> HashSet<u32> {
-        // TODO - more configurable streams of interest
-        let mut streams: HashSet<u32> = HashSet::new();
-        streams.insert(1);
-        streams
+        self.config.streams().iter().cloned().collect()
     }
 
     fn create_version_message(&self) -> Message {
         let port = self.config.port();
-        let our_addr = to_socket_addr(SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), port));
+        let listen_addr = match self.config.listen_addr() {
+            ip if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            ip => ip
+        };
+        let our_addr = PeerAddr::Ip(SocketAddr::new(listen_addr, port));
         let nonce = self.config.nonce();
         let user_agent = self.config.user_agent().to_string();
-        let streams = vec![ 1 ];
+        let streams = self.config.streams().iter().map(|&stream| stream as u64).collect();
 
         Message::Version(VersionData {
             version: 3,
@@ -166,10 +192,11 @@ impl MessageResponder {
 #[cfg(test)]
 mod tests {
     use config::Config;
+    use events::Events;
     use inventory::{Inventory,calculate_inventory_vector};
     use known_nodes::KnownNodes;
     use message::{InventoryVector,KnownNode,Message,Object,GetPubKey,ObjectData,VersionData};
-    use net::to_socket_addr;
+    use net::{PeerAddr,to_socket_addr};
     use persist::Persister;
     use std::sync::Mutex;
     use std::sync::mpsc::SendError;
@@ -214,7 +241,7 @@ mod tests {
         assert_eq!(1, known_nodes.len());
 
         let peer_node = &known_nodes[0];
-        assert_eq!(to_socket_addr("127.0.0.1:8444"), peer_node.socket_addr);
+        assert_eq!(PeerAddr::Ip(to_socket_addr("127.0.0.1:8444")), peer_node.peer_addr);
         assert_eq!(1, peer_node.services);
         assert_eq!(1, peer_node.stream);
 
@@ -232,8 +259,8 @@ mod tests {
             version: 3,
             services: 1,
             timestamp: SystemTime::now(),
-            addr_recv: to_socket_addr("127.0.0.1:8555"),
-            addr_from: to_socket_addr("127.0.0.1:8444"),
+            addr_recv: PeerAddr::Ip(to_socket_addr("127.0.0.1:8555")),
+            addr_from: PeerAddr::Ip(to_socket_addr("127.0.0.1:8444")),
             nonce: 0x0102030405060708,
             user_agent: "test".to_string(),
             streams: vec![ 1 ]
@@ -260,10 +287,10 @@ mod tests {
             last_seen: UNIX_EPOCH + Duration::from_secs(5),
             stream: 1,
             services: 1,
-            socket_addr: to_socket_addr("12.13.14.15:1000")
+            peer_addr: PeerAddr::Ip(to_socket_addr("12.13.14.15:1000"))
         };
         persister.add_known_node(&known_node);
-        let mut inventory = Inventory::new(persister.clone());
+        let mut inventory = Inventory::new(persister.clone(), &Events::new());
         let persisted_message = create_object_message(1);
         inventory.add_object_message(&persisted_message);
 
@@ -279,7 +306,7 @@ mod tests {
                 assert_eq!(UNIX_EPOCH + Duration::from_secs(5), output_node.last_seen);
                 assert_eq!(1, output_node.stream);
                 assert_eq!(1, output_node.services);
-                assert_eq!(to_socket_addr("12.13.14.15:1000"), output_node.socket_addr);
+                assert_eq!(PeerAddr::Ip(to_socket_addr("12.13.14.15:1000")), output_node.peer_addr);
             },
             _ => panic!("Not an Addr message: {:?}", message1)
         }
@@ -302,7 +329,7 @@ mod tests {
             last_seen: UNIX_EPOCH + Duration::from_secs(6),
             stream: 1,
             services: 1,
-            socket_addr: to_socket_addr("22.33.44.55:6666")
+            peer_addr: PeerAddr::Ip(to_socket_addr("22.33.44.55:6666"))
         };
         let input = Message::Addr {
             addr_list: vec![ known_node.clone() ]
@@ -431,8 +458,8 @@ mod tests {
     fn run_test(input: Message, persister: Persister) -> Vec<Message> {
         let config = Config::new();
         let known_nodes = KnownNodes::new(persister.clone());
-        let inventory = Inventory::new(persister.clone());
-        let peer_addr = to_socket_addr("127.0.0.1:8444");
+        let inventory = Inventory::new(persister.clone(), &Events::new());
+        let peer_addr = PeerAddr::Ip(to_socket_addr("127.0.0.1:8444"));
         let mut responder = MessageResponder::new(&config, &known_nodes, &inventory, peer_addr);
 
         let output = Output::new();
@@ -1,46 +1,90 @@
+use config::Config;
+use events::{Event,Events};
 use inventory::Inventory;
-use message::{Message,Object,ObjectData};
-use message::pow::{ProofOfWork,GenerateError,network_pow_config};
-use std::time::{Duration,SystemTime};
+use message::{Message,ObjectData};
+use message::pow::{ProofOfWork,GenerateError,NETWORK_EXTRA_BYTES,NETWORK_TRIALS_PER_BYTE,pow_config_with_difficulty};
+use std::cmp::max;
+use std::error::Error;
+use std::fmt;
 use timegen::TimeType;
 
+#[derive(Debug)]
 pub enum MessageSendError {
     UnableToCreatePow(GenerateError)
 }
 
+impl fmt::Display for MessageSendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &MessageSendError::UnableToCreatePow(ref err) => write!(f, "Unable to create proof of work: {}", err)
+        }
+    }
+}
+
+impl Error for MessageSendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            &MessageSendError::UnableToCreatePow(ref err) => Some(err)
+        }
+    }
+}
+
 impl From<GenerateError> for MessageSendError {
     fn from(err: GenerateError) -> MessageSendError {
         MessageSendError::UnableToCreatePow(err)
     }
 }
 
+#[derive(Clone)]
 pub struct Sender {
-    inventory: Inventory
+    inventory: Inventory,
+    events: Events,
+    pow_threads: usize,
+    difficulty_override: Option<(u64, u64)>
 }
 
 impl Sender {
-    pub fn new(inventory: Inventory) -> Sender {
+    pub fn new(config: &Config, inventory: Inventory, events: &Events) -> Sender {
         Sender {
-            inventory: inventory
+            inventory: inventory,
+            events: events.clone(),
+            pow_threads: config.pow_threads(),
+            difficulty_override: None
         }
     }
 
-    pub fn send_message(&mut self, text: &str) -> Result<(), MessageSendError> {
-        let object_data_wrong_nonce = ObjectData {
-            nonce: 0,
-            expiry: SystemTime::now() + Duration::from_secs(345600), // 4 days
-            version: 1,
-            stream: 1,
-            object: Object::Msg { encrypted: text.bytes().collect() } // Very bad!!!
-        };
+    // Lets tests send objects without waiting for a real proof of work
+    #[cfg(test)]
+    pub fn override_difficulty(&mut self, trials_per_byte: u64, extra_bytes: u64) {
+        self.difficulty_override = Some((trials_per_byte, extra_bytes));
+    }
 
-        let pow = ProofOfWork::new(TimeType::Real);
-        let nonce = try!(pow.generate(&object_data_wrong_nonce, network_pow_config()));
+    // Does the proof of work, but leaves the caller to decide whether to add it to the inventory
+    pub fn generate_object(&self, object_data: ObjectData, trials_per_byte: u64, extra_bytes: u64) -> Result<Message, MessageSendError> {
+        // Recipients can ask for more work than the network minimum, but not less
+        let (trials_per_byte, extra_bytes) = self.difficulty_override.unwrap_or(
+            (max(trials_per_byte, NETWORK_TRIALS_PER_BYTE), max(extra_bytes, NETWORK_EXTRA_BYTES)));
+        let pow_config = pow_config_with_difficulty(trials_per_byte, extra_bytes);
 
-        let object_data_with_nonce = ObjectData { nonce: nonce, .. object_data_wrong_nonce };
+        let pow = ProofOfWork::with_threads(TimeType::Real, self.pow_threads);
+        let events = &self.events;
+        let nonce = try!(pow.generate(&object_data, pow_config, |trials, expected_trials| {
+            events.emit(Event::PowProgress { trials: trials, expected_trials: expected_trials });
+        }));
 
-        self.inventory.add_object_message(&Message::Object(object_data_with_nonce));
+        Ok(Message::Object(ObjectData { nonce: nonce, .. object_data }))
+    }
+
+    // Checks that an object we pass on for someone else carries enough work
+    pub fn verify_object(&self, object_data: &ObjectData) -> bool {
+        let (trials_per_byte, extra_bytes) = self.difficulty_override.unwrap_or((NETWORK_TRIALS_PER_BYTE, NETWORK_EXTRA_BYTES));
+        let pow = ProofOfWork::new(TimeType::Real);
+        pow.verify(object_data, pow_config_with_difficulty(trials_per_byte, extra_bytes)).is_ok()
+    }
 
+    pub fn send_object(&mut self, object_data: ObjectData, trials_per_byte: u64, extra_bytes: u64) -> Result<(), MessageSendError> {
+        let object_message = try!(self.generate_object(object_data, trials_per_byte, extra_bytes));
+        self.inventory.add_object_message(&object_message);
         Ok(())
     }
 }
@@ -1,9 +1,12 @@
 use super::{Message,ObjectData,VersionData};
 use super::pow::{ProofOfWork,VerifyError,network_pow_config};
 use config::Config;
+use std::error::Error;
+use std::fmt;
 use std::time::{Duration,SystemTime};
 use timegen::TimeType;
 
+#[derive(Debug)]
 pub enum MessageVerifierError {
     OurNonce,
     OldVersion,
@@ -11,6 +14,26 @@ pub enum MessageVerifierError {
     UnacceptablePow(VerifyError)
 }
 
+impl fmt::Display for MessageVerifierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &MessageVerifierError::OurNonce => write!(f, "Connected to ourselves"),
+            &MessageVerifierError::OldVersion => write!(f, "Peer uses an unsupported protocol version"),
+            &MessageVerifierError::NoClockSync => write!(f, "Peer clock is too far from ours"),
+            &MessageVerifierError::UnacceptablePow(ref err) => write!(f, "Unacceptable proof of work: {}", err)
+        }
+    }
+}
+
+impl Error for MessageVerifierError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            &MessageVerifierError::UnacceptablePow(ref err) => Some(err),
+            _ => None
+        }
+    }
+}
+
 impl From<VerifyError> for MessageVerifierError {
     fn from(err: VerifyError) -> MessageVerifierError {
         MessageVerifierError::UnacceptablePow(err)
@@ -89,7 +112,7 @@ mod tests {
     use config::Config;
     use message::{Message,Object,ObjectData,VersionData};
     use message::pow::VerifyError;
-    use net::to_socket_addr;
+    use net::{PeerAddr,to_socket_addr};
     use std::time::{Duration,SystemTime,UNIX_EPOCH};
     use timegen::TimeType;
 
@@ -147,8 +170,8 @@ mod tests {
             version: 3,
             services: 1,
             timestamp: SystemTime::now(),
-            addr_recv: to_socket_addr("127.0.0.1:8555"),
-            addr_from: to_socket_addr("127.0.0.1:8444"),
+            addr_recv: PeerAddr::Ip(to_socket_addr("127.0.0.1:8555")),
+            addr_from: PeerAddr::Ip(to_socket_addr("127.0.0.1:8444")),
             nonce: 0x0102030405060708,
             user_agent: "test".to_string(),
             streams: vec![ 1 ]
@@ -2,11 +2,13 @@ use byteorder::{BigEndian,WriteBytesExt};
 use checksum::sha512_checksum;
 use encoding::{Encoding,EncoderTrap};
 use encoding::all::ASCII;
+use net::{ONION_PREFIX,PeerAddr};
 use std::net::SocketAddr;
 use std::time::{SystemTime,UNIX_EPOCH};
 
-use super::{InventoryVector,KnownNode,GetPubKey,PubKey,Broadcast,Object,Message,ObjectData,VersionData};
+use super::{InventoryVector,KnownNode,GetPubKey,PubKey,Broadcast,Object,Message,ObjectData,VersionData,BroadcastContent,MsgContent,PubKeyContent};
 use super::{MAGIC,MAX_PAYLOAD_LENGTH,MAX_NODES_COUNT,MAX_GETDATA_COUNT,MAX_INV_COUNT};
+use super::{OBJECT_BROADCAST,OBJECT_GETPUBKEY,OBJECT_MSG,OBJECT_PUBKEY};
 
 pub fn write_message(output: &mut Vec<u8>, message: &Message) {
     let mut payload = vec![];
@@ -102,7 +104,7 @@ fn write_known_node(output: &mut Vec<u8>, known_node: &KnownNode) {
     write_i64(output, get_secs_from_time(&known_node.last_seen));
     write_u32(output, known_node.stream);
     write_u64(output, known_node.services);
-    write_address_and_port(output, &known_node.socket_addr);
+    write_address_and_port(output, &known_node.peer_addr);
 }
 
 fn write_getdata_message(output: &mut Vec<u8>, inventory: &[InventoryVector]) {
@@ -128,7 +130,7 @@ fn write_inventory_vector(output: &mut Vec<u8>, inventory_vector: &InventoryVect
     write_bytes(output, &inventory_vector.hash, 32);
 }
 
-fn write_version_message(output: &mut Vec<u8>, version: u32, services: u64, timestamp: &SystemTime, addr_recv: &SocketAddr, addr_from: &SocketAddr, nonce: u64, user_agent: &str, streams: &[u64]) {
+fn write_version_message(output: &mut Vec<u8>, version: u32, services: u64, timestamp: &SystemTime, addr_recv: &PeerAddr, addr_from: &PeerAddr, nonce: u64, user_agent: &str, streams: &[u64]) {
     write_u32(output, version);
     write_u64(output, services);
     write_i64(output, get_secs_from_time(timestamp));
@@ -157,12 +159,20 @@ fn write_object_message(output: &mut Vec<u8>, nonce: u64, expiry: &SystemTime, v
     write_object(output, object);
 }
 
+// Everything in the object header except the nonce, which is what signatures cover
+pub fn write_object_header(output: &mut Vec<u8>, expiry: &SystemTime, object_type: u32, version: u64, stream: u32) {
+    write_i64(output, get_secs_from_time(expiry));
+    write_u32(output, object_type);
+    write_var_int_64(output, version);
+    write_var_int_32(output, stream);
+}
+
 fn write_object_type(output: &mut Vec<u8>, object: &Object) {
     let object_type = match object {
-        &Object::GetPubKey(_) => 0,
-        &Object::PubKey(_) => 1,
-        &Object::Msg { encrypted: _ } => 2,
-        &Object::Broadcast(_) => 3
+        &Object::GetPubKey(_) => OBJECT_GETPUBKEY,
+        &Object::PubKey(_) => OBJECT_PUBKEY,
+        &Object::Msg { encrypted: _ } => OBJECT_MSG,
+        &Object::Broadcast(_) => OBJECT_BROADCAST
     };
 
     write_u32(output, object_type);
@@ -259,17 +269,81 @@ fn write_broadcast_v5(output: &mut Vec<u8>, tag: &[u8], encrypted: &[u8]) {
     write_bytes_no_check(output, encrypted);
 }
 
-fn write_address_and_port(output: &mut Vec<u8>, socket_addr: &SocketAddr) {
-    let v6_ip = match socket_addr {
-        &SocketAddr::V4(v4_addr) => v4_addr.ip().to_ipv6_mapped(),
-        &SocketAddr::V6(v6_addr) => v6_addr.ip().to_owned()
-    };
+pub fn write_pubkey_content(output: &mut Vec<u8>, content: &PubKeyContent) {
+    write_unsigned_pubkey_content(output, content);
+    write_var_int_bytes(output, &content.signature);
+}
+
+// Also the layout of a v3 pubkey object up to its signature
+pub fn write_unsigned_pubkey_content(output: &mut Vec<u8>, content: &PubKeyContent) {
+    write_u32(output, content.behaviour_bitfield);
+    write_bytes(output, &content.public_signing_key, 64);
+    write_bytes(output, &content.public_encryption_key, 64);
+    write_var_int_64(output, content.nonce_trials_per_byte);
+    write_var_int_64(output, content.extra_bytes);
+}
 
-    for &segment in v6_ip.segments().iter() {
-        write_u16(output, segment);
+pub fn write_msg_content(output: &mut Vec<u8>, content: &MsgContent) {
+    write_unsigned_msg_content(output, content);
+    write_var_int_bytes(output, &content.signature);
+}
+
+// The content up to the signature, which is the part that gets signed
+pub fn write_unsigned_msg_content(output: &mut Vec<u8>, content: &MsgContent) {
+    write_var_int_64(output, content.address_version);
+    write_var_int_64(output, content.stream as u64);
+    write_u32(output, content.behaviour_bitfield);
+    write_bytes(output, &content.public_signing_key, 64);
+    write_bytes(output, &content.public_encryption_key, 64);
+    if content.address_version >= 3 {
+        write_var_int_64(output, content.nonce_trials_per_byte);
+        write_var_int_64(output, content.extra_bytes);
+    }
+    write_bytes(output, &content.destination_ripe, 20);
+    write_var_int_64(output, content.encoding);
+    write_var_int_bytes(output, &content.message);
+    write_var_int_bytes(output, &content.ack_data);
+}
+
+pub fn write_broadcast_content(output: &mut Vec<u8>, content: &BroadcastContent) {
+    write_unsigned_broadcast_content(output, content);
+    write_var_int_bytes(output, &content.signature);
+}
+
+// The content up to the signature, which is the part that gets signed
+pub fn write_unsigned_broadcast_content(output: &mut Vec<u8>, content: &BroadcastContent) {
+    write_var_int_64(output, content.address_version);
+    write_var_int_64(output, content.stream as u64);
+    write_u32(output, content.behaviour_bitfield);
+    write_bytes(output, &content.public_signing_key, 64);
+    write_bytes(output, &content.public_encryption_key, 64);
+    if content.address_version >= 3 {
+        write_var_int_64(output, content.nonce_trials_per_byte);
+        write_var_int_64(output, content.extra_bytes);
+    }
+    write_var_int_64(output, content.encoding);
+    write_var_int_bytes(output, &content.message);
+}
+
+fn write_address_and_port(output: &mut Vec<u8>, peer_addr: &PeerAddr) {
+    match peer_addr {
+        &PeerAddr::Ip(socket_addr) => {
+            let v6_ip = match socket_addr {
+                SocketAddr::V4(v4_addr) => v4_addr.ip().to_ipv6_mapped(),
+                SocketAddr::V6(v6_addr) => v6_addr.ip().to_owned()
+            };
+
+            for &segment in v6_ip.segments().iter() {
+                write_u16(output, segment);
+            }
+        },
+        &PeerAddr::Onion(onion_addr) => {
+            write_bytes_no_check(output, &ONION_PREFIX);
+            write_bytes_no_check(output, onion_addr.id());
+        }
     }
 
-    let port = socket_addr.port();
+    let port = peer_addr.port();
     write_u16(output, port);
 }
 
@@ -305,7 +379,7 @@ fn write_var_int_usize(output: &mut Vec<u8>, value: usize) {
     write_var_int_64(output, value as u64);
 }
 
-fn write_var_int_64(output: &mut Vec<u8>, value: u64) {
+pub fn write_var_int_64(output: &mut Vec<u8>, value: u64) {
     if value <= 0xffffffff {
         write_var_int_32(output, value as u32);
     } else {
@@ -365,7 +439,7 @@ fn get_secs_from_time(time: &SystemTime) -> i64 {
 
 #[cfg(test)]
 mod tests {
-    use net::to_socket_addr;
+    use net::{OnionAddr,PeerAddr,to_socket_addr};
     use super::write_address_and_port;
     use super::write_var_str;
     use super::write_var_int_list;
@@ -376,8 +450,8 @@ mod tests {
     #[test]
     fn test_write_address_and_port_for_v4() {
         let mut payload = vec![];
-        let socket_addr = to_socket_addr("127.0.0.1:8444");
-        write_address_and_port(&mut payload, &socket_addr);
+        let peer_addr = PeerAddr::Ip(to_socket_addr("127.0.0.1:8444"));
+        write_address_and_port(&mut payload, &peer_addr);
 
         assert_eq!(payload, vec![ 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 127, 0, 0, 1, 32, 252 ]);
     }
@@ -385,12 +459,21 @@ mod tests {
     #[test]
     fn test_write_address_and_port_for_v6() {
         let mut payload = vec![];
-        let socket_addr = to_socket_addr("[2001:cdba:0:0:0:0:3257:9652]:8444");
-        write_address_and_port(&mut payload, &socket_addr);
+        let peer_addr = PeerAddr::Ip(to_socket_addr("[2001:cdba:0:0:0:0:3257:9652]:8444"));
+        write_address_and_port(&mut payload, &peer_addr);
 
         assert_eq!(payload, vec![ 0x20, 0x01, 0xcd, 0xba, 0, 0, 0, 0, 0, 0, 0, 0, 0x32, 0x57, 0x96, 0x52, 32, 252 ]);
     }
 
+    #[test]
+    fn test_write_address_and_port_for_onion() {
+        let mut payload = vec![];
+        let peer_addr = PeerAddr::Onion(OnionAddr::from_hostname("j5n7eiypq3u4mzar.onion", 8444).unwrap());
+        write_address_and_port(&mut payload, &peer_addr);
+
+        assert_eq!(payload, vec![ 0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43, 0x4f, 0x5b, 0xf2, 0x23, 0x0f, 0x86, 0xe9, 0xc6, 0x64, 0x11, 32, 252 ]);
+    }
+
     #[test]
     fn test_write_var_str() {
         let mut payload1 = vec![];
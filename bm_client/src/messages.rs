@@ -0,0 +1,351 @@
+use address::Address;
+use events::OutboxStatus;
+use inbox::{Inbox,InboxMessage};
+use outbox::{Outbox,OutboxMessage};
+use persist::Persister;
+use search::SearchTerms;
+use std::time::SystemTime;
+
+// A view over the inbox and outbox as the folders a mail client would show
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum Folder {
+    Inbox,
+    Outbox, // not yet on the network
+    Sent,
+    Trash
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct StoredMessage {
+    id: Vec<u8>, // the msgid for received messages, the ackdata for our own
+    folder: Folder,
+    from: Address,
+    to: Option<Address>, // None for broadcasts
+    subject: String,
+    body: String,
+    timestamp: SystemTime, // when it was received or queued
+    read: bool,
+    status: Option<OutboxStatus> // only for our own messages
+}
+
+impl StoredMessage {
+    fn from_inbox(inbox_message: &InboxMessage) -> StoredMessage {
+        StoredMessage {
+            id: inbox_message.msgid().to_vec(),
+            folder: if inbox_message.is_trashed() { Folder::Trash } else { Folder::Inbox },
+            from: inbox_message.from().clone(),
+            to: inbox_message.to().cloned(),
+            subject: inbox_message.subject().to_string(),
+            body: inbox_message.body().to_string(),
+            timestamp: inbox_message.received(),
+            read: inbox_message.is_read(),
+            status: None
+        }
+    }
+
+    fn from_outbox(outbox_message: &OutboxMessage) -> StoredMessage {
+        let folder = match outbox_message.status() {
+            _ if outbox_message.is_trashed() => Folder::Trash,
+            OutboxStatus::Sent | OutboxStatus::AckReceived | OutboxStatus::GaveUp => Folder::Sent,
+            _ => Folder::Outbox
+        };
+
+        StoredMessage {
+            id: outbox_message.ackdata().to_vec(),
+            folder: folder,
+            from: outbox_message.from().clone(),
+            to: outbox_message.to().cloned(),
+            subject: outbox_message.subject().to_string(),
+            body: outbox_message.body().to_string(),
+            timestamp: outbox_message.created(),
+            read: true,
+            status: Some(outbox_message.status())
+        }
+    }
+
+    pub fn id(&self) -> &[u8] {
+        &self.id
+    }
+
+    pub fn folder(&self) -> Folder {
+        self.folder
+    }
+
+    pub fn from(&self) -> &Address {
+        &self.from
+    }
+
+    pub fn to(&self) -> Option<&Address> {
+        self.to.as_ref()
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    pub fn timestamp(&self) -> SystemTime {
+        self.timestamp
+    }
+
+    pub fn is_read(&self) -> bool {
+        self.read
+    }
+
+    pub fn status(&self) -> Option<OutboxStatus> {
+        self.status
+    }
+
+    pub fn is_ours(&self) -> bool {
+        self.status.is_some()
+    }
+
+    // Our identity on the message: the recipient of received messages, the sender of ours
+    fn identity(&self) -> Option<&Address> {
+        if self.is_ours() { Some(&self.from) } else { self.to.as_ref() }
+    }
+
+    fn correspondent(&self) -> Option<&Address> {
+        if self.is_ours() { self.to.as_ref() } else { Some(&self.from) }
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct MessageQuery {
+    folder: Option<Folder>,
+    identity: Option<Address>,
+    correspondent: Option<Address>,
+    text: Option<SearchTerms>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+    offset: usize,
+    limit: Option<usize>
+}
+
+impl MessageQuery {
+    // Matches every message, newest first
+    pub fn new() -> MessageQuery {
+        MessageQuery {
+            folder: None,
+            identity: None,
+            correspondent: None,
+            text: None,
+            since: None,
+            until: None,
+            offset: 0,
+            limit: None
+        }
+    }
+
+    pub fn folder(mut self, folder: Folder) -> MessageQuery {
+        self.folder = Some(folder);
+        self
+    }
+
+    // Messages sent to or from one of our identities
+    pub fn identity(mut self, address: &Address) -> MessageQuery {
+        self.identity = Some(address.clone());
+        self
+    }
+
+    // Messages exchanged with someone else
+    pub fn correspondent(mut self, address: &Address) -> MessageQuery {
+        self.correspondent = Some(address.clone());
+        self
+    }
+
+    // Words to look for in the subject, body and addresses. Words are ANDed unless joined
+    // by OR, and words starting with - or following NOT must not appear.
+    pub fn text(mut self, text: &str) -> MessageQuery {
+        self.text = Some(SearchTerms::parse(text));
+        self
+    }
+
+    // Received or queued at this time or later
+    pub fn since(mut self, time: SystemTime) -> MessageQuery {
+        self.since = Some(time);
+        self
+    }
+
+    // Received or queued before this time
+    pub fn until(mut self, time: SystemTime) -> MessageQuery {
+        self.until = Some(time);
+        self
+    }
+
+    pub fn page(mut self, offset: usize, limit: usize) -> MessageQuery {
+        self.offset = offset;
+        self.limit = Some(limit);
+        self
+    }
+
+    fn matches(&self, message: &StoredMessage) -> bool {
+        self.folder.map_or(true, |folder| message.folder == folder) &&
+            self.identity.as_ref().map_or(true, |identity| message.identity() == Some(identity)) &&
+            self.correspondent.as_ref().map_or(true, |correspondent| message.correspondent() == Some(correspondent)) &&
+            self.since.map_or(true, |since| message.timestamp >= since) &&
+            self.until.map_or(true, |until| message.timestamp < until)
+    }
+}
+
+#[derive(Clone)]
+pub struct Messages {
+    persister: Persister,
+    inbox: Inbox,
+    outbox: Outbox
+}
+
+impl Messages {
+    pub fn new(persister: Persister, inbox: &Inbox, outbox: &Outbox) -> Messages {
+        Messages {
+            persister: persister,
+            inbox: inbox.clone(),
+            outbox: outbox.clone()
+        }
+    }
+
+    pub fn query(&self, query: &MessageQuery) -> Vec<StoredMessage> {
+        let mut messages: Vec<StoredMessage> = self.all().into_iter().filter(|message| query.matches(message)).collect();
+        if let Some(ref terms) = query.text {
+            let found = self.persister.search_messages(terms);
+            messages.retain(|message| found.contains(&message.id));
+        }
+        messages.sort_by(|a, b| b.timestamp.cmp(&a.timestamp).then_with(|| a.id.cmp(&b.id)));
+
+        let page = messages.into_iter().skip(query.offset);
+        match query.limit {
+            Some(limit) => page.take(limit).collect(),
+            None => page.collect()
+        }
+    }
+
+    pub fn get(&self, id: &[u8]) -> Option<StoredMessage> {
+        self.all().into_iter().find(|message| message.id == id)
+    }
+
+    // Our own messages are always read
+    pub fn mark_read(&mut self, id: &[u8], read: bool) -> bool {
+        self.inbox.set_read(id, read)
+    }
+
+    pub fn trash(&mut self, id: &[u8]) -> bool {
+        self.inbox.set_trashed(id, true) || self.outbox.set_trashed(id, true)
+    }
+
+    pub fn restore(&mut self, id: &[u8]) -> bool {
+        self.inbox.set_trashed(id, false) || self.outbox.set_trashed(id, false)
+    }
+
+    // Removes the message for good, trashed or not
+    pub fn delete(&mut self, id: &[u8]) -> bool {
+        self.inbox.remove(id) || self.outbox.remove(id)
+    }
+
+    fn all(&self) -> Vec<StoredMessage> {
+        let mut messages: Vec<StoredMessage> = self.inbox.list().iter().map(StoredMessage::from_inbox).collect();
+        messages.extend(self.outbox.list().iter().map(StoredMessage::from_outbox));
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use address::Address;
+    use events::{Events,OutboxStatus};
+    use inbox::{Inbox,InboxMessage};
+    use outbox::{Outbox,SendOptions};
+    use persist::Persister;
+    use std::str::FromStr;
+    use std::time::SystemTime;
+    use super::{Folder,MessageQuery,Messages};
+
+    fn address(index: usize) -> Address {
+        let addresses = [ "BM-2cTfvWz6rmkyjDemoWTC2RDyK4Vb14fK3z", "BM-2D84wQgp9bJ6unWgn67sYHxBgZEeNzpKba", "BM-2cW67GEKkHGonXKZLCzouLLxnLym3azS8r" ];
+        Address::from_str(addresses[index]).unwrap()
+    }
+
+    fn setup() -> (Messages, Inbox, Outbox) {
+        let persister = Persister::new();
+        let events = Events::new();
+        let inbox = Inbox::new(persister.clone(), &events);
+        let outbox = Outbox::new(persister.clone(), &events);
+        (Messages::new(persister, &inbox, &outbox), inbox, outbox)
+    }
+
+    #[test]
+    fn test_folders_and_flags() {
+        let (mut messages, mut inbox, mut outbox) = setup();
+        inbox.add(&InboxMessage::new(&[ 1; 32 ], &address(1), Some(&address(0)), "Hi", "Hello"));
+        let queued = outbox.queue(&address(0), &address(1), "Re: Hi", "Hello back", &SendOptions::new());
+        let sent = outbox.queue(&address(0), &address(2), "Other", "Body", &SendOptions::new());
+        outbox.set_status(&sent, OutboxStatus::Sent);
+
+        assert_eq!(vec![ vec![ 1; 32 ] ], ids(&messages, MessageQuery::new().folder(Folder::Inbox)));
+        assert_eq!(vec![ queued.clone() ], ids(&messages, MessageQuery::new().folder(Folder::Outbox)));
+        assert_eq!(vec![ sent.clone() ], ids(&messages, MessageQuery::new().folder(Folder::Sent)));
+
+        assert!(!messages.get(&[ 1; 32 ]).unwrap().is_read());
+        assert!(messages.mark_read(&[ 1; 32 ], true));
+        assert!(messages.get(&[ 1; 32 ]).unwrap().is_read());
+        assert!(!messages.mark_read(&queued, true));
+
+        assert!(messages.trash(&[ 1; 32 ]));
+        assert!(messages.trash(&queued));
+        assert_eq!(2, messages.query(&MessageQuery::new().folder(Folder::Trash)).len());
+        assert!(outbox.get(&queued).unwrap().is_trashed());
+        assert!(messages.restore(&queued));
+        assert_eq!(Folder::Outbox, messages.get(&queued).unwrap().folder());
+
+        assert!(messages.delete(&[ 1; 32 ]));
+        assert!(messages.delete(&sent));
+        assert!(!messages.delete(&sent));
+        assert_eq!(vec![ queued ], ids(&messages, MessageQuery::new()));
+    }
+
+    #[test]
+    fn test_query_by_address_with_paging() {
+        let (messages, mut inbox, mut outbox) = setup();
+        inbox.add(&InboxMessage::new(&[ 1; 32 ], &address(1), Some(&address(0)), "1", ""));
+        let to_first = outbox.queue(&address(0), &address(1), "2", "", &SendOptions::new());
+        let to_second = outbox.queue(&address(2), &address(1), "3", "", &SendOptions::new());
+        inbox.add(&InboxMessage::new(&[ 2; 32 ], &address(1), None, "4", ""));
+
+        assert_eq!(vec![ to_first.clone(), vec![ 1; 32 ] ], ids(&messages, MessageQuery::new().identity(&address(0))));
+        assert_eq!(4, messages.query(&MessageQuery::new().correspondent(&address(1))).len());
+        assert_eq!(vec![ to_second ], ids(&messages, MessageQuery::new().identity(&address(2)).correspondent(&address(1))));
+
+        let all = ids(&messages, MessageQuery::new());
+        assert_eq!(4, all.len());
+        assert_eq!(vec![ 1; 32 ], all[3]);
+        assert_eq!(all[1..3].to_vec(), ids(&messages, MessageQuery::new().page(1, 2)));
+        assert!(messages.query(&MessageQuery::new().page(4, 10)).is_empty());
+    }
+
+    #[test]
+    fn test_text_and_date_search() {
+        let (mut messages, mut inbox, mut outbox) = setup();
+        let start = SystemTime::now();
+        inbox.add(&InboxMessage::new(&[ 1; 32 ], &address(1), Some(&address(0)), "Lunch", "Pizza on Friday?"));
+        let reply = outbox.queue(&address(0), &address(1), "Re: Lunch", "Friday works", &SendOptions::new());
+        let middle = SystemTime::now();
+        inbox.add(&InboxMessage::new(&[ 2; 32 ], &address(2), None, "News", "Nothing about lunch"));
+
+        assert_eq!(vec![ reply.clone(), vec![ 1; 32 ] ], ids(&messages, MessageQuery::new().text("friday")));
+        assert_eq!(vec![ vec![ 1; 32 ] ], ids(&messages, MessageQuery::new().text("lunch pizza").folder(Folder::Inbox)));
+        assert_eq!(vec![ vec![ 2; 32 ] ], ids(&messages, MessageQuery::new().text("lunch -friday")));
+        assert_eq!(2, messages.query(&MessageQuery::new().text(&address(1).to_string())).len());
+        assert_eq!(vec![ vec![ 2; 32 ] ], ids(&messages, MessageQuery::new().text("lunch").since(middle)));
+        assert_eq!(2, messages.query(&MessageQuery::new().since(start).until(middle)).len());
+
+        assert!(messages.delete(&reply));
+        assert_eq!(vec![ vec![ 1; 32 ] ], ids(&messages, MessageQuery::new().text("friday")));
+    }
+
+    fn ids(messages: &Messages, query: MessageQuery) -> Vec<Vec<u8>> {
+        messages.query(&query).iter().map(|message| message.id().to_vec()).collect()
+    }
+}
@@ -0,0 +1,200 @@
+use byteorder::{BigEndian,ReadBytesExt};
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use rmp::Marker;
+use rmp::decode::{read_map_len,read_marker};
+use rmp::encode::{write_map_len,write_str};
+use std::io::{Cursor,Read,Write};
+
+// How the subject and body are packed into the message bytes of msgs and broadcasts
+
+const ENCODING_TRIVIAL: u64 = 1; // body only
+const ENCODING_SIMPLE: u64 = 2;
+const ENCODING_EXTENDED: u64 = 3; // zlib compressed msgpack
+
+// An object is at most 256KiB, so this allows for text compressing four times over
+const MAX_DECOMPRESSED_LENGTH: usize = 1048576;
+const MAX_EXTENDED_FIELDS: u32 = 16;
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq)]
+pub enum MessageEncoding {
+    Trivial, // drops the subject
+    Simple,
+    Extended
+}
+
+impl MessageEncoding {
+    pub fn number(&self) -> u64 {
+        match self {
+            &MessageEncoding::Trivial => ENCODING_TRIVIAL,
+            &MessageEncoding::Simple => ENCODING_SIMPLE,
+            &MessageEncoding::Extended => ENCODING_EXTENDED
+        }
+    }
+
+    pub fn from_number(number: u64) -> Option<MessageEncoding> {
+        match number {
+            ENCODING_TRIVIAL => Some(MessageEncoding::Trivial),
+            ENCODING_SIMPLE => Some(MessageEncoding::Simple),
+            ENCODING_EXTENDED => Some(MessageEncoding::Extended),
+            _ => None
+        }
+    }
+}
+
+pub fn encode(encoding: MessageEncoding, subject: &str, body: &str) -> Vec<u8> {
+    match encoding {
+        MessageEncoding::Trivial => body.as_bytes().to_vec(),
+        MessageEncoding::Simple => format!("Subject:{}\nBody:{}", subject, body).into_bytes(),
+        MessageEncoding::Extended => encode_extended(subject, body)
+    }
+}
+
+// Gives the subject and body, or None for encodings we don't understand or can't decode
+pub fn decode(encoding: u64, message: &[u8]) -> Option<(String, String)> {
+    match encoding {
+        ENCODING_TRIVIAL => Some((String::new(), String::from_utf8_lossy(message).into_owned())),
+        ENCODING_SIMPLE => Some(decode_simple(&String::from_utf8_lossy(message))),
+        ENCODING_EXTENDED => decode_extended(message),
+        _ => None
+    }
+}
+
+// Anything not in the expected layout is treated as all body
+fn decode_simple(text: &str) -> (String, String) {
+    if text.starts_with("Subject:") {
+        if let Some(end) = text.find("\nBody:") {
+            return (text["Subject:".len()..end].to_string(), text[end + "\nBody:".len()..].to_string());
+        }
+    }
+    (String::new(), text.to_string())
+}
+
+// The same layout as PyBitmessage's message type: { "": "message", "subject": ..., "body": ... }
+fn encode_extended(subject: &str, body: &str) -> Vec<u8> {
+    let mut packed = vec![];
+    write_map_len(&mut packed, 3).unwrap();
+    for &(key, value) in &[ ("", "message"), ("subject", subject), ("body", body) ] {
+        write_str(&mut packed, key).unwrap();
+        write_str(&mut packed, value).unwrap();
+    }
+
+    let mut encoder = ZlibEncoder::new(vec![], Compression::best());
+    encoder.write_all(&packed).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn decode_extended(message: &[u8]) -> Option<(String, String)> {
+    let mut packed = vec![];
+    let decoder = ZlibDecoder::new(message);
+    if decoder.take(MAX_DECOMPRESSED_LENGTH as u64 + 1).read_to_end(&mut packed).is_err() || packed.len() > MAX_DECOMPRESSED_LENGTH {
+        return None;
+    }
+
+    let mut cursor = Cursor::new(&packed[..]);
+    let field_count = match read_map_len(&mut cursor) {
+        Ok(field_count) if field_count <= MAX_EXTENDED_FIELDS => field_count,
+        _ => return None
+    };
+
+    let (mut message_type, mut subject, mut body) = (None, String::new(), String::new());
+    for _ in 0..field_count {
+        let key = match read_text(&mut cursor) {
+            Some(key) => key,
+            None => return None
+        };
+        let value = match read_text(&mut cursor) {
+            Some(value) => value,
+            None => return None
+        };
+        match &key[..] {
+            "" => message_type = Some(value),
+            "subject" => subject = value,
+            "body" => body = value,
+            _ => {}
+        }
+    }
+
+    // Other types, like votes, aren't messages for the inbox
+    match message_type {
+        Some(ref message_type) if message_type == "message" => Some((subject, body)),
+        _ => None
+    }
+}
+
+// Older msgpack versions pack strings as binary, so either will do
+fn read_text(cursor: &mut Cursor<&[u8]>) -> Option<String> {
+    let length = match read_marker(cursor) {
+        Ok(Marker::FixStr(length)) => length as usize,
+        Ok(Marker::Str8) | Ok(Marker::Bin8) => match cursor.read_u8() {
+            Ok(length) => length as usize,
+            Err(_) => return None
+        },
+        Ok(Marker::Str16) | Ok(Marker::Bin16) => match cursor.read_u16::<BigEndian>() {
+            Ok(length) => length as usize,
+            Err(_) => return None
+        },
+        Ok(Marker::Str32) | Ok(Marker::Bin32) => match cursor.read_u32::<BigEndian>() {
+            Ok(length) => length as usize,
+            Err(_) => return None
+        },
+        _ => return None
+    };
+
+    let start = cursor.position() as usize;
+    let bytes: &[u8] = *cursor.get_ref();
+    if length > bytes.len() - start {
+        return None;
+    }
+    cursor.set_position((start + length) as u64);
+    Some(String::from_utf8_lossy(&bytes[start..start + length]).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use flate2::Compression;
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+    use super::{MAX_DECOMPRESSED_LENGTH,MessageEncoding,decode,encode};
+
+    fn roundtrip(encoding: MessageEncoding, subject: &str, body: &str) -> Option<(String, String)> {
+        decode(encoding.number(), &encode(encoding, subject, body))
+    }
+
+    #[test]
+    fn test_simple_roundtrip() {
+        let message = encode(MessageEncoding::Simple, "Hi", "Hello\nBody:there");
+
+        assert_eq!(b"Subject:Hi\nBody:Hello\nBody:there".to_vec(), message);
+        assert_eq!(Some(("Hi".to_string(), "Hello\nBody:there".to_string())), roundtrip(MessageEncoding::Simple, "Hi", "Hello\nBody:there"));
+    }
+
+    #[test]
+    fn test_other_encodings() {
+        assert_eq!(Some(("".to_string(), "Hello".to_string())), decode(2, b"Hello"));
+        assert_eq!(Some(("".to_string(), "Hello".to_string())), roundtrip(MessageEncoding::Trivial, "Hi", "Hello"));
+        assert_eq!(None, decode(0, b"Hello"));
+    }
+
+    #[test]
+    fn test_extended_roundtrip() {
+        let body = "Hello ".repeat(1000);
+        let message = encode(MessageEncoding::Extended, "Hi", &body);
+
+        assert!(message.len() < 100);
+        assert_eq!(Some(("Hi".to_string(), body)), decode(3, &message));
+        assert_eq!(None, decode(3, &message[0..message.len() - 1]));
+    }
+
+    #[test]
+    fn test_extended_decompression_is_limited() {
+        let mut encoder = ZlibEncoder::new(vec![], Compression::best());
+        encoder.write_all(&[ 0x80 ]).unwrap();
+        encoder.write_all(&vec![ 0; MAX_DECOMPRESSED_LENGTH ]).unwrap();
+        let bomb = encoder.finish().unwrap();
+
+        assert!(bomb.len() < 2048);
+        assert_eq!(None, decode(3, &bomb));
+    }
+}
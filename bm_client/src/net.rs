@@ -1,5 +1,269 @@
-use std::net::{SocketAddr,ToSocketAddrs};
+use byteorder::{BigEndian,WriteBytesExt};
+use std::fmt;
+use std::io::{Error,ErrorKind,Read,Write};
+use std::net::{SocketAddr,TcpStream,ToSocketAddrs};
 
+// OnionCat maps the 80-bit hidden service id into fd87:d87e:eb43::/48
+pub const ONION_PREFIX: [u8; 6] = [ 0xfd, 0x87, 0xd8, 0x7e, 0xeb, 0x43 ];
+const BASE32_ALPHABET: &'static [u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub enum PeerAddr {
+    Ip(SocketAddr),
+    Onion(OnionAddr)
+}
+
+#[derive(Clone,Copy,Debug,PartialEq,Eq,Hash)]
+pub struct OnionAddr {
+    id: [u8; 10],
+    port: u16
+}
+
+impl PeerAddr {
+    pub fn port(&self) -> u16 {
+        match self {
+            &PeerAddr::Ip(socket_addr) => socket_addr.port(),
+            &PeerAddr::Onion(onion_addr) => onion_addr.port
+        }
+    }
+
+    pub fn is_onion(&self) -> bool {
+        match self {
+            &PeerAddr::Onion(_) => true,
+            _ => false
+        }
+    }
+}
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(socket_addr: SocketAddr) -> PeerAddr {
+        PeerAddr::Ip(socket_addr)
+    }
+}
+
+impl fmt::Display for PeerAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &PeerAddr::Ip(socket_addr) => write!(f, "{}", socket_addr),
+            &PeerAddr::Onion(onion_addr) => write!(f, "{}:{}", onion_addr.hostname(), onion_addr.port)
+        }
+    }
+}
+
+impl OnionAddr {
+    pub fn new(id: [u8; 10], port: u16) -> OnionAddr {
+        OnionAddr {
+            id: id,
+            port: port
+        }
+    }
+
+    pub fn from_hostname(hostname: &str, port: u16) -> Option<OnionAddr> {
+        let lower = hostname.to_lowercase();
+        let encoded = match lower.ends_with(".onion") {
+            true => &lower[..lower.len() - 6],
+            false => return None
+        };
+
+        if encoded.len() != 16 {
+            return None;
+        }
+
+        let mut bits: u64 = 0;
+        let mut bit_count = 0;
+        let mut id = [0u8; 10];
+        let mut index = 0;
+        for c in encoded.bytes() {
+            let value = match BASE32_ALPHABET.iter().position(|&a| a == c) {
+                Some(value) => value as u64,
+                None => return None
+            };
+
+            bits = (bits << 5) | value;
+            bit_count += 5;
+            if bit_count >= 8 {
+                bit_count -= 8;
+                id[index] = (bits >> bit_count) as u8;
+                index += 1;
+            }
+        }
+
+        Some(OnionAddr::new(id, port))
+    }
+
+    pub fn id(&self) -> &[u8; 10] {
+        &self.id
+    }
+
+    pub fn hostname(&self) -> String {
+        let mut hostname = String::with_capacity(22);
+        let mut bits: u64 = 0;
+        let mut bit_count = 0;
+        for &byte in self.id.iter() {
+            bits = (bits << 8) | byte as u64;
+            bit_count += 8;
+            while bit_count >= 5 {
+                bit_count -= 5;
+                hostname.push(BASE32_ALPHABET[((bits >> bit_count) & 0x1f) as usize] as char);
+            }
+        }
+
+        hostname.push_str(".onion");
+        hostname
+    }
+}
+
+#[cfg(test)]
 pub fn to_socket_addr<A: ToSocketAddrs>(addr: A) -> SocketAddr {
     addr.to_socket_addrs().unwrap().next().unwrap()
 }
+
+pub fn resolve_peer_addrs(host_and_port: &str, default_port: u16) -> Result<Vec<PeerAddr>,Error> {
+    let trimmed = host_and_port.trim();
+    if let Ok(socket_addr) = trimmed.parse::<SocketAddr>() {
+        return Ok(vec![ PeerAddr::Ip(socket_addr) ]);
+    }
+
+    let (host, port) = match trimmed.rfind(':') {
+        Some(index) if !trimmed[..index].contains(':') => {
+            let port = try!(trimmed[index + 1..].parse::<u16>().map_err(|_| Error::new(ErrorKind::InvalidInput, "Invalid port")));
+            (&trimmed[..index], port)
+        },
+        _ => (trimmed, default_port)
+    };
+
+    if host.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Missing host"));
+    }
+
+    if host.to_lowercase().ends_with(".onion") {
+        return match OnionAddr::from_hostname(host, port) {
+            Some(onion_addr) => Ok(vec![ PeerAddr::Onion(onion_addr) ]),
+            None => Err(Error::new(ErrorKind::InvalidInput, "Invalid onion address"))
+        };
+    }
+
+    let socket_addrs = try!((host, port).to_socket_addrs());
+    Ok(socket_addrs.map(PeerAddr::Ip).collect())
+}
+
+pub fn connect(peer_addr: &PeerAddr, socks_proxy: Option<SocketAddr>) -> Result<TcpStream,Error> {
+    match (peer_addr, socks_proxy) {
+        (&PeerAddr::Ip(socket_addr), None) => TcpStream::connect(&socket_addr),
+        (&PeerAddr::Onion(_), None) => Err(Error::new(ErrorKind::Other, "Onion peers can only be reached through a SOCKS proxy")),
+        (_, Some(proxy_addr)) => socks5_connect(proxy_addr, peer_addr)
+    }
+}
+
+fn socks5_connect(proxy_addr: SocketAddr, peer_addr: &PeerAddr) -> Result<TcpStream,Error> {
+    let mut stream = try!(TcpStream::connect(&proxy_addr));
+
+    // Version 5, one authentication method offered: none
+    try!(stream.write_all(&[ 5, 1, 0 ]));
+    let mut method_reply = [0u8; 2];
+    try!(stream.read_exact(&mut method_reply));
+    if method_reply != [ 5, 0 ] {
+        return Err(socks_error("SOCKS proxy refused unauthenticated access"));
+    }
+
+    // Version 5, CONNECT, reserved
+    let mut request: Vec<u8> = vec![ 5, 1, 0 ];
+    match peer_addr {
+        &PeerAddr::Ip(SocketAddr::V4(v4_addr)) => {
+            request.push(1);
+            request.extend(v4_addr.ip().octets().iter());
+        },
+        &PeerAddr::Ip(SocketAddr::V6(v6_addr)) => {
+            request.push(4);
+            request.extend(v6_addr.ip().octets().iter());
+        },
+        &PeerAddr::Onion(onion_addr) => {
+            let hostname = onion_addr.hostname();
+            request.push(3);
+            request.push(hostname.len() as u8);
+            request.extend(hostname.bytes());
+        }
+    }
+    request.write_u16::<BigEndian>(peer_addr.port()).unwrap();
+    try!(stream.write_all(&request));
+
+    let mut reply = [0u8; 4];
+    try!(stream.read_exact(&mut reply));
+    if reply[0] != 5 || reply[1] != 0 {
+        return Err(socks_error("SOCKS proxy could not connect to peer"));
+    }
+
+    let bound_addr_length = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut length = [0u8; 1];
+            try!(stream.read_exact(&mut length));
+            length[0] as usize
+        },
+        _ => return Err(socks_error("SOCKS proxy replied with an unknown address type"))
+    };
+    let mut bound_addr_and_port = vec![0u8; bound_addr_length + 2];
+    try!(stream.read_exact(&mut bound_addr_and_port));
+
+    Ok(stream)
+}
+
+fn socks_error(description: &str) -> Error {
+    Error::new(ErrorKind::Other, description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OnionAddr,PeerAddr,resolve_peer_addrs,to_socket_addr};
+
+    #[test]
+    fn test_onion_hostname() {
+        let onion_addr = OnionAddr::new([ 0x4f, 0x5b, 0xf2, 0x23, 0x0f, 0x86, 0xe9, 0xc6, 0x64, 0x11 ], 8444);
+        assert_eq!("j5n7eiypq3u4mzar.onion", onion_addr.hostname());
+    }
+
+    #[test]
+    fn test_onion_from_hostname_roundtrip() {
+        let onion_addr = OnionAddr::from_hostname("J5N7EIYPQ3U4MZAR.onion", 8444).unwrap();
+        assert_eq!(&[ 0x4f, 0x5b, 0xf2, 0x23, 0x0f, 0x86, 0xe9, 0xc6, 0x64, 0x11 ], onion_addr.id());
+        assert_eq!("j5n7eiypq3u4mzar.onion", onion_addr.hostname());
+    }
+
+    #[test]
+    fn test_onion_from_bad_hostname() {
+        assert!(OnionAddr::from_hostname("j5n7eiypq3u4mzar.com", 8444).is_none());
+        assert!(OnionAddr::from_hostname("j5n7eiypq3u4mza.onion", 8444).is_none());
+        assert!(OnionAddr::from_hostname("j5n7eiypq3u4mza1.onion", 8444).is_none());
+    }
+
+    #[test]
+    fn test_peer_addr_display() {
+        let ip = PeerAddr::Ip(to_socket_addr("127.0.0.1:8444"));
+        assert_eq!("127.0.0.1:8444", format!("{}", ip));
+
+        let onion = PeerAddr::Onion(OnionAddr::from_hostname("j5n7eiypq3u4mzar.onion", 8444).unwrap());
+        assert_eq!("j5n7eiypq3u4mzar.onion:8444", format!("{}", onion));
+    }
+
+    #[test]
+    fn test_resolve_literal_addresses() {
+        assert_eq!(vec![ PeerAddr::Ip(to_socket_addr("5.45.99.75:8444")) ], resolve_peer_addrs("5.45.99.75:8444", 1).unwrap());
+        assert_eq!(vec![ PeerAddr::Ip(to_socket_addr("5.45.99.75:8444")) ], resolve_peer_addrs(" 5.45.99.75 ", 8444).unwrap());
+        assert_eq!(vec![ PeerAddr::Ip(to_socket_addr("[2001:cdba::3257:9652]:8080")) ], resolve_peer_addrs("[2001:cdba::3257:9652]:8080", 8444).unwrap());
+        assert_eq!(vec![ PeerAddr::Ip(to_socket_addr("[2001:cdba::3257:9652]:8444")) ], resolve_peer_addrs("2001:cdba::3257:9652", 8444).unwrap());
+    }
+
+    #[test]
+    fn test_resolve_onion_addresses() {
+        let expected = PeerAddr::Onion(OnionAddr::from_hostname("j5n7eiypq3u4mzar.onion", 8448).unwrap());
+        assert_eq!(vec![ expected ], resolve_peer_addrs("j5n7eiypq3u4mzar.onion:8448", 8444).unwrap());
+        assert!(resolve_peer_addrs("notvalid.onion", 8444).is_err());
+    }
+
+    #[test]
+    fn test_resolve_bad_input_is_an_error() {
+        assert!(resolve_peer_addrs("", 8444).is_err());
+        assert!(resolve_peer_addrs("5.45.99.75:port", 8444).is_err());
+    }
+}
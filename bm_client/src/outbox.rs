@@ -0,0 +1,803 @@
+use address::Address;
+use config::Config;
+use disk::{address_from_json,address_to_json,bytes_from_json,bytes_to_json,string_from_json,time_from_json,time_to_json};
+use ecies;
+use events::{Event,Events,OutboxStatus};
+use identity::{BEHAVIOUR_DOES_ACK,Identities};
+use inventory::{Inventory,calculate_inventory_vector};
+use keys::sign;
+use message::{GetPubKey,InventoryVector,MsgContent,Message,NETWORK_EXTRA_BYTES,NETWORK_TRIALS_PER_BYTE,MAX_PAYLOAD_LENGTH_FOR_OBJECT,OBJECT_MSG,Object,ObjectData,Sender};
+use message::{write_message,write_msg_content,write_object_header,write_unsigned_msg_content};
+use msgcoding::{MessageEncoding,encode};
+use persist::Persister;
+use pubkeys::{KnownPubKey,PubKeys};
+use rand::{OsRng,Rng};
+use rustc_serialize::json::{Json,ToJson};
+use std::collections::BTreeMap;
+use std::cmp::{max,min};
+use std::io;
+use std::thread::{Builder,JoinHandle};
+use std::time::{Duration,Instant,SystemTime};
+use stop::{StopSignal,join_until};
+
+const DEFAULT_TTL_SECS: u64 = 345600; // 4 days
+const MIN_TTL_SECS: u64 = 3600; // 1 hour
+const MAX_TTL_SECS: u64 = 2419200; // 28 days
+const MSG_OBJECT_VERSION: u64 = 1;
+const ACKDATA_LENGTH: usize = 32;
+const POLL_INTERVAL_MILLIS: u64 = 100;
+
+// Leaves room for the keys, signature and encryption around the message
+pub const MAX_MESSAGE_LENGTH: usize = MAX_PAYLOAD_LENGTH_FOR_OBJECT as usize - 2048;
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct SendOptions {
+    ttl: Duration,
+    encoding: MessageEncoding
+}
+
+impl SendOptions {
+    pub fn new() -> SendOptions {
+        SendOptions {
+            ttl: Duration::from_secs(DEFAULT_TTL_SECS),
+            encoding: MessageEncoding::Simple
+        }
+    }
+
+    // How long the network keeps the message for; limited to between an hour and 28 days
+    pub fn ttl(mut self, ttl: Duration) -> SendOptions {
+        self.ttl = min(max(ttl, Duration::from_secs(MIN_TTL_SECS)), Duration::from_secs(MAX_TTL_SECS));
+        self
+    }
+
+    // Simple is understood by every client; extended compresses long messages
+    pub fn encoding(mut self, encoding: MessageEncoding) -> SendOptions {
+        self.encoding = encoding;
+        self
+    }
+}
+
+#[derive(Clone,Debug,PartialEq)]
+pub struct OutboxMessage {
+    ackdata: Vec<u8>, // 32 bytes, also used as the message id
+    from: Address,
+    to: Option<Address>, // None for broadcasts
+    subject: String,
+    body: String,
+    encoding: MessageEncoding,
+    ttl: Duration,
+    status: OutboxStatus,
+    created: SystemTime,
+    expiry: Option<SystemTime>, // of the last object sent for this message
+    resends: u32,
+    ack_inventory_vectors: Vec<InventoryVector>, // an ack for any of the resends will do
+    trashed: bool // trashed messages aren't worked on until restored
+}
+
+impl OutboxMessage {
+    // A message sent before, brought back from an archive. It never goes out again.
+    pub fn restored(ackdata: &[u8], from: &Address, to: Option<&Address>, subject: &str, body: &str, status: OutboxStatus, created: SystemTime) -> OutboxMessage {
+        OutboxMessage {
+            ackdata: ackdata.to_vec(),
+            from: from.clone(),
+            to: to.cloned(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            encoding: MessageEncoding::Simple,
+            ttl: Duration::from_secs(DEFAULT_TTL_SECS),
+            status: status,
+            created: created,
+            expiry: None,
+            resends: 0,
+            ack_inventory_vectors: vec![],
+            trashed: false
+        }
+    }
+
+    pub fn ackdata(&self) -> &[u8] {
+        &self.ackdata
+    }
+
+    pub fn from(&self) -> &Address {
+        &self.from
+    }
+
+    pub fn to(&self) -> Option<&Address> {
+        self.to.as_ref()
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        self.to.is_none()
+    }
+
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    pub fn encoding(&self) -> MessageEncoding {
+        self.encoding
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    pub fn status(&self) -> OutboxStatus {
+        self.status
+    }
+
+    pub fn created(&self) -> SystemTime {
+        self.created
+    }
+
+    pub fn expiry(&self) -> Option<SystemTime> {
+        self.expiry
+    }
+
+    pub fn resends(&self) -> u32 {
+        self.resends
+    }
+
+    pub fn is_trashed(&self) -> bool {
+        self.trashed
+    }
+
+    pub fn from_json(json: &Json) -> Option<OutboxMessage> {
+        let mut ack_inventory_vectors = vec![];
+        for hash in return_none_on_none!(json.find("ack_inventory_vectors").and_then(|hashes| hashes.as_array())) {
+            ack_inventory_vectors.push(InventoryVector { hash: return_none_on_none!(bytes_from_json(Some(hash))) });
+        }
+
+        Some(OutboxMessage {
+            ackdata: return_none_on_none!(bytes_from_json(json.find("ackdata"))),
+            from: return_none_on_none!(address_from_json(json.find("from"))),
+            to: address_from_json(json.find("to")),
+            subject: return_none_on_none!(string_from_json(json.find("subject"))),
+            body: return_none_on_none!(string_from_json(json.find("body"))),
+            encoding: return_none_on_none!(json.find("encoding").and_then(|encoding| encoding.as_u64()).and_then(MessageEncoding::from_number)),
+            ttl: Duration::from_secs(return_none_on_none!(json.find("ttl").and_then(|ttl| ttl.as_u64()))),
+            status: return_none_on_none!(json.find("status").and_then(|status| status.as_string()).and_then(status_from_name)),
+            created: return_none_on_none!(time_from_json(json.find("created"))),
+            expiry: time_from_json(json.find("expiry")),
+            resends: return_none_on_none!(json.find("resends").and_then(|resends| resends.as_u64())) as u32,
+            ack_inventory_vectors: ack_inventory_vectors,
+            trashed: return_none_on_none!(json.find("trashed").and_then(|trashed| trashed.as_boolean()))
+        })
+    }
+
+    // Proof of work in progress is lost when the client stops, as are the pubkey requests and
+    // pubkeys that were only held in memory, so those messages start over when loaded
+    pub fn restart_unfinished(&mut self) {
+        if self.status == OutboxStatus::DoingPow || self.status == OutboxStatus::AwaitingPubKey {
+            self.status = OutboxStatus::Queued;
+        }
+    }
+
+    // Sent messages that no ack will come back for are finished with
+    fn needs_resend(&self, now: SystemTime) -> bool {
+        let waiting = match self.status {
+            OutboxStatus::AwaitingPubKey => true,
+            OutboxStatus::Sent => !self.ack_inventory_vectors.is_empty(),
+            _ => false
+        };
+        waiting && self.expiry.map_or(false, |expiry| expiry <= now)
+    }
+}
+
+impl ToJson for OutboxMessage {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("ackdata".to_string(), bytes_to_json(&self.ackdata));
+        object.insert("from".to_string(), address_to_json(&self.from));
+        if let Some(ref to) = self.to {
+            object.insert("to".to_string(), address_to_json(to));
+        }
+        object.insert("subject".to_string(), self.subject.to_json());
+        object.insert("body".to_string(), self.body.to_json());
+        object.insert("encoding".to_string(), self.encoding.number().to_json());
+        object.insert("ttl".to_string(), self.ttl.as_secs().to_json());
+        object.insert("status".to_string(), status_name(self.status).to_json());
+        object.insert("created".to_string(), time_to_json(self.created));
+        if let Some(expiry) = self.expiry {
+            object.insert("expiry".to_string(), time_to_json(expiry));
+        }
+        object.insert("resends".to_string(), (self.resends as u64).to_json());
+        object.insert("ack_inventory_vectors".to_string(), Json::Array(self.ack_inventory_vectors.iter().map(|inventory_vector| bytes_to_json(&inventory_vector.hash)).collect()));
+        object.insert("trashed".to_string(), self.trashed.to_json());
+        Json::Object(object)
+    }
+}
+
+pub fn status_name(status: OutboxStatus) -> &'static str {
+    match status {
+        OutboxStatus::Queued => "queued",
+        OutboxStatus::AwaitingPubKey => "awaiting_pubkey",
+        OutboxStatus::DoingPow => "doing_pow",
+        OutboxStatus::Sent => "sent",
+        OutboxStatus::AckReceived => "ack_received",
+        OutboxStatus::Failed => "failed",
+        OutboxStatus::GaveUp => "gave_up"
+    }
+}
+
+pub fn status_from_name(name: &str) -> Option<OutboxStatus> {
+    match name {
+        "queued" => Some(OutboxStatus::Queued),
+        "awaiting_pubkey" => Some(OutboxStatus::AwaitingPubKey),
+        "doing_pow" => Some(OutboxStatus::DoingPow),
+        "sent" => Some(OutboxStatus::Sent),
+        "ack_received" => Some(OutboxStatus::AckReceived),
+        "failed" => Some(OutboxStatus::Failed),
+        "gave_up" => Some(OutboxStatus::GaveUp),
+        _ => None
+    }
+}
+
+#[derive(Clone)]
+pub struct Outbox {
+    persister: Persister,
+    events: Events
+}
+
+impl Outbox {
+    pub fn new(persister: Persister, events: &Events) -> Outbox {
+        Outbox {
+            persister: persister,
+            events: events.clone()
+        }
+    }
+
+    // Returns the ackdata, which identifies the message from then on
+    pub fn queue(&mut self, from: &Address, to: &Address, subject: &str, body: &str, options: &SendOptions) -> Vec<u8> {
+        self.add(from, Some(to), subject, body, options)
+    }
+
+    // Broadcasts are never acknowledged, but the ackdata still serves as their id
+    pub fn queue_broadcast(&mut self, from: &Address, subject: &str, body: &str, options: &SendOptions) -> Vec<u8> {
+        self.add(from, None, subject, body, options)
+    }
+
+    fn add(&mut self, from: &Address, to: Option<&Address>, subject: &str, body: &str, options: &SendOptions) -> Vec<u8> {
+        let mut ackdata = vec![0u8; ACKDATA_LENGTH];
+        OsRng::new().unwrap().fill_bytes(&mut ackdata);
+
+        let outbox_message = OutboxMessage {
+            ackdata: ackdata.clone(),
+            from: from.clone(),
+            to: to.cloned(),
+            subject: subject.to_string(),
+            body: body.to_string(),
+            encoding: options.encoding,
+            ttl: options.ttl,
+            status: OutboxStatus::Queued,
+            created: SystemTime::now(),
+            expiry: None,
+            resends: 0,
+            ack_inventory_vectors: vec![],
+            trashed: false
+        };
+        self.persister.add_outbox_message(&outbox_message);
+        self.events.emit(Event::OutboxStatusChanged { ackdata: ackdata.clone(), status: OutboxStatus::Queued });
+
+        ackdata
+    }
+
+    // Without an expiry or ack to wait for, the worker leaves the message alone
+    pub fn restore(&mut self, outbox_message: &OutboxMessage) -> bool {
+        if self.get(outbox_message.ackdata()).is_some() {
+            return false;
+        }
+
+        self.persister.add_outbox_message(outbox_message);
+        true
+    }
+
+    pub fn get(&self, ackdata: &[u8]) -> Option<OutboxMessage> {
+        self.list().into_iter().find(|outbox_message| outbox_message.ackdata() == ackdata)
+    }
+
+    pub fn list(&self) -> Vec<OutboxMessage> {
+        self.persister.get_outbox_messages()
+    }
+
+    pub fn with_status(&self, status: OutboxStatus) -> Vec<OutboxMessage> {
+        self.list().into_iter().filter(|outbox_message| outbox_message.status() == status).collect()
+    }
+
+    // Returns false if the message has been cancelled in the meantime
+    pub fn set_status(&mut self, ackdata: &[u8], status: OutboxStatus) -> bool {
+        let updated = self.persister.update_outbox_message(ackdata, |outbox_message| outbox_message.status = status);
+        if updated {
+            self.events.emit(Event::OutboxStatusChanged { ackdata: ackdata.to_vec(), status: status });
+        }
+        updated
+    }
+
+    pub fn mark_sent(&mut self, ackdata: &[u8], expiry: SystemTime, ack_inventory_vector: Option<InventoryVector>) -> bool {
+        let updated = self.persister.update_outbox_message(ackdata, |outbox_message| {
+            outbox_message.status = OutboxStatus::Sent;
+            outbox_message.expiry = Some(expiry);
+            outbox_message.ack_inventory_vectors.extend(ack_inventory_vector);
+        });
+        if updated {
+            self.events.emit(Event::OutboxStatusChanged { ackdata: ackdata.to_vec(), status: OutboxStatus::Sent });
+        }
+        updated
+    }
+
+    pub fn record_expiry(&mut self, ackdata: &[u8], expiry: SystemTime) -> bool {
+        self.persister.update_outbox_message(ackdata, |outbox_message| outbox_message.expiry = Some(expiry))
+    }
+
+    // Puts the message back in the queue to go out again with a new TTL
+    pub fn schedule_resend(&mut self, ackdata: &[u8], ttl: Duration) -> bool {
+        let updated = self.persister.update_outbox_message(ackdata, |outbox_message| {
+            outbox_message.status = OutboxStatus::Queued;
+            outbox_message.ttl = ttl;
+            outbox_message.expiry = None;
+            outbox_message.resends += 1;
+        });
+        if updated {
+            self.events.emit(Event::OutboxStatusChanged { ackdata: ackdata.to_vec(), status: OutboxStatus::Queued });
+        }
+        updated
+    }
+
+    // Marks the message whose ack this is as delivered, returning its ackdata.
+    // The ack may be for an earlier send of a message that has been queued again since.
+    pub fn acknowledge(&mut self, inventory_vector: &InventoryVector) -> Option<Vec<u8>> {
+        let acknowledged = self.list().into_iter().find(|outbox_message| {
+            outbox_message.status != OutboxStatus::AckReceived && outbox_message.ack_inventory_vectors.contains(inventory_vector)
+        });
+
+        match acknowledged {
+            Some(outbox_message) => {
+                let ackdata = outbox_message.ackdata;
+                if !self.set_status(&ackdata, OutboxStatus::AckReceived) {
+                    return None;
+                }
+                self.events.emit(Event::AckReceived { ackdata: ackdata.clone() });
+                Some(ackdata)
+            },
+            None => None
+        }
+    }
+
+    pub fn set_trashed(&mut self, ackdata: &[u8], trashed: bool) -> bool {
+        self.persister.update_outbox_message(ackdata, |outbox_message| outbox_message.trashed = trashed)
+    }
+
+    pub fn remove(&mut self, ackdata: &[u8]) -> bool {
+        self.persister.remove_outbox_message_if(ackdata, |_| true)
+    }
+
+    // Once a message is on the network it's too late to cancel it
+    pub fn cancel(&mut self, ackdata: &[u8]) -> bool {
+        self.persister.remove_outbox_message_if(ackdata, |outbox_message| {
+            outbox_message.status != OutboxStatus::Sent && outbox_message.status != OutboxStatus::AckReceived
+        })
+    }
+}
+
+pub struct OutboxWorker {
+    jobs: OutboxJobs,
+    stop_signal: StopSignal,
+    thread: Option<JoinHandle<()>>
+}
+
+impl OutboxWorker {
+    pub fn new(config: &Config, outbox: &Outbox, identities: &Identities, pubkeys: &PubKeys, inventory: &Inventory, sender: &Sender) -> OutboxWorker {
+        OutboxWorker {
+            jobs: OutboxJobs {
+                max_resends: config.max_resends(),
+                outbox: outbox.clone(),
+                identities: identities.clone(),
+                pubkeys: pubkeys.clone(),
+                inventory: inventory.clone(),
+                sender: sender.clone()
+            },
+            stop_signal: StopSignal::new(),
+            thread: None
+        }
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        let mut jobs = self.jobs.clone();
+        let stop_signal = StopSignal::new();
+        self.stop_signal = stop_signal.clone();
+
+        let name = "Outbox Worker".to_string();
+        let thread = try!(Builder::new().name(name).spawn(move || {
+            loop {
+                jobs.run(&stop_signal);
+                if !stop_signal.sleep(Duration::from_millis(POLL_INTERVAL_MILLIS)) {
+                    break;
+                }
+            }
+        }));
+
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    pub fn stop(&mut self, deadline: Instant) {
+        self.stop_signal.stop(deadline);
+        if let Some(thread) = self.thread.take() {
+            join_until(thread, deadline);
+        }
+    }
+
+    // Runs one pass of the jobs on the calling thread
+    #[cfg(test)]
+    pub fn run_once(&mut self) {
+        self.jobs.run(&StopSignal::new());
+    }
+}
+
+#[derive(Clone)]
+struct OutboxJobs {
+    max_resends: u32,
+    outbox: Outbox,
+    identities: Identities,
+    pubkeys: PubKeys,
+    inventory: Inventory,
+    sender: Sender
+}
+
+impl OutboxJobs {
+    // Moves each message on as far as it can go
+    fn run(&mut self, stop_signal: &StopSignal) {
+        for outbox_message in self.outbox.list() {
+            if stop_signal.is_stopped() {
+                return;
+            }
+            if outbox_message.is_trashed() {
+                continue;
+            }
+
+            if outbox_message.needs_resend(SystemTime::now()) {
+                self.resend(&outbox_message);
+                continue;
+            }
+
+            match outbox_message.status() {
+                OutboxStatus::Queued => match outbox_message.to() {
+                    None => self.send_broadcast(&outbox_message),
+                    Some(to) => match self.find_pubkey(to, true) {
+                        Some(pubkey) => self.send_msg(&outbox_message, &pubkey),
+                        None => self.request_pubkey(&outbox_message, to)
+                    }
+                },
+                OutboxStatus::AwaitingPubKey => if let Some(pubkey) = outbox_message.to().and_then(|to| self.find_pubkey(to, false)) {
+                    self.send_msg(&outbox_message, &pubkey);
+                },
+                _ => {}
+            }
+        }
+    }
+
+    // Pubkeys that arrive later are picked up by the object processor, so the inventory only needs searching once
+    fn find_pubkey(&mut self, address: &Address, search_inventory: bool) -> Option<KnownPubKey> {
+        if let Some(identity) = self.identities.get(address) {
+            return Some(KnownPubKey::from_identity(&identity));
+        }
+        if let Some(pubkey) = self.pubkeys.get(address) {
+            return Some(pubkey);
+        }
+        if !search_inventory {
+            return None;
+        }
+
+        for inventory_vector in self.inventory.iterator() {
+            if let Some(Message::Object(object_data)) = self.inventory.get_object_message(&inventory_vector) {
+                if let Some(pubkey) = KnownPubKey::from_object(address, &object_data) {
+                    self.pubkeys.add(&pubkey);
+                    return Some(pubkey);
+                }
+            }
+        }
+
+        None
+    }
+
+    // Each resend doubles the TTL, so the recipient has longer to come online
+    fn resend(&mut self, outbox_message: &OutboxMessage) {
+        if outbox_message.resends() >= self.max_resends {
+            self.outbox.set_status(outbox_message.ackdata(), OutboxStatus::GaveUp);
+            return;
+        }
+
+        let ttl = min(outbox_message.ttl() * 2, Duration::from_secs(MAX_TTL_SECS));
+        self.outbox.schedule_resend(outbox_message.ackdata(), ttl);
+    }
+
+    fn request_pubkey(&mut self, outbox_message: &OutboxMessage, to: &Address) {
+        if !self.outbox.set_status(outbox_message.ackdata(), OutboxStatus::AwaitingPubKey) {
+            return;
+        }
+
+        let getpubkey = match to.version() {
+            4 => GetPubKey::V4 { tag: to.tag() },
+            _ => GetPubKey::V3 { ripe: to.ripe().to_vec() }
+        };
+        let expiry = SystemTime::now() + outbox_message.ttl();
+        let object_data = ObjectData::new(expiry, to.version(), to.stream(), Object::GetPubKey(getpubkey));
+
+        match self.sender.send_object(object_data, NETWORK_TRIALS_PER_BYTE, NETWORK_EXTRA_BYTES) {
+            Ok(()) => self.outbox.record_expiry(outbox_message.ackdata(), expiry),
+            Err(_) => self.outbox.set_status(outbox_message.ackdata(), OutboxStatus::Failed)
+        };
+    }
+
+    fn send_msg(&mut self, outbox_message: &OutboxMessage, pubkey: &KnownPubKey) {
+        let ackdata = outbox_message.ackdata();
+        if !self.outbox.set_status(ackdata, OutboxStatus::DoingPow) {
+            return;
+        }
+
+        let expiry = SystemTime::now() + outbox_message.ttl();
+        let generated = self.generate_msg(outbox_message, expiry, pubkey);
+        self.publish(ackdata, expiry, generated);
+    }
+
+    fn send_broadcast(&mut self, outbox_message: &OutboxMessage) {
+        let ackdata = outbox_message.ackdata();
+        if !self.outbox.set_status(ackdata, OutboxStatus::DoingPow) {
+            return;
+        }
+
+        let expiry = SystemTime::now() + outbox_message.ttl();
+        let generated = self.generate_broadcast(outbox_message, expiry).map(|object_message| (object_message, None));
+        self.publish(ackdata, expiry, generated);
+    }
+
+    fn publish(&mut self, ackdata: &[u8], expiry: SystemTime, generated: Option<(Message, Option<InventoryVector>)>) {
+        match generated {
+            Some((object_message, ack_inventory_vector)) => {
+                // Cancelling, or an ack for an earlier send, during the proof of work means it shouldn't go out
+                let still_wanted = self.outbox.get(ackdata).map_or(false, |current| current.status() == OutboxStatus::DoingPow);
+                if still_wanted {
+                    self.inventory.add_object_message(&object_message);
+                    self.outbox.mark_sent(ackdata, expiry, ack_inventory_vector);
+                }
+            },
+            None => {
+                self.outbox.set_status(ackdata, OutboxStatus::Failed);
+            }
+        }
+    }
+
+    // Gives the msg object, and the inventory vector of its ack if there is one
+    fn generate_msg(&self, outbox_message: &OutboxMessage, expiry: SystemTime, pubkey: &KnownPubKey) -> Option<(Message, Option<InventoryVector>)> {
+        let identity = match self.identities.get(outbox_message.from()) {
+            Some(identity) => identity,
+            None => return None
+        };
+
+        let stream = pubkey.address().stream();
+        let ack_message = match pubkey.behaviour_bitfield() & BEHAVIOUR_DOES_ACK {
+            0 => None,
+            _ => match self.generate_ack(outbox_message, expiry, stream) {
+                Some(ack_message) => Some(ack_message),
+                None => return None
+            }
+        };
+        let mut ack_data = vec![];
+        if let Some(ref ack_message) = ack_message {
+            write_message(&mut ack_data, ack_message);
+        }
+
+        let mut content = MsgContent {
+            address_version: identity.address().version(),
+            stream: identity.address().stream(),
+            behaviour_bitfield: identity.behaviour_bitfield(),
+            public_signing_key: identity.public_signing_key(),
+            public_encryption_key: identity.public_encryption_key(),
+            nonce_trials_per_byte: identity.nonce_trials_per_byte(),
+            extra_bytes: identity.extra_bytes(),
+            destination_ripe: pubkey.address().ripe().to_vec(),
+            encoding: outbox_message.encoding.number(),
+            message: encode(outbox_message.encoding, outbox_message.subject(), outbox_message.body()),
+            ack_data: ack_data,
+            signature: vec![]
+        };
+        let mut signed = vec![];
+        write_object_header(&mut signed, &expiry, OBJECT_MSG, MSG_OBJECT_VERSION, stream);
+        write_unsigned_msg_content(&mut signed, &content);
+        content.signature = sign(identity.private_signing_key(), &signed);
+
+        let mut plaintext = vec![];
+        write_msg_content(&mut plaintext, &content);
+
+        let encrypted = match ecies::encrypt(pubkey.public_encryption_key(), &plaintext) {
+            Ok(encrypted) => encrypted,
+            Err(_) => return None
+        };
+
+        let object_data = ObjectData::new(expiry, MSG_OBJECT_VERSION, stream, Object::Msg { encrypted: encrypted });
+
+        match self.sender.generate_object(object_data, pubkey.nonce_trials_per_byte(), pubkey.extra_bytes()) {
+            Ok(object_message) => Some((object_message, ack_message.as_ref().map(calculate_inventory_vector))),
+            Err(_) => None
+        }
+    }
+
+    // The recipient puts this out on the network as it is, so the proof of work has to be done now
+    fn generate_ack(&self, outbox_message: &OutboxMessage, expiry: SystemTime, stream: u32) -> Option<Message> {
+        let object_data = ObjectData::new(expiry, MSG_OBJECT_VERSION, stream, Object::Msg { encrypted: outbox_message.ackdata().to_vec() });
+
+        self.sender.generate_object(object_data, NETWORK_TRIALS_PER_BYTE, NETWORK_EXTRA_BYTES).ok()
+    }
+
+    fn generate_broadcast(&self, outbox_message: &OutboxMessage, expiry: SystemTime) -> Option<Message> {
+        let identity = match self.identities.get(outbox_message.from()) {
+            Some(identity) => identity,
+            None => return None
+        };
+        let message = encode(outbox_message.encoding, outbox_message.subject(), outbox_message.body());
+        let object_data = identity.broadcast_object_data(expiry, outbox_message.encoding.number(), message);
+
+        self.sender.generate_object(object_data, NETWORK_TRIALS_PER_BYTE, NETWORK_EXTRA_BYTES).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use ecies;
+    use events::{Event,Events,OutboxStatus};
+    use identity::{Identities,Identity};
+    use inventory::Inventory;
+    use message::{GetPubKey,Message,Object,Sender,read_message,read_msg_content};
+    use persist::Persister;
+    use pubkeys::PubKeys;
+    use std::io::Cursor;
+    use std::time::{Duration,SystemTime};
+    use stop::StopSignal;
+    use subscriptions::open_broadcast;
+    use super::{Outbox,OutboxJobs,SendOptions};
+
+    fn create_jobs(persister: &Persister, events: &Events) -> OutboxJobs {
+        let inventory = Inventory::new(persister.clone(), events);
+        let mut sender = Sender::new(&Config::new(), inventory.clone(), events);
+        sender.override_difficulty(1, 1);
+
+        OutboxJobs {
+            max_resends: 1,
+            outbox: Outbox::new(persister.clone(), events),
+            identities: Identities::new(persister.clone()),
+            pubkeys: PubKeys::new(persister.clone()),
+            inventory: inventory,
+            sender: sender
+        }
+    }
+
+    #[test]
+    fn test_message_to_unknown_address_waits_for_pubkey() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let mut jobs = create_jobs(&persister, &events);
+        let watcher = jobs.inventory.watch();
+        let from = Identity::random("From", 1);
+        let to = Identity::random("To", 1);
+        jobs.identities.add(&from);
+
+        let ackdata = jobs.outbox.queue(from.address(), to.address(), "Hi", "Hello", &SendOptions::new());
+        jobs.run(&StopSignal::new());
+
+        assert_eq!(OutboxStatus::AwaitingPubKey, jobs.outbox.get(&ackdata).unwrap().status());
+        match watcher.try_recv().unwrap() {
+            Message::Object(object_data) => assert_eq!(&Object::GetPubKey(GetPubKey::V4 { tag: to.address().tag() }), object_data.object()),
+            _ => panic!("Expected an object")
+        }
+    }
+
+    #[test]
+    fn test_message_is_encrypted_to_recipient() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let receiver = events.subscribe();
+        let mut jobs = create_jobs(&persister, &events);
+        let watcher = jobs.inventory.watch();
+        let from = Identity::random("From", 1);
+        let to = Identity::random("To", 1);
+        jobs.identities.add(&from);
+        jobs.sender.send_object(to.pubkey_object_data(SystemTime::now()), 1, 1).unwrap();
+        watcher.try_recv().unwrap();
+
+        let ackdata = jobs.outbox.queue(from.address(), to.address(), "Hi", "Hello", &SendOptions::new());
+        jobs.run(&StopSignal::new());
+
+        assert_eq!(OutboxStatus::Sent, jobs.outbox.get(&ackdata).unwrap().status());
+        let encrypted = match watcher.try_recv().unwrap() {
+            Message::Object(object_data) => match object_data.object() {
+                &Object::Msg { ref encrypted } => encrypted.clone(),
+                _ => panic!("Expected a msg")
+            },
+            _ => panic!("Expected an object")
+        };
+        let content = read_msg_content(&ecies::decrypt(to.private_encryption_key(), &encrypted).unwrap()).unwrap();
+        assert_eq!(b"Subject:Hi\nBody:Hello".to_vec(), content.message);
+        match read_message(&mut Cursor::new(content.ack_data)).unwrap() {
+            Message::Object(object_data) => assert_eq!(&Object::Msg { encrypted: ackdata.clone() }, object_data.object()),
+            _ => panic!("Expected an ack object")
+        }
+        assert_eq!(to.address().ripe(), &content.destination_ripe[..]);
+
+        let statuses: Vec<OutboxStatus> = receiver.try_iter().filter_map(|event| match event {
+            Event::OutboxStatusChanged { status, .. } => Some(status),
+            _ => None
+        }).collect();
+        assert_eq!(vec![ OutboxStatus::Queued, OutboxStatus::DoingPow, OutboxStatus::Sent ], statuses);
+    }
+
+    #[test]
+    fn test_cancel_before_sending() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let mut jobs = create_jobs(&persister, &events);
+        let from = Identity::random("From", 1);
+        jobs.identities.add(&from);
+
+        let ackdata = jobs.outbox.queue(from.address(), from.address(), "Hi", "Hello", &SendOptions::new());
+        assert!(jobs.outbox.cancel(&ackdata));
+        jobs.run(&StopSignal::new());
+
+        assert!(jobs.outbox.get(&ackdata).is_none());
+        assert!(!jobs.outbox.cancel(&ackdata));
+    }
+
+    #[test]
+    fn test_unacknowledged_message_is_resent_then_given_up() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let mut jobs = create_jobs(&persister, &events);
+        let from = Identity::random("From", 1);
+        jobs.identities.add(&from);
+        let expire = |jobs: &mut OutboxJobs, ackdata: &[u8]| {
+            jobs.outbox.persister.update_outbox_message(ackdata, |outbox_message| outbox_message.expiry = Some(SystemTime::now()));
+        };
+
+        let ackdata = jobs.outbox.queue(from.address(), from.address(), "Hi", "Hello", &SendOptions::new());
+        jobs.run(&StopSignal::new());
+        expire(&mut jobs, &ackdata);
+        jobs.run(&StopSignal::new());
+
+        let resent = jobs.outbox.get(&ackdata).unwrap();
+        assert_eq!(OutboxStatus::Queued, resent.status());
+        assert_eq!(1, resent.resends());
+        assert_eq!(Duration::from_secs(8 * 24 * 60 * 60), resent.ttl());
+
+        jobs.run(&StopSignal::new());
+        assert_eq!(OutboxStatus::Sent, jobs.outbox.get(&ackdata).unwrap().status());
+        expire(&mut jobs, &ackdata);
+        jobs.run(&StopSignal::new());
+
+        assert_eq!(OutboxStatus::GaveUp, jobs.outbox.get(&ackdata).unwrap().status());
+    }
+
+    #[test]
+    fn test_broadcast_is_sent_without_ack() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let mut jobs = create_jobs(&persister, &events);
+        let watcher = jobs.inventory.watch();
+        let from = Identity::random("From", 1);
+        jobs.identities.add(&from);
+
+        let ackdata = jobs.outbox.queue_broadcast(from.address(), "News", "Hello all", &SendOptions::new());
+        jobs.run(&StopSignal::new());
+
+        let outbox_message = jobs.outbox.get(&ackdata).unwrap();
+        assert_eq!(OutboxStatus::Sent, outbox_message.status());
+        assert!(!outbox_message.needs_resend(outbox_message.expiry().unwrap()));
+        match watcher.try_recv().unwrap() {
+            Message::Object(object_data) => assert_eq!(b"Subject:News\nBody:Hello all".to_vec(), open_broadcast(from.address(), &object_data).unwrap().message),
+            _ => panic!("Expected an object")
+        }
+    }
+}
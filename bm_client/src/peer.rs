@@ -1,61 +1,132 @@
+use bootstrap::Bootstrapper;
 use config::Config;
 use connection::{Connection,ConnectionState};
+use events::{Event,Events};
 use inventory::Inventory;
 use known_nodes::KnownNodes;
 use message::{MessageHandler,MessageResponder,MessageVerifier};
-use std::net::SocketAddr;
-use std::time::{Duration};
-use std::thread::{Builder,sleep};
+use net::PeerAddr;
+use std::collections::HashSet;
+use std::io;
+use std::sync::{Arc,RwLock};
+use std::time::{Duration,Instant};
+use std::thread::{Builder,JoinHandle};
+use stop::{StopSignal,join_until};
 use timegen::TimeType;
 
 pub struct PeerConnector {
     config: Config,
     known_nodes: KnownNodes,
-    inventory: Inventory
+    inventory: Inventory,
+    events: Events,
+    connections: Arc<RwLock<Vec<(PeerAddr, ConnectionState)>>>, // as of the last pass of the thread
+    stop_signal: StopSignal,
+    thread: Option<JoinHandle<()>>
 }
 
 impl PeerConnector
 {
-    pub fn new(config: &Config, known_nodes: &KnownNodes, inventory: &Inventory) -> PeerConnector {
+    pub fn new(config: &Config, known_nodes: &KnownNodes, inventory: &Inventory, events: &Events) -> PeerConnector {
         PeerConnector {
             config: config.clone(),
             known_nodes: known_nodes.clone(),
-            inventory: inventory.clone()
+            inventory: inventory.clone(),
+            events: events.clone(),
+            connections: Arc::new(RwLock::new(vec![])),
+            stop_signal: StopSignal::new(),
+            thread: None
         }
     }
 
-    pub fn start(&mut self)
+    pub fn start(&mut self) -> io::Result<()>
     {
-        let connection_count_target = 1;
         let config = self.config.clone();
-        let known_nodes = self.known_nodes.clone();
+        let connection_count_target = config.concurrent_connection_attempts() as usize;
+        let mut known_nodes = self.known_nodes.clone();
         let inventory = self.inventory.clone();
-
-        println!("Config says {} connections - we're only using 1", config.concurrent_connection_attempts());
+        let events = self.events.clone();
+        let connection_states = self.connections.clone();
+        let mut bootstrapper = Bootstrapper::new(&config, &events);
+        let stop_signal = StopSignal::new();
+        self.stop_signal = stop_signal.clone();
 
         let name = "Peer Connector".to_string();
-        Builder::new().name(name).spawn(move || {
+        let thread = try!(Builder::new().name(name).spawn(move || {
             let mut connections: Vec<Connection> = vec![];
+            let mut connected: HashSet<PeerAddr> = HashSet::new();
+
+            while !stop_signal.is_stopped() {
+                bootstrapper.bootstrap_if_needed(&mut known_nodes);
 
-            loop {
+                let previous_states = connection_states.read().unwrap().clone();
                 connections.retain(|connection|  {
                     let current_state = connection.state();
-                    current_state != ConnectionState::Error && current_state != ConnectionState::Stale
+                    let keep = current_state != ConnectionState::Error && current_state != ConnectionState::Stale;
+                    if let Some(peer_addr) = connection.peer_addr() {
+                        if !keep {
+                            events.emit(Event::ConnectionStateChanged(peer_addr, current_state));
+                        }
+                        match (current_state, keep) {
+                            (ConnectionState::Established(_), true) => if connected.insert(peer_addr) {
+                                events.emit(Event::PeerConnected(peer_addr));
+                            },
+                            (_, false) => if connected.remove(&peer_addr) {
+                                events.emit(Event::PeerDisconnected(peer_addr));
+                            },
+                            _ => {}
+                        }
+                    }
+                    keep
                 });
 
-                while connections.len() < connection_count_target {
-                    let socket_addrs_in_use: Vec<SocketAddr> = (&connections).iter().filter_map(|connection| connection.peer_addr()).collect();
-                    let known_node = break_on_none!(known_nodes.get_random_but_not(socket_addrs_in_use));
-                    let peer_addr = known_node.socket_addr;
+                while connections.len() < connection_count_target && !stop_signal.is_stopped() {
+                    let peer_addrs_in_use: Vec<PeerAddr> = (&connections).iter().filter_map(|connection| connection.peer_addr()).collect();
+                    let onion_reachable = config.socks_proxy().is_some();
+                    let known_node = break_on_none!(known_nodes.get_random_connectable_but_not(peer_addrs_in_use, onion_reachable));
+                    let peer_addr = known_node.peer_addr;
                     let message_handler = MessageHandler::new(
                         MessageVerifier::new(&config, TimeType::Real),
                         MessageResponder::new(&config, &known_nodes, &inventory, peer_addr)
                     );
-                    let connection = Connection::new(message_handler, peer_addr);
+                    let connection = Connection::new(&config, message_handler, peer_addr);
                     connections.push(connection);
                 }
-                sleep(Duration::from_millis(100));
+
+                let current_states: Vec<(PeerAddr, ConnectionState)> = connections.iter()
+                    .filter_map(|connection| connection.peer_addr().map(|peer_addr| (peer_addr, connection.state())))
+                    .collect();
+                for &(peer_addr, state) in &current_states {
+                    if !previous_states.contains(&(peer_addr, state)) {
+                        events.emit(Event::ConnectionStateChanged(peer_addr, state));
+                    }
+                }
+                *connection_states.write().unwrap() = current_states;
+                stop_signal.sleep(Duration::from_millis(100));
+            }
+
+            let deadline = stop_signal.deadline().unwrap_or_else(Instant::now);
+            for connection in connections.iter_mut() {
+                connection.stop(deadline);
+            }
+            connection_states.write().unwrap().clear();
+            for peer_addr in connected {
+                events.emit(Event::PeerDisconnected(peer_addr));
             }
-        }).unwrap();
+        }));
+
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    // Includes connections still handshaking, not just those reported by PeerConnected
+    pub fn connections(&self) -> Vec<(PeerAddr, ConnectionState)> {
+        self.connections.read().unwrap().clone()
+    }
+
+    pub fn stop(&mut self, deadline: Instant) {
+        self.stop_signal.stop(deadline);
+        if let Some(thread) = self.thread.take() {
+            join_until(thread, deadline);
+        }
     }
 }
@@ -1,5 +1,19 @@
+use address::Address;
+use addressbook::Contact;
+use disk::{AddressBookFile,IdentityFile,MessageFile};
+use events::{Event,Events};
+use filter::FilterMode;
+use identity::Identity;
+use inbox::InboxMessage;
 use message::{InventoryVector,KnownNode,Message};
-use std::collections::BTreeMap;
+use outbox::OutboxMessage;
+use pubkeys::KnownPubKey;
+use search::{SearchIndex,SearchTerms};
+use subscriptions::Subscription;
+use std::collections::{BTreeMap,HashMap,HashSet};
+use std::fs::create_dir_all;
+use std::io;
+use std::path::Path;
 use std::sync::{Arc,RwLock};
 
 #[derive(Clone)]
@@ -14,6 +28,46 @@ impl Persister {
         }
     }
 
+    // Keeps the identities, subscriptions, address book and messages in the data directory, so they survive a restart
+    // Saving failures are reported as warnings to the events' subscribers
+    pub fn open(data_dir: &Path, events: &Events) -> io::Result<Persister> {
+        try!(create_dir_all(data_dir));
+        let identity_file = IdentityFile::new(data_dir);
+        let (identities, chans, subscriptions) = try!(identity_file.load());
+        let address_book_file = AddressBookFile::new(data_dir);
+        let (contacts, filter_mode, blacklist, whitelist) = try!(address_book_file.load());
+        let message_file = MessageFile::new(data_dir);
+        let (inbox, mut outbox) = try!(message_file.load());
+        for outbox_message in outbox.iter_mut() {
+            outbox_message.restart_unfinished();
+        }
+
+        let mut memory_persister = MemoryPersister::new();
+        for inbox_message in &inbox {
+            memory_persister.index_inbox_message(inbox_message);
+        }
+        for outbox_message in &outbox {
+            memory_persister.index_outbox_message(outbox_message);
+        }
+        memory_persister.inbox = inbox;
+        memory_persister.outbox = outbox;
+        memory_persister.message_file = Some(message_file);
+        memory_persister.identities = identities;
+        memory_persister.chans = chans;
+        memory_persister.subscriptions = subscriptions;
+        memory_persister.identity_file = Some(identity_file);
+        memory_persister.contacts = contacts;
+        memory_persister.filter_mode = filter_mode;
+        memory_persister.filter_entries.insert(FilterMode::Blacklist, blacklist);
+        memory_persister.filter_entries.insert(FilterMode::Whitelist, whitelist);
+        memory_persister.address_book_file = Some(address_book_file);
+        memory_persister.events = events.clone();
+
+        Ok(Persister {
+            inner: Arc::new(RwLock::new(memory_persister))
+        })
+    }
+
     pub fn get_known_nodes(&self) -> Vec<KnownNode> {
         let inner_read = self.inner.read().unwrap();
         inner_read.get_known_nodes()
@@ -24,6 +78,16 @@ impl Persister {
         inner_write.add_known_node(known_node);
     }
 
+    pub fn get_local_nodes(&self) -> Vec<KnownNode> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_local_nodes()
+    }
+
+    pub fn add_local_node(&mut self, known_node: &KnownNode) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.add_local_node(known_node);
+    }
+
     pub fn inventory_iterator(&self) -> InventoryIterator {
         let inner_read = self.inner.read().unwrap();
         inner_read.inventory_iterator()
@@ -38,18 +102,198 @@ impl Persister {
         let mut inner_write = self.inner.write().unwrap();
         inner_write.add_object_message(inventory_vector, object_message);
     }
+
+    pub fn object_count(&self) -> usize {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.object_count()
+    }
+
+    pub fn get_identities(&self) -> Vec<Identity> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_identities()
+    }
+
+    pub fn add_identity(&mut self, identity: &Identity) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.add_identity(identity);
+    }
+
+    pub fn get_chans(&self) -> Vec<Identity> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_chans()
+    }
+
+    pub fn add_chan(&mut self, chan: &Identity) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.add_chan(chan);
+    }
+
+    pub fn remove_chan(&mut self, address: &Address) -> bool {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.remove_chan(address)
+    }
+
+    pub fn get_pubkey(&self, address: &Address) -> Option<KnownPubKey> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_pubkey(address)
+    }
+
+    pub fn add_pubkey(&mut self, pubkey: &KnownPubKey) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.add_pubkey(pubkey);
+    }
+
+    pub fn get_outbox_messages(&self) -> Vec<OutboxMessage> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_outbox_messages()
+    }
+
+    pub fn add_outbox_message(&mut self, outbox_message: &OutboxMessage) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.add_outbox_message(outbox_message);
+    }
+
+    // Returns false if the message is no longer in the outbox
+    pub fn update_outbox_message<F>(&mut self, ackdata: &[u8], update: F) -> bool
+        where F: FnOnce(&mut OutboxMessage)
+    {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.update_outbox_message(ackdata, update)
+    }
+
+    // The check and removal happen under one lock, so the message can't change in between
+    pub fn remove_outbox_message_if<F>(&mut self, ackdata: &[u8], predicate: F) -> bool
+        where F: FnOnce(&OutboxMessage) -> bool
+    {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.remove_outbox_message_if(ackdata, predicate)
+    }
+
+    pub fn get_inbox_messages(&self) -> Vec<InboxMessage> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_inbox_messages()
+    }
+
+    pub fn add_inbox_message(&mut self, inbox_message: &InboxMessage) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.add_inbox_message(inbox_message);
+    }
+
+    // Returns false if the message is no longer in the inbox
+    pub fn update_inbox_message<F>(&mut self, msgid: &[u8], update: F) -> bool
+        where F: FnOnce(&mut InboxMessage)
+    {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.update_inbox_message(msgid, update)
+    }
+
+    pub fn remove_inbox_message(&mut self, msgid: &[u8]) -> bool {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.remove_inbox_message(msgid)
+    }
+
+    // Gives the ids of the inbox and outbox messages matching the terms
+    pub fn search_messages(&self, terms: &SearchTerms) -> HashSet<Vec<u8>> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.search_messages(terms)
+    }
+
+    pub fn get_subscriptions(&self) -> Vec<Subscription> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_subscriptions()
+    }
+
+    pub fn add_subscription(&mut self, subscription: &Subscription) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.add_subscription(subscription);
+    }
+
+    pub fn remove_subscription(&mut self, address: &Address) -> bool {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.remove_subscription(address)
+    }
+
+    pub fn get_contacts(&self) -> Vec<Contact> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_contacts()
+    }
+
+    pub fn add_contact(&mut self, contact: &Contact) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.add_contact(contact);
+    }
+
+    pub fn remove_contact(&mut self, address: &Address) -> bool {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.remove_contact(address)
+    }
+
+    pub fn get_filter_mode(&self) -> FilterMode {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_filter_mode()
+    }
+
+    pub fn set_filter_mode(&mut self, mode: FilterMode) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.set_filter_mode(mode);
+    }
+
+    pub fn get_filter_entries(&self, list: FilterMode) -> Vec<Contact> {
+        let inner_read = self.inner.read().unwrap();
+        inner_read.get_filter_entries(list)
+    }
+
+    pub fn add_filter_entry(&mut self, list: FilterMode, contact: &Contact) {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.add_filter_entry(list, contact);
+    }
+
+    pub fn remove_filter_entry(&mut self, list: FilterMode, address: &Address) -> bool {
+        let mut inner_write = self.inner.write().unwrap();
+        inner_write.remove_filter_entry(list, address)
+    }
 }
 
 pub struct MemoryPersister {
     objects: Arc<RwLock<BTreeMap<InventoryVector, Message>>>,
-    known_nodes: Vec<KnownNode>
+    known_nodes: Vec<KnownNode>,
+    local_nodes: Vec<KnownNode>,
+    identities: Vec<Identity>,
+    chans: Vec<Identity>,
+    pubkeys: HashMap<Address, KnownPubKey>,
+    outbox: Vec<OutboxMessage>,
+    inbox: Vec<InboxMessage>,
+    subscriptions: Vec<Subscription>,
+    search_index: SearchIndex,
+    contacts: Vec<Contact>,
+    filter_mode: FilterMode,
+    filter_entries: HashMap<FilterMode, Vec<Contact>>,
+    message_file: Option<MessageFile>,
+    identity_file: Option<IdentityFile>,
+    address_book_file: Option<AddressBookFile>,
+    events: Events
 }
 
 impl MemoryPersister {
     pub fn new() -> MemoryPersister {
         MemoryPersister {
             objects: Arc::new(RwLock::new(BTreeMap::new())),
-            known_nodes: vec![]
+            known_nodes: vec![],
+            local_nodes: vec![],
+            identities: vec![],
+            chans: vec![],
+            pubkeys: HashMap::new(),
+            outbox: vec![],
+            inbox: vec![],
+            subscriptions: vec![],
+            search_index: SearchIndex::new(),
+            contacts: vec![],
+            filter_mode: FilterMode::Blacklist,
+            filter_entries: HashMap::new(),
+            message_file: None,
+            identity_file: None,
+            address_book_file: None,
+            events: Events::new()
         }
     }
 
@@ -57,8 +301,25 @@ impl MemoryPersister {
         self.known_nodes.clone()
     }
 
+    // The same node in the same stream is kept once, with the latest news of it
     fn add_known_node(&mut self, known_node: &KnownNode) {
-        self.known_nodes.push(known_node.clone());
+        let existing = self.known_nodes.iter_mut()
+            .find(|existing| existing.peer_addr == known_node.peer_addr && existing.stream == known_node.stream);
+        match existing {
+            Some(existing) => if known_node.last_seen > existing.last_seen {
+                existing.last_seen = known_node.last_seen;
+                existing.services = known_node.services;
+            },
+            None => self.known_nodes.push(known_node.clone())
+        }
+    }
+
+    fn get_local_nodes(&self) -> Vec<KnownNode> {
+        self.local_nodes.clone()
+    }
+
+    fn add_local_node(&mut self, known_node: &KnownNode) {
+        self.local_nodes.push(known_node.clone());
     }
 
     pub fn inventory_iterator(&self) -> InventoryIterator {
@@ -74,6 +335,240 @@ impl MemoryPersister {
         let mut write_objects = self.objects.write().unwrap();
         write_objects.insert(inventory_vector.clone(), object_message.clone());
     }
+
+    fn object_count(&self) -> usize {
+        let read_objects = self.objects.read().unwrap();
+        read_objects.len()
+    }
+
+    fn get_identities(&self) -> Vec<Identity> {
+        self.identities.clone()
+    }
+
+    // Deterministic identities can be created again from the same passphrase
+    fn add_identity(&mut self, identity: &Identity) {
+        if !self.identities.iter().any(|existing| existing.address() == identity.address()) {
+            self.identities.push(identity.clone());
+            self.save_identities();
+        }
+    }
+
+    fn get_chans(&self) -> Vec<Identity> {
+        self.chans.clone()
+    }
+
+    // Joining a chan twice changes nothing, since the keys come from the passphrase
+    fn add_chan(&mut self, chan: &Identity) {
+        if !self.chans.iter().any(|existing| existing.address() == chan.address()) {
+            self.chans.push(chan.clone());
+            self.save_identities();
+        }
+    }
+
+    fn remove_chan(&mut self, address: &Address) -> bool {
+        let count = self.chans.len();
+        self.chans.retain(|chan| chan.address() != address);
+        if self.chans.len() == count {
+            return false;
+        }
+        self.save_identities();
+        true
+    }
+
+    // Unlike a lost message, a lost identity can't be got back, so the warning says so
+    fn save_identities(&self) {
+        if let Some(ref identity_file) = self.identity_file {
+            if let Err(err) = identity_file.save(&self.identities, &self.chans, &self.subscriptions) {
+                self.events.emit(Event::Warning(format!("Could not save identities, they will be lost on exit: {}", err)));
+            }
+        }
+    }
+
+    fn get_pubkey(&self, address: &Address) -> Option<KnownPubKey> {
+        self.pubkeys.get(address).cloned()
+    }
+
+    fn add_pubkey(&mut self, pubkey: &KnownPubKey) {
+        self.pubkeys.insert(pubkey.address().clone(), pubkey.clone());
+    }
+
+    fn get_outbox_messages(&self) -> Vec<OutboxMessage> {
+        self.outbox.clone()
+    }
+
+    fn add_outbox_message(&mut self, outbox_message: &OutboxMessage) {
+        self.index_outbox_message(outbox_message);
+        self.outbox.push(outbox_message.clone());
+        self.save_messages();
+    }
+
+    fn update_outbox_message<F>(&mut self, ackdata: &[u8], update: F) -> bool
+        where F: FnOnce(&mut OutboxMessage)
+    {
+        match self.outbox.iter_mut().find(|outbox_message| outbox_message.ackdata() == ackdata) {
+            Some(outbox_message) => update(outbox_message),
+            None => return false
+        }
+        self.save_messages();
+        true
+    }
+
+    fn remove_outbox_message_if<F>(&mut self, ackdata: &[u8], predicate: F) -> bool
+        where F: FnOnce(&OutboxMessage) -> bool
+    {
+        match self.outbox.iter().position(|outbox_message| outbox_message.ackdata() == ackdata) {
+            Some(index) if predicate(&self.outbox[index]) => {
+                self.search_index.remove(ackdata);
+                self.outbox.remove(index);
+                self.save_messages();
+                true
+            },
+            _ => false
+        }
+    }
+
+    fn get_inbox_messages(&self) -> Vec<InboxMessage> {
+        self.inbox.clone()
+    }
+
+    fn add_inbox_message(&mut self, inbox_message: &InboxMessage) {
+        self.index_inbox_message(inbox_message);
+        self.inbox.push(inbox_message.clone());
+        self.save_messages();
+    }
+
+    fn update_inbox_message<F>(&mut self, msgid: &[u8], update: F) -> bool
+        where F: FnOnce(&mut InboxMessage)
+    {
+        match self.inbox.iter_mut().find(|inbox_message| inbox_message.msgid() == msgid) {
+            Some(inbox_message) => update(inbox_message),
+            None => return false
+        }
+        self.save_messages();
+        true
+    }
+
+    fn remove_inbox_message(&mut self, msgid: &[u8]) -> bool {
+        match self.inbox.iter().position(|inbox_message| inbox_message.msgid() == msgid) {
+            Some(index) => {
+                self.search_index.remove(msgid);
+                self.inbox.remove(index);
+                self.save_messages();
+                true
+            },
+            None => false
+        }
+    }
+
+    fn search_messages(&self, terms: &SearchTerms) -> HashSet<Vec<u8>> {
+        self.search_index.search(terms)
+    }
+
+    fn index_inbox_message(&mut self, inbox_message: &InboxMessage) {
+        let to = inbox_message.to().map(|to| to.to_string()).unwrap_or(String::new());
+        self.search_index.add(inbox_message.msgid(), &[ inbox_message.subject(), inbox_message.body(), &inbox_message.from().to_string(), &to ]);
+    }
+
+    fn index_outbox_message(&mut self, outbox_message: &OutboxMessage) {
+        let to = outbox_message.to().map(|to| to.to_string()).unwrap_or(String::new());
+        self.search_index.add(outbox_message.ackdata(), &[ outbox_message.subject(), outbox_message.body(), &outbox_message.from().to_string(), &to ]);
+    }
+
+    // Failing to save isn't fatal, since everything is still in memory and the next change tries again
+    fn save_messages(&self) {
+        if let Some(ref message_file) = self.message_file {
+            if let Err(err) = message_file.save(&self.inbox, &self.outbox) {
+                self.events.emit(Event::Warning(format!("Could not save messages: {}", err)));
+            }
+        }
+    }
+
+    fn get_subscriptions(&self) -> Vec<Subscription> {
+        self.subscriptions.clone()
+    }
+
+    fn add_subscription(&mut self, subscription: &Subscription) {
+        self.subscriptions.retain(|existing| existing.address() != subscription.address());
+        self.subscriptions.push(subscription.clone());
+        self.save_identities();
+    }
+
+    fn remove_subscription(&mut self, address: &Address) -> bool {
+        let count = self.subscriptions.len();
+        self.subscriptions.retain(|subscription| subscription.address() != address);
+        if self.subscriptions.len() == count {
+            return false;
+        }
+        self.save_identities();
+        true
+    }
+
+    fn get_contacts(&self) -> Vec<Contact> {
+        self.contacts.clone()
+    }
+
+    fn add_contact(&mut self, contact: &Contact) {
+        remove_contact(&mut self.contacts, contact.address());
+        self.contacts.push(contact.clone());
+        self.save_address_book();
+    }
+
+    fn remove_contact(&mut self, address: &Address) -> bool {
+        if !remove_contact(&mut self.contacts, address) {
+            return false;
+        }
+        self.save_address_book();
+        true
+    }
+
+    fn get_filter_mode(&self) -> FilterMode {
+        self.filter_mode
+    }
+
+    fn set_filter_mode(&mut self, mode: FilterMode) {
+        self.filter_mode = mode;
+        self.save_address_book();
+    }
+
+    fn get_filter_entries(&self, list: FilterMode) -> Vec<Contact> {
+        self.filter_entries.get(&list).cloned().unwrap_or(vec![])
+    }
+
+    fn add_filter_entry(&mut self, list: FilterMode, contact: &Contact) {
+        {
+            let entries = self.filter_entries.entry(list).or_insert(vec![]);
+            remove_contact(entries, contact.address());
+            entries.push(contact.clone());
+        }
+        self.save_address_book();
+    }
+
+    fn remove_filter_entry(&mut self, list: FilterMode, address: &Address) -> bool {
+        let removed = match self.filter_entries.get_mut(&list) {
+            Some(entries) => remove_contact(entries, address),
+            None => false
+        };
+        if removed {
+            self.save_address_book();
+        }
+        removed
+    }
+
+    fn save_address_book(&self) {
+        if let Some(ref address_book_file) = self.address_book_file {
+            let blacklist = self.get_filter_entries(FilterMode::Blacklist);
+            let whitelist = self.get_filter_entries(FilterMode::Whitelist);
+            if let Err(err) = address_book_file.save(&self.contacts, self.filter_mode, &blacklist, &whitelist) {
+                self.events.emit(Event::Warning(format!("Could not save the address book: {}", err)));
+            }
+        }
+    }
+}
+
+fn remove_contact(contacts: &mut Vec<Contact>, address: &Address) -> bool {
+    let count = contacts.len();
+    contacts.retain(|contact| contact.address() != address);
+    contacts.len() != count
 }
 
 pub struct InventoryIterator {
@@ -0,0 +1,444 @@
+use address::Address;
+use ecies;
+use events::OutboxStatus;
+use filter::SenderFilter;
+use identity::{Identities,Identity};
+use inbox::{Inbox,InboxMessage};
+use inventory::{Inventory,calculate_inventory_vector};
+use keys::{ripe,verify};
+use message::{GetPubKey,InventoryVector,Message,NETWORK_EXTRA_BYTES,NETWORK_TRIALS_PER_BYTE,OBJECT_MSG,Object,ObjectData,Sender};
+use message::{read_message,read_msg_content,write_object_header,write_unsigned_msg_content};
+use msgcoding;
+use outbox::Outbox;
+use pubkeys::{KnownPubKey,PubKeys};
+use std::collections::HashMap;
+use std::io;
+use std::io::Cursor;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread::{Builder,JoinHandle};
+use std::time::{Duration,Instant,SystemTime};
+use stop::{StopSignal,join_until};
+use subscriptions::{Subscriptions,open_broadcast};
+
+const PUBKEY_TTL_SECS: u64 = 2419200; // 28 days
+const POLL_INTERVAL_MILLIS: u64 = 100;
+
+// Acts on objects as they arrive in the inventory: pubkeys we're waiting for, requests for our own pubkeys,
+// msgs sent to us, broadcasts we're subscribed to and acks for msgs we sent
+pub struct ObjectProcessor {
+    inventory: Inventory,
+    actions: ObjectActions,
+    stop_signal: StopSignal,
+    thread: Option<JoinHandle<()>>
+}
+
+impl ObjectProcessor {
+    pub fn new(identities: &Identities, pubkeys: &PubKeys, subscriptions: &Subscriptions, filter: &SenderFilter, inbox: &Inbox, outbox: &Outbox, inventory: &Inventory, sender: &Sender) -> ObjectProcessor {
+        ObjectProcessor {
+            inventory: inventory.clone(),
+            actions: ObjectActions {
+                inventory: inventory.clone(),
+                identities: identities.clone(),
+                pubkeys: pubkeys.clone(),
+                subscriptions: subscriptions.clone(),
+                filter: filter.clone(),
+                inbox: inbox.clone(),
+                outbox: outbox.clone(),
+                sender: sender.clone(),
+                published: HashMap::new()
+            },
+            stop_signal: StopSignal::new(),
+            thread: None
+        }
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        let mut actions = self.actions.clone();
+        let receiver = self.inventory.watch();
+        let stop_signal = StopSignal::new();
+        self.stop_signal = stop_signal.clone();
+
+        let name = "Object Processor".to_string();
+        let thread = try!(Builder::new().name(name).spawn(move || {
+            while !stop_signal.is_stopped() {
+                match receiver.recv_timeout(Duration::from_millis(POLL_INTERVAL_MILLIS)) {
+                    Ok(Message::Object(object_data)) => actions.process(&object_data),
+                    Ok(_) => {},
+                    Err(RecvTimeoutError::Timeout) => {},
+                    Err(RecvTimeoutError::Disconnected) => break
+                }
+            }
+        }));
+
+        self.thread = Some(thread);
+        Ok(())
+    }
+
+    pub fn stop(&mut self, deadline: Instant) {
+        self.stop_signal.stop(deadline);
+        if let Some(thread) = self.thread.take() {
+            join_until(thread, deadline);
+        }
+    }
+}
+
+#[derive(Clone)]
+struct ObjectActions {
+    inventory: Inventory,
+    identities: Identities,
+    pubkeys: PubKeys,
+    subscriptions: Subscriptions,
+    filter: SenderFilter,
+    inbox: Inbox,
+    outbox: Outbox,
+    sender: Sender,
+    published: HashMap<Address, SystemTime> // expiry of the last pubkey we sent out for each identity
+}
+
+impl ObjectActions {
+    fn process(&mut self, object_data: &ObjectData) {
+        match object_data.object() {
+            &Object::PubKey(_) => self.process_pubkey(object_data),
+            &Object::GetPubKey(ref getpubkey) => self.process_getpubkey(object_data.stream(), getpubkey),
+            &Object::Msg { ref encrypted } => self.process_msg(object_data, encrypted),
+            &Object::Broadcast(_) => self.process_broadcast(object_data)
+        }
+    }
+
+    fn process_msg(&mut self, object_data: &ObjectData, encrypted: &[u8]) {
+        let inventory_vector = calculate_inventory_vector(&Message::Object(object_data.clone()));
+        if self.outbox.acknowledge(&inventory_vector).is_some() {
+            return;
+        }
+
+        for identity in self.identities.list() {
+            let content = match ecies::decrypt(identity.private_encryption_key(), encrypted) {
+                Ok(plaintext) => match read_msg_content(&plaintext) {
+                    Ok(content) => content,
+                    Err(_) => continue
+                },
+                Err(_) => continue
+            };
+            if &content.destination_ripe[..] != identity.address().ripe() {
+                continue;
+            }
+
+            // It's ours but can't be trusted, so it gets neither delivered nor acked
+            let mut signed = vec![];
+            write_object_header(&mut signed, &object_data.expiry(), OBJECT_MSG, object_data.version(), object_data.stream());
+            write_unsigned_msg_content(&mut signed, &content);
+            if !verify(&content.public_signing_key, &signed, &content.signature) {
+                return;
+            }
+
+            // Filtered senders don't learn that the msg arrived either
+            let from = Address::new(content.address_version, content.stream, &ripe(&content.public_signing_key, &content.public_encryption_key));
+            if !self.filter.allows(&from) {
+                return;
+            }
+            self.deliver(&inventory_vector, &from, Some(identity.address()), content.encoding, &content.message);
+            if !identity.is_chan() {
+                self.publish_ack(&content.ack_data);
+            }
+            return;
+        }
+    }
+
+    // Only broadcasts from addresses we subscribe to are opened at all.
+    // V4 broadcasts carry no tag, so every older subscription has to be tried.
+    fn process_broadcast(&mut self, object_data: &ObjectData) {
+        for subscription in self.subscriptions.list() {
+            if let Some(content) = open_broadcast(subscription.address(), object_data) {
+                let inventory_vector = calculate_inventory_vector(&Message::Object(object_data.clone()));
+                self.deliver(&inventory_vector, subscription.address(), None, content.encoding, &content.message);
+                return;
+            }
+        }
+    }
+
+    fn deliver(&mut self, inventory_vector: &InventoryVector, from: &Address, to: Option<&Address>, encoding: u64, message: &[u8]) {
+        if let Some((subject, body)) = msgcoding::decode(encoding, message) {
+            self.inbox.add(&InboxMessage::new(&inventory_vector.hash, from, to, &subject, &body));
+        }
+    }
+
+    // The sender did the work for the ack already, so it just needs checking and passing on
+    fn publish_ack(&mut self, ack_data: &[u8]) {
+        if ack_data.is_empty() {
+            return;
+        }
+
+        if let Ok(ack_message) = read_message(&mut Cursor::new(ack_data)) {
+            let valid = match ack_message {
+                Message::Object(ref ack_object_data) => self.sender.verify_object(ack_object_data),
+                _ => false
+            };
+            if valid {
+                self.inventory.add_object_message(&ack_message);
+            }
+        }
+    }
+
+    fn process_pubkey(&mut self, object_data: &ObjectData) {
+        for outbox_message in self.outbox.with_status(OutboxStatus::AwaitingPubKey) {
+            if let Some(pubkey) = outbox_message.to().and_then(|to| KnownPubKey::from_object(to, object_data)) {
+                self.pubkeys.add(&pubkey);
+                return;
+            }
+        }
+    }
+
+    fn process_getpubkey(&mut self, stream: u32, getpubkey: &GetPubKey) {
+        let identity = match self.identities.list().into_iter().find(|identity| is_requested(identity, stream, getpubkey)) {
+            Some(identity) => identity,
+            None => return
+        };
+
+        // Everyone who asked can still get the one we sent before
+        let now = SystemTime::now();
+        if let Some(&expiry) = self.published.get(identity.address()) {
+            if expiry > now {
+                return;
+            }
+        }
+
+        let expiry = now + Duration::from_secs(PUBKEY_TTL_SECS);
+        if self.sender.send_object(identity.pubkey_object_data(expiry), NETWORK_TRIALS_PER_BYTE, NETWORK_EXTRA_BYTES).is_ok() {
+            self.published.insert(identity.address().clone(), expiry);
+        }
+    }
+}
+
+fn is_requested(identity: &Identity, stream: u32, getpubkey: &GetPubKey) -> bool {
+    // Everyone in a chan already has its keys
+    let address = identity.address();
+    if !identity.enabled() || identity.is_chan() || address.stream() != stream {
+        return false;
+    }
+
+    match getpubkey {
+        &GetPubKey::V3 { ref ripe } => address.version() <= 3 && &ripe[..] == address.ripe(),
+        &GetPubKey::V4 { ref tag } => address.version() == 4 && tag == &address.tag()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use config::Config;
+    use ecies;
+    use addressbook::Contact;
+    use events::{Event,Events,OutboxStatus};
+    use filter::{FilterMode,SenderFilter};
+    use identity::{Identities,Identity};
+    use inbox::Inbox;
+    use inventory::Inventory;
+    use message::{GetPubKey,Message,MsgContent,Object,ObjectData,Sender,write_msg_content};
+    use msgcoding::{MessageEncoding,encode};
+    use outbox::{Outbox,OutboxWorker,SendOptions};
+    use persist::Persister;
+    use pubkeys::{KnownPubKey,PubKeys};
+    use std::collections::HashMap;
+    use std::time::{Duration,SystemTime};
+    use subscriptions::{Subscription,Subscriptions};
+    use super::ObjectActions;
+
+    fn create_actions(persister: &Persister, events: &Events, inventory: &Inventory) -> ObjectActions {
+        let mut sender = Sender::new(&Config::new(), inventory.clone(), events);
+        sender.override_difficulty(1, 1);
+
+        ObjectActions {
+            inventory: inventory.clone(),
+            identities: Identities::new(persister.clone()),
+            pubkeys: PubKeys::new(persister.clone()),
+            subscriptions: Subscriptions::new(persister.clone()),
+            filter: SenderFilter::new(persister.clone()),
+            inbox: Inbox::new(persister.clone(), events),
+            outbox: Outbox::new(persister.clone(), events),
+            sender: sender,
+            published: HashMap::new()
+        }
+    }
+
+    #[test]
+    fn test_awaited_pubkey_is_stored() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let inventory = Inventory::new(persister.clone(), &events);
+        let mut actions = create_actions(&persister, &events, &inventory);
+        let from = Identity::random("From", 1);
+        let to = Identity::random("To", 1);
+        let ackdata = actions.outbox.queue(from.address(), to.address(), "Hi", "Hello", &SendOptions::new());
+        actions.outbox.set_status(&ackdata, OutboxStatus::AwaitingPubKey);
+
+        actions.process(&to.pubkey_object_data(SystemTime::now()));
+
+        assert_eq!(Some(KnownPubKey::from_identity(&to)), actions.pubkeys.get(to.address()));
+    }
+
+    #[test]
+    fn test_getpubkey_for_our_identity_is_answered_once() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let inventory = Inventory::new(persister.clone(), &events);
+        let watcher = inventory.watch();
+        let mut actions = create_actions(&persister, &events, &inventory);
+        let identity = Identity::random("Me", 1);
+        actions.identities.add(&identity);
+        let expiry = SystemTime::now() + Duration::from_secs(3600);
+        let getpubkey = ObjectData::new(expiry, 4, 1, Object::GetPubKey(GetPubKey::V4 { tag: identity.address().tag() }));
+
+        actions.process(&getpubkey);
+        actions.process(&getpubkey);
+
+        match watcher.try_recv().unwrap() {
+            Message::Object(object_data) => assert_eq!(Some(KnownPubKey::from_identity(&identity)), KnownPubKey::from_object(identity.address(), &object_data)),
+            _ => panic!("Expected an object")
+        }
+        assert!(watcher.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_ack_is_published_and_recognised() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let receiver = events.subscribe();
+        let inventory = Inventory::new(persister.clone(), &events);
+        let watcher = inventory.watch();
+        let mut actions = create_actions(&persister, &events, &inventory);
+        let identity = Identity::random("Me", 1);
+        actions.identities.add(&identity);
+        let mut outbox_worker = OutboxWorker::new(&Config::new(), &actions.outbox, &actions.identities, &actions.pubkeys, &inventory, &actions.sender);
+
+        let ackdata = actions.outbox.queue(identity.address(), identity.address(), "Hi", "Hello", &SendOptions::new());
+        outbox_worker.run_once();
+        assert_eq!(OutboxStatus::Sent, actions.outbox.get(&ackdata).unwrap().status());
+
+        let msg = watcher.try_recv().unwrap();
+        match msg {
+            Message::Object(ref object_data) => actions.process(object_data),
+            _ => panic!("Expected an object")
+        }
+        let ack = watcher.try_recv().unwrap();
+        match ack {
+            Message::Object(ref object_data) => {
+                assert_eq!(&Object::Msg { encrypted: ackdata.clone() }, object_data.object());
+                actions.process(object_data);
+            },
+            _ => panic!("Expected an object")
+        }
+
+        assert_eq!(OutboxStatus::AckReceived, actions.outbox.get(&ackdata).unwrap().status());
+        assert!(receiver.try_iter().any(|event| event == Event::AckReceived { ackdata: ackdata.clone() }));
+
+        let inbox_message = &actions.inbox.list()[0];
+        assert_eq!(("Hi", "Hello"), (inbox_message.subject(), inbox_message.body()));
+        assert_eq!(Some(identity.address()), inbox_message.to());
+    }
+
+    #[test]
+    fn test_subscribed_broadcast_is_delivered_once() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let inventory = Inventory::new(persister.clone(), &events);
+        let mut actions = create_actions(&persister, &events, &inventory);
+        let identity = Identity::random("Someone", 1);
+        let broadcast = identity.broadcast_object_data(SystemTime::now(), MessageEncoding::Extended.number(), encode(MessageEncoding::Extended, "News", "Hello all"));
+
+        actions.process(&broadcast);
+        assert!(actions.inbox.list().is_empty());
+
+        actions.subscriptions.add(&Subscription::new("Someone", identity.address()));
+        actions.process(&broadcast);
+        actions.process(&broadcast);
+
+        let inbox = actions.inbox.list();
+        assert_eq!(1, inbox.len());
+        assert_eq!(identity.address(), inbox[0].from());
+        assert!(inbox[0].is_broadcast());
+        assert_eq!(("News", "Hello all"), (inbox[0].subject(), inbox[0].body()));
+    }
+
+    #[test]
+    fn test_chan_message_is_delivered_without_ack() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let inventory = Inventory::new(persister.clone(), &events);
+        let watcher = inventory.watch();
+        let mut actions = create_actions(&persister, &events, &inventory);
+        let chan = Identity::chan("general", 1);
+        actions.identities.add(&chan);
+        let mut outbox_worker = OutboxWorker::new(&Config::new(), &actions.outbox, &actions.identities, &actions.pubkeys, &inventory, &actions.sender);
+
+        actions.outbox.queue(chan.address(), chan.address(), "Hi", "Hello", &SendOptions::new());
+        outbox_worker.run_once();
+        match watcher.try_recv().unwrap() {
+            Message::Object(ref object_data) => actions.process(object_data),
+            _ => panic!("Expected an object")
+        }
+
+        assert!(watcher.try_recv().is_err());
+        assert_eq!(Some(chan.address()), actions.inbox.list()[0].to());
+    }
+
+    #[test]
+    fn test_msg_from_filtered_sender_is_dropped() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let inventory = Inventory::new(persister.clone(), &events);
+        let watcher = inventory.watch();
+        let mut actions = create_actions(&persister, &events, &inventory);
+        let chan = Identity::chan("general", 1);
+        actions.identities.add(&chan);
+        let mut outbox_worker = OutboxWorker::new(&Config::new(), &actions.outbox, &actions.identities, &actions.pubkeys, &inventory, &actions.sender);
+        actions.outbox.queue(chan.address(), chan.address(), "Hi", "Hello", &SendOptions::new());
+        outbox_worker.run_once();
+        let object_data = match watcher.try_recv().unwrap() {
+            Message::Object(object_data) => object_data,
+            _ => panic!("Expected an object")
+        };
+
+        actions.filter.add(FilterMode::Blacklist, &Contact::new("Spammer", chan.address()));
+        actions.process(&object_data);
+        assert!(actions.inbox.list().is_empty());
+
+        actions.filter.set_mode(FilterMode::Whitelist);
+        actions.process(&object_data);
+        assert!(actions.inbox.list().is_empty());
+
+        actions.filter.add(FilterMode::Whitelist, &Contact::new("Friend", chan.address()));
+        actions.process(&object_data);
+        assert_eq!(1, actions.inbox.list().len());
+    }
+
+    #[test]
+    fn test_msg_with_bad_signature_is_rejected() {
+        let persister = Persister::new();
+        let events = Events::new();
+        let inventory = Inventory::new(persister.clone(), &events);
+        let mut actions = create_actions(&persister, &events, &inventory);
+        let identity = Identity::random("Me", 1);
+        actions.identities.add(&identity);
+
+        let content = MsgContent {
+            address_version: 4,
+            stream: 1,
+            behaviour_bitfield: 1,
+            public_signing_key: identity.public_signing_key(),
+            public_encryption_key: identity.public_encryption_key(),
+            nonce_trials_per_byte: 1000,
+            extra_bytes: 1000,
+            destination_ripe: identity.address().ripe().to_vec(),
+            encoding: 2,
+            message: b"Subject:Hi\nBody:Hello".to_vec(),
+            ack_data: vec![],
+            signature: vec![ 0x30, 0x06, 0x02, 0x01, 0x01, 0x02, 0x01, 0x01 ]
+        };
+        let mut plaintext = vec![];
+        write_msg_content(&mut plaintext, &content);
+        let encrypted = ecies::encrypt(&identity.public_encryption_key(), &plaintext).unwrap();
+
+        actions.process(&ObjectData::new(SystemTime::now(), 1, 1, Object::Msg { encrypted: encrypted }));
+
+        assert!(actions.inbox.list().is_empty());
+    }
+}
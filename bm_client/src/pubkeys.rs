@@ -0,0 +1,205 @@
+use address::Address;
+use ecies;
+use identity::Identity;
+use keys::{ripe,verify};
+use message::{NETWORK_EXTRA_BYTES,NETWORK_TRIALS_PER_BYTE,OBJECT_PUBKEY,Object,ObjectData,PubKey,PubKeyContent};
+use message::{read_pubkey_content,write_object_header,write_unsigned_pubkey_content};
+use persist::Persister;
+
+// Everything needed to encrypt a message to an address and pay for its proof of work
+#[derive(Clone,Debug,PartialEq)]
+pub struct KnownPubKey {
+    address: Address,
+    behaviour_bitfield: u32,
+    public_signing_key: Vec<u8>, // 64 bytes
+    public_encryption_key: Vec<u8>, // 64 bytes
+    nonce_trials_per_byte: u64,
+    extra_bytes: u64
+}
+
+impl KnownPubKey {
+    pub fn from_identity(identity: &Identity) -> KnownPubKey {
+        KnownPubKey {
+            address: identity.address().clone(),
+            behaviour_bitfield: identity.behaviour_bitfield(),
+            public_signing_key: identity.public_signing_key(),
+            public_encryption_key: identity.public_encryption_key(),
+            nonce_trials_per_byte: identity.nonce_trials_per_byte(),
+            extra_bytes: identity.extra_bytes()
+        }
+    }
+
+    // Gives the pubkey in the object if it is the one for the address, and v3 and v4 pubkeys are properly signed
+    pub fn from_object(address: &Address, object_data: &ObjectData) -> Option<KnownPubKey> {
+        if object_data.stream() != address.stream() || object_data.version() != address.version() {
+            return None;
+        }
+
+        // The tag goes between the header and content in what's signed; v2 pubkeys aren't signed at all
+        let (content, signed_tag) = match object_data.object() {
+            &Object::PubKey(PubKey::V2 { behaviour_bitfield, ref public_signing_key, ref public_encryption_key }) => (PubKeyContent {
+                behaviour_bitfield: behaviour_bitfield,
+                public_signing_key: public_signing_key.clone(),
+                public_encryption_key: public_encryption_key.clone(),
+                nonce_trials_per_byte: NETWORK_TRIALS_PER_BYTE,
+                extra_bytes: NETWORK_EXTRA_BYTES,
+                signature: vec![]
+            }, None),
+            &Object::PubKey(PubKey::V3 { behaviour_bitfield, ref public_signing_key, ref public_encryption_key, nonce_trials_per_byte, extra_bytes, ref signature }) => (PubKeyContent {
+                behaviour_bitfield: behaviour_bitfield,
+                public_signing_key: public_signing_key.clone(),
+                public_encryption_key: public_encryption_key.clone(),
+                nonce_trials_per_byte: nonce_trials_per_byte,
+                extra_bytes: extra_bytes,
+                signature: signature.clone()
+            }, Some(&[][..])),
+            &Object::PubKey(PubKey::V4 { ref tag, ref encrypted }) => {
+                if tag != &address.tag() {
+                    return None;
+                }
+                let content = match ecies::decrypt(&address.tag_private_key(), encrypted) {
+                    Ok(plaintext) => match read_pubkey_content(&plaintext) {
+                        Ok(content) => content,
+                        Err(_) => return None
+                    },
+                    Err(_) => return None
+                };
+                (content, Some(&tag[..]))
+            },
+            _ => return None
+        };
+
+        if ripe(&content.public_signing_key, &content.public_encryption_key) != address.ripe() {
+            return None;
+        }
+        if let Some(tag) = signed_tag {
+            let mut signed = vec![];
+            write_object_header(&mut signed, &object_data.expiry(), OBJECT_PUBKEY, object_data.version(), object_data.stream());
+            signed.extend(tag);
+            write_unsigned_pubkey_content(&mut signed, &content);
+            if !verify(&content.public_signing_key, &signed, &content.signature) {
+                return None;
+            }
+        }
+
+        Some(KnownPubKey {
+            address: address.clone(),
+            behaviour_bitfield: content.behaviour_bitfield,
+            public_signing_key: content.public_signing_key,
+            public_encryption_key: content.public_encryption_key,
+            nonce_trials_per_byte: content.nonce_trials_per_byte,
+            extra_bytes: content.extra_bytes
+        })
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    pub fn behaviour_bitfield(&self) -> u32 {
+        self.behaviour_bitfield
+    }
+
+    pub fn public_encryption_key(&self) -> &[u8] {
+        &self.public_encryption_key
+    }
+
+    pub fn nonce_trials_per_byte(&self) -> u64 {
+        self.nonce_trials_per_byte
+    }
+
+    pub fn extra_bytes(&self) -> u64 {
+        self.extra_bytes
+    }
+}
+
+#[derive(Clone)]
+pub struct PubKeys {
+    persister: Persister
+}
+
+impl PubKeys {
+    pub fn new(persister: Persister) -> PubKeys {
+        PubKeys {
+            persister: persister
+        }
+    }
+
+    pub fn get(&self, address: &Address) -> Option<KnownPubKey> {
+        self.persister.get_pubkey(address)
+    }
+
+    pub fn add(&mut self, pubkey: &KnownPubKey) {
+        self.persister.add_pubkey(pubkey);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use address::Address;
+    use identity::Identity;
+    use keys::sign;
+    use message::{OBJECT_PUBKEY,Object,ObjectData,PubKey,PubKeyContent,write_object_header,write_unsigned_pubkey_content};
+    use std::time::{Duration,SystemTime};
+    use super::KnownPubKey;
+
+    #[test]
+    fn test_pubkey_from_v4_object() {
+        let identity = Identity::random("Test", 1);
+        let object_data = identity.pubkey_object_data(SystemTime::now());
+
+        let pubkey = KnownPubKey::from_object(identity.address(), &object_data);
+
+        assert_eq!(Some(KnownPubKey::from_identity(&identity)), pubkey);
+    }
+
+    #[test]
+    fn test_pubkey_for_another_address_is_ignored() {
+        let identity = Identity::random("Test", 1);
+        let other = Identity::random("Other", 1);
+        let object_data = identity.pubkey_object_data(SystemTime::now());
+
+        assert_eq!(None, KnownPubKey::from_object(other.address(), &object_data));
+    }
+
+    #[test]
+    fn test_pubkey_with_bad_signature_is_ignored() {
+        let identity = Identity::random("Test", 1);
+        let object_data = identity.pubkey_object_data(SystemTime::now());
+        let later = ObjectData::new(object_data.expiry() + Duration::from_secs(3600), 4, 1, object_data.object().clone());
+
+        assert_eq!(None, KnownPubKey::from_object(identity.address(), &later));
+    }
+
+    #[test]
+    fn test_v3_pubkey_signature_is_checked() {
+        let identity = Identity::random("Test", 1);
+        let address = Address::new(3, 1, identity.address().ripe());
+        let expiry = SystemTime::now();
+        let mut content = PubKeyContent {
+            behaviour_bitfield: 1,
+            public_signing_key: identity.public_signing_key(),
+            public_encryption_key: identity.public_encryption_key(),
+            nonce_trials_per_byte: 1000,
+            extra_bytes: 1000,
+            signature: vec![]
+        };
+        let mut signed = vec![];
+        write_object_header(&mut signed, &expiry, OBJECT_PUBKEY, 3, 1);
+        write_unsigned_pubkey_content(&mut signed, &content);
+        content.signature = sign(identity.private_signing_key(), &signed);
+
+        let v3_object = |content: &PubKeyContent| ObjectData::new(expiry, 3, 1, Object::PubKey(PubKey::V3 {
+            behaviour_bitfield: content.behaviour_bitfield,
+            public_signing_key: content.public_signing_key.clone(),
+            public_encryption_key: content.public_encryption_key.clone(),
+            nonce_trials_per_byte: content.nonce_trials_per_byte,
+            extra_bytes: content.extra_bytes,
+            signature: content.signature.clone()
+        }));
+        assert!(KnownPubKey::from_object(&address, &v3_object(&content)).is_some());
+
+        content.nonce_trials_per_byte = 1;
+        assert_eq!(None, KnownPubKey::from_object(&address, &v3_object(&content)));
+    }
+}
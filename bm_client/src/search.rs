@@ -0,0 +1,176 @@
+use std::collections::{HashMap,HashSet};
+
+// Words of the subject, body and addresses of each stored message, so that searches
+// don't have to scan every message
+
+pub struct SearchIndex {
+    words: HashMap<String, HashSet<Vec<u8>>>,
+    documents: HashMap<Vec<u8>, Vec<String>> // the words indexed for each message id
+}
+
+impl SearchIndex {
+    pub fn new() -> SearchIndex {
+        SearchIndex {
+            words: HashMap::new(),
+            documents: HashMap::new()
+        }
+    }
+
+    pub fn add(&mut self, id: &[u8], texts: &[&str]) {
+        self.remove(id);
+
+        let mut document_words: Vec<String> = texts.iter().flat_map(|text| split_words(text)).collect();
+        document_words.sort();
+        document_words.dedup();
+        for word in &document_words {
+            self.words.entry(word.clone()).or_insert_with(HashSet::new).insert(id.to_vec());
+        }
+        self.documents.insert(id.to_vec(), document_words);
+    }
+
+    pub fn remove(&mut self, id: &[u8]) {
+        if let Some(document_words) = self.documents.remove(id) {
+            for word in document_words {
+                let now_unused = match self.words.get_mut(&word) {
+                    Some(ids) => {
+                        ids.remove(id);
+                        ids.is_empty()
+                    },
+                    None => false
+                };
+                if now_unused {
+                    self.words.remove(&word);
+                }
+            }
+        }
+    }
+
+    // Gives the ids of the messages matching the terms
+    pub fn search(&self, terms: &SearchTerms) -> HashSet<Vec<u8>> {
+        let mut found: HashSet<Vec<u8>> = self.documents.keys().cloned().collect();
+        for alternatives in &terms.required {
+            let mut matching = HashSet::new();
+            for phrase in alternatives {
+                matching.extend(self.with_all(phrase));
+            }
+            found = found.intersection(&matching).cloned().collect();
+        }
+        for phrase in &terms.excluded {
+            for id in self.with_all(phrase) {
+                found.remove(&id);
+            }
+        }
+        found
+    }
+
+    fn with_all(&self, words: &[String]) -> HashSet<Vec<u8>> {
+        let mut sets = words.iter().map(|word| self.words.get(word));
+        let mut found = match sets.next() {
+            Some(Some(ids)) => ids.clone(),
+            _ => return HashSet::new()
+        };
+        for ids in sets {
+            found = match ids {
+                Some(ids) => found.intersection(ids).cloned().collect(),
+                None => return HashSet::new()
+            };
+        }
+        found
+    }
+}
+
+// A parsed search like "hello world", "cats OR dogs", "-spam" or "NOT spam".
+// Terms are ANDed unless joined by OR, and a term of several words, like an address,
+// needs all of them.
+#[derive(Clone,Debug,PartialEq)]
+pub struct SearchTerms {
+    required: Vec<Vec<Vec<String>>>, // each entry needs one of its alternatives to match
+    excluded: Vec<Vec<String>>
+}
+
+impl SearchTerms {
+    pub fn parse(text: &str) -> SearchTerms {
+        let mut terms = SearchTerms {
+            required: vec![],
+            excluded: vec![]
+        };
+
+        let (mut negate, mut or) = (false, false);
+        for term in text.split_whitespace() {
+            match term {
+                "AND" => continue,
+                "OR" => {
+                    or = !terms.required.is_empty();
+                    continue;
+                },
+                "NOT" => {
+                    negate = true;
+                    continue;
+                },
+                _ => {}
+            }
+
+            let (negated, term) = if term.starts_with('-') { (true, &term[1..]) } else { (negate, term) };
+            let words = split_words(term);
+            if !words.is_empty() {
+                if negated {
+                    terms.excluded.push(words);
+                } else if or {
+                    terms.required.last_mut().unwrap().push(words);
+                } else {
+                    terms.required.push(vec![ words ]);
+                }
+            }
+            negate = false;
+            or = false;
+        }
+
+        terms
+    }
+}
+
+fn split_words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric()).filter(|word| !word.is_empty()).map(|word| word.to_lowercase()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::{SearchIndex,SearchTerms};
+
+    fn search(index: &SearchIndex, text: &str) -> Vec<u8> {
+        let found: HashSet<Vec<u8>> = index.search(&SearchTerms::parse(text));
+        let mut ids: Vec<u8> = found.into_iter().map(|id| id[0]).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_boolean_terms() {
+        let mut index = SearchIndex::new();
+        index.add(&[ 1 ], &[ "Cats", "I like cats and dogs", "BM-2cTfvWz6rmkyjDemoWTC2RDyK4Vb14fK3z" ]);
+        index.add(&[ 2 ], &[ "Dogs", "Only dogs here", "BM-2D84wQgp9bJ6unWgn67sYHxBgZEeNzpKba" ]);
+        index.add(&[ 3 ], &[ "Spam", "Buy cheap dogs!", "BM-2D84wQgp9bJ6unWgn67sYHxBgZEeNzpKba" ]);
+
+        assert_eq!(vec![ 1, 2, 3 ], search(&index, "DOGS"));
+        assert_eq!(vec![ 1 ], search(&index, "cats dogs"));
+        assert_eq!(vec![ 1, 3 ], search(&index, "cats OR cheap"));
+        assert_eq!(vec![ 1, 2 ], search(&index, "dogs -cheap"));
+        assert_eq!(vec![ 1, 2 ], search(&index, "NOT spam"));
+        assert_eq!(vec![ 2, 3 ], search(&index, "BM-2D84wQgp9bJ6unWgn67sYHxBgZEeNzpKba"));
+        assert_eq!(vec![ 1, 2, 3 ], search(&index, ""));
+        assert!(search(&index, "birds").is_empty());
+    }
+
+    #[test]
+    fn test_removed_messages_are_forgotten() {
+        let mut index = SearchIndex::new();
+        index.add(&[ 1 ], &[ "Hello", "world" ]);
+        index.add(&[ 2 ], &[ "Hello", "again" ]);
+        index.remove(&[ 1 ]);
+
+        assert_eq!(vec![ 2 ], search(&index, "hello"));
+        assert!(search(&index, "world").is_empty());
+        assert!(!index.words.contains_key("world"));
+    }
+}
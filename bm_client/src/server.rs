@@ -0,0 +1,60 @@
+use std::io;
+use std::net::{SocketAddr,TcpListener,TcpStream,ToSocketAddrs};
+use std::time::Duration;
+use stop::StopSignal;
+
+// What the API server and the mail gateway have in common
+
+const ACCEPT_POLL_MILLIS: u64 = 100;
+
+// Polled, so that the thread notices when it's stopped
+pub struct PolledListener {
+    listener: TcpListener
+}
+
+impl PolledListener {
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<PolledListener> {
+        let listener = try!(TcpListener::bind(addr));
+        try!(listener.set_nonblocking(true));
+        Ok(PolledListener {
+            listener: listener
+        })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    // Hands over each connection until stopped. Accepted streams can inherit the listener's
+    // non-blocking mode, so they are put back to blocking first.
+    pub fn accept_until_stopped<F: FnMut(TcpStream)>(&self, stop_signal: &StopSignal, mut handle: F) {
+        while !stop_signal.is_stopped() {
+            match self.listener.accept() {
+                Ok((stream, _)) => if stream.set_nonblocking(false).is_ok() {
+                    handle(stream);
+                },
+                Err(_) => {
+                    stop_signal.sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
+                }
+            }
+        }
+    }
+}
+
+// Compared in full, so the time taken doesn't give away how much of it was right
+pub fn secrets_match(given: &[u8], expected: &[u8]) -> bool {
+    given.len() == expected.len() && given.iter().zip(expected.iter()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::secrets_match;
+
+    #[test]
+    fn test_secrets_match() {
+        assert!(secrets_match(b"user:secret", b"user:secret"));
+        assert!(!secrets_match(b"user:secreT", b"user:secret"));
+        assert!(!secrets_match(b"user:secret2", b"user:secret"));
+        assert!(!secrets_match(b"", b"user:secret"));
+    }
+}
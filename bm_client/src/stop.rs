@@ -0,0 +1,93 @@
+use std::cmp::min;
+use std::sync::{Arc,Mutex};
+use std::thread::{JoinHandle,sleep};
+use std::time::{Duration,Instant};
+
+const POLL_INTERVAL_MILLIS: u64 = 10;
+const SLEEP_SLICE_MILLIS: u64 = 100;
+
+// Shared between a component and its threads: once stopped, threads should wind up by the deadline
+#[derive(Clone)]
+pub struct StopSignal {
+    deadline: Arc<Mutex<Option<Instant>>>
+}
+
+impl StopSignal {
+    pub fn new() -> StopSignal {
+        StopSignal {
+            deadline: Arc::new(Mutex::new(None))
+        }
+    }
+
+    pub fn stop(&self, deadline: Instant) {
+        let mut guard = self.deadline.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(deadline);
+        }
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.deadline().is_some()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.deadline.lock().unwrap()
+    }
+
+    // Sleeps for the duration, waking early if stopped. Returns false if stopped.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let wake_time = Instant::now() + duration;
+        loop {
+            if self.is_stopped() {
+                return false;
+            }
+
+            let now = Instant::now();
+            if now >= wake_time {
+                return true;
+            }
+
+            sleep(min(wake_time - now, Duration::from_millis(SLEEP_SLICE_MILLIS)));
+        }
+    }
+}
+
+// Joins the thread if it finishes before the deadline, otherwise leaves it to finish on its own
+pub fn join_until(handle: JoinHandle<()>, deadline: Instant) -> bool {
+    while !handle.is_finished() {
+        if Instant::now() >= deadline {
+            return false;
+        }
+        sleep(Duration::from_millis(POLL_INTERVAL_MILLIS));
+    }
+
+    let _ = handle.join();
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration,Instant};
+    use super::{StopSignal,join_until};
+
+    #[test]
+    fn test_sleep_wakes_when_stopped() {
+        let stop_signal = StopSignal::new();
+        let thread_signal = stop_signal.clone();
+        let handle = thread::spawn(move || {
+            assert!(!thread_signal.sleep(Duration::from_secs(60)));
+        });
+
+        let start = Instant::now();
+        stop_signal.stop(Instant::now() + Duration::from_secs(5));
+        assert!(join_until(handle, Instant::now() + Duration::from_secs(5)));
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_join_until_gives_up_at_deadline() {
+        let handle = thread::spawn(|| thread::sleep(Duration::from_millis(500)));
+        assert!(!join_until(handle, Instant::now() + Duration::from_millis(20)));
+    }
+}
@@ -0,0 +1,162 @@
+use address::Address;
+use disk::{address_from_json,address_to_json,string_from_json};
+use ecies;
+use keys::{ripe,verify};
+use message::{Broadcast,BroadcastContent,OBJECT_BROADCAST,Object,ObjectData,read_broadcast_content,write_object_header,write_unsigned_broadcast_content};
+use persist::Persister;
+use rustc_serialize::json::{Json,ToJson};
+use std::collections::BTreeMap;
+
+// An address whose broadcasts we want to receive
+#[derive(Clone,Debug,PartialEq)]
+pub struct Subscription {
+    label: String,
+    address: Address
+}
+
+impl Subscription {
+    pub fn new(label: &str, address: &Address) -> Subscription {
+        Subscription {
+            label: label.to_string(),
+            address: address.clone()
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn address(&self) -> &Address {
+        &self.address
+    }
+
+    pub fn from_json(json: &Json) -> Option<Subscription> {
+        Some(Subscription {
+            label: return_none_on_none!(string_from_json(json.find("label"))),
+            address: return_none_on_none!(address_from_json(json.find("address")))
+        })
+    }
+}
+
+impl ToJson for Subscription {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("label".to_string(), self.label.to_json());
+        object.insert("address".to_string(), address_to_json(&self.address));
+        Json::Object(object)
+    }
+}
+
+#[derive(Clone)]
+pub struct Subscriptions {
+    persister: Persister
+}
+
+impl Subscriptions {
+    pub fn new(persister: Persister) -> Subscriptions {
+        Subscriptions {
+            persister: persister
+        }
+    }
+
+    // Subscribing to the same address again just changes the label
+    pub fn add(&mut self, subscription: &Subscription) {
+        self.persister.add_subscription(subscription);
+    }
+
+    pub fn remove(&mut self, address: &Address) -> bool {
+        self.persister.remove_subscription(address)
+    }
+
+    pub fn list(&self) -> Vec<Subscription> {
+        self.persister.get_subscriptions()
+    }
+}
+
+// Gives the content of the broadcast if it was sent from the address and properly signed
+pub fn open_broadcast(address: &Address, object_data: &ObjectData) -> Option<BroadcastContent> {
+    if object_data.stream() != address.stream() {
+        return None;
+    }
+
+    let (tag, encrypted) = match object_data.object() {
+        &Object::Broadcast(Broadcast::V5 { ref tag, ref encrypted }) if address.version() >= 4 && tag == &address.tag() => (Some(tag), encrypted),
+        &Object::Broadcast(Broadcast::V4 { ref encrypted }) if address.version() <= 3 => (None, encrypted),
+        _ => return None
+    };
+
+    let content = match ecies::decrypt(&address.broadcast_private_key(), encrypted) {
+        Ok(plaintext) => match read_broadcast_content(&plaintext) {
+            Ok(content) => content,
+            Err(_) => return None
+        },
+        Err(_) => return None
+    };
+    if content.address_version != address.version() || content.stream != address.stream() ||
+        ripe(&content.public_signing_key, &content.public_encryption_key) != address.ripe() {
+        return None;
+    }
+
+    let mut signed = vec![];
+    write_object_header(&mut signed, &object_data.expiry(), OBJECT_BROADCAST, object_data.version(), object_data.stream());
+    if let Some(tag) = tag {
+        signed.extend(tag);
+    }
+    write_unsigned_broadcast_content(&mut signed, &content);
+    if !verify(&content.public_signing_key, &signed, &content.signature) {
+        return None;
+    }
+
+    Some(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use identity::Identity;
+    use message::{Broadcast,Object,ObjectData};
+    use msgcoding::{MessageEncoding,encode};
+    use persist::Persister;
+    use std::time::{Duration,SystemTime};
+    use super::{Subscription,Subscriptions,open_broadcast};
+
+    #[test]
+    fn test_broadcast_is_opened_by_subscribers_only() {
+        let identity = Identity::random("Me", 1);
+        let other = Identity::random("Other", 1);
+        let object_data = identity.broadcast_object_data(SystemTime::now(), MessageEncoding::Simple.number(), encode(MessageEncoding::Simple, "Hi", "Hello"));
+
+        let content = open_broadcast(identity.address(), &object_data).unwrap();
+        assert_eq!(encode(MessageEncoding::Simple, "Hi", "Hello"), content.message);
+        assert_eq!(None, open_broadcast(other.address(), &object_data));
+    }
+
+    #[test]
+    fn test_tampered_broadcast_is_rejected() {
+        let identity = Identity::random("Me", 1);
+        let object_data = identity.broadcast_object_data(SystemTime::now(), MessageEncoding::Simple.number(), encode(MessageEncoding::Simple, "Hi", "Hello"));
+
+        // The expiry is covered by the signature
+        let later = ObjectData::new(object_data.expiry() + Duration::from_secs(3600), object_data.version(), object_data.stream(), object_data.object().clone());
+        assert_eq!(None, open_broadcast(identity.address(), &later));
+
+        let truncated = match object_data.object() {
+            &Object::Broadcast(Broadcast::V5 { ref tag, ref encrypted }) => Object::Broadcast(Broadcast::V5 { tag: tag.clone(), encrypted: encrypted[1..].to_vec() }),
+            _ => panic!("Expected a v5 broadcast")
+        };
+        let truncated = ObjectData::new(object_data.expiry(), object_data.version(), object_data.stream(), truncated);
+        assert_eq!(None, open_broadcast(identity.address(), &truncated));
+    }
+
+    #[test]
+    fn test_subscribing_again_replaces() {
+        let mut subscriptions = Subscriptions::new(Persister::new());
+        let identity = Identity::random("Me", 1);
+
+        subscriptions.add(&Subscription::new("First", identity.address()));
+        subscriptions.add(&Subscription::new("Second", identity.address()));
+
+        assert_eq!(vec![ Subscription::new("Second", identity.address()) ], subscriptions.list());
+        assert!(subscriptions.remove(identity.address()));
+        assert!(!subscriptions.remove(identity.address()));
+    }
+}
@@ -0,0 +1,80 @@
+use bm_client::Folder;
+use std::time::Duration;
+
+// What the commands need from a client, whether it runs in this process or in a daemon
+
+pub struct IdentityInfo {
+    pub address: String,
+    pub label: String,
+    pub chan: bool,
+    pub enabled: bool
+}
+
+pub struct MessageInfo {
+    pub id: String,
+    pub from: String,
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub received: u64, // unix seconds
+    pub read: bool
+}
+
+pub struct ObjectCounts {
+    pub getpubkeys: u64,
+    pub pubkeys: u64,
+    pub msgs: u64,
+    pub broadcasts: u64,
+    pub total: u64
+}
+
+pub enum ArchiveFormat {
+    Mbox, // one file
+    Eml // a directory with a file per message
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum SendOutcome {
+    Finished, // on the network, so nothing more needs doing locally
+    Unfinished, // not on the network yet
+    Failed
+}
+
+pub struct SendResult {
+    pub ackdata: String,
+    pub status: String,
+    pub outcome: SendOutcome
+}
+
+pub trait Backend {
+    fn create_identity(&mut self, label: &str) -> Result<String, String>;
+
+    fn identities(&mut self) -> Result<Vec<IdentityInfo>, String>;
+
+    // In PyBitmessage's keys.dat format; every identity and chan without an address
+    fn export_identities(&mut self, address: Option<&str>) -> Result<String, String>;
+
+    // From the contents of a keys.dat. Returns how many were imported and skipped.
+    fn import_identities(&mut self, keys_dat: &str) -> Result<(usize, usize), String>;
+
+    fn send(&mut self, from: &str, to: &str, subject: &str, body: &str, wait: Duration) -> Result<SendResult, String>;
+
+    fn inbox(&mut self) -> Result<Vec<MessageInfo>, String>;
+
+    // Marks the message read
+    fn read_message(&mut self, id: &str) -> Result<MessageInfo, String>;
+
+    // Moves the message to the trash
+    fn delete_message(&mut self, id: &str) -> Result<(), String>;
+
+    // Returns how many messages were written
+    fn export_messages(&mut self, folders: &[Folder], format: ArchiveFormat, path: &str) -> Result<usize, String>;
+
+    // From an mbox file, an .eml file or a directory of them. Returns how many were imported and skipped.
+    fn import_messages(&mut self, path: &str) -> Result<(usize, usize), String>;
+
+    // Pairs of peer and connection state
+    fn peers(&mut self, wait: Duration) -> Result<Vec<(String, String)>, String>;
+
+    fn object_counts(&mut self, wait: Duration) -> Result<ObjectCounts, String>;
+}
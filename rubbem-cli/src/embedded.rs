@@ -0,0 +1,242 @@
+use backend::{ArchiveFormat,Backend,IdentityInfo,MessageInfo,ObjectCounts,SendOutcome,SendResult};
+use bm_client::{Address,BMClient,Config,ConnectionState,Event,Folder,MessageQuery,OutboxStatus,SendOptions,StoredMessage};
+use rustc_serialize::hex::{FromHex,ToHex};
+use std::cmp::min;
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+use std::thread::sleep;
+use std::time::{Duration,Instant,UNIX_EPOCH};
+
+// Runs the client in this process, on the same data directory a daemon would use.
+// The network is only started by the commands that need it.
+
+const POLL_INTERVAL_MILLIS: u64 = 500;
+const PASS_ON_TIMEOUT_SECS: u64 = 60;
+const PASS_ON_LINGER_SECS: u64 = 5;
+
+pub struct Embedded {
+    client: BMClient
+}
+
+impl Embedded {
+    pub fn new(config: Config) -> Result<Embedded, String> {
+        if config.data_dir().is_none() {
+            return Err("Without --api, a data directory is needed (--data-dir, or data_dir in the config file)".to_string());
+        }
+        let client = try!(BMClient::with_config(config).map_err(|err| err.to_string()));
+        Ok(Embedded {
+            client: client
+        })
+    }
+
+    fn start(&mut self) -> Result<(), String> {
+        self.client.start().map_err(|err| format!("Cannot start: {}", err))
+    }
+
+    fn inbox_message(&self, id: &str) -> Result<StoredMessage, String> {
+        let id = try!(id.from_hex().map_err(|_| format!("{} is not a message id", id)));
+        match self.client.message(&id) {
+            Some(message) if !message.is_ours() => Ok(message),
+            _ => Err(format!("No message {} in the inbox", id.to_hex()))
+        }
+    }
+
+    // Runs the network for a while, to see what it brings in
+    fn run_for(&mut self, wait: Duration) -> Result<(), String> {
+        try!(self.start());
+        sleep(wait);
+        Ok(())
+    }
+
+    // Connected peers are offered a new object straight away, but one of them has to be
+    // around, and given time to fetch it, before we go
+    fn pass_on(&self, events: &Receiver<Event>) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(PASS_ON_TIMEOUT_SECS);
+        let mut connected = self.client.connections().iter().any(|&(_, state)| match state {
+            ConnectionState::Established(_) => true,
+            _ => false
+        });
+        while !connected {
+            match next_event(events, deadline) {
+                Some(Event::PeerConnected(_)) => connected = true,
+                Some(_) => {},
+                None => return false
+            }
+        }
+        sleep(Duration::from_secs(PASS_ON_LINGER_SECS));
+        true
+    }
+}
+
+impl Backend for Embedded {
+    fn create_identity(&mut self, label: &str) -> Result<String, String> {
+        Ok(self.client.create_identity(label).to_string())
+    }
+
+    fn identities(&mut self) -> Result<Vec<IdentityInfo>, String> {
+        Ok(self.client.identities().iter().chain(self.client.chans().iter()).map(|identity| IdentityInfo {
+            address: identity.address().to_string(),
+            label: identity.label().to_string(),
+            chan: identity.is_chan(),
+            enabled: identity.enabled()
+        }).collect())
+    }
+
+    fn export_identities(&mut self, address: Option<&str>) -> Result<String, String> {
+        let address = match address {
+            Some(address) => Some(try!(parse_address(address))),
+            None => None
+        };
+        self.client.export_keys_dat(address.as_ref()).map_err(|err| err.to_string())
+    }
+
+    fn import_identities(&mut self, keys_dat: &str) -> Result<(usize, usize), String> {
+        let stats = try!(self.client.import_keys_dat(keys_dat).map_err(|err| err.to_string()));
+        Ok((stats.imported(), stats.skipped()))
+    }
+
+    // Messages waiting for a pubkey or the proof of work stay in the outbox, and carry on the
+    // next time the client runs. A sent one is only held in memory until a peer takes it.
+    fn send(&mut self, from: &str, to: &str, subject: &str, body: &str, wait: Duration) -> Result<SendResult, String> {
+        let (from, to) = (try!(parse_address(from)), try!(parse_address(to)));
+        let events = self.client.subscribe();
+        let ackdata = try!(self.client.send_message(&from, &to, subject, body, SendOptions::new()).map_err(|err| err.to_string()));
+        try!(self.start());
+
+        let deadline = Instant::now() + wait;
+        let status = loop {
+            let status = try!(self.client.outbox_message(&ackdata).map(|message| message.status()).ok_or("The message was taken out of the outbox".to_string()));
+            match status {
+                OutboxStatus::Sent | OutboxStatus::AckReceived | OutboxStatus::Failed | OutboxStatus::GaveUp => break status,
+                _ => {}
+            }
+            if Instant::now() >= deadline {
+                break status;
+            }
+            next_event(&events, min(deadline, Instant::now() + Duration::from_millis(POLL_INTERVAL_MILLIS)));
+        };
+
+        let passed_on = status == OutboxStatus::Sent && self.pass_on(&events);
+        let (status, outcome) = send_outcome(status, passed_on);
+        self.client.stop();
+
+        Ok(SendResult {
+            ackdata: ackdata.to_hex(),
+            status: status,
+            outcome: outcome
+        })
+    }
+
+    fn inbox(&mut self) -> Result<Vec<MessageInfo>, String> {
+        Ok(self.client.messages(&MessageQuery::new().folder(Folder::Inbox)).iter().map(message_info).collect())
+    }
+
+    fn read_message(&mut self, id: &str) -> Result<MessageInfo, String> {
+        let message = try!(self.inbox_message(id));
+        self.client.mark_read(message.id(), true);
+        Ok(message_info(&message))
+    }
+
+    fn delete_message(&mut self, id: &str) -> Result<(), String> {
+        let message = try!(self.inbox_message(id));
+        self.client.trash_message(message.id());
+        Ok(())
+    }
+
+    fn export_messages(&mut self, folders: &[Folder], format: ArchiveFormat, path: &str) -> Result<usize, String> {
+        let result = match format {
+            ArchiveFormat::Mbox => self.client.export_mbox(folders, path),
+            ArchiveFormat::Eml => self.client.export_eml(folders, path)
+        };
+        result.map_err(|err| format!("{}: {}", path, err))
+    }
+
+    fn import_messages(&mut self, path: &str) -> Result<(usize, usize), String> {
+        let stats = try!(self.client.import_messages(path).map_err(|err| format!("{}: {}", path, err)));
+        Ok((stats.imported(), stats.skipped()))
+    }
+
+    fn peers(&mut self, wait: Duration) -> Result<Vec<(String, String)>, String> {
+        try!(self.run_for(wait));
+        let connections = self.client.connections();
+        self.client.stop();
+        Ok(connections.iter().map(|&(peer_addr, state)| (peer_addr.to_string(), state.to_string())).collect())
+    }
+
+    // Objects are only held in memory, so these are the ones received while waiting
+    fn object_counts(&mut self, wait: Duration) -> Result<ObjectCounts, String> {
+        try!(self.run_for(wait));
+        let stats = self.client.object_stats();
+        self.client.stop();
+        Ok(ObjectCounts {
+            getpubkeys: stats.getpubkeys() as u64,
+            pubkeys: stats.pubkeys() as u64,
+            msgs: stats.msgs() as u64,
+            broadcasts: stats.broadcasts() as u64,
+            total: stats.total() as u64
+        })
+    }
+}
+
+fn parse_address(address: &str) -> Result<Address, String> {
+    Address::from_str(address.trim()).map_err(|err| format!("{}: {}", address, err))
+}
+
+fn next_event(events: &Receiver<Event>, deadline: Instant) -> Option<Event> {
+    let now = Instant::now();
+    if now >= deadline {
+        return None;
+    }
+    events.recv_timeout(deadline - now).ok()
+}
+
+fn send_outcome(status: OutboxStatus, passed_on: bool) -> (String, SendOutcome) {
+    match status {
+        OutboxStatus::Sent if passed_on => ("sent".to_string(), SendOutcome::Finished),
+        OutboxStatus::Sent => ("sent, but no peer could be reached to pass it on, so it has to be sent again".to_string(), SendOutcome::Unfinished),
+        OutboxStatus::AckReceived => ("acknowledged".to_string(), SendOutcome::Finished),
+        OutboxStatus::Failed | OutboxStatus::GaveUp => (status_text(status).to_string(), SendOutcome::Failed),
+        status => (format!("{}, and will carry on the next time the client runs", status_text(status)), SendOutcome::Unfinished)
+    }
+}
+
+fn status_text(status: OutboxStatus) -> &'static str {
+    match status {
+        OutboxStatus::Queued => "queued",
+        OutboxStatus::AwaitingPubKey => "waiting for the recipient's pubkey",
+        OutboxStatus::DoingPow => "doing the proof of work",
+        OutboxStatus::Sent => "sent",
+        OutboxStatus::AckReceived => "acknowledged",
+        OutboxStatus::Failed => "failed",
+        OutboxStatus::GaveUp => "given up on"
+    }
+}
+
+fn message_info(message: &StoredMessage) -> MessageInfo {
+    MessageInfo {
+        id: message.id().to_hex(),
+        from: message.from().to_string(),
+        to: message.to().map(|to| to.to_string()).unwrap_or("[broadcast]".to_string()),
+        subject: message.subject().to_string(),
+        body: message.body().to_string(),
+        received: message.timestamp().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0),
+        read: message.is_read()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use backend::SendOutcome;
+    use bm_client::OutboxStatus;
+    use super::send_outcome;
+
+    #[test]
+    fn test_send_outcome() {
+        assert_eq!(send_outcome(OutboxStatus::Sent, true), ("sent".to_string(), SendOutcome::Finished));
+        assert_eq!(send_outcome(OutboxStatus::Sent, false).1, SendOutcome::Unfinished);
+        assert_eq!(send_outcome(OutboxStatus::AckReceived, false).1, SendOutcome::Finished);
+        assert_eq!(send_outcome(OutboxStatus::Failed, false), ("failed".to_string(), SendOutcome::Failed));
+        assert_eq!(send_outcome(OutboxStatus::DoingPow, false),
+            ("doing the proof of work, and will carry on the next time the client runs".to_string(), SendOutcome::Unfinished));
+    }
+}
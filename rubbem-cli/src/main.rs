@@ -0,0 +1,338 @@
+extern crate bm_client;
+extern crate rustc_serialize;
+
+mod backend;
+mod embedded;
+mod remote;
+
+use backend::{ArchiveFormat,Backend,SendOutcome};
+use bm_client::{Config,ConfigBuilder,Folder,benchmark_pow,short_date};
+use embedded::Embedded;
+use remote::Remote;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{self,Read};
+use std::process::exit;
+use std::time::{Duration,UNIX_EPOCH};
+
+// Scripts the client: either runs it in this process on a data directory, or talks to
+// a running rubbemd through its API
+
+const USAGE: &'static str = "\
+Usage: rubbem-cli [--config FILE] [--data-dir DIR] [--api HOST:PORT [--user NAME --password SECRET]] COMMAND
+
+Commands:
+  identity new [LABEL]
+  identity list
+  identity export [ADDRESS]
+  identity import FILE
+  send --from ADDRESS --to ADDRESS --subject TEXT [--body TEXT] [--wait SECS]
+  inbox list [--unread]
+  inbox read ID
+  inbox delete ID
+  messages export (--mbox FILE | --eml DIR) [--folders inbox,outbox,sent,trash]
+  messages import PATH
+  peers [--wait SECS]
+  objects stats [--wait SECS]
+  pow bench [--threads N] [--size BYTES] [--ttl SECS]
+
+Without --body, the message body is read from standard input. send exits with 1 if
+the message failed, and with 3 if it is not on the network yet: one waiting for a
+pubkey or the proof of work carries on the next time the client runs, but one no
+peer could be reached for has to be sent again.
+identity export and import use PyBitmessage's keys.dat format.
+messages import takes an mbox file, an .eml file or a directory of .eml files.";
+
+const VALUE_OPTIONS: &'static [&'static str] = &[
+    "--config", "--data-dir", "--api", "--user", "--password",
+    "--from", "--to", "--subject", "--body", "--wait", "--threads", "--size", "--ttl",
+    "--mbox", "--eml", "--folders"
+];
+const FLAG_OPTIONS: &'static [&'static str] = &[ "--unread" ];
+
+const DEFAULT_SEND_WAIT_SECS: u64 = 600;
+const DEFAULT_NETWORK_WAIT_SECS: u64 = 10;
+const DEFAULT_BENCH_SIZE: u64 = 1000;
+const DEFAULT_BENCH_TTL_SECS: u64 = 345600;
+
+enum Failure {
+    Usage(String),
+    Error(String),
+    Unfinished
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Failure {
+        Failure::Error(message)
+    }
+}
+
+struct Args {
+    values: HashMap<String, String>,
+    flags: Vec<String>,
+    words: Vec<String>
+}
+
+impl Args {
+    fn value(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(|value| value.as_str())
+    }
+
+    fn required(&self, name: &str) -> Result<&str, Failure> {
+        self.value(name).ok_or(Failure::Usage(format!("{} is needed", name)))
+    }
+
+    fn number(&self, name: &str, default: u64) -> Result<u64, Failure> {
+        match self.value(name) {
+            Some(value) => value.parse().map_err(|_| Failure::Usage(format!("{} must be a number", name))),
+            None => Ok(default)
+        }
+    }
+
+    fn has_flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|flag| flag == name)
+    }
+}
+
+fn main() {
+    let result = parse_args(env::args().skip(1).collect()).and_then(|args| run(&args));
+    match result {
+        Ok(()) => {},
+        Err(Failure::Usage(message)) => {
+            eprintln!("{}\n\n{}", message, USAGE);
+            exit(2);
+        },
+        Err(Failure::Error(message)) => {
+            eprintln!("{}", message);
+            exit(1);
+        },
+        Err(Failure::Unfinished) => exit(3)
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Args, Failure> {
+    let mut parsed = Args {
+        values: HashMap::new(),
+        flags: vec![],
+        words: vec![]
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--help" || arg == "-h" {
+            return Err(Failure::Usage("Scripts Rubbem from the command line.".to_string()));
+        } else if VALUE_OPTIONS.contains(&arg.as_str()) {
+            match args.next() {
+                Some(value) => parsed.values.insert(arg, value),
+                None => return Err(Failure::Usage(format!("{} needs a value", arg)))
+            };
+        } else if FLAG_OPTIONS.contains(&arg.as_str()) {
+            parsed.flags.push(arg);
+        } else if arg.starts_with("--") {
+            return Err(Failure::Usage(format!("Unknown option {}", arg)));
+        } else {
+            parsed.words.push(arg);
+        }
+    }
+
+    Ok(parsed)
+}
+
+fn run(args: &Args) -> Result<(), Failure> {
+    let words: Vec<&str> = args.words.iter().map(|word| word.as_str()).collect();
+    // The benchmark needs no client at all
+    if words == [ "pow", "bench" ] {
+        return pow_bench(args);
+    }
+
+    let mut backend = try!(open_backend(args));
+    let wait = Duration::from_secs(try!(args.number("--wait", DEFAULT_NETWORK_WAIT_SECS)));
+    match &words[..] {
+        [ "identity", "new" ] => println!("{}", try!(backend.create_identity(""))),
+        [ "identity", "new", label ] => println!("{}", try!(backend.create_identity(label))),
+        [ "identity", "list" ] => for identity in try!(backend.identities()) {
+            let chan = if identity.chan { " (chan)" } else { "" };
+            let disabled = if identity.enabled { "" } else { " (disabled)" };
+            println!("{}  {}{}{}", identity.address, identity.label, chan, disabled);
+        },
+        [ "identity", "export" ] => print!("{}", try!(backend.export_identities(None))),
+        [ "identity", "export", address ] => print!("{}", try!(backend.export_identities(Some(address)))),
+        [ "identity", "import", path ] => {
+            let keys_dat = try!(fs::read_to_string(path).map_err(|err| format!("Cannot read {}: {}", path, err)));
+            let (imported, skipped) = try!(backend.import_identities(&keys_dat));
+            println!("Imported {} identities, skipped {} we already had", imported, skipped);
+        },
+        [ "send" ] => return send(args, &mut *backend),
+        [ "inbox", "list" ] => for message in try!(backend.inbox()) {
+            if args.has_flag("--unread") && message.read {
+                continue;
+            }
+            let unread = if message.read { " " } else { "*" };
+            println!("{}{}  {}  {}  {}", unread, message.id, format_time(message.received), message.from, message.subject);
+        },
+        [ "inbox", "read", id ] => {
+            let message = try!(backend.read_message(id));
+            println!("From: {}\nTo: {}\nDate: {}\nSubject: {}\n\n{}", message.from, message.to, format_time(message.received), message.subject, message.body);
+        },
+        [ "inbox", "delete", id ] => try!(backend.delete_message(id)),
+        [ "messages", "export" ] => return export_messages(args, &mut *backend),
+        [ "messages", "import", path ] => {
+            let (imported, skipped) = try!(backend.import_messages(path));
+            println!("Imported {} messages, skipped {}", imported, skipped);
+        },
+        [ "peers" ] => for (peer, state) in try!(backend.peers(wait)) {
+            println!("{}  {}", peer, state);
+        },
+        [ "objects", "stats" ] => {
+            let counts = try!(backend.object_counts(wait));
+            println!("getpubkey  {}\npubkey     {}\nmsg        {}\nbroadcast  {}\ntotal      {}", counts.getpubkeys, counts.pubkeys, counts.msgs, counts.broadcasts, counts.total);
+        },
+        [] => return Err(Failure::Usage("No command given".to_string())),
+        _ => return Err(Failure::Usage(format!("Unknown command {}", words.join(" "))))
+    }
+    Ok(())
+}
+
+fn send(args: &Args, backend: &mut dyn Backend) -> Result<(), Failure> {
+    let (from, to, subject) = (try!(args.required("--from")), try!(args.required("--to")), try!(args.required("--subject")));
+    let body = match args.value("--body") {
+        Some(body) => body.to_string(),
+        None => {
+            let mut body = String::new();
+            try!(io::stdin().read_to_string(&mut body).map_err(|err| format!("Cannot read the body: {}", err)));
+            body
+        }
+    };
+    let wait = Duration::from_secs(try!(args.number("--wait", DEFAULT_SEND_WAIT_SECS)));
+
+    let result = try!(backend.send(from, to, &subject, &body, wait));
+    println!("{}", result.ackdata);
+    if result.outcome == SendOutcome::Failed {
+        return Err(Failure::Error(format!("Message {}", result.status)));
+    }
+    eprintln!("Message {}", result.status);
+    match result.outcome {
+        SendOutcome::Unfinished => Err(Failure::Unfinished),
+        _ => Ok(())
+    }
+}
+
+fn export_messages(args: &Args, backend: &mut dyn Backend) -> Result<(), Failure> {
+    let (format, path) = match (args.value("--mbox"), args.value("--eml")) {
+        (Some(path), None) => (ArchiveFormat::Mbox, path),
+        (None, Some(path)) => (ArchiveFormat::Eml, path),
+        _ => return Err(Failure::Usage("Either --mbox or --eml is needed".to_string()))
+    };
+    let folders = match args.value("--folders") {
+        Some(names) => try!(parse_folders(names)),
+        None => vec![ Folder::Inbox, Folder::Outbox, Folder::Sent, Folder::Trash ]
+    };
+
+    let count = try!(backend.export_messages(&folders, format, path));
+    println!("Exported {} messages", count);
+    Ok(())
+}
+
+fn parse_folders(names: &str) -> Result<Vec<Folder>, Failure> {
+    let mut folders = vec![];
+    for name in names.split(',').map(|name| name.trim()) {
+        let folder = match name {
+            "inbox" => Folder::Inbox,
+            "outbox" => Folder::Outbox,
+            "sent" => Folder::Sent,
+            "trash" => Folder::Trash,
+            _ => return Err(Failure::Usage(format!("Unknown folder {}", name)))
+        };
+        if !folders.contains(&folder) {
+            folders.push(folder);
+        }
+    }
+    Ok(folders)
+}
+
+fn pow_bench(args: &Args) -> Result<(), Failure> {
+    let default_threads = match args.value("--config") {
+        Some(_) => try!(load_config(args)).pow_threads() as u64,
+        None => 1
+    };
+    let threads = try!(args.number("--threads", default_threads));
+    let size = try!(args.number("--size", DEFAULT_BENCH_SIZE));
+    let ttl = try!(args.number("--ttl", DEFAULT_BENCH_TTL_SECS));
+    if threads == 0 {
+        return Err(Failure::Usage("--threads must be at least 1".to_string()));
+    }
+
+    println!("Proof of work for a {} byte msg living {} seconds, on {} thread(s)", size, ttl, threads);
+    let benchmark = try!(benchmark_pow(threads as usize, size as usize, Duration::from_secs(ttl)).map_err(|err| format!("Benchmark failed: {}", err)));
+    let elapsed = benchmark.elapsed();
+    println!("Took {}.{:03} seconds", elapsed.as_secs(), elapsed.subsec_millis());
+    println!("Expected trials: {}", benchmark.expected_trials());
+    println!("Trials per second: {}", benchmark.trials_per_second());
+    Ok(())
+}
+
+fn load_config(args: &Args) -> Result<Config, Failure> {
+    let mut builder = match args.value("--config") {
+        Some(path) => try!(ConfigBuilder::from_file(path).map_err(|err| err.to_string())),
+        None => Config::builder()
+    };
+    if let Some(data_dir) = args.value("--data-dir") {
+        builder = builder.data_dir(data_dir);
+    }
+    Ok(try!(builder.build().map_err(|err| err.to_string())))
+}
+
+// The API credentials can come from the daemon's config file
+fn open_backend(args: &Args) -> Result<Box<dyn Backend>, Failure> {
+    let api_addr = match args.value("--api") {
+        Some(api_addr) => api_addr,
+        None => return Ok(Box::new(try!(Embedded::new(try!(load_config(args))))))
+    };
+
+    let (username, password) = match (args.value("--user"), args.value("--password")) {
+        (Some(username), Some(password)) => (username.to_string(), password.to_string()),
+        _ if args.value("--config").is_some() => {
+            let config = try!(load_config(args));
+            (config.api_username().to_string(), config.api_password().to_string())
+        },
+        _ => return Err(Failure::Usage("--api needs --user and --password, or a --config file with them".to_string()))
+    };
+    Ok(Box::new(Remote::new(api_addr, &username, &password)))
+}
+
+fn format_time(unix_secs: u64) -> String {
+    short_date(UNIX_EPOCH + Duration::from_secs(unix_secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use bm_client::Folder;
+    use super::{format_time,parse_args,parse_folders};
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!("1970-01-01 00:00", format_time(0));
+        assert_eq!("2000-02-29 12:34", format_time(951827640));
+        assert_eq!("2024-12-31 23:59", format_time(1735689599));
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let args = |args: &[&str]| parse_args(args.iter().map(|arg| arg.to_string()).collect());
+
+        let parsed = args(&[ "--api", "127.0.0.1:8442", "inbox", "list", "--unread" ]).ok().unwrap();
+        assert_eq!(Some("127.0.0.1:8442"), parsed.value("--api"));
+        assert!(parsed.has_flag("--unread"));
+        assert_eq!(vec![ "inbox", "list" ], parsed.words);
+
+        assert!(args(&[ "send", "--from" ]).is_err());
+        assert!(args(&[ "send", "--colour", "red" ]).is_err());
+    }
+
+    #[test]
+    fn test_parse_folders() {
+        assert_eq!(vec![ Folder::Inbox, Folder::Trash ], parse_folders("inbox, trash,inbox").ok().unwrap());
+        assert!(parse_folders("drafts").is_err());
+    }
+}
@@ -0,0 +1,221 @@
+use backend::{ArchiveFormat,Backend,IdentityInfo,MessageInfo,ObjectCounts,SendOutcome,SendResult};
+use bm_client::Folder;
+use rustc_serialize::base64::{FromBase64,STANDARD,ToBase64};
+use rustc_serialize::json::{Json,ToJson};
+use std::collections::BTreeMap;
+use std::io::{Read,Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+// Talks to a running daemon through its JSON-RPC API
+
+const REQUEST_TIMEOUT_SECS: u64 = 30;
+
+pub struct Remote {
+    api_addr: String,
+    authorization: String,
+    next_id: u64
+}
+
+impl Remote {
+    pub fn new(api_addr: &str, username: &str, password: &str) -> Remote {
+        Remote {
+            api_addr: api_addr.to_string(),
+            authorization: format!("{}:{}", username, password).as_bytes().to_base64(STANDARD),
+            next_id: 1
+        }
+    }
+
+    fn call(&mut self, method: &str, params: Vec<Json>) -> Result<Json, String> {
+        let mut request = BTreeMap::new();
+        request.insert("jsonrpc".to_string(), "2.0".to_json());
+        request.insert("id".to_string(), self.next_id.to_json());
+        request.insert("method".to_string(), method.to_json());
+        request.insert("params".to_string(), Json::Array(params));
+        self.next_id += 1;
+        let body = Json::Object(request).to_string();
+
+        let mut stream = try!(TcpStream::connect(&self.api_addr[..]).map_err(|err| format!("Cannot reach the API at {}: {}", self.api_addr, err)));
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)));
+        let _ = stream.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)));
+        try!(write!(stream, "POST / HTTP/1.1\r\nHost: {}\r\nAuthorization: Basic {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.api_addr, self.authorization, body.len(), body).map_err(|err| format!("Cannot send to the API: {}", err)));
+
+        let mut response = vec![];
+        try!(stream.read_to_end(&mut response).map_err(|err| format!("No response from the API: {}", err)));
+        read_response(&String::from_utf8_lossy(&response))
+    }
+
+    // Most methods give back their result as JSON text
+    fn call_for_json(&mut self, method: &str, params: Vec<Json>) -> Result<Json, String> {
+        match try!(self.call(method, params)) {
+            Json::String(text) => Json::from_str(&text).map_err(|_| format!("{} gave back something other than JSON", method)),
+            _ => Err(format!("{} gave back something other than text", method))
+        }
+    }
+
+    fn call_for_string(&mut self, method: &str, params: Vec<Json>) -> Result<String, String> {
+        match try!(self.call(method, params)) {
+            Json::String(text) => Ok(text),
+            _ => Err(format!("{} gave back something other than text", method))
+        }
+    }
+}
+
+impl Backend for Remote {
+    fn create_identity(&mut self, label: &str) -> Result<String, String> {
+        self.call_for_string("createRandomAddress", vec![ encode(label) ])
+    }
+
+    fn identities(&mut self) -> Result<Vec<IdentityInfo>, String> {
+        let addresses = try!(self.call_for_json("listAddresses2", vec![]));
+        let mut identities = vec![];
+        for entry in try!(list(&addresses, "addresses")) {
+            identities.push(IdentityInfo {
+                address: try!(string(entry, "address")),
+                label: try!(decoded(entry, "label")),
+                chan: entry.find("chan").and_then(|chan| chan.as_boolean()).unwrap_or(false),
+                enabled: entry.find("enabled").and_then(|enabled| enabled.as_boolean()).unwrap_or(true)
+            });
+        }
+        Ok(identities)
+    }
+
+    // The API has no way to hand out private keys
+    fn export_identities(&mut self, _address: Option<&str>) -> Result<String, String> {
+        Err("Keys can't be exported through the API; run without --api, on the daemon's data directory".to_string())
+    }
+
+    fn import_identities(&mut self, _keys_dat: &str) -> Result<(usize, usize), String> {
+        Err("Keys can't be imported through the API; stop the daemon and run without --api on its data directory".to_string())
+    }
+
+    // The daemon keeps working on it, so this only queues it
+    fn send(&mut self, from: &str, to: &str, subject: &str, body: &str, _wait: Duration) -> Result<SendResult, String> {
+        let ackdata = try!(self.call_for_string("sendMessage", vec![ to.to_json(), from.to_json(), encode(subject), encode(body) ]));
+        Ok(SendResult {
+            ackdata: ackdata,
+            status: "queued by the daemon".to_string(),
+            outcome: SendOutcome::Finished
+        })
+    }
+
+    fn inbox(&mut self) -> Result<Vec<MessageInfo>, String> {
+        let messages = try!(self.call_for_json("getAllInboxMessages", vec![]));
+        let mut inbox = vec![];
+        for entry in try!(list(&messages, "inboxMessages")) {
+            inbox.push(try!(message_info(entry)));
+        }
+        Ok(inbox)
+    }
+
+    fn read_message(&mut self, id: &str) -> Result<MessageInfo, String> {
+        let messages = try!(self.call_for_json("getInboxMessageById", vec![ id.to_json(), true.to_json() ]));
+        match try!(list(&messages, "inboxMessage")).first() {
+            Some(entry) => message_info(entry),
+            None => Err(format!("No message {} in the inbox", id))
+        }
+    }
+
+    fn delete_message(&mut self, id: &str) -> Result<(), String> {
+        try!(self.read_message(id));
+        self.call("trashInboxMessage", vec![ id.to_json() ]).map(|_| ())
+    }
+
+    // The daemon is already connected, so there's nothing to wait for
+    fn export_messages(&mut self, _folders: &[Folder], _format: ArchiveFormat, _path: &str) -> Result<usize, String> {
+        Err("Messages can't be exported through the API; run without --api, on the daemon's data directory".to_string())
+    }
+
+    fn import_messages(&mut self, _path: &str) -> Result<(usize, usize), String> {
+        Err("Messages can't be imported through the API; stop the daemon and run without --api on its data directory".to_string())
+    }
+
+    fn peers(&mut self, _wait: Duration) -> Result<Vec<(String, String)>, String> {
+        let connections = try!(self.call_for_json("listConnections", vec![]));
+        let mut peers = vec![];
+        for entry in try!(list(&connections, "connections")) {
+            peers.push((try!(string(entry, "peer")), try!(string(entry, "state"))));
+        }
+        Ok(peers)
+    }
+
+    fn object_counts(&mut self, _wait: Duration) -> Result<ObjectCounts, String> {
+        let stats = try!(self.call_for_json("getObjectStats", vec![]));
+        let count = |key| stats.find(key).and_then(|count| count.as_u64()).ok_or(format!("getObjectStats gave no {} count", key));
+        Ok(ObjectCounts {
+            getpubkeys: try!(count("getpubkey")),
+            pubkeys: try!(count("pubkey")),
+            msgs: try!(count("msg")),
+            broadcasts: try!(count("broadcast")),
+            total: try!(count("total"))
+        })
+    }
+}
+
+// Gives the result, or the API's error message
+fn read_response(response: &str) -> Result<Json, String> {
+    let status = response.lines().next().unwrap_or("");
+    if !status.starts_with("HTTP/1.1 200") && !status.starts_with("HTTP/1.0 200") {
+        return match status.split_whitespace().nth(1) {
+            Some("401") => Err("The API did not accept the username and password".to_string()),
+            _ => Err(format!("The API answered: {}", status))
+        };
+    }
+
+    let body = match response.find("\r\n\r\n") {
+        Some(end) => &response[end + 4..],
+        None => return Err("The API's response was cut short".to_string())
+    };
+    let json = try!(Json::from_str(body).map_err(|_| "The API's response is not JSON".to_string()));
+    if let Some(error) = json.find("error") {
+        return Err(error.find("message").and_then(|message| message.as_string()).unwrap_or("The API gave an error").to_string());
+    }
+    json.find("result").cloned().ok_or("The API's response has no result".to_string())
+}
+
+fn encode(text: &str) -> Json {
+    text.as_bytes().to_base64(STANDARD).to_json()
+}
+
+fn list<'a>(json: &'a Json, key: &str) -> Result<&'a Vec<Json>, String> {
+    json.find(key).and_then(|entries| entries.as_array()).ok_or(format!("The API gave no {} list", key))
+}
+
+fn string(json: &Json, key: &str) -> Result<String, String> {
+    json.find(key).and_then(|value| value.as_string()).map(|value| value.to_string()).ok_or(format!("The API gave no {}", key))
+}
+
+fn decoded(json: &Json, key: &str) -> Result<String, String> {
+    let bytes = try!(try!(string(json, key)).from_base64().map_err(|_| format!("The API's {} is not base64", key)));
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn message_info(json: &Json) -> Result<MessageInfo, String> {
+    Ok(MessageInfo {
+        id: try!(string(json, "msgid")),
+        from: try!(string(json, "fromAddress")),
+        to: try!(string(json, "toAddress")),
+        subject: try!(decoded(json, "subject")),
+        body: try!(decoded(json, "message")),
+        received: try!(string(json, "receivedTime")).parse().unwrap_or(0),
+        read: json.find("read").and_then(|read| read.as_u64()).map_or(false, |read| read != 0)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::Json;
+    use super::read_response;
+
+    #[test]
+    fn test_read_response() {
+        let ok = "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{\"id\":1,\"jsonrpc\":\"2.0\",\"result\":5}";
+        assert_eq!(Ok(Json::U64(5)), read_response(ok));
+
+        let error = "HTTP/1.1 200 OK\r\n\r\n{\"error\":{\"code\":13,\"message\":\"API Error 0013: Could not find your fromAddress\"},\"id\":1}";
+        assert_eq!(Err("API Error 0013: Could not find your fromAddress".to_string()), read_response(error));
+
+        assert_eq!(Err("The API did not accept the username and password".to_string()), read_response("HTTP/1.1 401 Unauthorized\r\n\r\nUnauthorized\n"));
+    }
+}
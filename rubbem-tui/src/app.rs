@@ -0,0 +1,489 @@
+use bm_client::{Address,BMClient,ConnectionState,Event,Folder,MessageQuery,OutboxStatus,PeerAddr,SendOptions,StoredMessage};
+use std::collections::HashMap;
+use std::str::FromStr;
+use termion::event::Key;
+
+// What the terminal UI shows and how keys change it; drawing is left to the view
+
+pub enum Screen {
+    Messages,
+    Reading { id: Vec<u8>, scroll: usize },
+    Composing(Draft),
+    Identities(IdentityPicker)
+}
+
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub enum Field {
+    To,
+    Subject,
+    Body
+}
+
+pub struct Draft {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+    pub field: Field
+}
+
+impl Draft {
+    fn new() -> Draft {
+        Draft {
+            to: String::new(),
+            subject: String::new(),
+            body: String::new(),
+            field: Field::To
+        }
+    }
+
+    fn text(&mut self) -> &mut String {
+        match self.field {
+            Field::To => &mut self.to,
+            Field::Subject => &mut self.subject,
+            Field::Body => &mut self.body
+        }
+    }
+}
+
+// The first entry stands for every identity
+pub struct IdentityPicker {
+    pub entries: Vec<(Option<Address>, String)>,
+    pub selected: usize,
+    pub new_label: Option<String> // being typed in for a new identity
+}
+
+pub struct App {
+    client: BMClient,
+    streams: Vec<u32>,
+    screen: Screen,
+    folder: Folder,
+    identity: Option<Address>,
+    messages: Vec<StoredMessage>,
+    selected: usize,
+    connections: HashMap<PeerAddr, ConnectionState>,
+    objects: usize,
+    pow_percent: Option<u64>,
+    notice: String,
+    quit: bool
+}
+
+impl App {
+    pub fn new(client: BMClient, streams: &[u32]) -> App {
+        let connections = client.connections().into_iter().collect();
+        let objects = client.object_stats().total();
+        let mut app = App {
+            client: client,
+            streams: streams.to_vec(),
+            screen: Screen::Messages,
+            folder: Folder::Inbox,
+            identity: None,
+            messages: vec![],
+            selected: 0,
+            connections: connections,
+            objects: objects,
+            pow_percent: None,
+            notice: String::new(),
+            quit: false
+        };
+        app.reload();
+        app
+    }
+
+    pub fn client_mut(&mut self) -> &mut BMClient {
+        &mut self.client
+    }
+
+    pub fn screen(&self) -> &Screen {
+        &self.screen
+    }
+
+    pub fn folder(&self) -> Folder {
+        self.folder
+    }
+
+    pub fn messages(&self) -> &[StoredMessage] {
+        &self.messages
+    }
+
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    pub fn message(&self, id: &[u8]) -> Option<StoredMessage> {
+        self.client.message(id)
+    }
+
+    pub fn should_quit(&self) -> bool {
+        self.quit
+    }
+
+    pub fn identity_name(&self) -> String {
+        match self.identity {
+            Some(ref address) => self.name_of(address),
+            None => "All identities".to_string()
+        }
+    }
+
+    // Where new messages are sent from: the identity picked, or else the first we have
+    pub fn sender(&self) -> Option<Address> {
+        self.identity.clone().or_else(|| self.all_identities().into_iter().next().map(|(address, _)| address))
+    }
+
+    pub fn status_line(&self) -> String {
+        let established = self.connections.values().filter(|state| match state {
+            &&ConnectionState::Established(_) => true,
+            _ => false
+        }).count();
+        let streams: Vec<String> = self.streams.iter().map(|stream| stream.to_string()).collect();
+
+        let mut parts = vec![
+            format!("Peers: {}/{}", established, self.connections.len()),
+            format!("Streams: {}", streams.join(",")),
+            format!("Objects: {}", self.objects)
+        ];
+        if let Some(percent) = self.pow_percent {
+            parts.push(format!("PoW: {}%", percent));
+        }
+        if !self.notice.is_empty() {
+            parts.push(self.notice.clone());
+        }
+        parts.join(" | ")
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        match event {
+            &Event::ConnectionStateChanged(peer, ConnectionState::Stale) | &Event::ConnectionStateChanged(peer, ConnectionState::Error) => {
+                self.connections.remove(&peer);
+            },
+            &Event::ConnectionStateChanged(peer, state) => {
+                self.connections.insert(peer, state);
+            },
+            &Event::ObjectCount(objects) => self.objects = objects,
+            &Event::PowProgress { trials, expected_trials } if expected_trials > 0 => {
+                self.pow_percent = Some((trials.saturating_mul(100) / expected_trials).min(100));
+            },
+            &Event::InboxMessage { ref msgid } => {
+                if let Some(message) = self.client.message(msgid) {
+                    self.notice = format!("New message from {}", self.name_of(message.from()));
+                }
+                self.reload();
+            },
+            &Event::OutboxStatusChanged { status, .. } => {
+                if status != OutboxStatus::DoingPow {
+                    self.pow_percent = None;
+                }
+                match status {
+                    OutboxStatus::Sent => self.notice = "Message sent".to_string(),
+                    OutboxStatus::Failed => self.notice = "A message could not be sent".to_string(),
+                    _ => {}
+                }
+                self.reload();
+            },
+            &Event::AckReceived { .. } => {
+                self.notice = "Message acknowledged".to_string();
+                self.reload();
+            },
+            &Event::Warning(ref warning) => self.notice = warning.clone(),
+            _ => {}
+        }
+    }
+
+    pub fn handle_key(&mut self, key: Key) {
+        let screen = ::std::mem::replace(&mut self.screen, Screen::Messages);
+        self.screen = match screen {
+            Screen::Messages => self.messages_key(key),
+            Screen::Reading { id, scroll } => self.reading_key(key, id, scroll),
+            Screen::Composing(draft) => self.composing_key(key, draft),
+            Screen::Identities(picker) => self.identities_key(key, picker)
+        };
+    }
+
+    fn messages_key(&mut self, key: Key) -> Screen {
+        match key {
+            Key::Char('q') => self.quit = true,
+            Key::Up | Key::Char('k') => self.selected = self.selected.saturating_sub(1),
+            Key::Down | Key::Char('j') => if self.selected + 1 < self.messages.len() {
+                self.selected += 1;
+            },
+            Key::Char('\t') => {
+                self.folder = if self.folder == Folder::Inbox { Folder::Sent } else { Folder::Inbox };
+                self.selected = 0;
+                self.reload();
+            },
+            Key::Char('\n') => if let Some(id) = self.messages.get(self.selected).map(|message| message.id().to_vec()) {
+                return self.open(id);
+            },
+            Key::Char('d') => if let Some(id) = self.messages.get(self.selected).map(|message| message.id().to_vec()) {
+                self.trash(&id);
+            },
+            Key::Char('c') => return Screen::Composing(Draft::new()),
+            Key::Char('i') => return Screen::Identities(self.picker()),
+            _ => {}
+        }
+        Screen::Messages
+    }
+
+    fn reading_key(&mut self, key: Key, id: Vec<u8>, scroll: usize) -> Screen {
+        match key {
+            Key::Char('q') | Key::Esc => Screen::Messages,
+            Key::Up | Key::Char('k') => Screen::Reading { id: id, scroll: scroll.saturating_sub(1) },
+            Key::Down | Key::Char('j') => Screen::Reading { id: id, scroll: scroll + 1 },
+            Key::Char('d') => {
+                self.trash(&id);
+                Screen::Messages
+            },
+            Key::Char('r') => match self.client.message(&id) {
+                Some(ref message) if !message.is_ours() => Screen::Composing(reply_to(message)),
+                _ => Screen::Reading { id: id, scroll: scroll }
+            },
+            _ => Screen::Reading { id: id, scroll: scroll }
+        }
+    }
+
+    fn composing_key(&mut self, key: Key, mut draft: Draft) -> Screen {
+        match key {
+            Key::Esc => {
+                self.notice = "Message discarded".to_string();
+                return Screen::Messages;
+            },
+            Key::Ctrl('s') => return match self.send(&draft) {
+                true => Screen::Messages,
+                false => Screen::Composing(draft)
+            },
+            Key::Char('\t') => draft.field = match draft.field {
+                Field::To => Field::Subject,
+                Field::Subject => Field::Body,
+                Field::Body => Field::To
+            },
+            Key::BackTab => draft.field = match draft.field {
+                Field::To => Field::Body,
+                Field::Subject => Field::To,
+                Field::Body => Field::Subject
+            },
+            Key::Char('\n') if draft.field != Field::Body => draft.field = if draft.field == Field::To { Field::Subject } else { Field::Body },
+            Key::Char(c) => draft.text().push(c),
+            Key::Backspace => {
+                draft.text().pop();
+            },
+            _ => {}
+        }
+        Screen::Composing(draft)
+    }
+
+    fn identities_key(&mut self, key: Key, mut picker: IdentityPicker) -> Screen {
+        if let Some(mut label) = picker.new_label.take() {
+            match key {
+                Key::Esc => {},
+                Key::Char('\n') => {
+                    let address = self.client.create_identity(&label);
+                    self.notice = format!("Created {}", address);
+                    let mut picker = self.picker();
+                    picker.selected = picker.entries.iter().position(|entry| entry.0.as_ref() == Some(&address)).unwrap_or(0);
+                    return Screen::Identities(picker);
+                },
+                Key::Char(c) => {
+                    label.push(c);
+                    picker.new_label = Some(label);
+                },
+                Key::Backspace => {
+                    label.pop();
+                    picker.new_label = Some(label);
+                },
+                _ => picker.new_label = Some(label)
+            }
+            return Screen::Identities(picker);
+        }
+
+        match key {
+            Key::Char('q') | Key::Esc => return Screen::Messages,
+            Key::Up | Key::Char('k') => picker.selected = picker.selected.saturating_sub(1),
+            Key::Down | Key::Char('j') => if picker.selected + 1 < picker.entries.len() {
+                picker.selected += 1;
+            },
+            Key::Char('n') => picker.new_label = Some(String::new()),
+            Key::Char('\n') => {
+                self.identity = picker.entries[picker.selected].0.clone();
+                self.selected = 0;
+                self.reload();
+                return Screen::Messages;
+            },
+            _ => {}
+        }
+        Screen::Identities(picker)
+    }
+
+    fn open(&mut self, id: Vec<u8>) -> Screen {
+        self.client.mark_read(&id, true);
+        self.reload();
+        Screen::Reading { id: id, scroll: 0 }
+    }
+
+    fn trash(&mut self, id: &[u8]) {
+        self.client.trash_message(id);
+        self.notice = "Moved to the trash".to_string();
+        self.reload();
+    }
+
+    fn send(&mut self, draft: &Draft) -> bool {
+        let from = match self.sender() {
+            Some(from) => from,
+            None => {
+                self.notice = "Create an identity first (i, then n)".to_string();
+                return false;
+            }
+        };
+        let to = match Address::from_str(draft.to.trim()) {
+            Ok(to) => to,
+            Err(err) => {
+                self.notice = format!("Bad recipient: {}", err);
+                return false;
+            }
+        };
+
+        match self.client.send_message(&from, &to, &draft.subject, &draft.body, SendOptions::new()) {
+            Ok(_) => {
+                self.notice = "Message queued".to_string();
+                self.reload();
+                true
+            },
+            Err(err) => {
+                self.notice = err.to_string();
+                false
+            }
+        }
+    }
+
+    // The sent list also has the messages still waiting to go out
+    fn reload(&mut self) {
+        let query = match self.identity {
+            Some(ref address) => MessageQuery::new().identity(address),
+            None => MessageQuery::new()
+        };
+        let folder = self.folder;
+        self.messages = self.client.messages(&query).into_iter().filter(|message| match (folder, message.folder()) {
+            (Folder::Inbox, Folder::Inbox) | (Folder::Sent, Folder::Sent) | (Folder::Sent, Folder::Outbox) => true,
+            _ => false
+        }).collect();
+        if self.selected >= self.messages.len() {
+            self.selected = self.messages.len().saturating_sub(1);
+        }
+    }
+
+    fn picker(&self) -> IdentityPicker {
+        let mut entries = vec![ (None, "All identities".to_string()) ];
+        entries.extend(self.all_identities().into_iter().map(|(address, label)| (Some(address), label)));
+        let selected = entries.iter().position(|entry| entry.0 == self.identity).unwrap_or(0);
+        IdentityPicker {
+            entries: entries,
+            selected: selected,
+            new_label: None
+        }
+    }
+
+    fn all_identities(&self) -> Vec<(Address, String)> {
+        self.client.identities().iter().chain(self.client.chans().iter()).map(|identity| {
+            let label = match identity.label() {
+                "" => identity.address().to_string(),
+                label => format!("{} <{}>", label, identity.address())
+            };
+            (identity.address().clone(), label)
+        }).collect()
+    }
+
+    // Our own label for an address, when we have one
+    pub fn name_of(&self, address: &Address) -> String {
+        let ours = self.client.identities().into_iter().chain(self.client.chans().into_iter())
+            .find(|identity| identity.address() == address)
+            .map(|identity| identity.label().to_string());
+        let label = ours.or_else(|| self.client.contact(address).map(|contact| contact.label().to_string()));
+        match label {
+            Some(ref label) if !label.is_empty() => label.clone(),
+            _ => address.to_string()
+        }
+    }
+}
+
+fn reply_to(message: &StoredMessage) -> Draft {
+    let subject = match message.subject().starts_with("Re: ") {
+        true => message.subject().to_string(),
+        false => format!("Re: {}", message.subject())
+    };
+    let quoted: Vec<String> = message.body().lines().map(|line| format!("> {}", line)).collect();
+    Draft {
+        to: message.from().to_string(),
+        subject: subject,
+        body: format!("{}\n\n", quoted.join("\n")),
+        field: Field::Body
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bm_client::{BMClient,ConnectionState,Event,Folder,PeerAddr};
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::time::Instant;
+    use super::{App,Screen};
+    use termion::event::Key;
+
+    fn type_text(app: &mut App, text: &str) {
+        for c in text.chars() {
+            app.handle_key(Key::Char(c));
+        }
+    }
+
+    #[test]
+    fn test_create_identity_and_send() {
+        let mut app = App::new(BMClient::new().unwrap(), &[ 1 ]);
+        app.handle_key(Key::Char('i'));
+        app.handle_key(Key::Char('n'));
+        type_text(&mut app, "Me\n\n");
+        assert_eq!("Me", app.identity_name());
+        let to = app.sender().unwrap().to_string();
+
+        app.handle_key(Key::Char('c'));
+        type_text(&mut app, &format!("{}\nHi\nHello", to));
+        match app.screen() {
+            &Screen::Composing(ref draft) => assert_eq!("Hello", draft.body),
+            _ => panic!("Expected to be composing")
+        }
+        app.handle_key(Key::Ctrl('s'));
+        assert!(app.status_line().ends_with("| Message queued"));
+
+        app.handle_key(Key::Char('\t'));
+        assert_eq!(Folder::Sent, app.folder());
+        assert_eq!(1, app.messages().len());
+        assert_eq!("Hi", app.messages()[0].subject());
+    }
+
+    #[test]
+    fn test_bad_recipient_keeps_the_draft() {
+        let mut app = App::new(BMClient::new().unwrap(), &[ 1 ]);
+        app.client_mut().create_identity("Me");
+        app.handle_key(Key::Char('c'));
+        type_text(&mut app, "BM-nonsense");
+        app.handle_key(Key::Ctrl('s'));
+        assert!(app.status_line().contains("| Bad recipient"));
+        match app.screen() {
+            &Screen::Composing(ref draft) => assert_eq!("BM-nonsense", draft.to),
+            _ => panic!("Expected to still be composing")
+        }
+    }
+
+    #[test]
+    fn test_status_line() {
+        let mut app = App::new(BMClient::new().unwrap(), &[ 1, 2 ]);
+        let (first, second) = (PeerAddr::from(SocketAddr::from_str("127.0.0.1:8444").unwrap()), PeerAddr::from(SocketAddr::from_str("127.0.0.2:8444").unwrap()));
+        app.handle_event(&Event::ConnectionStateChanged(first, ConnectionState::Established(Instant::now())));
+        app.handle_event(&Event::ConnectionStateChanged(second, ConnectionState::Fresh(Instant::now())));
+        app.handle_event(&Event::ObjectCount(7));
+        app.handle_event(&Event::PowProgress { trials: 50, expected_trials: 200 });
+        assert_eq!("Peers: 1/2 | Streams: 1,2 | Objects: 7 | PoW: 25%", app.status_line());
+
+        app.handle_event(&Event::ConnectionStateChanged(second, ConnectionState::Error));
+        assert_eq!("Peers: 1/1 | Streams: 1,2 | Objects: 7 | PoW: 25%", app.status_line());
+
+        app.handle_event(&Event::Warning("Could not save messages: disk full".to_string()));
+        assert!(app.status_line().ends_with("| Could not save messages: disk full"));
+    }
+}
@@ -0,0 +1,171 @@
+extern crate bm_client;
+extern crate termion;
+
+mod app;
+mod view;
+
+use app::App;
+use bm_client::{BMClient,Config,ConfigBuilder,Event};
+use std::env;
+use std::io::{self,Write};
+use std::process::exit;
+use std::sync::mpsc::{RecvTimeoutError,Sender,channel};
+use std::thread;
+use std::time::Duration;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use termion::{clear,cursor,style,terminal_size};
+
+// Runs the client with a UI in the terminal, for machines without GTK
+
+// Also how often a resized terminal is noticed
+const REDRAW_INTERVAL_MILLIS: u64 = 500;
+
+const USAGE: &'static str = "Usage: rubbem-tui [--config FILE] [--data-dir DIR]";
+
+struct Options {
+    config_file: Option<String>,
+    data_dir: Option<String>
+}
+
+enum Input {
+    Key(Key),
+    Event(Event)
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            exit(2);
+        }
+    };
+
+    if let Err(message) = run(&options) {
+        eprintln!("{}", message);
+        exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        config_file: None,
+        data_dir: None
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let target = match arg.as_str() {
+            "--config" => &mut options.config_file,
+            "--data-dir" => &mut options.data_dir,
+            "--help" | "-h" => return Err("Runs Rubbem in the terminal.".to_string()),
+            _ => return Err(format!("Unknown argument {}", arg))
+        };
+        match args.next() {
+            Some(value) => *target = Some(value),
+            None => return Err(format!("{} needs a value", arg))
+        }
+    }
+
+    Ok(options)
+}
+
+fn load_config(options: &Options) -> Result<Config, String> {
+    let mut builder = match options.config_file {
+        Some(ref path) => try!(ConfigBuilder::from_file(path).map_err(|err| err.to_string())),
+        None => Config::builder()
+    };
+    if let Some(ref data_dir) = options.data_dir {
+        builder = builder.data_dir(data_dir);
+    }
+    builder.build().map_err(|err| err.to_string())
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let config = try!(load_config(options));
+    let streams = config.streams().to_vec();
+    let mut client = try!(BMClient::with_config(config).map_err(|err| format!("Cannot start: {}", err)));
+
+    let (sender, receiver) = channel();
+    forward_events(client.subscribe(), sender.clone());
+    forward_keys(sender);
+    try!(client.start().map_err(|err| format!("Cannot start: {}", err)));
+    let mut app = App::new(client, &streams);
+
+    let result = {
+        let terminal = try!(io::stdout().into_raw_mode().map_err(|err| format!("Cannot use the terminal: {}", err)));
+        let mut screen = AlternateScreen::from(terminal);
+        let mut size = (0, 0);
+        let result = loop {
+            if let Err(err) = draw(&app, &mut screen, &mut size) {
+                break Err(format!("Cannot draw: {}", err));
+            }
+
+            // Everything waiting is taken in before drawing again, so bursts of events draw once
+            let mut input = match receiver.recv_timeout(Duration::from_millis(REDRAW_INTERVAL_MILLIS)) {
+                Ok(input) => Some(input),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break Ok(())
+            };
+            while let Some(next) = input {
+                match next {
+                    Input::Key(key) => app.handle_key(key),
+                    Input::Event(event) => app.handle_event(&event)
+                }
+                input = receiver.try_recv().ok();
+            }
+            if app.should_quit() {
+                break Ok(());
+            }
+        };
+        let _ = write!(screen, "{}", cursor::Show);
+        result
+    };
+
+    app.client_mut().stop();
+    result
+}
+
+fn forward_events(events: ::std::sync::mpsc::Receiver<Event>, sender: Sender<Input>) {
+    thread::spawn(move || for event in events.iter() {
+        if sender.send(Input::Event(event)).is_err() {
+            break;
+        }
+    });
+}
+
+fn forward_keys(sender: Sender<Input>) {
+    thread::spawn(move || for key in io::stdin().keys() {
+        match key {
+            Ok(key) => if sender.send(Input::Key(key)).is_err() {
+                break;
+            },
+            Err(_) => break
+        }
+    });
+}
+
+// Every line is padded to the full width, so only a resize needs the screen cleared
+fn draw<W: Write>(app: &App, screen: &mut W, size: &mut (u16, u16)) -> io::Result<()> {
+    let new_size = try!(terminal_size());
+    if new_size != *size {
+        *size = new_size;
+        try!(write!(screen, "{}", clear::All));
+    }
+
+    let (width, height) = (size.0 as usize, size.1 as usize);
+    let lines = view::render(app, width, height);
+    try!(write!(screen, "{}", cursor::Hide));
+    for (row, line) in lines.iter().enumerate() {
+        let emphasis = row == 0 || row + 1 == lines.len();
+        if emphasis {
+            try!(write!(screen, "{}{}{}{}", cursor::Goto(1, row as u16 + 1), style::Invert, line, style::Reset));
+        } else {
+            try!(write!(screen, "{}{}", cursor::Goto(1, row as u16 + 1), line));
+        }
+    }
+    screen.flush()
+}
@@ -0,0 +1,180 @@
+use app::{App,Draft,Field,IdentityPicker,Screen};
+use bm_client::{Folder,StoredMessage};
+use std::time::SystemTime;
+
+// Lays the screen out as plain lines: a title, the content and the status bar last
+
+pub fn render(app: &App, width: usize, height: usize) -> Vec<String> {
+    let rows = height.saturating_sub(2);
+    let (title, content) = match app.screen() {
+        &Screen::Messages => message_list(app, rows),
+        &Screen::Reading { ref id, scroll } => reading(app, id, scroll, width, rows),
+        &Screen::Composing(ref draft) => composing(app, draft, width),
+        &Screen::Identities(ref picker) => identities(picker, rows)
+    };
+
+    let mut lines = vec![ fit(&title, width) ];
+    lines.extend(content.iter().take(rows).map(|line| fit(line, width)));
+    while lines.len() < height.saturating_sub(1) {
+        lines.push(fit("", width));
+    }
+    lines.push(fit(&app.status_line(), width));
+    lines.truncate(height);
+    lines
+}
+
+fn message_list(app: &App, rows: usize) -> (String, Vec<String>) {
+    let (folder, help) = match app.folder() {
+        Folder::Sent => ("Sent", "Tab inbox"),
+        _ => ("Inbox", "Tab sent")
+    };
+    let title = format!("{} - {}  [{}, Enter read, c compose, d delete, i identities, q quit]", folder, app.identity_name(), help);
+
+    let messages = app.messages();
+    if messages.is_empty() {
+        return (title, vec![ "  No messages".to_string() ]);
+    }
+    // Scrolled so the selected message stays in view
+    let first = (app.selected() + 1).saturating_sub(rows);
+    let now = SystemTime::now();
+    let lines = messages.iter().enumerate().skip(first).take(rows).map(|(index, message)| {
+        let marker = if index == app.selected() { ">" } else { " " };
+        let unread = if message.is_read() { " " } else { "*" };
+        format!("{}{} {:>4}  {:<24}  {}", marker, unread, format_age(now, message), fit(&correspondent(app, message), 24), message.subject())
+    }).collect();
+    (title, lines)
+}
+
+fn reading(app: &App, id: &[u8], scroll: usize, width: usize, rows: usize) -> (String, Vec<String>) {
+    let title = "Message  [Up/Down scroll, r reply, d delete, Esc back]".to_string();
+    let message = match app.message(id) {
+        Some(message) => message,
+        None => return (title, vec![ "  This message is gone".to_string() ])
+    };
+
+    let mut lines = vec![
+        format!("From:    {}", app.name_of(message.from())),
+        format!("To:      {}", message.to().map(|to| app.name_of(to)).unwrap_or("[broadcast]".to_string())),
+        format!("Subject: {}", message.subject())
+    ];
+    if let Some(status) = message.status() {
+        lines.push(format!("Status:  {:?}", status));
+    }
+    lines.push(String::new());
+    lines.extend(wrap(message.body(), width));
+
+    let last_scroll = lines.len().saturating_sub(rows);
+    (title, lines.into_iter().skip(scroll.min(last_scroll)).collect())
+}
+
+fn composing(app: &App, draft: &Draft, width: usize) -> (String, Vec<String>) {
+    let title = "New message  [Tab next field, Ctrl-S send, Esc discard]".to_string();
+    let cursor = |field| if draft.field == field { "_" } else { "" };
+    let from = app.sender().map(|from| app.name_of(&from)).unwrap_or("(no identity yet)".to_string());
+
+    let mut lines = vec![
+        format!("From:    {}", from),
+        format!("To:      {}{}", draft.to, cursor(Field::To)),
+        format!("Subject: {}{}", draft.subject, cursor(Field::Subject)),
+        "-".repeat(width)
+    ];
+    lines.extend(wrap(&format!("{}{}", draft.body, cursor(Field::Body)), width));
+    (title, lines)
+}
+
+fn identities(picker: &IdentityPicker, rows: usize) -> (String, Vec<String>) {
+    let title = "Identities  [Enter pick, n new, Esc back]".to_string();
+    let mut lines: Vec<String> = picker.entries.iter().enumerate().map(|(index, &(_, ref label))| {
+        let marker = if index == picker.selected { ">" } else { " " };
+        format!("{} {}", marker, label)
+    }).collect();
+
+    if let Some(ref label) = picker.new_label {
+        // Kept on screen however many identities there are
+        lines.truncate(rows.saturating_sub(2));
+        lines.push(String::new());
+        lines.push(format!("Label for the new identity: {}_", label));
+    }
+    (title, lines)
+}
+
+fn correspondent(app: &App, message: &StoredMessage) -> String {
+    match (message.is_ours(), message.to()) {
+        (false, _) => app.name_of(message.from()),
+        (true, Some(to)) => app.name_of(to),
+        (true, None) => "[broadcast]".to_string()
+    }
+}
+
+fn format_age(now: SystemTime, message: &StoredMessage) -> String {
+    let secs = now.duration_since(message.timestamp()).map(|age| age.as_secs()).unwrap_or(0);
+    if secs < 60 {
+        "now".to_string()
+    } else if secs < 3600 {
+        format!("{}m", secs / 60)
+    } else if secs < 86400 {
+        format!("{}h", secs / 3600)
+    } else {
+        format!("{}d", secs / 86400)
+    }
+}
+
+// Breaks on spaces where it can, and mid-word where it must
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = vec![];
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        for word in paragraph.split(' ') {
+            let (line_length, word_length) = (line.chars().count(), word.chars().count());
+            if line_length > 0 && line_length + 1 + word_length > width {
+                lines.push(line);
+                line = String::new();
+            } else if line_length > 0 {
+                line.push(' ');
+            }
+            let mut rest: Vec<char> = word.chars().collect();
+            while line.chars().count() + rest.len() > width {
+                let take = width - line.chars().count();
+                line.extend(rest.drain(..take));
+                lines.push(line);
+                line = String::new();
+            }
+            line.extend(rest);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+// Cut or padded to exactly the width
+fn fit(line: &str, width: usize) -> String {
+    let mut fitted: String = line.chars().filter(|c| !c.is_control()).take(width).collect();
+    let length = fitted.chars().count();
+    fitted.extend((length..width).map(|_| ' '));
+    fitted
+}
+
+#[cfg(test)]
+mod tests {
+    use app::App;
+    use bm_client::BMClient;
+    use super::{render,wrap};
+
+    #[test]
+    fn test_wrap() {
+        assert_eq!(vec![ "one two", "three" ], wrap("one two three", 8));
+        assert_eq!(vec![ "abcd", "efgh", "ij" ], wrap("abcdefghij", 4));
+        assert_eq!(vec![ "a", "", "b" ], wrap("a\n\nb", 10));
+    }
+
+    #[test]
+    fn test_render_fills_the_screen() {
+        let app = App::new(BMClient::new().unwrap(), &[ 1 ]);
+        let lines = render(&app, 40, 10);
+        assert_eq!(10, lines.len());
+        assert!(lines.iter().all(|line| line.chars().count() == 40));
+        assert!(lines[0].starts_with("Inbox - All identities"));
+        assert!(lines[9].starts_with("Peers: 0/0 | Streams: 1"));
+    }
+}
@@ -0,0 +1,201 @@
+use bm_client::{Address,Event,Identity,OutboxStatus,SendOptions};
+use gtk;
+use gtk::prelude::*;
+use std::cell::{Cell,RefCell};
+use std::rc::Rc;
+use std::str::FromStr;
+use window::{SharedClient,form,scrolled,show_error};
+
+// Writes one message, and stays open with the proof of work's progress until it is sent
+
+pub struct Compose {
+    bm_client: SharedClient,
+    parent: gtk::Window,
+    dialog: gtk::Dialog,
+    from: gtk::ComboBoxText,
+    to: gtk::Entry,
+    subject: gtk::Entry,
+    body: gtk::TextView,
+    progress: gtk::ProgressBar,
+    ackdata: RefCell<Option<Vec<u8>>>,
+    doing_pow: Cell<bool>,
+    closed: Cell<bool>
+}
+
+impl Compose {
+    pub fn new(bm_client: SharedClient, parent: &gtk::Window) -> Rc<Compose> {
+        let dialog = gtk::Dialog::with_buttons(Some("New message"), Some(parent), gtk::DialogFlags::DESTROY_WITH_PARENT,
+            &[ ("Close", gtk::ResponseType::Close), ("Send", gtk::ResponseType::Accept) ]);
+        dialog.set_default_size(500, 400);
+
+        let from = gtk::ComboBoxText::new();
+        {
+            let bm_client = bm_client.borrow();
+            for identity in bm_client.identities().iter().chain(bm_client.chans().iter()) {
+                from.append(Some(&identity.address().to_string()), &sender_text(identity));
+            }
+        }
+        from.set_active(Some(0));
+        let to = gtk::Entry::new();
+        let subject = gtk::Entry::new();
+        let body = gtk::TextView::new();
+        body.set_wrap_mode(gtk::WrapMode::Word);
+        let progress = gtk::ProgressBar::new();
+        progress.set_show_text(true);
+        progress.set_text(Some(""));
+
+        let content = dialog.get_content_area();
+        content.pack_start(&form(&[ ("From", from.upcast_ref()), ("To", to.upcast_ref()), ("Subject", subject.upcast_ref()) ]), false, false, 0);
+        content.pack_start(&scrolled(&body), true, true, 0);
+        content.pack_start(&progress, false, false, 6);
+
+        let compose = Rc::new(Compose {
+            bm_client: bm_client,
+            parent: parent.clone(),
+            dialog: dialog,
+            from: from,
+            to: to,
+            subject: subject,
+            body: body,
+            progress: progress,
+            ackdata: RefCell::new(None),
+            doing_pow: Cell::new(false),
+            closed: Cell::new(false)
+        });
+
+        {
+            let compose_ref = compose.clone();
+            compose.dialog.connect_response(move |_, response| match response {
+                gtk::ResponseType::Accept => compose_ref.send(),
+                _ => compose_ref.close()
+            });
+        }
+        compose
+    }
+
+    pub fn show(&self) {
+        self.dialog.show_all();
+    }
+
+    // false once the dialog is done with
+    pub fn handle(&self, event: &Event) -> bool {
+        match event {
+            &Event::OutboxStatusChanged { ref ackdata, status } if self.is_ours(ackdata) => {
+                self.doing_pow.set(status == OutboxStatus::DoingPow);
+                match status {
+                    OutboxStatus::Sent | OutboxStatus::AckReceived => self.close(),
+                    status => if let Some(text) = progress_text(status) {
+                        self.progress.set_text(Some(text));
+                    }
+                }
+            },
+            // The progress doesn't say which message it is for, so it's only shown while ours is being worked on
+            &Event::PowProgress { trials, expected_trials } if self.doing_pow.get() => {
+                if let Some((fraction, text)) = pow_progress(trials, expected_trials) {
+                    self.progress.set_fraction(fraction);
+                    self.progress.set_text(Some(&text));
+                }
+            },
+            _ => {}
+        }
+        !self.closed.get()
+    }
+
+    fn is_ours(&self, ackdata: &[u8]) -> bool {
+        self.ackdata.borrow().as_ref().map_or(false, |ours| &ours[..] == ackdata)
+    }
+
+    fn send(&self) {
+        if self.ackdata.borrow().is_some() {
+            return;
+        }
+
+        let from = match self.from.get_active_id().and_then(|from| Address::from_str(&from).ok()) {
+            Some(from) => from,
+            None => return show_error(&self.parent, "Pick one of your identities to send from")
+        };
+        let to = match Address::from_str(self.to.get_text().trim()) {
+            Ok(to) => to,
+            Err(err) => return show_error(&self.parent, &format!("The recipient's address is not valid: {}", err))
+        };
+        let body = match self.body.get_buffer() {
+            Some(buffer) => buffer.get_text(&buffer.get_start_iter(), &buffer.get_end_iter(), false).map(|body| body.to_string()).unwrap_or(String::new()),
+            None => String::new()
+        };
+
+        let result = self.bm_client.borrow_mut().send_message(&from, &to, &self.subject.get_text(), &body, SendOptions::new());
+        match result {
+            Ok(ackdata) => {
+                *self.ackdata.borrow_mut() = Some(ackdata);
+                for widget in &[ self.from.upcast_ref::<gtk::Widget>(), self.to.upcast_ref(), self.subject.upcast_ref(), self.body.upcast_ref() ] {
+                    widget.set_sensitive(false);
+                }
+                self.dialog.set_response_sensitive(gtk::ResponseType::Accept, false);
+                self.progress.set_text(Some("Queued"));
+            },
+            Err(err) => show_error(&self.parent, &err.to_string())
+        }
+    }
+
+    // A message already queued carries on in the outbox
+    fn close(&self) {
+        if !self.closed.get() {
+            self.closed.set(true);
+            self.dialog.close();
+        }
+    }
+}
+
+fn sender_text(identity: &Identity) -> String {
+    match identity.label() {
+        "" => identity.address().to_string(),
+        label => format!("{} <{}>", label, identity.address())
+    }
+}
+
+// Shown under the message while it waits; None leaves what's there
+fn progress_text(status: OutboxStatus) -> Option<&'static str> {
+    match status {
+        OutboxStatus::AwaitingPubKey => Some("Waiting for the recipient's pubkey"),
+        OutboxStatus::DoingPow => Some("Doing the proof of work"),
+        OutboxStatus::Failed | OutboxStatus::GaveUp => Some("Could not be sent"),
+        OutboxStatus::Queued | OutboxStatus::Sent | OutboxStatus::AckReceived => None
+    }
+}
+
+// How much of the work is done, and how that reads; nothing when there's no estimate
+fn pow_progress(trials: u64, expected_trials: u64) -> Option<(f64, String)> {
+    if expected_trials == 0 {
+        return None;
+    }
+    let fraction = (trials as f64 / expected_trials as f64).min(1.0);
+    Some((fraction, format!("Doing the proof of work: {}%", (fraction * 100.0) as u32)))
+}
+
+#[cfg(test)]
+mod tests {
+    use bm_client::{Identity,OutboxStatus};
+    use super::{pow_progress,progress_text,sender_text};
+
+    #[test]
+    fn test_sender_text() {
+        let identity = Identity::random("Me", 1);
+        assert_eq!(format!("Me <{}>", identity.address()), sender_text(&identity));
+        let unlabelled = Identity::random("", 1);
+        assert_eq!(unlabelled.address().to_string(), sender_text(&unlabelled));
+    }
+
+    #[test]
+    fn test_status_text() {
+        assert_eq!(Some("Doing the proof of work"), progress_text(OutboxStatus::DoingPow));
+        assert_eq!(Some("Could not be sent"), progress_text(OutboxStatus::GaveUp));
+        assert_eq!(None, progress_text(OutboxStatus::Queued));
+    }
+
+    #[test]
+    fn test_pow_progress() {
+        assert_eq!(Some((0.25, "Doing the proof of work: 25%".to_string())), pow_progress(50, 200));
+        assert_eq!(Some((1.0, "Doing the proof of work: 100%".to_string())), pow_progress(300, 200));
+        assert_eq!(None, pow_progress(50, 0));
+    }
+}
@@ -0,0 +1,136 @@
+use bm_client::{Address,Identity};
+use glib;
+use gtk;
+use gtk::prelude::*;
+use std::rc::Rc;
+use std::str::FromStr;
+use window::{SharedClient,form,get_string,scrolled,show_error,text_column};
+
+// The sidebar of our identities and chans; picking one narrows the messages down to it
+
+const LABEL_COLUMN: i32 = 0;
+const ADDRESS_COLUMN: i32 = 1;
+
+pub struct IdentityList {
+    bm_client: SharedClient,
+    parent: gtk::Window,
+    widget: gtk::Box,
+    store: gtk::ListStore,
+    view: gtk::TreeView
+}
+
+impl IdentityList {
+    pub fn new(bm_client: SharedClient, parent: &gtk::Window) -> Rc<IdentityList> {
+        let store = gtk::ListStore::new(&[ glib::Type::String, glib::Type::String ]);
+        let view = gtk::TreeView::with_model(&store);
+        text_column(&view, "Identities", LABEL_COLUMN, None);
+        view.set_tooltip_column(ADDRESS_COLUMN);
+
+        let new_button = gtk::Button::with_label("New identity");
+        let widget = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        widget.set_size_request(220, -1);
+        widget.pack_start(&scrolled(&view), true, true, 0);
+        widget.pack_start(&new_button, false, false, 0);
+
+        let identity_list = Rc::new(IdentityList {
+            bm_client: bm_client,
+            parent: parent.clone(),
+            widget: widget,
+            store: store,
+            view: view
+        });
+        identity_list.refresh();
+
+        {
+            let identity_list = identity_list.clone();
+            new_button.connect_clicked(move |_| identity_list.create());
+        }
+        identity_list
+    }
+
+    pub fn widget(&self) -> &gtk::Box {
+        &self.widget
+    }
+
+    // The first row stands for all of them
+    pub fn refresh(&self) {
+        self.store.clear();
+        self.store.insert_with_values(None, &[ LABEL_COLUMN as u32, ADDRESS_COLUMN as u32 ], &[ &"All identities", &"" ]);
+
+        let bm_client = self.bm_client.borrow();
+        for identity in bm_client.identities().iter().chain(bm_client.chans().iter()) {
+            self.store.insert_with_values(None, &[ LABEL_COLUMN as u32, ADDRESS_COLUMN as u32 ], &[ &row_label(identity), &identity.address().to_string() ]);
+        }
+    }
+
+    pub fn connect_selected<F: Fn(Option<Address>) + 'static>(&self, f: F) {
+        self.view.get_selection().connect_changed(move |selection| {
+            if let Some((model, iter)) = selection.get_selected() {
+                f(Address::from_str(&get_string(&model, &iter, ADDRESS_COLUMN)).ok());
+            }
+        });
+    }
+
+    // A deterministic identity comes back from the same passphrase on any machine
+    fn create(&self) {
+        let dialog = gtk::Dialog::with_buttons(Some("New identity"), Some(&self.parent), gtk::DialogFlags::MODAL,
+            &[ ("Cancel", gtk::ResponseType::Cancel), ("Create", gtk::ResponseType::Accept) ]);
+        let label = gtk::Entry::new();
+        let random = gtk::RadioButton::with_label("Random");
+        let deterministic = gtk::RadioButton::with_label_from_widget(&random, "From a passphrase");
+        let passphrase = gtk::Entry::new();
+        passphrase.set_visibility(false);
+        passphrase.set_sensitive(false);
+        {
+            let passphrase = passphrase.clone();
+            deterministic.connect_toggled(move |deterministic| passphrase.set_sensitive(deterministic.get_active()));
+        }
+
+        let kind = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        kind.pack_start(&random, false, false, 0);
+        kind.pack_start(&deterministic, false, false, 0);
+        dialog.get_content_area().add(&form(&[
+            ("Label", label.upcast_ref()),
+            ("Address", kind.upcast_ref()),
+            ("Passphrase", passphrase.upcast_ref())
+        ]));
+        dialog.show_all();
+
+        while dialog.run() == gtk::ResponseType::Accept {
+            let result = match deterministic.get_active() {
+                true => self.bm_client.borrow_mut().create_deterministic_identity(&label.get_text(), &passphrase.get_text()),
+                false => Ok(self.bm_client.borrow_mut().create_identity(&label.get_text()))
+            };
+            match result {
+                Ok(_) => {
+                    self.refresh();
+                    break;
+                },
+                Err(err) => show_error(&self.parent, &err.to_string())
+            }
+        }
+        dialog.close();
+    }
+}
+
+fn row_label(identity: &Identity) -> String {
+    match (identity.label(), identity.is_chan()) {
+        ("", _) => identity.address().to_string(),
+        (label, true) => format!("{} (chan)", label),
+        (label, false) => label.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bm_client::Identity;
+    use super::row_label;
+
+    #[test]
+    fn test_row_label() {
+        assert_eq!("Me", row_label(&Identity::random("Me", 1)));
+        assert_eq!("[chan] general (chan)", row_label(&Identity::chan("general", 1)));
+        let unlabelled = Identity::random("", 1);
+        assert_eq!(unlabelled.address().to_string(), row_label(&unlabelled));
+    }
+}
@@ -1,31 +1,96 @@
-extern crate gtk;
 extern crate bm_client;
+extern crate glib;
+extern crate gtk;
+extern crate rustc_serialize;
+
+mod compose;
+mod identities;
+mod messages;
+mod network;
+mod window;
 
-use gtk::prelude::*;
-use bm_client::BMClient;
+use bm_client::{BMClient,Config,ConfigBuilder};
+use std::cell::RefCell;
+use std::env;
+use std::process::exit;
+use std::rc::Rc;
+use window::MainWindow;
+
+const USAGE: &'static str = "Usage: rubbem [--config FILE] [--data-dir DIR]";
+
+struct Options {
+    config_file: Option<String>,
+    data_dir: Option<String>
+}
 
 fn main() {
-    let mut bm_client = BMClient::new();
-    bm_client.start();
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            exit(2);
+        }
+    };
+
+    let mut bm_client = match load_config(&options).and_then(|config| BMClient::with_config(config).map_err(|err| err.to_string())) {
+        Ok(bm_client) => bm_client,
+        Err(err) => {
+            println!("Cannot start: {}", err);
+            return;
+        }
+    };
+
+    if let Err(err) = bm_client.start() {
+        println!("Cannot start: {}", err);
+        return;
+    }
 
+    let bm_client = Rc::new(RefCell::new(bm_client));
     match gtk::init() {
-        Err(_) => println!("Cannot start because GTK is not working / available."),
-        Ok(_) => gtk_main()
+        Err(_) => println!("Cannot start because GTK is not working / available. Use rubbemd to run without a user interface."),
+        Ok(_) => gtk_main(bm_client.clone())
     }
+
+    bm_client.borrow_mut().stop();
 }
 
-fn gtk_main()
-{
-    let window = gtk::Window::new(gtk::WindowType::Toplevel);
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        config_file: None,
+        data_dir: None
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let target = match arg.as_str() {
+            "--config" => &mut options.config_file,
+            "--data-dir" => &mut options.data_dir,
+            "--help" | "-h" => return Err("Runs Rubbem with a GTK user interface.".to_string()),
+            _ => return Err(format!("Unknown argument {}", arg))
+        };
+        match args.next() {
+            Some(value) => *target = Some(value),
+            None => return Err(format!("{} needs a value", arg))
+        }
+    }
 
-    window.set_title("Rubbem");
-    window.set_position(gtk::WindowPosition::Center);
+    Ok(options)
+}
 
-    window.connect_delete_event(|_, _| {
-        gtk::main_quit();
-        gtk::Inhibit(false)
-    });
+fn load_config(options: &Options) -> Result<Config, String> {
+    let mut builder = match options.config_file {
+        Some(ref path) => try!(ConfigBuilder::from_file(path).map_err(|err| err.to_string())),
+        None => Config::builder()
+    };
+    if let Some(ref data_dir) = options.data_dir {
+        builder = builder.data_dir(data_dir);
+    }
+    builder.build().map_err(|err| err.to_string())
+}
 
-    window.show_all();
+fn gtk_main(bm_client: Rc<RefCell<BMClient>>)
+{
+    let main_window = MainWindow::new(bm_client);
+    main_window.show();
     gtk::main();
 }
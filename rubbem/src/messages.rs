@@ -0,0 +1,316 @@
+use bm_client::{Address,Event,Folder,MessageQuery,OutboxStatus,StoredMessage};
+use glib;
+use gtk;
+use gtk::prelude::*;
+use rustc_serialize::hex::{FromHex,ToHex};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+use window::{SharedClient,find_row,get_string,scrolled,text_column};
+
+// The inbox and sent messages, with the selected one shown underneath
+
+const ID_COLUMN: i32 = 0;
+const ADDRESS_COLUMN: i32 = 1;
+const SUBJECT_COLUMN: i32 = 2;
+const DETAIL_COLUMN: i32 = 3; // when it came in, or how far it has got
+const WEIGHT_COLUMN: i32 = 4;
+
+const NORMAL_WEIGHT: i32 = 400;
+const UNREAD_WEIGHT: i32 = 700;
+
+struct MessageList {
+    store: gtk::ListStore,
+    view: gtk::TreeView,
+    folders: &'static [Folder]
+}
+
+impl MessageList {
+    fn new(address_title: &str, detail_title: &str, folders: &'static [Folder]) -> MessageList {
+        let store = gtk::ListStore::new(&[ glib::Type::String, glib::Type::String, glib::Type::String, glib::Type::String, glib::Type::I32 ]);
+        let view = gtk::TreeView::with_model(&store);
+        text_column(&view, address_title, ADDRESS_COLUMN, Some(WEIGHT_COLUMN));
+        text_column(&view, "Subject", SUBJECT_COLUMN, Some(WEIGHT_COLUMN));
+        text_column(&view, detail_title, DETAIL_COLUMN, Some(WEIGHT_COLUMN));
+        MessageList {
+            store: store,
+            view: view,
+            folders: folders
+        }
+    }
+
+    fn load(&self, messages: &[StoredMessage]) {
+        self.store.clear();
+        for message in messages.iter().filter(|message| self.folders.contains(&message.folder())) {
+            self.insert(message, None);
+        }
+    }
+
+    fn insert(&self, message: &StoredMessage, position: Option<u32>) {
+        let address = correspondent(message);
+        let detail = match message.status() {
+            Some(status) => status_text(status).to_string(),
+            None => format_time(message)
+        };
+        let weight = if message.is_read() { NORMAL_WEIGHT } else { UNREAD_WEIGHT };
+        self.store.insert_with_values(position, &[ ID_COLUMN as u32, ADDRESS_COLUMN as u32, SUBJECT_COLUMN as u32, DETAIL_COLUMN as u32, WEIGHT_COLUMN as u32 ],
+            &[ &message.id().to_hex(), &address, &message.subject(), &detail, &weight ]);
+    }
+
+    fn find(&self, id: &[u8]) -> Option<gtk::TreeIter> {
+        find_row(&self.store, ID_COLUMN, &id.to_hex())
+    }
+
+    fn selected_id(&self) -> Option<Vec<u8>> {
+        self.view.get_selection().get_selected().and_then(|(model, iter)| get_string(&model, &iter, ID_COLUMN).from_hex().ok())
+    }
+}
+
+pub struct MessageView {
+    bm_client: SharedClient,
+    widget: gtk::Box,
+    compose_button: gtk::Button,
+    tabs: gtk::Notebook,
+    inbox: MessageList,
+    sent: MessageList,
+    viewer: gtk::TextView,
+    identity: RefCell<Option<Address>>
+}
+
+impl MessageView {
+    pub fn new(bm_client: SharedClient, parent: &gtk::Window) -> Rc<MessageView> {
+        let inbox = MessageList::new("From", "Received", &[ Folder::Inbox ]);
+        let sent = MessageList::new("To", "Status", &[ Folder::Outbox, Folder::Sent ]);
+        let tabs = gtk::Notebook::new();
+        tabs.append_page(&scrolled(&inbox.view), Some(&gtk::Label::new(Some("Inbox"))));
+        tabs.append_page(&scrolled(&sent.view), Some(&gtk::Label::new(Some("Sent"))));
+
+        let viewer = gtk::TextView::new();
+        viewer.set_editable(false);
+        viewer.set_cursor_visible(false);
+        viewer.set_wrap_mode(gtk::WrapMode::Word);
+        viewer.set_left_margin(6);
+
+        let compose_button = gtk::Button::with_label("Compose");
+        let trash_button = gtk::Button::with_label("Trash");
+        let buttons = gtk::Box::new(gtk::Orientation::Horizontal, 6);
+        buttons.pack_start(&compose_button, false, false, 0);
+        buttons.pack_start(&trash_button, false, false, 0);
+
+        let paned = gtk::Paned::new(gtk::Orientation::Vertical);
+        paned.pack1(&tabs, true, false);
+        paned.pack2(&scrolled(&viewer), true, false);
+        paned.set_position(parent.get_default_size().1 / 2);
+
+        let widget = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        widget.pack_start(&buttons, false, false, 0);
+        widget.pack_start(&paned, true, true, 0);
+
+        let message_view = Rc::new(MessageView {
+            bm_client: bm_client,
+            widget: widget,
+            compose_button: compose_button,
+            tabs: tabs,
+            inbox: inbox,
+            sent: sent,
+            viewer: viewer,
+            identity: RefCell::new(None)
+        });
+        message_view.reload();
+
+        for list in &[ &message_view.inbox, &message_view.sent ] {
+            let message_view = message_view.clone();
+            list.view.get_selection().connect_changed(move |selection| {
+                if let Some((model, iter)) = selection.get_selected() {
+                    if let Ok(id) = get_string(&model, &iter, ID_COLUMN).from_hex() {
+                        message_view.open(&id);
+                    }
+                }
+            });
+        }
+        {
+            let message_view = message_view.clone();
+            trash_button.connect_clicked(move |_| message_view.trash_selected());
+        }
+        {
+            let view = message_view.clone();
+            message_view.tabs.connect_switch_page(move |_, _, _| view.show_text(""));
+        }
+        message_view
+    }
+
+    pub fn widget(&self) -> &gtk::Box {
+        &self.widget
+    }
+
+    pub fn connect_compose<F: Fn() + 'static>(&self, f: F) {
+        self.compose_button.connect_clicked(move |_| f());
+    }
+
+    // None shows the messages of every identity
+    pub fn show_identity(&self, address: Option<Address>) {
+        *self.identity.borrow_mut() = address;
+        self.reload();
+    }
+
+    pub fn handle(&self, event: &Event) {
+        match list_change(event) {
+            Some(ListChange::Received(msgid)) => {
+                let message = self.bm_client.borrow().message(msgid);
+                if let Some(message) = message {
+                    if message.folder() == Folder::Inbox && is_shown(self.identity.borrow().as_ref(), &message) {
+                        self.inbox.insert(&message, Some(0));
+                    }
+                }
+            },
+            Some(ListChange::Status(ackdata, status)) => self.update_status(ackdata, status),
+            None => {}
+        }
+    }
+
+    fn reload(&self) {
+        let query = match *self.identity.borrow() {
+            Some(ref address) => MessageQuery::new().identity(address),
+            None => MessageQuery::new()
+        };
+        let messages = self.bm_client.borrow().messages(&query);
+        self.inbox.load(&messages);
+        self.sent.load(&messages);
+        self.show_text("");
+    }
+
+    // A message we haven't listed yet has just been queued
+    fn update_status(&self, ackdata: &[u8], status: OutboxStatus) {
+        match self.sent.find(ackdata) {
+            Some(iter) => self.sent.store.set_value(&iter, DETAIL_COLUMN as u32, &status_text(status).to_value()),
+            None => {
+                let message = self.bm_client.borrow().message(ackdata);
+                if let Some(message) = message {
+                    if self.sent.folders.contains(&message.folder()) && is_shown(self.identity.borrow().as_ref(), &message) {
+                        self.sent.insert(&message, Some(0));
+                    }
+                }
+            }
+        }
+    }
+
+    fn open(&self, id: &[u8]) {
+        let message = match self.bm_client.borrow().message(id) {
+            Some(message) => message,
+            None => return
+        };
+        if !message.is_read() {
+            self.bm_client.borrow_mut().mark_read(id, true);
+            if let Some(iter) = self.inbox.find(id) {
+                self.inbox.store.set_value(&iter, WEIGHT_COLUMN as u32, &NORMAL_WEIGHT.to_value());
+            }
+        }
+
+        self.show_text(&message_text(&message, &format_time(&message)));
+    }
+
+    fn trash_selected(&self) {
+        let list = match self.tabs.get_current_page() {
+            Some(1) => &self.sent,
+            _ => &self.inbox
+        };
+        if let Some(id) = list.selected_id() {
+            self.bm_client.borrow_mut().trash_message(&id);
+            if let Some(iter) = list.find(&id) {
+                list.store.remove(&iter);
+            }
+            self.show_text("");
+        }
+    }
+
+    fn show_text(&self, text: &str) {
+        if let Some(buffer) = self.viewer.get_buffer() {
+            buffer.set_text(text);
+        }
+    }
+}
+
+// What an event changes in the lists
+#[derive(Debug,PartialEq)]
+enum ListChange<'a> {
+    Received(&'a [u8]),
+    Status(&'a [u8], OutboxStatus)
+}
+
+fn list_change(event: &Event) -> Option<ListChange> {
+    match event {
+        &Event::InboxMessage { ref msgid } => Some(ListChange::Received(msgid)),
+        &Event::OutboxStatusChanged { ref ackdata, status } => Some(ListChange::Status(ackdata, status)),
+        &Event::AckReceived { ref ackdata } => Some(ListChange::Status(ackdata, OutboxStatus::AckReceived)),
+        _ => None
+    }
+}
+
+// Whether the message belongs to the identity picked, if any
+fn is_shown(identity: Option<&Address>, message: &StoredMessage) -> bool {
+    let ours = if message.is_ours() { Some(message.from()) } else { message.to() };
+    identity.map_or(true, |identity| ours == Some(identity))
+}
+
+// The other side of the conversation
+fn correspondent(message: &StoredMessage) -> String {
+    match (message.is_ours(), message.to()) {
+        (false, _) => message.from().to_string(),
+        (true, Some(to)) => to.to_string(),
+        (true, None) => "[broadcast]".to_string()
+    }
+}
+
+fn message_text(message: &StoredMessage, time: &str) -> String {
+    let to = message.to().map(|to| to.to_string()).unwrap_or("[broadcast]".to_string());
+    format!("From: {}\nTo: {}\nDate: {}\nSubject: {}\n\n{}", message.from(), to, time, message.subject(), message.body())
+}
+
+fn format_time(message: &StoredMessage) -> String {
+    let secs = message.timestamp().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+    glib::DateTime::from_unix_local(secs as i64).format("%Y-%m-%d %H:%M").map(|time| time.to_string()).unwrap_or(String::new())
+}
+
+fn status_text(status: OutboxStatus) -> &'static str {
+    match status {
+        OutboxStatus::Queued => "Queued",
+        OutboxStatus::AwaitingPubKey => "Waiting for pubkey",
+        OutboxStatus::DoingPow => "Doing proof of work",
+        OutboxStatus::Sent => "Sent",
+        OutboxStatus::AckReceived => "Acknowledged",
+        OutboxStatus::Failed => "Failed",
+        OutboxStatus::GaveUp => "Gave up"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bm_client::{BMClient,Event,OutboxStatus,SendOptions};
+    use super::{ListChange,correspondent,is_shown,list_change,message_text};
+
+    #[test]
+    fn test_list_change() {
+        assert_eq!(Some(ListChange::Received(&[ 1 ])), list_change(&Event::InboxMessage { msgid: vec![ 1 ] }));
+        assert_eq!(Some(ListChange::Status(&[ 2 ], OutboxStatus::DoingPow)), list_change(&Event::OutboxStatusChanged { ackdata: vec![ 2 ], status: OutboxStatus::DoingPow }));
+        assert_eq!(Some(ListChange::Status(&[ 2 ], OutboxStatus::AckReceived)), list_change(&Event::AckReceived { ackdata: vec![ 2 ] }));
+        assert_eq!(None, list_change(&Event::ObjectCount(1)));
+    }
+
+    #[test]
+    fn test_sent_message_rows() {
+        let mut bm_client = BMClient::new().unwrap();
+        let me = bm_client.create_identity("Me");
+        let other = bm_client.create_identity("Other");
+        let someone = BMClient::new().unwrap().create_identity("Someone");
+        let ackdata = bm_client.send_message(&me, &someone, "Hi", "Hello", SendOptions::new()).unwrap();
+        let message = bm_client.message(&ackdata).unwrap();
+        let broadcast = bm_client.send_broadcast(&me, "News", "", SendOptions::new()).unwrap();
+
+        assert_eq!(someone.to_string(), correspondent(&message));
+        assert_eq!("[broadcast]", correspondent(&bm_client.message(&broadcast).unwrap()));
+        assert!(is_shown(None, &message));
+        assert!(is_shown(Some(&me), &message));
+        assert!(!is_shown(Some(&other), &message));
+        assert_eq!(format!("From: {}\nTo: {}\nDate: today\nSubject: Hi\n\nHello", me, someone), message_text(&message, "today"));
+    }
+}
@@ -0,0 +1,152 @@
+use bm_client::{ConnectionState,Event,PeerAddr};
+use glib;
+use gtk;
+use gtk::prelude::*;
+use std::rc::Rc;
+use window::{SharedClient,find_row,form,scrolled,text_column};
+
+// Who we're connected to and how far each connection has got, with the objects we hold
+
+const PEER_COLUMN: i32 = 0;
+const STATE_COLUMN: i32 = 1;
+
+pub struct NetworkView {
+    bm_client: SharedClient,
+    widget: gtk::Box,
+    store: gtk::ListStore,
+    getpubkeys: gtk::Label,
+    pubkeys: gtk::Label,
+    msgs: gtk::Label,
+    broadcasts: gtk::Label,
+    total: gtk::Label
+}
+
+impl NetworkView {
+    pub fn new(bm_client: SharedClient) -> Rc<NetworkView> {
+        let store = gtk::ListStore::new(&[ glib::Type::String, glib::Type::String ]);
+        let view = gtk::TreeView::with_model(&store);
+        text_column(&view, "Peer", PEER_COLUMN, None);
+        text_column(&view, "Connection", STATE_COLUMN, None);
+
+        let (getpubkeys, pubkeys, msgs, broadcasts, total) = (count_label(), count_label(), count_label(), count_label(), count_label());
+        let counts = form(&[
+            ("Pubkey requests", getpubkeys.upcast_ref()),
+            ("Pubkeys", pubkeys.upcast_ref()),
+            ("Messages", msgs.upcast_ref()),
+            ("Broadcasts", broadcasts.upcast_ref()),
+            ("Objects", total.upcast_ref())
+        ]);
+
+        let widget = gtk::Box::new(gtk::Orientation::Vertical, 6);
+        widget.pack_start(&scrolled(&view), true, true, 0);
+        widget.pack_start(&counts, false, false, 0);
+
+        let network_view = Rc::new(NetworkView {
+            bm_client: bm_client,
+            widget: widget,
+            store: store,
+            getpubkeys: getpubkeys,
+            pubkeys: pubkeys,
+            msgs: msgs,
+            broadcasts: broadcasts,
+            total: total
+        });
+
+        let connections = network_view.bm_client.borrow().connections();
+        for (peer, state) in connections {
+            network_view.set_row(&peer, state_text(state));
+        }
+        network_view.refresh_stats();
+        network_view
+    }
+
+    pub fn widget(&self) -> &gtk::Box {
+        &self.widget
+    }
+
+    pub fn handle(&self, event: &Event) {
+        match event {
+            &Event::ObjectCount(_) => self.refresh_stats(),
+            event => if let Some((peer, state)) = peer_row(event) {
+                self.set_row(&peer, state);
+            }
+        }
+    }
+
+    fn refresh_stats(&self) {
+        let stats = self.bm_client.borrow().object_stats();
+        self.getpubkeys.set_text(&stats.getpubkeys().to_string());
+        self.pubkeys.set_text(&stats.pubkeys().to_string());
+        self.msgs.set_text(&stats.msgs().to_string());
+        self.broadcasts.set_text(&stats.broadcasts().to_string());
+        self.total.set_text(&stats.total().to_string());
+    }
+
+    // A peer without a state is taken off the list
+    fn set_row(&self, peer: &PeerAddr, state: Option<String>) {
+        match (state, self.find(peer)) {
+            (None, Some(iter)) => {
+                self.store.remove(&iter);
+            },
+            (None, None) => {},
+            (Some(state), Some(iter)) => self.store.set_value(&iter, STATE_COLUMN as u32, &state.to_value()),
+            (Some(state), None) => {
+                self.store.insert_with_values(None, &[ PEER_COLUMN as u32, STATE_COLUMN as u32 ], &[ &peer.to_string(), &state ]);
+            }
+        }
+    }
+
+    fn find(&self, peer: &PeerAddr) -> Option<gtk::TreeIter> {
+        find_row(&self.store, PEER_COLUMN, &peer.to_string())
+    }
+}
+
+// The row an event changes, with what its state column should now say
+fn peer_row(event: &Event) -> Option<(PeerAddr, Option<String>)> {
+    match event {
+        &Event::ConnectionStateChanged(peer, state) => Some((peer, state_text(state))),
+        &Event::PeerDisconnected(peer) => Some((peer, None)),
+        _ => None
+    }
+}
+
+// Connections that have ended have no state worth showing
+fn state_text(state: ConnectionState) -> Option<String> {
+    match state {
+        ConnectionState::Stale | ConnectionState::Error => None,
+        state => Some(state.to_string())
+    }
+}
+
+fn count_label() -> gtk::Label {
+    let label = gtk::Label::new(Some("0"));
+    label.set_xalign(0.0);
+    label
+}
+
+#[cfg(test)]
+mod tests {
+    use bm_client::{ConnectionState,Event,PeerAddr};
+    use std::net::SocketAddr;
+    use std::str::FromStr;
+    use std::time::Instant;
+    use super::{peer_row,state_text};
+
+    #[test]
+    fn test_state_text() {
+        assert_eq!(Some("connecting".to_string()), state_text(ConnectionState::Fresh(Instant::now())));
+        assert_eq!(Some("established".to_string()), state_text(ConnectionState::Established(Instant::now())));
+        assert_eq!(None, state_text(ConnectionState::Stale));
+        assert_eq!(None, state_text(ConnectionState::Error));
+    }
+
+    #[test]
+    fn test_peer_row() {
+        let peer = PeerAddr::from(SocketAddr::from_str("127.0.0.1:8444").unwrap());
+        assert_eq!(Some((peer, Some("handshaking".to_string()))), peer_row(&Event::ConnectionStateChanged(peer, ConnectionState::GotVersionAwaitingVerack(Instant::now()))));
+        assert_eq!(Some((peer, None)), peer_row(&Event::ConnectionStateChanged(peer, ConnectionState::Error)));
+        assert_eq!(Some((peer, None)), peer_row(&Event::PeerDisconnected(peer)));
+        assert_eq!(None, peer_row(&Event::PeerConnected(peer)));
+        assert_eq!(None, peer_row(&Event::ObjectCount(1)));
+    }
+}
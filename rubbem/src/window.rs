@@ -0,0 +1,143 @@
+use bm_client::{BMClient,Event};
+use compose::Compose;
+use glib;
+use gtk;
+use gtk::prelude::*;
+use identities::IdentityList;
+use messages::MessageView;
+use network::NetworkView;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::thread;
+
+// The client is only touched from the GTK thread
+pub type SharedClient = Rc<RefCell<BMClient>>;
+
+pub struct MainWindow {
+    window: gtk::Window
+}
+
+impl MainWindow {
+    pub fn new(bm_client: SharedClient) -> MainWindow {
+        let window = gtk::Window::new(gtk::WindowType::Toplevel);
+        window.set_title("Rubbem");
+        window.set_position(gtk::WindowPosition::Center);
+        window.set_default_size(900, 600);
+        window.connect_delete_event(|_, _| {
+            gtk::main_quit();
+            gtk::Inhibit(false)
+        });
+
+        let identities = IdentityList::new(bm_client.clone(), &window);
+        let messages = MessageView::new(bm_client.clone(), &window);
+        let network = NetworkView::new(bm_client.clone());
+        let composers: Rc<RefCell<Vec<Rc<Compose>>>> = Rc::new(RefCell::new(vec![]));
+
+        {
+            let messages = messages.clone();
+            identities.connect_selected(move |address| messages.show_identity(address));
+        }
+        {
+            let (bm_client, window, composers) = (bm_client.clone(), window.clone(), composers.clone());
+            messages.connect_compose(move || {
+                let compose = Compose::new(bm_client.clone(), &window);
+                compose.show();
+                composers.borrow_mut().push(compose);
+            });
+        }
+
+        let tabs = gtk::Notebook::new();
+        tabs.append_page(messages.widget(), Some(&gtk::Label::new(Some("Messages"))));
+        tabs.append_page(network.widget(), Some(&gtk::Label::new(Some("Network"))));
+
+        let paned = gtk::Paned::new(gtk::Orientation::Horizontal);
+        paned.pack1(identities.widget(), false, false);
+        paned.pack2(&tabs, true, false);
+        window.add(&paned);
+
+        // Events arrive on the client's threads, so they are handed over to the GTK main loop
+        let events = bm_client.borrow().subscribe();
+        let (sender, receiver) = glib::MainContext::channel(glib::PRIORITY_DEFAULT);
+        thread::spawn(move || for event in events.iter() {
+            if sender.send(event).is_err() {
+                break;
+            }
+        });
+        receiver.attach(None, move |event: Event| {
+            messages.handle(&event);
+            network.handle(&event);
+            composers.borrow_mut().retain(|compose| compose.handle(&event));
+            glib::Continue(true)
+        });
+
+        MainWindow {
+            window: window
+        }
+    }
+
+    pub fn show(&self) {
+        self.window.show_all();
+    }
+}
+
+pub fn show_error(parent: &gtk::Window, message: &str) {
+    let dialog = gtk::MessageDialog::new(Some(parent), gtk::DialogFlags::MODAL, gtk::MessageType::Error, gtk::ButtonsType::Ok, message);
+    dialog.run();
+    dialog.close();
+}
+
+// Puts each widget on a row after its label
+pub fn form(rows: &[(&str, &gtk::Widget)]) -> gtk::Grid {
+    let grid = gtk::Grid::new();
+    grid.set_row_spacing(6);
+    grid.set_column_spacing(12);
+    grid.set_border_width(12);
+    for (row, &(label, widget)) in rows.iter().enumerate() {
+        let label = gtk::Label::new(Some(label));
+        label.set_xalign(0.0);
+        grid.attach(&label, 0, row as i32, 1, 1);
+        widget.set_hexpand(true);
+        grid.attach(widget, 1, row as i32, 1, 1);
+    }
+    grid
+}
+
+pub fn text_column(view: &gtk::TreeView, title: &str, column: i32, weight_column: Option<i32>) {
+    let cell = gtk::CellRendererText::new();
+    let view_column = gtk::TreeViewColumn::new();
+    view_column.set_title(title);
+    view_column.set_resizable(true);
+    view_column.pack_start(&cell, true);
+    view_column.add_attribute(&cell, "text", column);
+    if let Some(weight_column) = weight_column {
+        view_column.add_attribute(&cell, "weight", weight_column);
+    }
+    view.append_column(&view_column);
+}
+
+pub fn scrolled<W: IsA<gtk::Widget>>(widget: &W) -> gtk::ScrolledWindow {
+    let scrolled = gtk::ScrolledWindow::new(None::<&gtk::Adjustment>, None::<&gtk::Adjustment>);
+    scrolled.set_policy(gtk::PolicyType::Automatic, gtk::PolicyType::Automatic);
+    scrolled.add(widget);
+    scrolled
+}
+
+pub fn get_string(model: &gtk::TreeModel, iter: &gtk::TreeIter, column: i32) -> String {
+    model.get_value(iter, column).get::<String>().ok().and_then(|value| value).unwrap_or(String::new())
+}
+
+pub fn find_row(store: &gtk::ListStore, column: i32, value: &str) -> Option<gtk::TreeIter> {
+    let model = store.clone().upcast::<gtk::TreeModel>();
+    let iter = match store.get_iter_first() {
+        Some(iter) => iter,
+        None => return None
+    };
+    loop {
+        if get_string(&model, &iter, column) == value {
+            return Some(iter);
+        }
+        if !store.iter_next(&iter) {
+            return None;
+        }
+    }
+}
@@ -0,0 +1,232 @@
+extern crate bm_client;
+extern crate rustc_serialize;
+extern crate signal_hook;
+
+use bm_client::{ApiServer,BMClient,Config,ConfigBuilder,Event,MailGateway};
+use rustc_serialize::hex::ToHex;
+use signal_hook::consts::{SIGINT,SIGTERM};
+use std::env;
+use std::fs::{File,OpenOptions};
+use std::io::{self,Write};
+use std::process::exit;
+use std::sync::{Arc,Mutex};
+use std::sync::atomic::{AtomicBool,Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::{Duration,Instant,SystemTime,UNIX_EPOCH};
+
+// Runs the client without a UI, driven through the API server and the mail gateway
+
+const POLL_INTERVAL_MILLIS: u64 = 200;
+const SERVER_STOP_TIMEOUT_SECS: u64 = 5;
+
+const USAGE: &'static str = "Usage: rubbemd [--config FILE] [--data-dir DIR] [--log FILE]";
+
+struct Options {
+    config_file: Option<String>,
+    data_dir: Option<String>,
+    log_file: Option<String>
+}
+
+enum Log {
+    Stderr,
+    File(File)
+}
+
+impl Log {
+    fn open(path: Option<&str>) -> io::Result<Log> {
+        match path {
+            Some(path) => OpenOptions::new().create(true).append(true).open(path).map(Log::File),
+            None => Ok(Log::Stderr)
+        }
+    }
+
+    fn write(&mut self, line: &str) {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or(0);
+        let line = format!("{} {}\n", secs, line);
+        let _ = match self {
+            &mut Log::Stderr => io::stderr().write_all(line.as_bytes()),
+            &mut Log::File(ref mut file) => file.write_all(line.as_bytes())
+        };
+    }
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}\n{}", message, USAGE);
+            exit(2);
+        }
+    };
+
+    let mut log = match Log::open(options.log_file.as_ref().map(|path| path.as_str())) {
+        Ok(log) => log,
+        Err(err) => {
+            eprintln!("Cannot open log file: {}", err);
+            exit(1);
+        }
+    };
+
+    if let Err(message) = run(&options, &mut log) {
+        log.write(&message);
+        exit(1);
+    }
+}
+
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut options = Options {
+        config_file: None,
+        data_dir: None,
+        log_file: None
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let target = match arg.as_str() {
+            "--config" => &mut options.config_file,
+            "--data-dir" => &mut options.data_dir,
+            "--log" => &mut options.log_file,
+            "--help" | "-h" => return Err("Runs Rubbem without a user interface.".to_string()),
+            _ => return Err(format!("Unknown argument {}", arg))
+        };
+        match args.next() {
+            Some(value) => *target = Some(value),
+            None => return Err(format!("{} needs a value", arg))
+        }
+    }
+
+    Ok(options)
+}
+
+fn load_config(options: &Options) -> Result<Config, String> {
+    let mut builder = match options.config_file {
+        Some(ref path) => try!(ConfigBuilder::from_file(path).map_err(|err| err.to_string())),
+        None => Config::builder()
+    };
+    if let Some(ref data_dir) = options.data_dir {
+        builder = builder.data_dir(data_dir);
+    }
+    builder.build().map_err(|err| err.to_string())
+}
+
+fn run(options: &Options, log: &mut Log) -> Result<(), String> {
+    let config = try!(load_config(options));
+
+    // Registered before anything starts, so an early signal still shuts down cleanly
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for &signal in &[ SIGINT, SIGTERM ] {
+        try!(signal_hook::flag::register(signal, shutdown.clone()).map_err(|err| format!("Cannot handle signals: {}", err)));
+    }
+
+    let mut client = try!(BMClient::with_config(config.clone()).map_err(|err| format!("Cannot start: {}", err)));
+    let events = client.subscribe();
+    try!(client.start().map_err(|err| format!("Cannot start: {}", err)));
+    log.write("Started");
+
+    let client = Arc::new(Mutex::new(client));
+    let mut api_server = ApiServer::new(&config, client.clone());
+    if config.api_enabled() {
+        if let Err(err) = api_server.start() {
+            client.lock().unwrap().stop();
+            return Err(format!("Cannot start the API server: {}", err));
+        }
+        log.write(&format!("API server listening on {}", api_server.local_addr().unwrap()));
+    }
+    let mut gateway = MailGateway::new(&config, client.clone());
+    if config.gateway_enabled() {
+        if let Err(err) = gateway.start() {
+            client.lock().unwrap().stop();
+            return Err(format!("Cannot start the mail gateway: {}", err));
+        }
+        log.write(&format!("Mail gateway listening for SMTP on {} and IMAP on {}", gateway.smtp_addr().unwrap(), gateway.imap_addr().unwrap()));
+    }
+
+    while !shutdown.load(Ordering::Relaxed) {
+        match events.recv_timeout(Duration::from_millis(POLL_INTERVAL_MILLIS)) {
+            Ok(event) => if let Some(line) = describe(&event) {
+                log.write(&line);
+            },
+            Err(RecvTimeoutError::Timeout) => {},
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+    }
+
+    log.write("Shutting down");
+    api_server.stop(Instant::now() + Duration::from_secs(SERVER_STOP_TIMEOUT_SECS));
+    gateway.stop(Instant::now() + Duration::from_secs(SERVER_STOP_TIMEOUT_SECS));
+    client.lock().unwrap().stop();
+    log.write("Stopped");
+    Ok(())
+}
+
+// Progress events are too frequent to be worth logging, and connection states repeat the peer events
+fn describe(event: &Event) -> Option<String> {
+    match event {
+        &Event::InboxMessage { ref msgid } => Some(format!("Received message {}", msgid.to_hex())),
+        &Event::AckReceived { ref ackdata } => Some(format!("Message {} was acknowledged", ackdata.to_hex())),
+        &Event::OutboxStatusChanged { ref ackdata, status } => Some(format!("Message {} is now {:?}", ackdata.to_hex(), status)),
+        &Event::PeerConnected(ref peer) => Some(format!("Connected to {}", peer)),
+        &Event::PeerDisconnected(ref peer) => Some(format!("Disconnected from {}", peer)),
+        &Event::Warning(ref warning) => Some(warning.clone()),
+        &Event::PowProgress { .. } | &Event::ObjectCount(_) | &Event::ConnectionStateChanged(..) => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bm_client::{Event,OutboxStatus,PeerAddr};
+    use std::env::temp_dir;
+    use std::fs::{File,remove_file};
+    use std::io::Write;
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::process;
+    use super::{Options,describe,load_config,parse_args};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(args(&[ "--config", "rubbem.conf", "--data-dir", "data", "--log", "rubbemd.log" ])).ok().unwrap();
+        assert_eq!(Some("rubbem.conf".to_string()), options.config_file);
+        assert_eq!(Some("data".to_string()), options.data_dir);
+        assert_eq!(Some("rubbemd.log".to_string()), options.log_file);
+
+        assert_eq!(Some("Unknown argument --verbose".to_string()), parse_args(args(&[ "--verbose" ])).err());
+        assert_eq!(Some("--data-dir needs a value".to_string()), parse_args(args(&[ "--config", "rubbem.conf", "--data-dir" ])).err());
+        assert_eq!(Some("Runs Rubbem without a user interface.".to_string()), parse_args(args(&[ "--help" ])).err());
+    }
+
+    #[test]
+    fn test_describe() {
+        let peer = PeerAddr::Ip("127.0.0.1:8444".parse::<SocketAddr>().unwrap());
+        assert_eq!(Some("Received message 0102".to_string()), describe(&Event::InboxMessage { msgid: vec![ 1, 2 ] }));
+        assert_eq!(Some("Message 0a is now Sent".to_string()), describe(&Event::OutboxStatusChanged { ackdata: vec![ 10 ], status: OutboxStatus::Sent }));
+        assert_eq!(Some("Connected to 127.0.0.1:8444".to_string()), describe(&Event::PeerConnected(peer)));
+        assert_eq!(Some("Could not save messages: disk full".to_string()), describe(&Event::Warning("Could not save messages: disk full".to_string())));
+        assert_eq!(None, describe(&Event::ObjectCount(3)));
+    }
+
+    #[test]
+    fn test_data_dir_argument_overrides_config_file() {
+        let config_path = temp_dir().join(format!("rubbemd_test_{}.conf", process::id()));
+        File::create(&config_path).unwrap().write_all(b"data_dir = from-file\n[network]\nport = 8555\n").unwrap();
+        let mut options = Options {
+            config_file: Some(config_path.to_str().unwrap().to_string()),
+            data_dir: None,
+            log_file: None
+        };
+
+        let config = load_config(&options).unwrap();
+        assert_eq!(Some(Path::new("from-file")), config.data_dir());
+        assert_eq!(8555, config.port());
+
+        options.data_dir = Some("from-args".to_string());
+        let config = load_config(&options).unwrap();
+        assert_eq!(Some(Path::new("from-args")), config.data_dir());
+        assert_eq!(8555, config.port());
+        remove_file(&config_path).unwrap();
+    }
+}